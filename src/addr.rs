@@ -0,0 +1,67 @@
+use core::mem::size_of;
+
+use crate::{
+    disk::DiskError,
+    fs::{FsError, BLOCK_SIZE, INODE_SIZE},
+};
+
+/// The number of block pointers in an indirect block
+pub const POINTERS_PER_BLOCK: usize = BLOCK_SIZE / size_of::<u32>();
+
+/// A byte address on the disk.
+///
+/// All conversions between block ids, inode numbers and byte addresses go through here, so they
+/// can't be mixed up and an overflow (which can only come from garbage on the disk) turns into
+/// `FsError::Corrupted` instead of silently wrapping around.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Addr(usize);
+
+impl Addr {
+    /// The address of the first byte of the block `block_id`
+    pub fn block(block_id: u32) -> Result<Self, FsError> {
+        (block_id as usize)
+            .checked_mul(BLOCK_SIZE)
+            .map(Self)
+            .ok_or(FsError::Corrupted)
+    }
+
+    /// `block` for the IO layers below a `Disk`, which report an address that doesn't fit as out
+    /// of bounds
+    pub fn block_io(block_id: u32) -> Result<Self, DiskError> {
+        Self::block(block_id).map_err(|_| DiskError::OutOfBounds)
+    }
+
+    /// The address of the inode `inode_nbr`
+    pub fn inode(inode_nbr: u32) -> Result<Self, FsError> {
+        (inode_nbr as usize)
+            .checked_mul(INODE_SIZE)
+            .map(Self)
+            .ok_or(FsError::Corrupted)
+    }
+
+    /// The address of the `index`th block pointer in the indirect block `block_id`
+    pub fn pointer_entry(block_id: u32, index: usize) -> Result<Self, FsError> {
+        if index >= POINTERS_PER_BLOCK {
+            return Err(FsError::Corrupted);
+        }
+        Self::block(block_id)?.offset(index * size_of::<u32>())
+    }
+
+    pub fn offset(self, off: usize) -> Result<Self, FsError> {
+        self.0.checked_add(off).map(Self).ok_or(FsError::Corrupted)
+    }
+
+    pub fn get(self) -> usize {
+        self.0
+    }
+
+    /// The block this address lies in
+    pub fn block_id(self) -> Result<u32, FsError> {
+        u32::try_from(self.0 / BLOCK_SIZE).map_err(|_| FsError::Corrupted)
+    }
+
+    /// The number of the inode at this address
+    pub fn inode_nbr(self) -> Result<u32, FsError> {
+        u32::try_from(self.0 / INODE_SIZE).map_err(|_| FsError::Corrupted)
+    }
+}
@@ -0,0 +1,228 @@
+use std::{
+    fs::File,
+    future::Future,
+    os::unix::fs::FileExt,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+use tokio::{runtime::Handle, task::spawn_blocking};
+
+use crate::{
+    directory::DirEntry,
+    disk::{Disk, DiskError, IO},
+    fs::{FileSystem, FsError, MountOptions, Stat},
+};
+
+/// What the methods of `AsyncIO` return
+pub type IoFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, DiskError>> + Send + 'a>>;
+
+/// `IO` for backends that are async themselves, like network storage. See `AsyncDisk` for using
+/// one as the disk of a file system.
+pub trait AsyncIO: Send {
+    fn read_lossy<'a>(&'a mut self, addr: usize, buf: &'a mut [u8]) -> IoFuture<'a, usize>;
+    fn write_lossy<'a>(&'a mut self, addr: usize, buf: &'a [u8]) -> IoFuture<'a, usize>;
+    /// Makes everything written so far durable
+    fn sync(&mut self) -> IoFuture<'_, ()> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+impl AsyncIO for Vec<u8> {
+    fn read_lossy<'a>(&'a mut self, addr: usize, buf: &'a mut [u8]) -> IoFuture<'a, usize> {
+        Box::pin(async move { IO::read_lossy(self, addr, buf) })
+    }
+
+    fn write_lossy<'a>(&'a mut self, addr: usize, buf: &'a [u8]) -> IoFuture<'a, usize> {
+        Box::pin(async move { IO::write_lossy(self, addr, buf) })
+    }
+}
+
+fn join_error(error: tokio::task::JoinError) -> DiskError {
+    // the closure panicked or the runtime is shutting down
+    DiskError::from(std::io::Error::other(error))
+}
+
+/// An image file read and written on the blocking thread pool of tokio, like `tokio::fs` does
+pub struct TokioFile {
+    file: Arc<File>,
+}
+
+impl TokioFile {
+    pub fn new(file: File) -> Self {
+        Self {
+            file: Arc::new(file),
+        }
+    }
+}
+
+impl AsyncIO for TokioFile {
+    fn read_lossy<'a>(&'a mut self, addr: usize, buf: &'a mut [u8]) -> IoFuture<'a, usize> {
+        let file = self.file.clone();
+        let len = buf.len();
+        Box::pin(async move {
+            let data = spawn_blocking(move || {
+                let mut data = vec![0; len];
+                let read = file.read_at(&mut data, addr as u64)?;
+                data.truncate(read);
+                Ok::<_, std::io::Error>(data)
+            })
+            .await
+            .map_err(join_error)??;
+            buf[..data.len()].copy_from_slice(&data);
+            Ok(data.len())
+        })
+    }
+
+    fn write_lossy<'a>(&'a mut self, addr: usize, buf: &'a [u8]) -> IoFuture<'a, usize> {
+        let file = self.file.clone();
+        let data = buf.to_vec();
+        Box::pin(async move {
+            Ok(spawn_blocking(move || file.write_at(&data, addr as u64))
+                .await
+                .map_err(join_error)??)
+        })
+    }
+
+    fn sync(&mut self) -> IoFuture<'_, ()> {
+        let file = self.file.clone();
+        Box::pin(async move {
+            Ok(spawn_blocking(move || file.sync_data())
+                .await
+                .map_err(join_error)??)
+        })
+    }
+}
+
+/// An `AsyncIO` as the `IO` of a `Disk`. Every access blocks the calling thread until the
+/// future is done, so a file system on it must only be used outside of the async runtime, e.g.
+/// on its blocking thread pool like `AsyncFileSystem` does. Used on a thread of the runtime
+/// itself, tokio panics.
+pub struct AsyncDisk {
+    io: Box<dyn AsyncIO>,
+    handle: Handle,
+}
+
+impl AsyncDisk {
+    /// Runs the futures of `io` on the runtime of `handle`
+    pub fn new(io: Box<dyn AsyncIO>, handle: Handle) -> Self {
+        Self { io, handle }
+    }
+}
+
+impl IO for AsyncDisk {
+    fn read_lossy(&mut self, addr: usize, buf: &mut [u8]) -> Result<usize, DiskError> {
+        self.handle.block_on(self.io.read_lossy(addr, buf))
+    }
+
+    fn write_lossy(&mut self, addr: usize, buf: &[u8]) -> Result<usize, DiskError> {
+        self.handle.block_on(self.io.write_lossy(addr, buf))
+    }
+
+    fn sync(&mut self) -> Result<(), DiskError> {
+        self.handle.block_on(self.io.sync())
+    }
+}
+
+/// A `FileSystem` for async code. Every operation runs on the blocking thread pool of tokio, so
+/// block accesses never block the executor. Clones share the file system, and operations on it
+/// run one after the other.
+#[derive(Debug, Clone)]
+pub struct AsyncFileSystem {
+    fs: Arc<Mutex<FileSystem>>,
+}
+
+impl AsyncFileSystem {
+    pub fn new(fs: FileSystem) -> Self {
+        Self {
+            fs: Arc::new(Mutex::new(fs)),
+        }
+    }
+
+    /// Opens the file system on `io`, see `FileSystem::mount`. Has to be called within a tokio
+    /// runtime, which then runs the futures of `io`.
+    pub async fn mount(io: Box<dyn AsyncIO>, options: MountOptions) -> Result<Self, FsError> {
+        let disk = Disk::new(Box::new(AsyncDisk::new(io, Handle::current())));
+        spawn_blocking(move || FileSystem::mount(disk, options))
+            .await
+            .map_err(|e| FsError::from(join_error(e)))?
+            .map(Self::new)
+    }
+
+    /// Runs `op` on the file system on the blocking thread pool, for everything that doesn't
+    /// have an async version here
+    pub async fn run<R: Send + 'static>(
+        &self,
+        op: impl FnOnce(&mut FileSystem) -> Result<R, FsError> + Send + 'static,
+    ) -> Result<R, FsError> {
+        let fs = self.fs.clone();
+        spawn_blocking(move || op(&mut fs.lock().unwrap_or_else(|e| e.into_inner())))
+            .await
+            .map_err(|e| FsError::from(join_error(e)))?
+    }
+
+    /// See `FileSystem::lookup_path`
+    pub async fn lookup_path(&self, path: &str) -> Result<u32, FsError> {
+        let path = path.to_string();
+        self.run(move |fs| fs.lookup_path(&path)).await
+    }
+
+    /// See `FileSystem::stat`
+    pub async fn stat(&self, inode_nbr: u32) -> Result<Stat, FsError> {
+        self.run(move |fs| fs.stat(inode_nbr)).await
+    }
+
+    /// The entries of the directory `dir_nbr` except `.` and `..`, see `Inode::read_dir_entries`
+    pub async fn read_dir(&self, dir_nbr: u32) -> Result<Vec<DirEntry>, FsError> {
+        self.run(move |fs| fs.read_inode(dir_nbr)?.read_dir_entries(fs))
+            .await
+    }
+
+    /// The whole contents of the file `inode_nbr`
+    pub async fn read_file(&self, inode_nbr: u32) -> Result<Vec<u8>, FsError> {
+        self.run(move |fs| {
+            let contents = fs.read_inode(inode_nbr)?.read_all(fs)?;
+            fs.mark_accessed(inode_nbr)?;
+            Ok(contents)
+        })
+        .await
+    }
+
+    /// Up to `len` bytes of the file `inode_nbr` from byte `offset` on, fewer at its end
+    pub async fn read_at(
+        &self,
+        inode_nbr: u32,
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, FsError> {
+        self.run(move |fs| {
+            let mut data = vec![0; len];
+            let read = fs.read_inode(inode_nbr)?.read(offset, &mut data, fs)?;
+            data.truncate(read);
+            fs.mark_accessed(inode_nbr)?;
+            Ok(data)
+        })
+        .await
+    }
+
+    /// See `FileSystem::write_at`
+    pub async fn write_at(
+        &self,
+        inode_nbr: u32,
+        offset: usize,
+        data: Vec<u8>,
+    ) -> Result<usize, FsError> {
+        self.run(move |fs| fs.write_at(inode_nbr, offset, &data))
+            .await
+    }
+
+    /// See `FileSystem::close`
+    pub async fn close(&self, inode_nbr: u32) -> Result<(), FsError> {
+        self.run(move |fs| fs.close(inode_nbr)).await
+    }
+
+    /// See `FileSystem::sync`
+    pub async fn sync(&self) -> Result<(), FsError> {
+        self.run(FileSystem::sync).await
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    fs::File,
+    os::unix::fs::{FileExt, FileTypeExt, MetadataExt, OpenOptionsExt},
+    os::unix::io::AsRawFd,
+    path::Path,
+};
+
+use crate::disk::{DiskError, IO};
+
+/// `BLKGETSIZE64`, the size of a block device in bytes
+const BLKGETSIZE64: libc::Ioctl = libc::_IOR::<u64>(0x12, 114);
+
+/// How `BlockDeviceIO::open` opens a device
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockDeviceOptions {
+    pub read_only: bool,
+    /// bypass the page cache of the kernel (`O_DIRECT`). Every access then has to be aligned to
+    /// the sector size, which `BlockDeviceIO` takes care of.
+    pub direct: bool,
+    /// every write is durable once it returns (`O_SYNC`)
+    pub sync: bool,
+}
+
+/// A raw block device (or an image file) as an `IO`. Every access to the device covers whole
+/// sectors from a buffer aligned to the sector size, so it also works with `O_DIRECT`; writes
+/// that don't cover whole sectors read the sectors at their ends first. The size of the device
+/// is reported to `FileSystem::mount`, which refuses file systems claiming more blocks.
+#[derive(Debug)]
+pub struct BlockDeviceIO {
+    file: File,
+    /// the logical sector size of the device, for files their preferred IO size
+    sector_size: usize,
+    size: u64,
+    /// the aligned buffer every access goes through, with spare room to align it
+    buf: Vec<u8>,
+}
+
+fn ioctl_error() -> DiskError {
+    std::io::Error::last_os_error().into()
+}
+
+impl BlockDeviceIO {
+    pub fn open(path: impl AsRef<Path>, options: BlockDeviceOptions) -> Result<Self, DiskError> {
+        let mut flags = 0;
+        if options.direct {
+            flags |= libc::O_DIRECT;
+        }
+        if options.sync {
+            flags |= libc::O_SYNC;
+        }
+        let file = File::options()
+            .read(true)
+            .write(!options.read_only)
+            .custom_flags(flags)
+            .open(path)?;
+
+        let meta = file.metadata()?;
+        let (sector_size, size) = if meta.file_type().is_block_device() {
+            let mut sector_size: libc::c_int = 0;
+            let mut size: u64 = 0;
+            // both write a single integer of the given type
+            unsafe {
+                if libc::ioctl(file.as_raw_fd(), libc::BLKSSZGET, &mut sector_size) != 0 {
+                    return Err(ioctl_error());
+                }
+                if libc::ioctl(file.as_raw_fd(), BLKGETSIZE64, &mut size) != 0 {
+                    return Err(ioctl_error());
+                }
+            }
+            (sector_size as usize, size)
+        } else {
+            (meta.blksize() as usize, meta.len())
+        };
+        if !sector_size.is_power_of_two() {
+            return Err(DiskError::Unsupported);
+        }
+
+        Ok(Self {
+            file,
+            sector_size,
+            size,
+            buf: Vec::new(),
+        })
+    }
+
+    /// The size accesses to the device are aligned to
+    pub fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    /// The whole sectors around `addr..addr + len`, cut off at the end of the device
+    fn sectors(&self, addr: usize, len: usize) -> (u64, usize) {
+        let start = addr / self.sector_size * self.sector_size;
+        let end = (addr + len).next_multiple_of(self.sector_size) as u64;
+        (
+            start as u64,
+            (end.min(self.size) as usize).saturating_sub(start),
+        )
+    }
+
+    /// An aligned buffer of `len` bytes
+    fn aligned(buf: &mut Vec<u8>, len: usize, align: usize) -> &mut [u8] {
+        if buf.len() < len + align {
+            *buf = vec![0; len + align];
+        }
+        let offset = buf.as_ptr().align_offset(align);
+        &mut buf[offset..offset + len]
+    }
+
+    /// Reads `buf.len()` bytes at `addr` into the aligned `buf`, fewer only at the end of the
+    /// device
+    fn read_sectors(file: &File, addr: u64, buf: &mut [u8]) -> Result<usize, DiskError> {
+        let mut read = 0;
+        while read < buf.len() {
+            match file.read_at(&mut buf[read..], addr + read as u64) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(read)
+    }
+}
+
+impl IO for BlockDeviceIO {
+    fn read_lossy(&mut self, addr: usize, buf: &mut [u8]) -> Result<usize, DiskError> {
+        let (start, len) = self.sectors(addr, buf.len());
+        let skip = addr - start as usize;
+        if len <= skip {
+            return Ok(0);
+        }
+        let sectors = Self::aligned(&mut self.buf, len, self.sector_size);
+        let read = Self::read_sectors(&self.file, start, sectors)?.saturating_sub(skip);
+        let read = read.min(buf.len());
+        buf[..read].copy_from_slice(&sectors[skip..skip + read]);
+        Ok(read)
+    }
+
+    fn write_lossy(&mut self, addr: usize, buf: &[u8]) -> Result<usize, DiskError> {
+        let (start, len) = self.sectors(addr, buf.len());
+        let skip = addr - start as usize;
+        if len <= skip {
+            return Ok(0);
+        }
+        let sector_size = self.sector_size;
+        let sectors = Self::aligned(&mut self.buf, len, sector_size);
+        // the parts of the first and last sector that aren't overwritten
+        if skip != 0 {
+            Self::read_sectors(&self.file, start, &mut sectors[..sector_size.min(len)])?;
+        }
+        let written = buf.len().min(len - skip);
+        if skip + written != len {
+            let last = (len - 1) / sector_size * sector_size;
+            Self::read_sectors(&self.file, start + last as u64, &mut sectors[last..])?;
+        }
+        sectors[skip..skip + written].copy_from_slice(&buf[..written]);
+        self.file.write_all_at(sectors, start)?;
+        Ok(written)
+    }
+
+    fn sync(&mut self) -> Result<(), DiskError> {
+        Ok(self.file.sync_data()?)
+    }
+
+    fn size(&mut self) -> Option<u64> {
+        Some(self.size)
+    }
+}
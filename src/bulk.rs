@@ -0,0 +1,71 @@
+use crate::{
+    fs::{FileSystem, FsError},
+    inode::Inode,
+};
+use alloc::string::String;
+
+/// A context for creating many files at once. The superblock and the block array descriptors are
+/// kept in memory while it is alive and only written back on `commit` (or when dropped), so
+/// creating a file doesn't cost a superblock and bitmap write for every allocated block.
+pub struct BulkImport<'a> {
+    fs: &'a mut FileSystem,
+    committed: bool,
+}
+
+impl<'a> BulkImport<'a> {
+    pub(crate) fn new(fs: &'a mut FileSystem) -> Self {
+        let blocks = fs.metadata_block_ids();
+        fs.disk().start_buffering(blocks);
+        Self {
+            fs,
+            committed: false,
+        }
+    }
+
+    /// Gives access to the file system for operations the import context doesn't wrap. Metadata
+    /// written through it is buffered as well.
+    pub fn fs(&mut self) -> &mut FileSystem {
+        self.fs
+    }
+
+    pub fn create_file(
+        &mut self,
+        parent_nbr: u32,
+        name: String,
+        inode: Inode,
+        contents: &[u8],
+    ) -> Result<u32, FsError> {
+        let inode_nbr = self.fs.create_dir_entry(parent_nbr, inode, name)?;
+        if !contents.is_empty() {
+            let mut inode = self.fs.read_inode(inode_nbr)?;
+            inode.file_write(contents, self.fs, inode_nbr)?;
+        }
+        Ok(inode_nbr)
+    }
+
+    pub fn create_dir(
+        &mut self,
+        parent_nbr: u32,
+        name: String,
+        inode: Inode,
+    ) -> Result<u32, FsError> {
+        self.fs.create_dir_entry(parent_nbr, inode, name)
+    }
+
+    /// Writes the buffered superblock and block array descriptors to disk
+    pub fn commit(mut self) -> Result<(), FsError> {
+        self.committed = true;
+        self.fs.disk().flush_buffer()?;
+        Ok(())
+    }
+}
+
+impl Drop for BulkImport<'_> {
+    fn drop(&mut self) {
+        if !self.committed {
+            // everything else was already written, so the metadata has to follow to keep the image
+            // consistent. There's no way to report the error here; use `commit` to get it.
+            let _ = self.fs.disk().flush_buffer();
+        }
+    }
+}
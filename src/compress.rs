@@ -0,0 +1,198 @@
+//! Transparent compression of file contents, see `FileSystem::set_compression`. The contents of a
+//! compressed file are stored in clusters of `CLUSTER_BLOCKS` blocks. A cluster that compresses
+//! well takes fewer blocks, starting with a small header, and leaves the other block pointers of
+//! the cluster empty. A cluster that doesn't uses all of its blocks and is stored as it is.
+
+use alloc::vec::Vec;
+
+use crate::fs::{FsError, BLOCK_SIZE};
+
+/// How the contents of a regular file are stored
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Compression {
+    #[default]
+    None,
+    /// LZ4 block format, fast to compress and decompress, needs the `lz4` cargo feature
+    Lz4,
+    /// Zstandard frames at the fastest level, needs the `zstd` cargo feature
+    Zstd,
+}
+
+impl Compression {
+    pub(crate) fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::None),
+            1 => Some(Self::Lz4),
+            2 => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_u8(self) -> u8 {
+        match self {
+            Self::None => 0,
+            Self::Lz4 => 1,
+            Self::Zstd => 2,
+        }
+    }
+
+    /// Whether this implementation was built with the codec
+    pub fn is_available(self) -> bool {
+        match self {
+            Self::None => true,
+            Self::Lz4 => cfg!(feature = "lz4"),
+            Self::Zstd => cfg!(feature = "zstd"),
+        }
+    }
+}
+
+/// The number of blocks compressed together
+pub const CLUSTER_BLOCKS: u32 = 4;
+/// The bytes of file contents in a cluster
+pub const CLUSTER_SIZE: usize = CLUSTER_BLOCKS as usize * BLOCK_SIZE;
+/// The codec, a reserved byte, the length of the contents and the length of the compressed data
+const HEADER_SIZE: usize = 8;
+
+/// The header and the compressed `data` (at most a cluster), `None` if that doesn't save a block
+pub(crate) fn encode(algo: Compression, data: &[u8]) -> Result<Option<Vec<u8>>, FsError> {
+    if algo == Compression::None {
+        return Ok(None);
+    }
+    let compressed = compress(algo, data)?;
+    let len = HEADER_SIZE + compressed.len();
+    if len > CLUSTER_SIZE - BLOCK_SIZE {
+        return Ok(None);
+    }
+
+    let mut encoded = Vec::with_capacity(len);
+    encoded.extend([algo.as_u8(), 0]);
+    encoded.extend((data.len() as u16).to_ne_bytes());
+    encoded.extend((compressed.len() as u32).to_ne_bytes());
+    encoded.extend(compressed);
+    Ok(Some(encoded))
+}
+
+/// Decompresses the cluster `encoded` (its blocks, starting with the header) into `out`, which
+/// holds a cluster. Everything after the contents is zeroed.
+pub(crate) fn decode(encoded: &[u8], out: &mut [u8]) -> Result<(), FsError> {
+    if encoded.len() < HEADER_SIZE {
+        return Err(FsError::Corrupted);
+    }
+    let algo = Compression::from_u8(encoded[0]).ok_or(FsError::Corrupted)?;
+    let len = u16::from_ne_bytes([encoded[2], encoded[3]]) as usize;
+    let compressed_len = u32::from_ne_bytes(encoded[4..8].try_into().unwrap()) as usize;
+    let compressed = encoded[HEADER_SIZE..]
+        .get(..compressed_len)
+        .ok_or(FsError::Corrupted)?;
+    if algo == Compression::None || len > out.len() {
+        return Err(FsError::Corrupted);
+    }
+
+    if decompress(algo, compressed, &mut out[..len])? != len {
+        return Err(FsError::Corrupted);
+    }
+    out[len..].fill(0);
+    Ok(())
+}
+
+#[cfg_attr(not(any(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+fn compress(algo: Compression, data: &[u8]) -> Result<Vec<u8>, FsError> {
+    match algo {
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => Ok(lz4_flex::block::compress(data)),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => Ok(ruzstd::encoding::compress_to_vec(
+            data,
+            ruzstd::encoding::CompressionLevel::Fastest,
+        )),
+        _ => Err(FsError::UnsupportedFeatures),
+    }
+}
+
+/// Decompresses `compressed` into `out`, returns the length of the contents
+#[cfg_attr(not(any(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+fn decompress(algo: Compression, compressed: &[u8], out: &mut [u8]) -> Result<usize, FsError> {
+    match algo {
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => {
+            lz4_flex::block::decompress_into(compressed, out).map_err(|_| FsError::Corrupted)
+        }
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => ruzstd::decoding::FrameDecoder::new()
+            .decode_all(compressed, out)
+            .map_err(|_| FsError::Corrupted),
+        _ => Err(FsError::UnsupportedFeatures),
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::{
+        addr::Addr,
+        disk::Disk,
+        fs::{FileSystem, MountOptions},
+        Sfs,
+    };
+
+    const BLOCKS: u32 = 300;
+
+    /// Text that compresses well for `len` bytes, then bytes that don't compress at all
+    fn contents(len: usize, noise: usize) -> Vec<u8> {
+        let mut data: Vec<u8> = b"all work and no play "
+            .iter()
+            .copied()
+            .cycle()
+            .take(len)
+            .collect();
+        let mut state = 0x2545f491u32;
+        data.extend((0..noise).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        }));
+        data
+    }
+
+    #[test]
+    fn compressed_clusters_survive_remounting() {
+        for algo in [Compression::Lz4, Compression::Zstd] {
+            let mut sfs = Sfs::from(FileSystem::create(BLOCKS, "compress").unwrap());
+            // compressible clusters, a stored one and a partial one at the end
+            let mut data = contents(3 * CLUSTER_SIZE, CLUSTER_SIZE);
+            data.extend(contents(BLOCK_SIZE + 100, 0));
+            sfs.write("/file", &data).unwrap();
+            let fs = sfs.fs();
+            let file = fs.lookup_path("/file").unwrap();
+            if !algo.is_available() {
+                assert!(matches!(
+                    fs.set_compression(file, algo),
+                    Err(FsError::UnsupportedFeatures)
+                ));
+                continue;
+            }
+            let uncompressed = fs.stat(file).unwrap().blocks;
+            fs.set_compression(file, algo).unwrap();
+            assert!(fs.stat(file).unwrap().blocks < uncompressed);
+
+            // a write across two clusters and a cut in the middle of one
+            let offset = CLUSTER_SIZE - 10;
+            fs.write_at(file, offset, &[0xaa; 20]).unwrap();
+            data[offset..offset + 20].fill(0xaa);
+            let len = 4 * CLUSTER_SIZE + 5;
+            fs.truncate(file, len as u64).unwrap();
+            data.truncate(len);
+
+            let mut image = vec![0; Addr::block(BLOCKS).unwrap().get()];
+            fs.export_image(&mut image).unwrap();
+            let fs =
+                FileSystem::mount(Disk::new(Box::new(image)), MountOptions::default()).unwrap();
+            let mut sfs = Sfs::from(fs);
+            assert!(sfs.read("/file").unwrap() == data);
+            let fs = sfs.fs();
+            assert_eq!(fs.compression(file).unwrap(), algo);
+            assert!(fs.fsck(false).unwrap().is_clean());
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use alloc::{collections::BTreeMap, string::ToString};
+
+use crate::{
+    fs::{FileSystem, FsError, BLOCK_SIZE},
+    inode::{Inode, InodeType, INODE_FLAG_SORTED_DIR},
+};
+
+/// What `FileSystem::copy_from` copied
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CopyStats {
+    /// the copy of the inode the copy started at
+    pub root: u32,
+    /// the inodes created, hard links to an inode that was already copied don't count
+    pub inodes: u32,
+    /// the bytes of file contents copied, without holes
+    pub bytes: u64,
+}
+
+/// Copies trees from one image to another, see `FileSystem::copy_from`
+pub(crate) struct TreeCopy<'a> {
+    src: &'a mut FileSystem,
+    dst: &'a mut FileSystem,
+    /// the copies of the inodes with more than one link, so further links to them are linked to
+    /// the copy instead of copying them again
+    copied: BTreeMap<u32, u32>,
+    stats: CopyStats,
+}
+
+impl<'a> TreeCopy<'a> {
+    pub(crate) fn new(src: &'a mut FileSystem, dst: &'a mut FileSystem) -> Self {
+        Self {
+            src,
+            dst,
+            copied: BTreeMap::new(),
+            stats: CopyStats::default(),
+        }
+    }
+
+    /// Copies `src_nbr` and everything below it to the new entry `name` of `parent_nbr`
+    pub(crate) fn run(
+        mut self,
+        src_nbr: u32,
+        parent_nbr: u32,
+        name: &str,
+    ) -> Result<CopyStats, FsError> {
+        match self.dst.lookup(parent_nbr, name) {
+            Ok(_) => return Err(FsError::AlreadyExists),
+            Err(FsError::NoEntry) => {}
+            Err(e) => return Err(e),
+        }
+        self.stats.root = self.copy(src_nbr, parent_nbr, name)?;
+        Ok(self.stats)
+    }
+
+    fn copy(&mut self, src_nbr: u32, parent_nbr: u32, name: &str) -> Result<u32, FsError> {
+        if let Some(&dst_nbr) = self.copied.get(&src_nbr) {
+            self.dst.link(dst_nbr, parent_nbr, name)?;
+            return Ok(dst_nbr);
+        }
+
+        let src = self.src.read_inode(src_nbr)?;
+        let typ = src.type_and_permission.get_type();
+        let mut inode = Inode::create(
+            src.type_and_permission,
+            src.uid,
+            src.gid,
+            0,
+            0,
+            match typ {
+                InodeType::File | InodeType::Symlink => 0,
+                _ => src.meta,
+            },
+        );
+        inode.creation_time = src.creation_time;
+        let dst_nbr = self
+            .dst
+            .create_dir_entry(parent_nbr, inode, name.to_string())?;
+        self.stats.inodes += 1;
+        if typ != InodeType::Directory && src.hardlinks > 1 {
+            self.copied.insert(src_nbr, dst_nbr);
+        }
+
+        match typ {
+            InodeType::File => self.copy_contents(&src, dst_nbr)?,
+            InodeType::Symlink => {
+                let target = self.src.read_link(src_nbr)?;
+                self.dst.set_link_target(dst_nbr, &target)?;
+            }
+            InodeType::Directory => {
+                if src.flags & INODE_FLAG_SORTED_DIR != 0 {
+                    self.dst.set_dir_sorted(dst_nbr, true)?;
+                }
+                for entry in src.read_dir_entries(self.src)? {
+                    self.copy(entry.inode, dst_nbr, &entry.get_name())?;
+                }
+            }
+            _ => {}
+        }
+
+        for attr in self.src.list_xattr(src_nbr)? {
+            let value = self.src.get_xattr(src_nbr, &attr)?;
+            self.dst.set_xattr(dst_nbr, &attr, &value)?;
+        }
+
+        // last, since copying the contents and the entries changes the modification time
+        let mut inode = self.dst.read_inode(dst_nbr)?;
+        inode.flags |= src.flags;
+        inode.set_modified(src.modified());
+        inode.set_accessed(src.accessed());
+        self.dst.write_inode(dst_nbr, &inode)?;
+        Ok(dst_nbr)
+    }
+
+    /// Copies the data blocks of the file `src` to `dst_nbr`, leaving holes where `src` has them
+    fn copy_contents(&mut self, src: &Inode, dst_nbr: u32) -> Result<(), FsError> {
+        let size = src.size();
+        let mut buf = [0; BLOCK_SIZE];
+        if src.is_compressed() {
+            // the blocks of a compressed file don't hold its contents as they are, so it is read
+            // block by block, leaving out the zeroes
+            for offset in (0..size as usize).step_by(BLOCK_SIZE) {
+                let len = src.read(offset, &mut buf, self.src)?;
+                if buf[..len].iter().any(|&byte| byte != 0) {
+                    self.dst.write_at(dst_nbr, offset, &buf[..len])?;
+                    self.stats.bytes += len as u64;
+                }
+            }
+        } else {
+            for (index, block) in src.data_blocks(self.src)? {
+                let offset = index as u64 * BLOCK_SIZE as u64;
+                if offset >= size {
+                    break;
+                }
+                let len = (size - offset).min(BLOCK_SIZE as u64) as usize;
+                self.src
+                    .disk()
+                    .read_exact(FileSystem::pointer(block)?, &mut buf[..len])?;
+                self.dst.write_at(dst_nbr, offset as usize, &buf[..len])?;
+                self.stats.bytes += len as u64;
+            }
+        }
+        self.dst.truncate(dst_nbr, size)?;
+        self.dst.close(dst_nbr)
+    }
+}
@@ -0,0 +1,53 @@
+/// The lookup table for the reflected IEEE polynomial, one entry per byte value
+const TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// A plain CRC-32 (IEEE 802.3, the one of zlib and PNG), for checksums of on-disk metadata
+/// without any dependencies
+pub struct Crc32(u32);
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Self(!0)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.0 = TABLE[((self.0 ^ byte as u32) & 0xff) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    pub fn finalize(self) -> u32 {
+        !self.0
+    }
+}
+
+/// The checksum of `data`
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finalize()
+}
+
+/// The bytes of `value`. Only for the on-disk structures, which spell out their padding, so every
+/// byte is initialized.
+pub fn bytes_of<T>(value: &T) -> &[u8] {
+    unsafe { &*core::ptr::slice_from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
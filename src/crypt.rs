@@ -0,0 +1,596 @@
+//! Encrypted images, see `FileSystem::enable_encryption`. Every block but the superblock, the
+//! boot area and the nonce table is sealed with an AEAD cipher under a random data key, with the
+//! block number as associated data, so blocks can't be swapped unnoticed. The nonce and the tag of
+//! every block are kept in the nonce table, a run of blocks allocated like the hash tree. A zero
+//! entry means the block wasn't written since the image was encrypted and reads as zeros. The data
+//! key is stored in the superblock, wrapped with a key derived from the passphrase with Argon2id,
+//! so the passphrase can change without encrypting the image again.
+
+use alloc::string::String;
+#[cfg(feature = "encryption")]
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use core::{fmt::Debug, ops::Range};
+
+#[cfg(feature = "encryption")]
+use crate::{
+    addr::Addr,
+    disk::{DiskError, IO},
+    features::INCOMPAT_ENCRYPTION,
+    fs::BlockArrayEntry,
+};
+use crate::{
+    disk::Disk,
+    fs::{FileSystem, FsError, BLOCK_SIZE},
+    superblock::Superblock,
+};
+
+/// The cipher the blocks of an encrypted image are sealed with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Cipher {
+    /// XChaCha20-Poly1305, fast without AES instructions
+    #[default]
+    XChaCha20Poly1305,
+    /// AES-256-GCM, fast with AES instructions. Its nonces are only 12 bytes, so a key shouldn't
+    /// seal many more than 2^32 blocks.
+    Aes256Gcm,
+}
+
+#[cfg_attr(not(feature = "encryption"), allow(dead_code))]
+impl Cipher {
+    pub(crate) fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Self::XChaCha20Poly1305),
+            2 => Some(Self::Aes256Gcm),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_u8(self) -> u8 {
+        match self {
+            Self::XChaCha20Poly1305 => 1,
+            Self::Aes256Gcm => 2,
+        }
+    }
+}
+
+/// The passphrase of an encrypted image, see `MountOptions::passphrase`. It is never printed.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Passphrase(String);
+
+impl Debug for Passphrase {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("Passphrase(..)")
+    }
+}
+
+impl From<&str> for Passphrase {
+    fn from(passphrase: &str) -> Self {
+        Self(passphrase.into())
+    }
+}
+
+impl From<String> for Passphrase {
+    fn from(passphrase: String) -> Self {
+        Self(passphrase)
+    }
+}
+
+/// The bytes of a nonce in the table, AES-256-GCM only uses the first 12
+#[cfg(feature = "encryption")]
+const NONCE_SIZE: usize = 24;
+#[cfg(feature = "encryption")]
+const TAG_SIZE: usize = 16;
+/// The nonce and the tag of a block
+const ENTRY_SIZE: usize = 40;
+/// The entries in one block of the nonce table
+pub const ENTRIES_PER_BLOCK: u32 = (BLOCK_SIZE / ENTRY_SIZE) as u32;
+/// Argon2id parameters for new keys: 19 MiB, 2 passes and one lane
+#[cfg(feature = "encryption")]
+const KDF_PARAMS: (u32, u32, u32) = (19 * 1024, 2, 1);
+/// The associated data of the wrapped data key
+#[cfg(feature = "encryption")]
+const WRAP_AD: &[u8] = b"SFs key";
+
+/// The number of blocks of the nonce table of an image of `total_blocks` blocks
+pub fn table_blocks(total_blocks: u32) -> u32 {
+    total_blocks.div_ceil(ENTRIES_PER_BLOCK)
+}
+
+/// The unlocked data key of an image, kept by the file system to write exports of it
+#[derive(Clone)]
+#[cfg_attr(not(feature = "encryption"), allow(dead_code))]
+pub(crate) struct DataKey {
+    #[cfg(feature = "encryption")]
+    key: Key,
+    /// the blocks that are stored as they are: the superblock, the boot area and the table
+    plain: [Range<u32>; 3],
+    total: u32,
+}
+
+impl Debug for DataKey {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("DataKey(..)")
+    }
+}
+
+#[cfg(feature = "encryption")]
+#[derive(Clone)]
+enum Key {
+    XChaCha20Poly1305(Box<chacha20poly1305::XChaCha20Poly1305>),
+    Aes256Gcm(Box<aes_gcm::Aes256Gcm>),
+}
+
+#[cfg(feature = "encryption")]
+impl Key {
+    fn new(cipher: Cipher, key: &[u8; 32]) -> Self {
+        use aes_gcm::aead::KeyInit;
+
+        match cipher {
+            Cipher::XChaCha20Poly1305 => Self::XChaCha20Poly1305(Box::new(
+                chacha20poly1305::XChaCha20Poly1305::new(key.into()),
+            )),
+            Cipher::Aes256Gcm => Self::Aes256Gcm(Box::new(aes_gcm::Aes256Gcm::new(key.into()))),
+        }
+    }
+
+    /// Encrypts `data` in place, returning the tag
+    fn seal(&self, nonce: &[u8; NONCE_SIZE], ad: &[u8], data: &mut [u8]) -> [u8; TAG_SIZE] {
+        use aes_gcm::aead::AeadInPlace;
+
+        let tag = match self {
+            Self::XChaCha20Poly1305(cipher) => {
+                cipher.encrypt_in_place_detached(nonce.into(), ad, data)
+            }
+            Self::Aes256Gcm(cipher) => {
+                cipher.encrypt_in_place_detached(nonce[..12].into(), ad, data)
+            }
+        };
+        // only fails for data longer than the ciphers allow, far more than a block
+        tag.expect("a block is short enough to seal").into()
+    }
+
+    /// Decrypts `data` in place, `false` if it doesn't match `tag`
+    fn open(&self, nonce: &[u8; NONCE_SIZE], ad: &[u8], data: &mut [u8], tag: &[u8]) -> bool {
+        use aes_gcm::aead::AeadInPlace;
+
+        match self {
+            Self::XChaCha20Poly1305(cipher) => cipher
+                .decrypt_in_place_detached(nonce.into(), ad, data, tag.into())
+                .is_ok(),
+            Self::Aes256Gcm(cipher) => cipher
+                .decrypt_in_place_detached(nonce[..12].into(), ad, data, tag.into())
+                .is_ok(),
+        }
+    }
+}
+
+#[cfg(feature = "encryption")]
+fn random<const N: usize>() -> Result<[u8; N], DiskError> {
+    let mut bytes = [0; N];
+    getrandom::getrandom(&mut bytes).map_err(|_| DiskError::GenericError)?;
+    Ok(bytes)
+}
+
+/// The key wrapping the data key, from `passphrase` and the parameters in the superblock
+#[cfg(feature = "encryption")]
+fn derive(sb: &Superblock, passphrase: &Passphrase) -> Result<[u8; 32], FsError> {
+    let params = argon2::Params::new(sb.kdf_memory, sb.kdf_iterations, sb.kdf_lanes, Some(32))
+        .map_err(|_| FsError::Corrupted)?;
+    let mut key = [0; 32];
+    argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+        .hash_password_into(passphrase.0.as_bytes(), &sb.kdf_salt, &mut key)
+        .map_err(|_| FsError::Corrupted)?;
+    Ok(key)
+}
+
+/// Wraps `data_key` into the superblock with a key derived from `passphrase` and a new salt
+#[cfg(feature = "encryption")]
+fn wrap(sb: &mut Superblock, passphrase: &Passphrase, data_key: &[u8; 32]) -> Result<(), FsError> {
+    (sb.kdf_memory, sb.kdf_iterations, sb.kdf_lanes) = KDF_PARAMS;
+    sb.kdf_salt = random()?;
+    sb.key_nonce = random()?;
+    let cipher = Cipher::from_u8(sb.crypt_cipher).ok_or(FsError::Corrupted)?;
+    let wrapping = Key::new(cipher, &derive(sb, passphrase)?);
+
+    let mut wrapped = [0; 48];
+    wrapped[..32].copy_from_slice(data_key);
+    let tag = wrapping.seal(&sb.key_nonce, WRAP_AD, &mut wrapped[..32]);
+    wrapped[32..].copy_from_slice(&tag);
+    sb.wrapped_key = wrapped;
+    Ok(())
+}
+
+/// The data key in the superblock, unwrapped with `passphrase`
+#[cfg(feature = "encryption")]
+fn unwrap(sb: &Superblock, passphrase: &Passphrase) -> Result<[u8; 32], FsError> {
+    let cipher = Cipher::from_u8(sb.crypt_cipher).ok_or(FsError::Corrupted)?;
+    let wrapping = Key::new(cipher, &derive(sb, passphrase)?);
+    let mut data_key = [0; 32];
+    data_key.copy_from_slice(&sb.wrapped_key[..32]);
+    if !wrapping.open(&sb.key_nonce, WRAP_AD, &mut data_key, &sb.wrapped_key[32..]) {
+        return Err(FsError::BadPassphrase);
+    }
+    Ok(data_key)
+}
+
+/// The data key of the image with the superblock `sb`, unwrapped with `passphrase`
+#[cfg(feature = "encryption")]
+fn data_key(sb: &Superblock, passphrase: &Passphrase) -> Result<DataKey, FsError> {
+    let cipher = Cipher::from_u8(sb.crypt_cipher).ok_or(FsError::Corrupted)?;
+    let table = sb.crypt_start..sb.crypt_start + sb.crypt_blocks;
+    Ok(DataKey {
+        key: Key::new(cipher, &unwrap(sb, passphrase)?),
+        plain: [1..2, 2..2 + sb.boot_area_blocks, table],
+        total: sb.total_blocks,
+    })
+}
+
+/// Encrypts the image with a new data key, see `FileSystem::enable_encryption`
+#[cfg(feature = "encryption")]
+pub(crate) fn enable(
+    fs: &mut FileSystem,
+    passphrase: &Passphrase,
+    cipher: Cipher,
+) -> Result<DataKey, FsError> {
+    let total = fs.superblock.total_blocks;
+    let len = table_blocks(total);
+    let start = fs.find_free_run(len)?.ok_or(FsError::NoSpace)?;
+    for block in start..start + len {
+        fs.allocate_block_id(block)?;
+    }
+    let zeros = [0; BLOCK_SIZE];
+    for block in start..start + len {
+        fs.disk().write_exact(Addr::block(block)?.get(), &zeros)?;
+    }
+
+    let mut sb = fs.superblock.clone();
+    sb.crypt_start = start;
+    sb.crypt_blocks = len;
+    sb.crypt_cipher = cipher.as_u8();
+    wrap(&mut sb, passphrase, &random()?)?;
+    let key = data_key(&sb, passphrase)?;
+    let mut used = Vec::new();
+    for block_id in 0..total {
+        if key.is_plain(block_id) {
+            continue;
+        }
+        if fs.block_type(block_id)? != BlockArrayEntry::Unused || fs.is_reserved(block_id) {
+            used.push(block_id);
+        }
+    }
+
+    // everything the disk still buffers is written in the clear before the layer is in place
+    fs.disk().flush()?;
+    let mut result = Ok(());
+    fs.disk().wrap_io(|io| {
+        let mut crypto = CryptoIO::new(io, key.clone());
+        result = crypto.encrypt_in_place(&used);
+        Box::new(crypto)
+    });
+    result?;
+
+    sb.feature_incompat |= INCOMPAT_ENCRYPTION;
+    fs.superblock = sb;
+    fs.write_superblock()?;
+    fs.disk().sync()?;
+    Ok(key)
+}
+
+#[cfg(not(feature = "encryption"))]
+pub(crate) fn enable(_: &mut FileSystem, _: &Passphrase, _: Cipher) -> Result<DataKey, FsError> {
+    Err(FsError::UnsupportedFeatures)
+}
+
+/// Makes every later access to `disk`, holding an encrypted image with the superblock `sb`, go
+/// through its data key, unwrapped with `passphrase`
+#[cfg(feature = "encryption")]
+pub(crate) fn unlock(
+    disk: &mut Disk,
+    sb: &Superblock,
+    passphrase: Option<&Passphrase>,
+) -> Result<DataKey, FsError> {
+    let passphrase = passphrase.ok_or(FsError::BadPassphrase)?;
+    if sb.crypt_blocks != table_blocks(sb.total_blocks) {
+        return Err(FsError::Corrupted);
+    }
+    let key = data_key(sb, passphrase)?;
+    disk.wrap_io(|io| Box::new(CryptoIO::new(io, key.clone())));
+    Ok(key)
+}
+
+#[cfg(not(feature = "encryption"))]
+pub(crate) fn unlock(
+    _: &mut Disk,
+    _: &Superblock,
+    _: Option<&Passphrase>,
+) -> Result<DataKey, FsError> {
+    Err(FsError::UnsupportedFeatures)
+}
+
+/// Wraps the data key of `fs` with `new` instead of `old`, see `FileSystem::change_passphrase`
+#[cfg(feature = "encryption")]
+pub(crate) fn change_passphrase(
+    fs: &mut FileSystem,
+    old: &Passphrase,
+    new: &Passphrase,
+) -> Result<(), FsError> {
+    let data_key = unwrap(&fs.superblock, old)?;
+    wrap(&mut fs.superblock, new, &data_key)?;
+    fs.write_superblock()?;
+    Ok(fs.disk().sync()?)
+}
+
+#[cfg(not(feature = "encryption"))]
+pub(crate) fn change_passphrase(
+    _: &mut FileSystem,
+    _: &Passphrase,
+    _: &Passphrase,
+) -> Result<(), FsError> {
+    Err(FsError::UnsupportedFeatures)
+}
+
+#[cfg(feature = "encryption")]
+impl DataKey {
+    /// `io` encrypted with this key, for a copy of the image. Its nonce table is zeroed, so the
+    /// blocks that aren't written to the copy read as zeros.
+    pub(crate) fn wrap<I: IO>(&self, mut io: I) -> Result<impl IO, DiskError> {
+        let zeros = [0; BLOCK_SIZE];
+        for block in self.table() {
+            io.write_exact(Addr::block_io(block)?.get(), &zeros)?;
+        }
+        Ok(CryptoIO::new(io, self.clone()))
+    }
+
+    /// The blocks of the nonce table
+    pub(crate) fn table(&self) -> Range<u32> {
+        self.plain[2].clone()
+    }
+
+    fn is_plain(&self, block_id: u32) -> bool {
+        block_id >= self.total || self.plain.iter().any(|range| range.contains(&block_id))
+    }
+}
+
+/// Seals every block written to `io` and opens every block read from it, except the plain
+/// ones. The entries of the nonce table are kept in memory once they were read.
+#[cfg(feature = "encryption")]
+struct CryptoIO<I> {
+    io: I,
+    key: DataKey,
+    entries: BTreeMap<u32, Box<[u8; BLOCK_SIZE]>>,
+}
+
+#[cfg(feature = "encryption")]
+impl<I: IO> CryptoIO<I> {
+    fn new(io: I, key: DataKey) -> Self {
+        Self {
+            io,
+            key,
+            entries: BTreeMap::new(),
+        }
+    }
+
+    /// The table block holding the entry of `block_id` and the offset of the entry in it
+    fn entry_addr(&self, block_id: u32) -> (u32, usize) {
+        (
+            self.key.table().start + block_id / ENTRIES_PER_BLOCK,
+            (block_id % ENTRIES_PER_BLOCK) as usize * ENTRY_SIZE,
+        )
+    }
+
+    fn entry(&mut self, block_id: u32) -> Result<[u8; ENTRY_SIZE], DiskError> {
+        let (table_block, offset) = self.entry_addr(block_id);
+        if !self.entries.contains_key(&table_block) {
+            let mut data = Box::new([0; BLOCK_SIZE]);
+            self.io
+                .read_exact(Addr::block_io(table_block)?.get(), data.as_mut_slice())?;
+            self.entries.insert(table_block, data);
+        }
+        Ok(self.entries[&table_block][offset..offset + ENTRY_SIZE]
+            .try_into()
+            .unwrap())
+    }
+
+    fn set_entry(&mut self, block_id: u32, entry: &[u8; ENTRY_SIZE]) -> Result<(), DiskError> {
+        let (table_block, offset) = self.entry_addr(block_id);
+        let addr = Addr::block_io(table_block)?.get() + offset;
+        self.io.write_exact(addr, entry)?;
+        if let Some(data) = self.entries.get_mut(&table_block) {
+            data[offset..offset + ENTRY_SIZE].copy_from_slice(entry);
+        }
+        Ok(())
+    }
+
+    fn read_block(&mut self, block_id: u32, data: &mut [u8; BLOCK_SIZE]) -> Result<(), DiskError> {
+        let entry = self.entry(block_id)?;
+        if entry == [0; ENTRY_SIZE] {
+            data.fill(0);
+            return Ok(());
+        }
+        self.io
+            .read_exact(Addr::block_io(block_id)?.get(), data.as_mut_slice())?;
+        let (nonce, tag) = entry.split_at(NONCE_SIZE);
+        if !self.key.key.open(
+            nonce.try_into().unwrap(),
+            &block_id.to_le_bytes(),
+            data.as_mut_slice(),
+            tag,
+        ) {
+            return Err(DiskError::DecryptionFailed { block: block_id });
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self, block_id: u32, data: &[u8; BLOCK_SIZE]) -> Result<(), DiskError> {
+        let nonce = random::<NONCE_SIZE>()?;
+        let mut sealed = *data;
+        let tag = self
+            .key
+            .key
+            .seal(&nonce, &block_id.to_le_bytes(), &mut sealed);
+        // the block goes first: a crash in between leaves it unreadable, but never readable as
+        // something that wasn't written
+        self.io
+            .write_exact(Addr::block_io(block_id)?.get(), &sealed)?;
+        let mut entry = [0; ENTRY_SIZE];
+        entry[..NONCE_SIZE].copy_from_slice(&nonce);
+        entry[NONCE_SIZE..].copy_from_slice(&tag);
+        self.set_entry(block_id, &entry)
+    }
+
+    /// Seals the blocks `blocks` as they are, while their entries are still zero
+    fn encrypt_in_place(&mut self, blocks: &[u32]) -> Result<(), FsError> {
+        let mut data = [0; BLOCK_SIZE];
+        for &block_id in blocks {
+            self.io
+                .read_exact(Addr::block(block_id)?.get(), &mut data)?;
+            self.write_block(block_id, &data)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl<I: IO> IO for CryptoIO<I> {
+    fn read_lossy(&mut self, addr: usize, buf: &mut [u8]) -> Result<usize, DiskError> {
+        let mut done = 0;
+        let mut block = [0; BLOCK_SIZE];
+        while done < buf.len() {
+            let block_id = ((addr + done) / BLOCK_SIZE) as u32;
+            let off = (addr + done) % BLOCK_SIZE;
+            let len = (BLOCK_SIZE - off).min(buf.len() - done);
+            if self.key.is_plain(block_id) {
+                let read = self
+                    .io
+                    .read_lossy(addr + done, &mut buf[done..done + len])?;
+                done += read;
+                if read != len {
+                    break;
+                }
+                continue;
+            }
+            self.read_block(block_id, &mut block)?;
+            buf[done..done + len].copy_from_slice(&block[off..off + len]);
+            done += len;
+        }
+        Ok(done)
+    }
+
+    fn write_lossy(&mut self, addr: usize, buf: &[u8]) -> Result<usize, DiskError> {
+        let mut done = 0;
+        let mut block = [0; BLOCK_SIZE];
+        while done < buf.len() {
+            let block_id = ((addr + done) / BLOCK_SIZE) as u32;
+            let off = (addr + done) % BLOCK_SIZE;
+            let len = (BLOCK_SIZE - off).min(buf.len() - done);
+            if self.key.is_plain(block_id) {
+                let written = self.io.write_lossy(addr + done, &buf[done..done + len])?;
+                done += written;
+                if written != len {
+                    break;
+                }
+                continue;
+            }
+            if len != BLOCK_SIZE {
+                self.read_block(block_id, &mut block)?;
+            }
+            block[off..off + len].copy_from_slice(&buf[done..done + len]);
+            self.write_block(block_id, &block)?;
+            done += len;
+        }
+        Ok(done)
+    }
+
+    fn sync(&mut self) -> Result<(), DiskError> {
+        self.io.sync()
+    }
+
+    fn size(&mut self) -> Option<u64> {
+        self.io.size()
+    }
+}
+
+#[cfg(all(test, feature = "encryption"))]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{
+        fs::{MkfsOptions, MountOptions},
+        Sfs,
+    };
+
+    const BLOCKS: u32 = 300;
+
+    /// An image that stays reachable after the file system on it is dropped, to look at the
+    /// blocks as they are stored
+    #[derive(Clone)]
+    struct SharedImage(Arc<Mutex<Vec<u8>>>);
+
+    impl IO for SharedImage {
+        fn read_lossy(&mut self, addr: usize, buf: &mut [u8]) -> Result<usize, DiskError> {
+            self.0.lock().unwrap().read_lossy(addr, buf)
+        }
+
+        fn write_lossy(&mut self, addr: usize, buf: &[u8]) -> Result<usize, DiskError> {
+            self.0.lock().unwrap().write_lossy(addr, buf)
+        }
+    }
+
+    fn mount(image: &SharedImage, passphrase: Option<&str>) -> Result<FileSystem, FsError> {
+        let options = MountOptions {
+            passphrase: passphrase.map(Passphrase::from),
+            ..Default::default()
+        };
+        FileSystem::mount(Disk::new(Box::new(image.clone())), options)
+    }
+
+    #[test]
+    fn encrypted_images_read_back_after_remounting() {
+        for cipher in [Cipher::XChaCha20Poly1305, Cipher::Aes256Gcm] {
+            let image = SharedImage(Arc::new(Mutex::new(vec![
+                0;
+                Addr::block(BLOCKS).unwrap().get()
+            ])));
+            let disk = Disk::new(Box::new(image.clone()));
+            let fs = FileSystem::format(disk, BLOCKS, "crypt", &MkfsOptions::default()).unwrap();
+            let mut sfs = Sfs::from(fs);
+            sfs.write("/before", &[1; 2 * BLOCK_SIZE]).unwrap();
+            sfs.fs().enable_encryption("secret", cipher).unwrap();
+            sfs.write("/after", b"written encrypted").unwrap();
+            sfs.sync().unwrap();
+            drop(sfs);
+
+            // nothing of the contents is stored in the clear
+            let stored = image.0.lock().unwrap().clone();
+            assert!(!stored
+                .windows(b"written encrypted".len())
+                .any(|window| window == b"written encrypted"));
+            assert!(!stored
+                .chunks(BLOCK_SIZE)
+                .any(|block| block == [1; BLOCK_SIZE]));
+
+            assert!(matches!(mount(&image, None), Err(FsError::BadPassphrase)));
+            assert!(matches!(
+                mount(&image, Some("wrong")),
+                Err(FsError::BadPassphrase)
+            ));
+            let mut fs = mount(&image, Some("secret")).unwrap();
+            assert!(fs.fsck(false).unwrap().is_clean());
+            let before = fs.lookup_path("/before").unwrap();
+            let block = fs.read_inode(before).unwrap().block_map(&mut fs).unwrap()[0];
+            let mut sfs = Sfs::from(fs);
+            assert_eq!(sfs.read("/before").unwrap(), [1; 2 * BLOCK_SIZE]);
+            assert_eq!(sfs.read("/after").unwrap(), b"written encrypted");
+            drop(sfs);
+
+            // a changed block fails to decrypt instead of reading as garbage
+            image.0.lock().unwrap()[Addr::block(block).unwrap().get() + 7] ^= 1;
+            let mut sfs = Sfs::from(mount(&image, Some("secret")).unwrap());
+            assert!(matches!(
+                sfs.read("/before"),
+                Err(FsError::DiskError(DiskError::DecryptionFailed { .. }))
+            ));
+        }
+    }
+}
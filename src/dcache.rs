@@ -0,0 +1,173 @@
+use alloc::{collections::BTreeMap, vec::Vec};
+
+type Key = (u32, Vec<u8>);
+
+/// The blocks of a directory (as indices into it) holding a name with each hash, see
+/// `DirCache::insert_index`
+pub(crate) type DirIndex = BTreeMap<u64, Vec<u32>>;
+
+/// A map that drops the least recently used entries beyond its capacity
+#[derive(Debug, Default)]
+pub(crate) struct Lru<K, V> {
+    capacity: usize,
+    /// every cached value and when it was last used
+    entries: BTreeMap<K, (V, u64)>,
+    /// every cached key by the time it was last used, the least recently used first
+    lru: BTreeMap<u64, K>,
+    clock: u64,
+}
+
+impl<K: Ord + Clone, V> Lru<K, V> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    pub(crate) fn get(&mut self, key: &K) -> Option<&V> {
+        let (value, used) = self.entries.get_mut(key)?;
+        self.clock += 1;
+        self.lru.remove(used);
+        *used = self.clock;
+        self.lru.insert(self.clock, key.clone());
+        Some(value)
+    }
+
+    pub(crate) fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(&key);
+        self.shrink(self.capacity - 1);
+
+        self.clock += 1;
+        self.entries.insert(key.clone(), (value, self.clock));
+        self.lru.insert(self.clock, key);
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some((_, used)) = self.entries.remove(key) {
+            self.lru.remove(&used);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.lru.clear();
+    }
+
+    /// Drops the least recently used entries until at most `len` are left
+    fn shrink(&mut self, len: usize) {
+        while self.entries.len() > len {
+            let Some((_, oldest)) = self.lru.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+    }
+
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.shrink(capacity);
+    }
+}
+
+impl<V> Lru<Key, V> {
+    /// Drops every entry of `dir`
+    fn forget_dir(&mut self, dir: u32) {
+        let keys: Vec<_> = self
+            .entries
+            .range((dir, Vec::new())..)
+            .take_while(|((entry_dir, _), _)| *entry_dir == dir)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in keys {
+            self.remove(&key);
+        }
+    }
+}
+
+/// The most recently looked up directory entries by directory and name, the names recently
+/// found not to exist and the hash indices of the most recently searched large directories.
+/// Entries are dropped whenever their directory inode is written, which every change to its
+/// entries does.
+#[derive(Debug, Default)]
+pub(crate) struct DirCache {
+    found: Lru<Key, u32>,
+    /// negative entries, bounded separately so probing for many missing names can't push out the
+    /// entries that exist
+    missing: Lru<Key, ()>,
+    indices: Lru<u32, DirIndex>,
+}
+
+impl DirCache {
+    pub(crate) fn new(capacity: usize, negative_capacity: usize, index_capacity: usize) -> Self {
+        let mut cache = Self::default();
+        cache.set_capacity(capacity, negative_capacity, index_capacity);
+        cache
+    }
+
+    /// `Some(Some(inode))` if the entry `name` of `dir` is cached, `Some(None)` if it is known not
+    /// to exist and `None` if the directory has to be read
+    pub(crate) fn get(&mut self, dir: u32, name: &[u8]) -> Option<Option<u32>> {
+        let key = (dir, name.to_vec());
+        if let Some(&inode) = self.found.get(&key) {
+            return Some(Some(inode));
+        }
+        self.missing.get(&key).map(|()| None)
+    }
+
+    pub(crate) fn insert(&mut self, dir: u32, name: &[u8], inode: u32) {
+        self.found.insert((dir, name.to_vec()), inode);
+    }
+
+    /// Remembers that `dir` has no entry `name`
+    pub(crate) fn insert_missing(&mut self, dir: u32, name: &[u8]) {
+        self.missing.insert((dir, name.to_vec()), ());
+    }
+
+    /// The blocks of `dir` that can hold a name with the hash `hash`, `None` if the directory has
+    /// no cached index
+    pub(crate) fn index_blocks(&mut self, dir: u32, hash: u64) -> Option<Vec<u32>> {
+        let index = self.indices.get(&dir)?;
+        Some(index.get(&hash).cloned().unwrap_or_default())
+    }
+
+    /// Caches the hash index of `dir`, which has to list every entry
+    pub(crate) fn insert_index(&mut self, dir: u32, index: DirIndex) {
+        self.indices.insert(dir, index);
+    }
+
+    /// Whether hash indices are cached at all
+    pub(crate) fn has_indices(&self) -> bool {
+        self.indices.capacity != 0
+    }
+
+    /// Drops every cached entry of `dir`, positive and negative, and its index
+    pub(crate) fn forget_dir(&mut self, dir: u32) {
+        self.found.forget_dir(dir);
+        self.missing.forget_dir(dir);
+        self.indices.remove(&dir);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.found.clear();
+        self.missing.clear();
+        self.indices.clear();
+    }
+
+    /// Changes the capacities, dropping the least recently used entries that don't fit anymore
+    pub(crate) fn set_capacity(
+        &mut self,
+        capacity: usize,
+        negative_capacity: usize,
+        index_capacity: usize,
+    ) {
+        self.found.set_capacity(capacity);
+        self.missing.set_capacity(negative_capacity);
+        self.indices.set_capacity(index_capacity);
+    }
+}
@@ -0,0 +1,129 @@
+use crate::{
+    fs::{BlockArrayEntry, FileSystem, FsError, BLOCK_SIZE, INODES_PER_BLOCK},
+    inode::{InodeType, INODE_FLAG_PINNED},
+};
+
+/// What a single `FileSystem::defrag_step` did
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DefragProgress {
+    /// data blocks moved next to their predecessor in the file
+    pub moved: u32,
+    /// inode blocks looked at
+    pub scanned: u32,
+    /// the whole image was walked, further steps won't do anything
+    pub done: bool,
+}
+
+/// An online defragmentation pass that works in small steps, so it can run next to normal use of
+/// the file system, e.g. a few blocks per timer tick of a long-lived embedder. The file system can
+/// be changed freely between steps; the pass only remembers where it stopped.
+///
+/// Every file and directory that isn't pinned (see `FileSystem::set_pinned`) is walked in inode
+/// order and every block that doesn't directly follow
+/// the previous block of the file is moved there. If that block is taken, the rest of the file is
+/// moved to the first free run that is long enough to hold all of it. Each move copies the data
+/// before pointing the inode at the new block and only then frees the old one, so an interrupted
+/// move leaves the file intact and at worst leaks the new block.
+#[derive(Debug, Default, Clone)]
+pub struct Defrag {
+    /// the inode the next step continues with
+    inode_nbr: u32,
+    /// the block index inside of that inode the next step continues with
+    block: u32,
+    done: bool,
+}
+
+impl Defrag {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    pub(crate) fn step(
+        &mut self,
+        fs: &mut FileSystem,
+        max_blocks: u32,
+    ) -> Result<DefragProgress, FsError> {
+        let mut progress = DefragProgress::default();
+        let end = fs.superblock.total_blocks * INODES_PER_BLOCK;
+
+        while !self.done && progress.moved + progress.scanned < max_blocks {
+            if self.inode_nbr >= end {
+                self.done = true;
+                break;
+            }
+
+            let inode_block = self.inode_nbr / INODES_PER_BLOCK;
+            if self.inode_nbr.is_multiple_of(INODES_PER_BLOCK) && self.block == 0 {
+                progress.scanned += 1;
+                if fs.block_type(inode_block)? != BlockArrayEntry::InodeBlock {
+                    self.inode_nbr += INODES_PER_BLOCK;
+                    continue;
+                }
+            }
+
+            let budget = max_blocks - progress.moved - progress.scanned;
+            let (moved, finished) = self.defrag_inode(fs, budget)?;
+            progress.moved += moved;
+            if !finished {
+                break;
+            }
+            self.inode_nbr += 1;
+            self.block = 0;
+        }
+
+        progress.done = self.done;
+        Ok(progress)
+    }
+
+    /// Moves up to `budget` blocks of the current inode. Returns how many were moved and whether
+    /// the inode is finished.
+    fn defrag_inode(&mut self, fs: &mut FileSystem, budget: u32) -> Result<(u32, bool), FsError> {
+        let mut inode = fs.read_inode(self.inode_nbr)?;
+        if inode.hardlinks == 0
+            || inode.flags & INODE_FLAG_PINNED != 0
+            || !matches!(
+                inode.type_and_permission.get_type(),
+                InodeType::File | InodeType::Directory
+            )
+        {
+            return Ok((0, true));
+        }
+
+        let mut blocks = inode.block_map(fs)?;
+        let mut moved = 0;
+        let mut buf = [0; BLOCK_SIZE];
+
+        for index in self.block.max(1) as usize..blocks.len() {
+            let target = blocks[index - 1] + 1;
+            if blocks[index] == target {
+                continue;
+            }
+            if moved == budget {
+                self.block = index as u32;
+                return Ok((moved, false));
+            }
+            let target = if fs.allocate_block_id(target)? {
+                target
+            } else {
+                let remaining = (blocks.len() - index) as u32;
+                match fs.find_free_run(remaining)? {
+                    Some(start) if fs.allocate_block_id(start)? => start,
+                    _ => continue,
+                }
+            };
+
+            fs.read_block(blocks[index], &mut buf)?;
+            fs.write_block(target, &buf)?;
+            inode.set_block_id(index as u32, target, fs, self.inode_nbr)?;
+            fs.free_block(blocks[index])?;
+            blocks[index] = target;
+            moved += 1;
+        }
+
+        Ok((moved, true))
+    }
+}
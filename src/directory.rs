@@ -1,55 +1,45 @@
-use std::mem::size_of;
+use alloc::{
+    boxed::Box,
+    collections::VecDeque,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
 
 use crate::{
-    disk::Disk,
     fs::{FileSystem, FsError, BLOCK_SIZE},
-    inode::Inode,
+    inode::{Inode, InodeType},
 };
 
 pub const DIRENTRY_NAME_LENGTH: usize = 0xff;
+/// The bytes in front of the name of every record: the inode, the record length, the name length
+/// and the type
+pub(crate) const DIRENT_HEADER_SIZE: u32 = 8;
+/// Records start at and span multiples of this many bytes
+const DIRENT_ALIGN: u32 = 4;
+/// The bytes a record with a one byte name takes up
+pub(crate) const MIN_RECORD_SIZE: u32 = (DIRENT_HEADER_SIZE + 1).next_multiple_of(DIRENT_ALIGN);
 
-#[derive(Debug)]
-#[repr(C)]
+#[derive(Debug, Clone)]
 pub struct DirEntry {
-    name_size: u8,
     pub inode: u32,
+    name_size: u8,
+    /// the upper nibble of the childs type bitfield (`InodeType::as_u16() >> 12`), so listings
+    /// dont have to read the inode to know what it is
+    file_type: u8,
     name: [u8; DIRENTRY_NAME_LENGTH],
 }
 
 impl DirEntry {
-    pub fn read_from_disk(
-        inode: &mut Inode,
-        fs: &mut FileSystem,
-        addr: usize,
-    ) -> Result<Self, FsError> {
-        let mut empty = Self {
-            name_size: 0,
-            inode: 0,
-            name: [0; DIRENTRY_NAME_LENGTH],
-        };
-
-        let mut value: [u8; 1] = [0];
-
-        inode.read_exact(addr, &mut value, fs)?;
-        empty.name_size = value[0];
-
-        empty.inode = inode.read_struct::<u32>(addr + 1, fs)?;
-
-        if empty.name_size != 0 {
-            inode.read_exact(addr + 5, &mut empty.name[0..empty.name_size as usize], fs)?;
-        }
-
-        Ok(empty)
-    }
-
-    pub fn create(inode: u32, name: String) -> Result<Self, FsError> {
-        if name.as_bytes().len() >= DIRENTRY_NAME_LENGTH || name.is_empty() {
+    pub fn create(inode: u32, typ: &InodeType, name: String) -> Result<Self, FsError> {
+        if name.len() >= DIRENTRY_NAME_LENGTH || name.is_empty() {
             return Err(FsError::NameTooLong);
         }
 
         let mut ent = DirEntry {
             name_size: name.len() as u8,
             inode,
+            file_type: (typ.as_u16() >> 12) as u8,
             name: [0; DIRENTRY_NAME_LENGTH],
         };
 
@@ -64,15 +54,57 @@ impl DirEntry {
         self.inode == 0 || self.name_size == 0
     }
 
+    /// The length of the smallest record this entry fits in
     pub fn get_size(&self) -> u32 {
-        5 + self.name_size as u32
+        (DIRENT_HEADER_SIZE + self.name_size as u32).next_multiple_of(DIRENT_ALIGN)
+    }
+
+    /// The type of the inode this entry links to, without having to read the inode
+    pub fn get_type(&self) -> InodeType {
+        InodeType::from_u16((self.file_type as u16) << 12)
     }
 
-    pub fn write_to_disk(&self, disk: &mut Disk, addr: usize) -> Result<(), FsError> {
-        disk.write_exact(addr, &[self.name_size])?;
-        disk.write_struct(addr + 1, &self.inode)?;
-        disk.write_exact(addr + 5, &self.name[0..self.name_size as usize])?;
-        Ok(())
+    pub fn set_type(&mut self, typ: &InodeType) {
+        self.file_type = (typ.as_u16() >> 12) as u8;
+    }
+
+    /// Parses the record at the start of `buf`, `None` if it is a tombstone or cut off
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        let inode = u32::from_ne_bytes(buf.get(0..4)?.try_into().ok()?);
+        let name_size = *buf.get(6)?;
+        if name_size == 0 || inode == 0 {
+            return None;
+        }
+
+        let mut name = [0; DIRENTRY_NAME_LENGTH];
+        let start = DIRENT_HEADER_SIZE as usize;
+        name[..name_size as usize].copy_from_slice(buf.get(start..start + name_size as usize)?);
+        Some(Self {
+            inode,
+            name_size,
+            file_type: buf[7],
+            name,
+        })
+    }
+
+    /// Writes the entry as a record of `rec_len` bytes to the start of `buf`. The bytes after the
+    /// name are left alone, nothing reads them.
+    pub fn encode(&self, buf: &mut [u8], rec_len: u32) {
+        buf[0..4].copy_from_slice(&self.inode.to_ne_bytes());
+        set_record_len(buf, rec_len);
+        buf[6] = self.name_size;
+        buf[7] = self.file_type;
+        let start = DIRENT_HEADER_SIZE as usize;
+        buf[start..start + self.name_size as usize].copy_from_slice(self.name_bytes());
+    }
+
+    pub fn name_bytes(&self) -> &[u8] {
+        &self.name[0..self.name_size as usize]
+    }
+
+    /// Whether this is the `.` or `..` entry of a directory, see `is_dot_name`
+    pub fn is_dot(&self) -> bool {
+        is_dot_name(self.name_bytes())
     }
 
     pub fn get_name(&self) -> String {
@@ -80,9 +112,161 @@ impl DirEntry {
     }
 }
 
+/// Whether `name` is `.` or `..`, which only the file system itself can create or remove, see
+/// `FileSystem::has_dot_entries`
+pub fn is_dot_name(name: &[u8]) -> bool {
+    matches!(name, b"." | b"..")
+}
+
+fn set_record_len(record: &mut [u8], rec_len: u32) {
+    record[4..6].copy_from_slice(&(rec_len as u16).to_ne_bytes());
+}
+
+/// One record of a directory block
+#[derive(Debug, Clone)]
+pub(crate) struct DirRecord {
+    /// in bytes from the start of the block
+    pub(crate) off: u32,
+    /// in bytes, up to the next record or the end of the block
+    pub(crate) len: u32,
+    /// `None` for a tombstone
+    pub(crate) entry: Option<DirEntry>,
+}
+
+impl DirRecord {
+    /// The bytes of the record its entry needs, the rest is free for another entry
+    fn used(&self) -> u32 {
+        self.entry.as_ref().map_or(0, DirEntry::get_size)
+    }
+}
+
+/// The records of a directory block in order. They cover the whole block; an all-zero block, as
+/// freshly allocated, is a single tombstone.
+pub(crate) fn parse_dir_block(buf: &[u8; BLOCK_SIZE]) -> Result<Vec<DirRecord>, FsError> {
+    let mut records = Vec::new();
+    let mut off = 0;
+
+    while off < BLOCK_SIZE as u32 {
+        let start = off as usize;
+        let len = u16::from_ne_bytes([buf[start + 4], buf[start + 5]]) as u32;
+        if off == 0 && len == 0 {
+            records.push(DirRecord {
+                off,
+                len: BLOCK_SIZE as u32,
+                entry: None,
+            });
+            break;
+        }
+        if len < DIRENT_HEADER_SIZE
+            || !len.is_multiple_of(DIRENT_ALIGN)
+            || off + len > BLOCK_SIZE as u32
+        {
+            return Err(FsError::Corrupted);
+        }
+
+        let entry = DirEntry::decode(&buf[start..start + len as usize]);
+        if entry.is_none() && buf[start..start + 4] != [0; 4] && buf[start + 6] != 0 {
+            // the name runs past the end of the record
+            return Err(FsError::Corrupted);
+        }
+        records.push(DirRecord { off, len, entry });
+        off += len;
+    }
+
+    Ok(records)
+}
+
+/// Packs `entries` into a block in order, the last record reaching to the end of the block. They
+/// have to fit.
+fn encode_dir_block(entries: &[&DirEntry]) -> Box<[u8; BLOCK_SIZE]> {
+    let mut buf = Box::new([0; BLOCK_SIZE]);
+    let mut off = 0;
+    for (i, entry) in entries.iter().enumerate() {
+        let len = if i + 1 == entries.len() {
+            BLOCK_SIZE as u32 - off
+        } else {
+            entry.get_size()
+        };
+        entry.encode(&mut buf[off as usize..], len);
+        off += len;
+    }
+    buf
+}
+
+/// Packs `entries` into as few blocks as they fit in, filling every block before the next one and
+/// keeping their order. There is always at least one block.
+pub(crate) fn pack_dir_blocks(entries: &[DirEntry]) -> Vec<Box<[u8; BLOCK_SIZE]>> {
+    let mut blocks = vec![Vec::new()];
+    let mut used = 0;
+    for entry in entries {
+        if used + entry.get_size() > BLOCK_SIZE as u32 {
+            blocks.push(Vec::new());
+            used = 0;
+        }
+        blocks
+            .last_mut()
+            .expect("there is always a block")
+            .push(entry);
+        used += entry.get_size();
+    }
+    blocks
+        .iter()
+        .map(|entries| encode_dir_block(entries))
+        .collect()
+}
+
+/// Puts `entry` into the first tombstone or the first free space behind a record that it fits
+/// in, splitting that record. `false` if the block has no room for it.
+pub(crate) fn insert_into_block(
+    buf: &mut [u8; BLOCK_SIZE],
+    entry: &DirEntry,
+) -> Result<bool, FsError> {
+    let size = entry.get_size();
+    for record in parse_dir_block(buf)? {
+        let used = record.used();
+        if record.len - used < size {
+            continue;
+        }
+        let off = (record.off + used) as usize;
+        if used != 0 {
+            set_record_len(&mut buf[record.off as usize..], used);
+        }
+        entry.encode(&mut buf[off..], record.len - used);
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+/// Removes the entry called `name` from the block and returns it. Its record is merged into the
+/// one in front of it, or becomes a tombstone if it is the first one of the block.
+pub(crate) fn remove_from_block(
+    buf: &mut [u8; BLOCK_SIZE],
+    name: &[u8],
+) -> Result<Option<DirEntry>, FsError> {
+    let records = parse_dir_block(buf)?;
+    let Some(pos) = records.iter().position(|record| {
+        record
+            .entry
+            .as_ref()
+            .is_some_and(|entry| entry.name_bytes() == name)
+    }) else {
+        return Ok(None);
+    };
+
+    let record = &records[pos];
+    match pos.checked_sub(1).map(|prev| &records[prev]) {
+        Some(prev) => set_record_len(&mut buf[prev.off as usize..], prev.len + record.len),
+        None => buf[0..4].fill(0),
+    }
+    Ok(record.entry.clone())
+}
+
+/// Iterates over the entries of a directory, without `.` and `..`
 pub struct DirectoryIterator<'a> {
-    next_off: u32,
     next_blk: u32,
+    /// the blocks of the directory, read on the first call
+    blocks: Option<u32>,
+    entries: VecDeque<DirEntry>,
     inode: Inode,
     fs: &'a mut FileSystem,
 }
@@ -93,7 +277,8 @@ impl<'a> DirectoryIterator<'a> {
             fs,
             inode,
             next_blk: 0,
-            next_off: 0,
+            blocks: None,
+            entries: VecDeque::new(),
         }
     }
 }
@@ -102,27 +287,124 @@ impl Iterator for DirectoryIterator<'_> {
     type Item = DirEntry;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let dir_entry = DirEntry::read_from_disk(
-            &mut self.inode,
-            &mut self.fs,
-            self.next_blk as usize * BLOCK_SIZE + self.next_off as usize,
-        )
-        .ok()?;
-
-        self.next_off += dir_entry.get_size();
-        if self.next_off + size_of::<DirEntry>() as u32 >= BLOCK_SIZE as u32 {
-            self.next_off = 0;
+        let blocks = match self.blocks {
+            Some(blocks) => blocks,
+            None => *self
+                .blocks
+                .insert(self.inode.block_map(self.fs).ok()?.len() as u32),
+        };
+
+        loop {
+            if let Some(dir_entry) = self.entries.pop_front() {
+                if dir_entry.is_dot() {
+                    continue;
+                }
+                return Some(dir_entry);
+            }
+            if self.next_blk >= blocks {
+                return None;
+            }
+            self.entries = self
+                .inode
+                .read_dir_block(self.fs, self.next_blk)
+                .ok()?
+                .into();
             self.next_blk += 1;
         }
-        if dir_entry.is_empty() {
-            return self.next();
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::{
+        addr::Addr,
+        disk::Disk,
+        fs::{FileSystem, MountOptions},
+        Sfs,
+    };
+
+    const BLOCKS: u32 = 300;
+
+    /// Names of every length class, so records of different sizes end up next to each other
+    fn name(i: usize) -> String {
+        format!("{i}{}", "x".repeat(i * 7 % 60))
+    }
+
+    #[test]
+    fn records_survive_removals_reuse_and_remounting() {
+        let mut sfs = Sfs::from(FileSystem::create(BLOCKS, "dirs").unwrap());
+        sfs.create_dir("/dir").unwrap();
+        let mut expected = BTreeSet::new();
+        // several blocks worth of entries
+        for i in 0..200 {
+            sfs.write(format!("/dir/{}", name(i)).as_str(), b"")
+                .unwrap();
+            expected.insert(name(i));
+        }
+        // tombstones at the start of blocks and merged records in between
+        for i in (0..200).step_by(3) {
+            sfs.remove(format!("/dir/{}", name(i)).as_str()).unwrap();
+            expected.remove(&name(i));
         }
+        // reusing the freed space with shorter and longer names
+        for i in 200..260 {
+            sfs.write(format!("/dir/{}", name(i)).as_str(), b"")
+                .unwrap();
+            expected.insert(name(i));
+        }
+        let fs = sfs.fs();
+        let dir = fs.lookup_path("/dir").unwrap();
+        fs.rename(dir, &name(1), dir, &"renamed".repeat(30))
+            .unwrap();
+        expected.remove(&name(1));
+        expected.insert("renamed".repeat(30));
 
-        self.next_off += dir_entry.get_size();
-        if self.next_off + size_of::<DirEntry>() as u32 >= BLOCK_SIZE as u32 {
-            self.next_off = 0;
-            self.next_blk += 1;
+        let mut image = vec![0; Addr::block(BLOCKS).unwrap().get()];
+        fs.export_image(&mut image).unwrap();
+        let fs = FileSystem::mount(Disk::new(Box::new(image)), MountOptions::default()).unwrap();
+        let mut sfs = Sfs::from(fs);
+        let listed: BTreeSet<String> = sfs
+            .list("/dir")
+            .unwrap()
+            .iter()
+            .map(DirEntry::get_name)
+            .collect();
+        assert_eq!(listed, expected);
+        for name in &expected {
+            assert!(sfs.exists(format!("/dir/{name}").as_str()).unwrap());
         }
-        Some(dir_entry)
+        assert!(sfs.fs().fsck(false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn removed_records_make_room_in_their_block() {
+        let entries: Vec<DirEntry> = (0..10)
+            .map(|i| DirEntry::create(i + 1, &InodeType::File, name(i as usize)).unwrap())
+            .collect();
+        let mut block = pack_dir_blocks(&entries).remove(0);
+        for removed in [0, 4, 5] {
+            let entry = remove_from_block(&mut block, entries[removed].name_bytes()).unwrap();
+            assert_eq!(entry.unwrap().inode, removed as u32 + 1);
+        }
+        let records = parse_dir_block(&block).unwrap();
+        assert!(records[0].entry.is_none());
+        assert_eq!(
+            records.iter().map(|record| record.len).sum::<u32>(),
+            BLOCK_SIZE as u32
+        );
+
+        // the first gap big enough takes the new entry
+        let new = DirEntry::create(99, &InodeType::File, name(4)).unwrap();
+        assert!(insert_into_block(&mut block, &new).unwrap());
+        let names: Vec<String> = parse_dir_block(&block)
+            .unwrap()
+            .into_iter()
+            .filter_map(|record| record.entry.map(|entry| entry.get_name()))
+            .collect();
+        assert_eq!(names.len(), 8);
+        assert!(names.contains(&name(4)));
     }
 }
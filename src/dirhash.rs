@@ -0,0 +1,111 @@
+/// The hash function used for directory indices, recorded per image in the superblock. Every
+/// algorithm is keyed with the per-image seed, so names that collide on one image don't collide
+/// on another and a directory populated from untrusted names can't be flooded with collisions
+/// (as long as the seed stays secret, which it does unless the image itself is handed out).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum DirHashAlgorithm {
+    /// FNV-1a over the seed and the name: fast, but an attacker that knows the seed can still
+    /// produce collisions
+    Fnv1a = 1,
+    /// SipHash-2-4 keyed with the seed: slower, but collision-flooding resistant
+    #[default]
+    SipHash24 = 2,
+}
+
+impl DirHashAlgorithm {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Self::Fnv1a),
+            2 => Some(Self::SipHash24),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Fnv1a => "fnv1a",
+            Self::SipHash24 => "siphash24",
+        }
+    }
+
+    pub fn hash(&self, seed: [u64; 2], name: &[u8]) -> u64 {
+        match self {
+            Self::Fnv1a => fnv1a(seed, name),
+            Self::SipHash24 => siphash24(seed, name),
+        }
+    }
+}
+
+fn fnv1a(seed: [u64; 2], name: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let seed_bytes = seed[0]
+        .to_le_bytes()
+        .into_iter()
+        .chain(seed[1].to_le_bytes());
+    for byte in seed_bytes.chain(name.iter().copied()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn siphash24(key: [u64; 2], data: &[u8]) -> u64 {
+    let mut v = [
+        key[0] ^ 0x736f6d6570736575,
+        key[1] ^ 0x646f72616e646f6d,
+        key[0] ^ 0x6c7967656e657261,
+        key[1] ^ 0x7465646279746573,
+    ];
+
+    fn round(v: &mut [u64; 4]) {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    }
+
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        let m = u64::from_le_bytes(chunk.try_into().expect("chunks are 8 bytes"));
+        v[3] ^= m;
+        round(&mut v);
+        round(&mut v);
+        v[0] ^= m;
+    }
+
+    let mut last = [0; 8];
+    last[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+    last[7] = data.len() as u8;
+    let m = u64::from_le_bytes(last);
+    v[3] ^= m;
+    round(&mut v);
+    round(&mut v);
+    v[0] ^= m;
+
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+/// A fresh random seed, from the randomly keyed hasher std uses for `HashMap`
+#[cfg(feature = "std")]
+pub fn random_seed() -> [u64; 2] {
+    use core::hash::{BuildHasher, Hasher};
+
+    let mut seed = [0; 2];
+    for (i, part) in seed.iter_mut().enumerate() {
+        let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+        hasher.write_usize(i);
+        *part = hasher.finish();
+    }
+    seed
+}
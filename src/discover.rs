@@ -0,0 +1,124 @@
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    addr::Addr,
+    disk::Disk,
+    fs::{FsError, BLOCK_SIZE},
+    superblock::{Superblock, Uuid},
+};
+
+/// Where an image can start inside of a file or device: a bare image, an MBR partition at the old
+/// cylinder boundary (sector 63) and a partition aligned to 1 MiB (sector 2048)
+pub const CANDIDATE_OFFSETS: [usize; 3] = [0, 63 * 512, 2048 * 512];
+
+/// An image found by `find_images`
+#[derive(Debug, Clone)]
+pub struct DiscoveredImage {
+    pub path: PathBuf,
+    /// the byte offset of the images first block in the file
+    pub offset: usize,
+    pub label: String,
+    /// `None` for images from before UUIDs were recorded
+    pub uuid: Option<Uuid>,
+    pub total_blocks: u32,
+}
+
+/// Scans all regular files and block devices directly inside of `dir` for sfs superblocks at any
+/// of the `CANDIDATE_OFFSETS`. Files that can't be opened (e.g. devices without permission) are
+/// skipped.
+pub fn find_images<P: AsRef<Path>>(dir: P) -> Result<Vec<DiscoveredImage>, FsError> {
+    let mut images = Vec::new();
+
+    for entry in std::fs::read_dir(dir).map_err(FsError::IoError)? {
+        let Ok(entry) = entry else { continue };
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if !file_type.is_file() && !is_block_device(&file_type) {
+            continue;
+        }
+        let Ok(file) = File::open(entry.path()) else {
+            continue;
+        };
+
+        let mut disk = Disk::new(Box::new(file));
+        for offset in CANDIDATE_OFFSETS {
+            let Ok(addr) = Addr::block(1).and_then(|addr| addr.offset(offset)) else {
+                continue;
+            };
+            // anything that isn't a readable superblock simply isn't an image
+            let Ok(superblock) = Superblock::read(&mut disk, addr.get()) else {
+                continue;
+            };
+            images.push(DiscoveredImage {
+                path: entry.path(),
+                offset,
+                label: superblock.get_name(),
+                uuid: Some(superblock.uuid).filter(|uuid| !uuid.is_nil()),
+                total_blocks: superblock.total_blocks,
+            });
+        }
+    }
+
+    images.sort_by(|a, b| a.path.cmp(&b.path).then(a.offset.cmp(&b.offset)));
+    Ok(images)
+}
+
+/// The image with the UUID `uuid` among the ones `find_images` finds in `dir`. If a copy of an
+/// image sits next to it, the first one in path order is returned.
+pub fn find_image_by_uuid<P: AsRef<Path>>(
+    dir: P,
+    uuid: Uuid,
+) -> Result<Option<DiscoveredImage>, FsError> {
+    Ok(find_images(dir)?
+        .into_iter()
+        .find(|image| image.uuid == Some(uuid)))
+}
+
+#[cfg(unix)]
+fn is_block_device(file_type: &std::fs::FileType) -> bool {
+    std::os::unix::fs::FileTypeExt::is_block_device(file_type)
+}
+
+#[cfg(not(unix))]
+fn is_block_device(_: &std::fs::FileType) -> bool {
+    false
+}
+
+impl DiscoveredImage {
+    /// The size of the image in bytes, according to its superblock
+    pub fn size(&self) -> usize {
+        self.total_blocks as usize * BLOCK_SIZE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Sfs;
+
+    #[test]
+    fn finds_images_by_uuid() {
+        let dir = std::env::temp_dir().join(format!("sfs-discover-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut uuids = Vec::new();
+        for name in ["a", "b"] {
+            let mut sfs = Sfs::create(dir.join(format!("{name}.img")), 64, name).unwrap();
+            sfs.sync().unwrap();
+            uuids.push(sfs.fs().superblock.uuid);
+        }
+        assert_ne!(uuids[0], uuids[1]);
+
+        let images = find_images(&dir).unwrap();
+        assert_eq!(images.len(), 2);
+        assert_eq!(images[1].uuid, Some(uuids[1]));
+        let uuid: Uuid = uuids[1].to_string().parse().unwrap();
+        let found = find_image_by_uuid(&dir, uuid).unwrap().unwrap();
+        assert_eq!(found.label, "b");
+        assert!(find_image_by_uuid(&dir, Uuid::NIL).unwrap().is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
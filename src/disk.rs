@@ -1,85 +1,1003 @@
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::{
+    fmt::Debug,
+    mem::{size_of, MaybeUninit},
+    ops::Bound,
+};
+#[cfg(feature = "std")]
 use std::{
-    fmt::Debug, fs::File, io::ErrorKind, mem::{size_of, MaybeUninit}, os::unix::fs::FileExt
+    fs::File,
+    io::ErrorKind,
+    ops::Range,
+    os::unix::fs::FileExt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc,
+    },
+    time::Duration,
 };
 
-use crate::fs::BLOCK_SIZE;
+use crate::{
+    fs::BLOCK_SIZE,
+    sync::{lock, Mutex, MutexGuard},
+};
 
 #[derive(Debug)]
 pub enum DiskError {
     NotEnoughSpace,
+    /// An error of the IO that doesn't fit any of the other kinds
     GenericError,
+    ReadOnly,
+    /// The disk was shut down after an error, see `Disk::shut_down`
+    ShutDown,
+    /// The read reached past the end of the IO
+    OutOfBounds,
+    /// The operation was interrupted or timed out before it finished and can be tried again
+    Interrupted,
+    /// The IO doesn't allow the operation, e.g. because of the permissions of the image file
+    PermissionDenied,
+    /// The media failed to read or write the data (`EIO`)
+    HardwareFailure,
+    /// The IO doesn't support the operation
+    Unsupported,
+    /// Only the first `written` bytes of a write reached the IO
+    ShortWrite {
+        written: usize,
+    },
+    /// Reading `block` back after writing it returned different data, see `Disk::set_verify`
+    VerifyFailed {
+        block: u32,
+    },
+    /// `block` doesn't match the hash tree of the image, see `crate::verity`
+    VerityMismatch {
+        block: u32,
+    },
+    /// `block` doesn't match its tag in an encrypted image, see `crate::crypt`
+    DecryptionFailed {
+        block: u32,
+    },
+}
+
+impl DiskError {
+    /// Whether the operation might succeed when tried again, see `RetryPolicy`
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Self::GenericError | Self::Interrupted | Self::HardwareFailure
+        )
+    }
+
+    /// Whether a write failing with this error may have changed the IO, see `Disk::is_degraded`
+    fn may_have_written(&self) -> bool {
+        matches!(
+            self,
+            Self::NotEnoughSpace
+                | Self::GenericError
+                | Self::PermissionDenied
+                | Self::HardwareFailure
+                | Self::ShortWrite { .. }
+                | Self::VerifyFailed { .. }
+        )
+    }
 }
 
-pub trait IO {
+#[cfg(feature = "std")]
+impl From<std::io::Error> for DiskError {
+    fn from(value: std::io::Error) -> Self {
+        const EIO: i32 = 5;
+
+        match value.kind() {
+            ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+                Self::Interrupted
+            }
+            ErrorKind::PermissionDenied => Self::PermissionDenied,
+            ErrorKind::ReadOnlyFilesystem => Self::ReadOnly,
+            ErrorKind::StorageFull | ErrorKind::FileTooLarge => Self::NotEnoughSpace,
+            ErrorKind::UnexpectedEof => Self::OutOfBounds,
+            ErrorKind::Unsupported => Self::Unsupported,
+            _ if value.raw_os_error() == Some(EIO) => Self::HardwareFailure,
+            _ => Self::GenericError,
+        }
+    }
+}
+
+pub trait IO: Send {
     fn read_lossy(&mut self, addr: usize, buf: &mut [u8]) -> Result<usize, DiskError>;
     fn write_lossy(&mut self, addr: usize, buf: &[u8]) -> Result<usize, DiskError>;
+    /// Makes everything written so far durable. In-memory IOs have nothing to do.
+    fn sync(&mut self) -> Result<(), DiskError> {
+        Ok(())
+    }
+    /// The size in bytes, for IOs that can't grow like block devices. `FileSystem::mount` refuses
+    /// file systems claiming more blocks.
+    fn size(&mut self) -> Option<u64> {
+        None
+    }
 
     fn read_exact(&mut self, addr: usize, buf: &mut [u8]) -> Result<(), DiskError> {
         if self.read_lossy(addr, buf)? != buf.len() {
-            Err(DiskError::NotEnoughSpace)
+            Err(DiskError::OutOfBounds)
         } else {
             Ok(())
         }
     }
     fn write_exact(&mut self, addr: usize, buf: &[u8]) -> Result<(), DiskError> {
-        if self.write_lossy(addr, buf)? != buf.len() {
-            Err(DiskError::NotEnoughSpace)
+        match self.write_lossy(addr, buf)? {
+            written if written != buf.len() => Err(DiskError::ShortWrite { written }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The blocks touched through a `Disk` since accounting was enabled
+#[derive(Debug, Default, Clone)]
+pub struct IoAccounting {
+    pub blocks_read: BTreeSet<u32>,
+    pub blocks_written: BTreeSet<u32>,
+}
+
+impl IoAccounting {
+    fn record(set: &mut BTreeSet<u32>, addr: usize, len: usize) {
+        if len == 0 {
+            return;
+        }
+        for block in addr / BLOCK_SIZE..=(addr + len - 1) / BLOCK_SIZE {
+            set.insert(block as u32);
+        }
+    }
+}
+
+/// Blocks whose writes are kept in memory until `Disk::flush_buffer` is called
+#[derive(Debug, Default)]
+struct WriteBuffer {
+    /// `None` buffers every block
+    blocks: Option<BTreeSet<u32>>,
+    dirty: BTreeMap<u32, Box<[u8; BLOCK_SIZE]>>,
+}
+
+/// When a `BlockCache` writes to the IO, see `Disk::set_cache`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CacheMode {
+    /// every write goes to the IO right away and updates the cached copy
+    #[default]
+    WriteThrough,
+    /// writes only change the cached copy, which is written to the IO once it's evicted or on
+    /// `Disk::flush` and `Disk::sync`
+    WriteBack,
+}
+
+#[derive(Debug)]
+struct CachedBlock {
+    data: Box<[u8; BLOCK_SIZE]>,
+    dirty: bool,
+    /// when the block was last used, the key in `BlockCache::lru`
+    used: u64,
+}
+
+/// The most recently used blocks of a disk
+#[derive(Debug)]
+struct BlockCache {
+    capacity: usize,
+    mode: CacheMode,
+    blocks: BTreeMap<u32, CachedBlock>,
+    /// every cached block by the time it was last used, the least recently used first
+    lru: BTreeMap<u64, u32>,
+    clock: u64,
+}
+
+impl BlockCache {
+    fn new(capacity: usize, mode: CacheMode) -> Self {
+        Self {
+            capacity,
+            mode,
+            blocks: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// The cached copy of `block`, loading it from `io` if needed. `None` if the block is past
+    /// the end of the IO.
+    fn get(&mut self, io: &mut dyn IO, block: u32) -> Result<Option<&mut CachedBlock>, DiskError> {
+        if !self.blocks.contains_key(&block) {
+            let mut data = Box::new([0; BLOCK_SIZE]);
+            if io.read_lossy(block as usize * BLOCK_SIZE, data.as_mut_slice())? != BLOCK_SIZE {
+                return Ok(None);
+            }
+            self.evict(io, self.capacity.saturating_sub(1))?;
+            self.blocks.insert(
+                block,
+                CachedBlock {
+                    data,
+                    dirty: false,
+                    used: 0,
+                },
+            );
+        }
+
+        self.clock += 1;
+        let cached = self
+            .blocks
+            .get_mut(&block)
+            .expect("the block was just loaded");
+        self.lru.remove(&cached.used);
+        cached.used = self.clock;
+        self.lru.insert(self.clock, block);
+        Ok(Some(cached))
+    }
+
+    /// Evicts the least recently used blocks until at most `len` are left
+    fn evict(&mut self, io: &mut dyn IO, len: usize) -> Result<(), DiskError> {
+        while self.blocks.len() > len {
+            let Some((_, block)) = self.lru.pop_first() else {
+                break;
+            };
+            let cached = self
+                .blocks
+                .remove(&block)
+                .expect("every block in lru is cached");
+            if cached.dirty {
+                io.write_exact(block as usize * BLOCK_SIZE, cached.data.as_slice())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes every dirty block to `io`, in block order
+    fn flush(&mut self, io: &mut dyn IO) -> Result<(), DiskError> {
+        for (block, cached) in &mut self.blocks {
+            if cached.dirty {
+                io.write_exact(*block as usize * BLOCK_SIZE, cached.data.as_slice())?;
+                cached.dirty = false;
+            }
+        }
+        Ok(())
+    }
+
+    /// Same as `flush`, but only writes the dirty blocks in `blocks`
+    fn flush_blocks(&mut self, io: &mut dyn IO, blocks: &BTreeSet<u32>) -> Result<(), DiskError> {
+        for block in blocks {
+            if let Some(cached) = self.blocks.get_mut(block).filter(|cached| cached.dirty) {
+                io.write_exact(*block as usize * BLOCK_SIZE, cached.data.as_slice())?;
+                cached.dirty = false;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads `buf` from the block at `addr`, `buf` must not cross a block boundary
+    fn read(&mut self, io: &mut dyn IO, addr: usize, buf: &mut [u8]) -> Result<usize, DiskError> {
+        let off = addr % BLOCK_SIZE;
+        match self.get(io, (addr / BLOCK_SIZE) as u32)? {
+            Some(cached) => {
+                buf.copy_from_slice(&cached.data[off..off + buf.len()]);
+                Ok(buf.len())
+            }
+            None => io.read_lossy(addr, buf),
+        }
+    }
+
+    /// Same as `read`, but a block that isn't cached is read from `io` without caching it
+    fn read_uncached(
+        &mut self,
+        io: &mut dyn IO,
+        addr: usize,
+        buf: &mut [u8],
+    ) -> Result<usize, DiskError> {
+        let off = addr % BLOCK_SIZE;
+        match self.blocks.get(&((addr / BLOCK_SIZE) as u32)) {
+            Some(cached) => {
+                buf.copy_from_slice(&cached.data[off..off + buf.len()]);
+                Ok(buf.len())
+            }
+            None => io.read_lossy(addr, buf),
+        }
+    }
+
+    /// Writes `buf` at `addr`. Unless `through` is set, write-back caches keep the write in
+    /// memory.
+    fn write(
+        &mut self,
+        io: &mut dyn IO,
+        addr: usize,
+        buf: &[u8],
+        through: bool,
+    ) -> Result<usize, DiskError> {
+        let mut done = 0;
+        while done < buf.len() {
+            let off = (addr + done) % BLOCK_SIZE;
+            let chunk_len = (BLOCK_SIZE - off).min(buf.len() - done);
+            let written =
+                self.write_block(io, addr + done, &buf[done..done + chunk_len], through)?;
+            done += written;
+            if written != chunk_len {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    /// Same as `write`, but `buf` must not cross a block boundary
+    fn write_block(
+        &mut self,
+        io: &mut dyn IO,
+        addr: usize,
+        buf: &[u8],
+        through: bool,
+    ) -> Result<usize, DiskError> {
+        let block = (addr / BLOCK_SIZE) as u32;
+        let off = addr % BLOCK_SIZE;
+        let through = through || self.mode == CacheMode::WriteThrough;
+
+        let cached = if through {
+            // write-through caches only keep blocks that were read
+            self.blocks.get_mut(&block)
         } else {
-            Ok(())
+            self.get(io, block)?
+        };
+        match cached {
+            Some(cached) => {
+                cached.data[off..off + buf.len()].copy_from_slice(buf);
+                if through {
+                    // the rest of the block can still hold earlier write-back changes
+                    let data = if cached.dirty { &cached.data[..] } else { buf };
+                    let start = if cached.dirty {
+                        block as usize * BLOCK_SIZE
+                    } else {
+                        addr
+                    };
+                    io.write_exact(start, data)?;
+                    cached.dirty = false;
+                } else {
+                    cached.dirty = true;
+                }
+                Ok(buf.len())
+            }
+            None => io.write_lossy(addr, buf),
+        }
+    }
+}
+
+/// Passes everything on to `io`, reading every write back if `verify` is set, see
+/// `Disk::set_verify`
+struct Verified<'a> {
+    io: &'a mut dyn IO,
+    verify: bool,
+}
+
+impl IO for Verified<'_> {
+    fn read_lossy(&mut self, addr: usize, buf: &mut [u8]) -> Result<usize, DiskError> {
+        self.io.read_lossy(addr, buf)
+    }
+
+    fn write_lossy(&mut self, addr: usize, buf: &[u8]) -> Result<usize, DiskError> {
+        let written = self.io.write_lossy(addr, buf)?;
+        if !self.verify {
+            return Ok(written);
+        }
+
+        let mut data = vec![0; written];
+        let read = self.io.read_lossy(addr, &mut data)?;
+        let mismatch = data[..read]
+            .iter()
+            .zip(buf)
+            .position(|(a, b)| a != b)
+            .or((read < written).then_some(read));
+        match mismatch {
+            Some(offset) => Err(DiskError::VerifyFailed {
+                block: ((addr + offset) / BLOCK_SIZE) as u32,
+            }),
+            None => Ok(written),
+        }
+    }
+
+    fn sync(&mut self) -> Result<(), DiskError> {
+        self.io.sync()
+    }
+}
+
+pub struct Disk {
+    io: Arc<Mutex<Box<dyn IO>>>,
+    read_only: bool,
+    /// sync the IO after every write, see `set_sync`
+    sync: bool,
+    /// refuse all reads and writes, see `shut_down`
+    shut_down: bool,
+    /// a write to the IO failed and all further writes are refused, see `is_degraded`
+    degraded: bool,
+    /// read every write back from the IO, see `set_verify`
+    verify: bool,
+    accounting: Option<IoAccounting>,
+    /// see `track_writes`
+    written: Option<BTreeSet<u32>>,
+    buffer: Option<WriteBuffer>,
+    /// see `set_cache`
+    cache: Option<BlockCache>,
+    /// set once a snapshot was taken from this disk, see `snapshot`
+    epochs: Option<Arc<Mutex<Epochs>>>,
+    /// set if this disk is a snapshot
+    snapshot: Option<Snapshot>,
+}
+
+/// The old contents of blocks that were overwritten while snapshots of an earlier state were
+/// alive, shared between a disk and all of its snapshots
+#[derive(Debug, Default)]
+struct Epochs {
+    /// the epoch of the next snapshot
+    next: u64,
+    /// the number of live snapshot handles per epoch
+    readers: BTreeMap<u64, usize>,
+    /// per block, its old contents by the last epoch that sees them
+    preserved: BTreeMap<u32, BTreeMap<u64, Box<[u8; BLOCK_SIZE]>>>,
+}
+
+impl Epochs {
+    /// Keeps the current contents of every block in `addr..addr + len` that a live snapshot still
+    /// needs, before they're overwritten
+    fn preserve(&mut self, io: &mut dyn IO, addr: usize, len: usize) -> Result<(), DiskError> {
+        let Some(&newest) = self.readers.keys().next_back() else {
+            return Ok(());
+        };
+        if len == 0 {
+            return Ok(());
         }
+        for block in addr / BLOCK_SIZE..=(addr + len - 1) / BLOCK_SIZE {
+            let versions = self.preserved.entry(block as u32).or_default();
+            // an older copy already serves every live snapshot if one was taken since the newest
+            // snapshot
+            if versions.range(newest..).next().is_some() {
+                continue;
+            }
+            let mut data = Box::new([0; BLOCK_SIZE]);
+            io.read_lossy(block * BLOCK_SIZE, data.as_mut_slice())?;
+            versions.insert(self.next - 1, data);
+        }
+        Ok(())
+    }
+
+    /// What a snapshot of `epoch` sees in `block`, or `None` if it wasn't overwritten since
+    fn preserved(&self, block: u32, epoch: u64) -> Option<&[u8; BLOCK_SIZE]> {
+        let versions = self.preserved.get(&block)?;
+        versions.range(epoch..).next().map(|(_, data)| &**data)
+    }
+
+    /// Drops every preserved block that no live snapshot can see anymore
+    fn reclaim(&mut self) {
+        let readers = &self.readers;
+        self.preserved.retain(|_, versions| {
+            // a copy is seen by the snapshots after the previous copy, up to its own epoch
+            let mut previous = None;
+            versions.retain(|&epoch, _| {
+                let first = previous.map_or(Bound::Unbounded, Bound::Excluded);
+                previous = Some(epoch);
+                readers
+                    .range((first, Bound::Included(epoch)))
+                    .next()
+                    .is_some()
+            });
+            !versions.is_empty()
+        });
     }
 }
 
-pub struct Disk(Box<dyn IO>);
+/// A registered reader of one epoch, see `Disk::snapshot`
+struct Snapshot {
+    epochs: Arc<Mutex<Epochs>>,
+    epoch: u64,
+}
+
+impl Snapshot {
+    fn new(epochs: Arc<Mutex<Epochs>>, epoch: u64) -> Self {
+        *lock(&epochs).readers.entry(epoch).or_default() += 1;
+        Self { epochs, epoch }
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let mut epochs = lock(&self.epochs);
+        if let Some(count) = epochs.readers.get_mut(&self.epoch) {
+            *count -= 1;
+            if *count == 0 {
+                epochs.readers.remove(&self.epoch);
+                epochs.reclaim();
+            }
+        }
+    }
+}
 
 impl Debug for Disk {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.write_str("Disk")
     }
 }
 impl Disk {
     pub fn new(io: Box<dyn IO>) -> Self {
-        Self(io)
+        Self {
+            io: Arc::new(Mutex::new(io)),
+            read_only: false,
+            sync: false,
+            shut_down: false,
+            degraded: false,
+            verify: false,
+            accounting: None,
+            written: None,
+            buffer: None,
+            cache: None,
+            epochs: None,
+            snapshot: None,
+        }
+    }
+
+    /// Another handle to the same IO that refuses all writes. Every access locks the IO, so the
+    /// handle can be moved to another thread while this one keeps writing. A fork of a snapshot
+    /// sees the same state as the snapshot.
+    pub fn fork_readonly(&self) -> Self {
+        Self {
+            io: self.io.clone(),
+            read_only: true,
+            sync: false,
+            shut_down: false,
+            degraded: false,
+            verify: false,
+            accounting: None,
+            written: None,
+            buffer: None,
+            cache: None,
+            epochs: None,
+            snapshot: self
+                .snapshot
+                .as_ref()
+                .map(|snapshot| Snapshot::new(snapshot.epochs.clone(), snapshot.epoch)),
+        }
+    }
+
+    /// A read-only handle that keeps seeing the disk as it is now. Before this disk overwrites a
+    /// block, the old contents are copied to memory for the snapshots that still need them, and
+    /// freed once the last handle of those snapshots is dropped. Buffered writes that aren't
+    /// flushed yet are not part of the snapshot, cached ones are written to the IO first.
+    pub fn snapshot(&mut self) -> Result<Self, DiskError> {
+        self.flush()?;
+        let epochs = self.epochs.get_or_insert_with(Default::default).clone();
+        let epoch = {
+            let mut epochs = lock(&epochs);
+            epochs.next += 1;
+            epochs.next - 1
+        };
+        let mut snapshot = self.fork_readonly();
+        snapshot.snapshot = Some(Snapshot::new(epochs, epoch));
+        Ok(snapshot)
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Replaces the IO with `wrap(io)`, e.g. to check everything read from it. Forks and
+    /// snapshots share the IO, so they go through the new one as well.
+    pub fn wrap_io(&mut self, wrap: impl FnOnce(Box<dyn IO>) -> Box<dyn IO>) {
+        let mut io = lock(&self.io);
+        let inner = core::mem::replace(&mut *io, Box::new(Vec::new()));
+        *io = wrap(inner);
+    }
+
+    /// Refuses all writes from now on
+    pub fn set_read_only(&mut self) {
+        self.read_only = true;
+    }
+
+    /// Refuses all reads and writes from now on, so nothing more is done with a damaged image
+    pub fn shut_down(&mut self) {
+        self.shut_down = true;
+    }
+
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down
+    }
+
+    /// Whether a write or sync failed in the IO. The disk is read-only from then on, because the
+    /// failed write may have left an update half done and the IO is likely to fail again.
+    pub fn is_degraded(&self) -> bool {
+        self.degraded
+    }
+
+    /// Makes the disk read-only after the IO failed to write
+    fn degrade<T>(&mut self, result: Result<T, DiskError>) -> Result<T, DiskError> {
+        if result.as_ref().is_err_and(DiskError::may_have_written) {
+            self.degraded = true;
+            self.read_only = true;
+        }
+        result
+    }
+
+    /// If set, every write is synced to the IO before it returns (like `O_SYNC`), otherwise writes
+    /// only become durable when the IO decides to or `sync` is called
+    pub fn set_sync(&mut self, sync: bool) {
+        self.sync = sync;
+    }
+
+    /// If set, every write to the IO is read back and compared, for media that can silently drop
+    /// or corrupt writes (like cheap SD cards). A mismatch fails the write with
+    /// `DiskError::VerifyFailed` and degrades the disk. Doubles the IO of every write.
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+
+    /// Makes all writes that reached the IO or the block cache durable. Buffered writes have to
+    /// be flushed first.
+    pub fn sync(&mut self) -> Result<(), DiskError> {
+        if self.read_only {
+            return Ok(());
+        }
+        self.flush()?;
+        let result = self.lock_io().sync();
+        self.degrade(result)
+    }
+
+    /// Writes every block changed in a write-back cache to the IO, without waiting for them to be
+    /// durable
+    pub fn flush(&mut self) -> Result<(), DiskError> {
+        let Some(cache) = &mut self.cache else {
+            return Ok(());
+        };
+        let result = cache.flush(&mut Verified {
+            io: &mut **lock(&self.io),
+            verify: self.verify,
+        });
+        self.degrade(result)
+    }
+
+    /// Same as `sync`, but only writes the blocks in `blocks` that changed in a write-back cache.
+    /// Everything else that reached the IO becomes durable too, as far as the IO can't sync
+    /// single blocks.
+    pub fn sync_blocks(&mut self, blocks: &BTreeSet<u32>) -> Result<(), DiskError> {
+        if self.read_only {
+            return Ok(());
+        }
+        if let Some(cache) = &mut self.cache {
+            let result = cache.flush_blocks(
+                &mut Verified {
+                    io: &mut **lock(&self.io),
+                    verify: self.verify,
+                },
+                blocks,
+            );
+            self.degrade(result)?;
+        }
+        let result = self.lock_io().sync();
+        self.degrade(result)
+    }
+
+    /// Orders the writes to `blocks` before every later write: their changes in a write-back
+    /// cache are written to the IO now, and with `set_sync` the IO is synced too. Without it,
+    /// the IO only has to keep the order until the next `sync`, like a page cache does.
+    pub fn barrier(&mut self, blocks: &BTreeSet<u32>) -> Result<(), DiskError> {
+        if self.sync {
+            return self.sync_blocks(blocks);
+        }
+        if self.read_only {
+            return Ok(());
+        }
+        let Some(cache) = &mut self.cache else {
+            return Ok(());
+        };
+        let result = cache.flush_blocks(
+            &mut Verified {
+                io: &mut **lock(&self.io),
+                verify: self.verify,
+            },
+            blocks,
+        );
+        self.degrade(result)
+    }
+
+    /// Keeps the `blocks` most recently used blocks in memory, so repeated reads of the same
+    /// blocks (like directory scans) don't go to the IO every time. 0 turns the cache off. The
+    /// old cache is flushed first. Forks and snapshots don't share the cache, so they can't see
+    /// writes that are still in a write-back cache.
+    pub fn set_cache(&mut self, blocks: usize, mode: CacheMode) -> Result<(), DiskError> {
+        self.flush()?;
+        self.cache = (blocks > 0).then(|| BlockCache::new(blocks, mode));
+        Ok(())
+    }
+
+    /// The size of the IO, if it has a fixed one, see `IO::size`
+    pub fn size(&self) -> Option<u64> {
+        self.lock_io().size()
+    }
+
+    fn lock_io(&self) -> MutexGuard<'_, Box<dyn IO>> {
+        lock(&self.io)
+    }
+
+    /// Keeps all writes to `blocks` in memory until `flush_buffer` is called
+    pub fn start_buffering(&mut self, blocks: BTreeSet<u32>) {
+        self.buffer = Some(WriteBuffer {
+            blocks: Some(blocks),
+            dirty: BTreeMap::new(),
+        });
+    }
+
+    /// Keeps all writes in memory until `flush_buffer` or `take_buffer` is called
+    pub fn start_buffering_all(&mut self) {
+        self.buffer = Some(WriteBuffer::default());
+    }
+
+    /// Stops buffering and returns the contents of every block written since buffering started,
+    /// without writing them to the IO
+    pub fn take_buffer(&mut self) -> BTreeMap<u32, Box<[u8; BLOCK_SIZE]>> {
+        self.buffer
+            .take()
+            .map(|buffer| buffer.dirty)
+            .unwrap_or_default()
+    }
+
+    /// Whether writes are kept in memory, see `start_buffering`
+    pub fn is_buffering(&self) -> bool {
+        self.buffer.is_some()
+    }
+
+    /// The blocks written since buffering started
+    pub fn buffered_blocks(&self) -> Vec<u32> {
+        self.buffer
+            .as_ref()
+            .map(|buffer| buffer.dirty.keys().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Writes all buffered blocks to the underlying IO and stops buffering
+    pub fn flush_buffer(&mut self) -> Result<(), DiskError> {
+        let Some(buffer) = self.buffer.take() else {
+            return Ok(());
+        };
+        for (block, data) in buffer.dirty {
+            self.write_io(block as usize * BLOCK_SIZE, data.as_slice())?;
+        }
+        Ok(())
+    }
+
+    /// Remembers every block that is written to the IO from now on, until `take_written` hands
+    /// them out. The journal uses this to notice writes made outside of transactions.
+    pub fn track_writes(&mut self) {
+        self.written.get_or_insert_with(BTreeSet::new);
+    }
+
+    /// The blocks written since the last `take_written`, empty if `track_writes` wasn't called
+    pub fn written_blocks(&self) -> BTreeSet<u32> {
+        self.written.clone().unwrap_or_default()
+    }
+
+    /// Returns the blocks written since the last call and keeps tracking
+    pub fn take_written(&mut self) -> BTreeSet<u32> {
+        self.written
+            .as_mut()
+            .map(core::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Starts recording every block that is read or written, discarding anything recorded before
+    pub fn start_accounting(&mut self) {
+        self.accounting = Some(IoAccounting::default());
+    }
+
+    /// Stops recording and returns what was recorded since `start_accounting`
+    pub fn stop_accounting(&mut self) -> Option<IoAccounting> {
+        self.accounting.take()
     }
 
     pub fn read_struct<T>(&mut self, addr: usize) -> Result<T, DiskError> {
         let mut c: MaybeUninit<T> = core::mem::MaybeUninit::uninit();
 
-        self.0.read_exact(addr, unsafe {
-            &mut *(core::ptr::slice_from_raw_parts_mut(&mut c as *mut _, size_of::<T>())
-                as *mut [u8])
+        self.read_exact(addr, unsafe {
+            &mut *core::ptr::slice_from_raw_parts_mut(c.as_mut_ptr() as *mut u8, size_of::<T>())
         })?;
 
         unsafe { Ok(c.assume_init()) }
     }
 
     pub fn write_struct<T>(&mut self, addr: usize, structure: &T) -> Result<(), DiskError> {
-        self.0.write_exact(addr, unsafe {
-            &*(core::ptr::slice_from_raw_parts(structure as *const _, size_of::<T>())
-                as *mut [u8])
+        self.write_exact(addr, unsafe {
+            &*core::ptr::slice_from_raw_parts(structure as *const T as *const u8, size_of::<T>())
         })
     }
 
     pub fn read_lossy(&mut self, addr: usize, buf: &mut [u8]) -> Result<usize, DiskError> {
-        self.0.read_lossy(addr, buf)
+        self.read_blocks(addr, buf, true)
+    }
+
+    /// Same as `read_lossy`, but blocks that aren't in the block cache yet are read from the IO
+    /// without being added to it, so a large read doesn't displace blocks that are used more
+    /// often
+    pub fn read_lossy_uncached(&mut self, addr: usize, buf: &mut [u8]) -> Result<usize, DiskError> {
+        self.read_blocks(addr, buf, false)
+    }
+
+    fn read_blocks(
+        &mut self,
+        addr: usize,
+        buf: &mut [u8],
+        cache: bool,
+    ) -> Result<usize, DiskError> {
+        // caches and snapshots need every read split into blocks as well
+        if self.buffer.is_none() && self.snapshot.is_none() && self.cache.is_none() {
+            return self.read_io(addr, buf, cache);
+        }
+
+        let mut done = 0;
+        while done < buf.len() {
+            let block = ((addr + done) / BLOCK_SIZE) as u32;
+            let off = (addr + done) % BLOCK_SIZE;
+            let chunk_len = (BLOCK_SIZE - off).min(buf.len() - done);
+            let chunk = &mut buf[done..done + chunk_len];
+
+            let read = match self.buffer.as_ref().and_then(|b| b.dirty.get(&block)) {
+                Some(data) => {
+                    chunk.copy_from_slice(&data[off..off + chunk_len]);
+                    chunk_len
+                }
+                None => self.read_io(addr + done, chunk, cache)?,
+            };
+            done += read;
+            if read != chunk_len {
+                break;
+            }
+        }
+        Ok(done)
     }
     pub fn write_lossy(&mut self, addr: usize, buf: &[u8]) -> Result<usize, DiskError> {
-        self.0.write_lossy(addr, buf)
+        if self.read_only {
+            return Err(DiskError::ReadOnly);
+        }
+        if self.buffer.is_none() {
+            return self.write_io(addr, buf);
+        }
+
+        let mut done = 0;
+        while done < buf.len() {
+            let block = ((addr + done) / BLOCK_SIZE) as u32;
+            let off = (addr + done) % BLOCK_SIZE;
+            let chunk_len = (BLOCK_SIZE - off).min(buf.len() - done);
+            let chunk = &buf[done..done + chunk_len];
+
+            let is_buffered = self.buffer.as_ref().is_some_and(|b| {
+                b.blocks
+                    .as_ref()
+                    .is_none_or(|blocks| blocks.contains(&block))
+            });
+            let written = if is_buffered {
+                let loaded = self
+                    .buffer
+                    .as_ref()
+                    .is_some_and(|b| b.dirty.contains_key(&block));
+                if !loaded {
+                    let mut data = Box::new([0; BLOCK_SIZE]);
+                    self.read_io(block as usize * BLOCK_SIZE, data.as_mut_slice(), true)?;
+                    if let Some(buffer) = &mut self.buffer {
+                        buffer.dirty.insert(block, data);
+                    }
+                }
+                if let Some(data) = self.buffer.as_mut().and_then(|b| b.dirty.get_mut(&block)) {
+                    data[off..off + chunk_len].copy_from_slice(chunk);
+                }
+                chunk_len
+            } else {
+                self.write_io(addr + done, chunk)?
+            };
+            done += written;
+            if written != chunk_len {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    /// Reads from the snapshot, the block cache (adding the block to it if `cache` is set) or
+    /// the IO
+    fn read_io(&mut self, addr: usize, buf: &mut [u8], cache: bool) -> Result<usize, DiskError> {
+        if self.shut_down {
+            return Err(DiskError::ShutDown);
+        }
+        let read = match &self.snapshot {
+            Some(snapshot) => {
+                // the writer can't overwrite the block between the lookup and the read
+                let epochs = lock(&snapshot.epochs);
+                let block = (addr / BLOCK_SIZE) as u32;
+                match epochs.preserved(block, snapshot.epoch) {
+                    Some(data) => {
+                        let off = addr % BLOCK_SIZE;
+                        buf.copy_from_slice(&data[off..off + buf.len()]);
+                        buf.len()
+                    }
+                    None => self.lock_io().read_lossy(addr, buf)?,
+                }
+            }
+            None => {
+                let mut io = lock(&self.io);
+                let mut io = Verified {
+                    io: &mut **io,
+                    verify: self.verify,
+                };
+                match &mut self.cache {
+                    Some(block_cache) if cache => block_cache.read(&mut io, addr, buf)?,
+                    Some(block_cache) => block_cache.read_uncached(&mut io, addr, buf)?,
+                    None => io.read_lossy(addr, buf)?,
+                }
+            }
+        };
+        if let Some(accounting) = &mut self.accounting {
+            IoAccounting::record(&mut accounting.blocks_read, addr, read);
+        }
+        Ok(read)
+    }
+    fn write_io(&mut self, addr: usize, buf: &[u8]) -> Result<usize, DiskError> {
+        if self.shut_down {
+            return Err(DiskError::ShutDown);
+        }
+        if self.read_only {
+            return Err(DiskError::ReadOnly);
+        }
+        let result = {
+            let mut epochs = self.epochs.as_deref().map(lock);
+            let mut io = lock(&self.io);
+            if let Some(epochs) = &mut epochs {
+                epochs.preserve(&mut **io, addr, buf.len())?;
+            }
+            let mut io = Verified {
+                io: &mut **io,
+                verify: self.verify,
+            };
+            // with `sync`, every write has to reach the IO before it returns
+            let result = match &mut self.cache {
+                Some(cache) => cache.write(&mut io, addr, buf, self.sync),
+                None => io.write_lossy(addr, buf),
+            };
+            result.and_then(|written| {
+                if self.sync {
+                    io.sync()?;
+                }
+                Ok(written)
+            })
+        };
+        let written = self.degrade(result)?;
+        if let Some(accounting) = &mut self.accounting {
+            IoAccounting::record(&mut accounting.blocks_written, addr, written);
+        }
+        if let Some(blocks) = &mut self.written {
+            IoAccounting::record(blocks, addr, written);
+        }
+        Ok(written)
     }
     pub fn read_exact(&mut self, addr: usize, buf: &mut [u8]) -> Result<(), DiskError> {
-        self.0.read_exact(addr, buf)
+        if self.read_lossy(addr, buf)? != buf.len() {
+            Err(DiskError::OutOfBounds)
+        } else {
+            Ok(())
+        }
     }
     pub fn write_exact(&mut self, addr: usize, buf: &[u8]) -> Result<(), DiskError> {
-        self.0.write_exact(addr, buf)
+        match self.write_lossy(addr, buf)? {
+            written if written != buf.len() => Err(DiskError::ShortWrite { written }),
+            _ => Ok(()),
+        }
     }
 
     pub fn new_virtual(blocks: u32) -> Self {
-        let mut vec = Vec::new();
-        vec.resize(blocks as usize * 4096, 0);
-        Self(Box::new(vec))
+        Self::new(Box::new(vec![0_u8; blocks as usize * 4096]))
     }
 
-    pub fn to_vec(&mut self) -> Result<Vec<u8>, DiskError> {
+    /// An in-memory disk that only keeps the first `memory_blocks` blocks in RAM, see `SpillIO`
+    #[cfg(feature = "std")]
+    pub fn new_spilling(blocks: u32, memory_blocks: u32) -> Result<Self, DiskError> {
+        Ok(Self::new(Box::new(SpillIO::new(blocks, memory_blocks)?)))
+    }
+
+    pub fn read_all(&mut self) -> Result<Vec<u8>, DiskError> {
         let mut vec = Vec::new();
         let mut block: [u8; 4096] = [0; 4096];
         let mut addr: usize = 0;
@@ -95,20 +1013,109 @@ impl Disk {
         }
     }
 
-    /// Errors when other could not be written to while self has more data
-    pub fn duplicate(&mut self, other: &mut dyn IO) -> Result<usize, DiskError> {
-        let mut block: [u8; 4096] = [0; 4096];
-        let mut addr: usize = 0;
+    /// Copies the block ranges of `groups` to the same place in `other`, returning the number of
+    /// blocks copied. Every group (like the used blocks of one block group) is read by one of
+    /// `threads` threads through its own read-only fork, and `check` sees every block on that
+    /// thread before it is written. The writes to `other` all happen on the calling thread, in no
+    /// particular order. The block cache is flushed first, writes that are still buffered (e.g.
+    /// by a running transaction) aren't copied.
+    #[cfg(feature = "std")]
+    pub fn duplicate(
+        &mut self,
+        other: &mut dyn IO,
+        groups: &[Vec<Range<u32>>],
+        threads: usize,
+        check: impl Fn(u32, &[u8]) + Sync,
+    ) -> Result<u64, DiskError> {
+        /// the blocks a thread reads and hands to the writer at once
+        const CHUNK_BLOCKS: u32 = 64;
 
-        loop {
-            let read = self.read_lossy(addr, &mut block)?;
-            if read == 0 {
-                return Ok(addr);
+        self.flush()?;
+        let next = AtomicUsize::new(0);
+        let threads = threads.clamp(1, groups.len().max(1));
+        let (sender, receiver) = mpsc::sync_channel(threads * 2);
+
+        std::thread::scope(|scope| {
+            for _ in 0..threads {
+                let sender = sender.clone();
+                let mut disk = self.fork_readonly();
+                let (next, check) = (&next, &check);
+                scope.spawn(move || {
+                    while let Some(runs) = groups.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        for run in runs {
+                            for start in run.clone().step_by(CHUNK_BLOCKS as usize) {
+                                let len = (run.end - start).min(CHUNK_BLOCKS) as usize;
+                                let mut data = vec![0; len * BLOCK_SIZE];
+                                let read = disk.read_exact(start as usize * BLOCK_SIZE, &mut data);
+                                let failed = read.is_err();
+                                let chunk = read.map(|()| {
+                                    for (i, block) in data.chunks_exact(BLOCK_SIZE).enumerate() {
+                                        check(start + i as u32, block);
+                                    }
+                                    (start, data)
+                                });
+                                // the writer is gone after an error and stops taking chunks
+                                if sender.send(chunk).is_err() || failed {
+                                    return;
+                                }
+                            }
+                        }
+                    }
+                });
             }
+            drop(sender);
 
-            other.write_exact(addr, &block)?;
-            addr += read;
-        }
+            let mut copied = 0;
+            for chunk in receiver {
+                let (start, data) = chunk?;
+                other.write_exact(start as usize * BLOCK_SIZE, &data)?;
+                copied += (data.len() / BLOCK_SIZE) as u64;
+            }
+            Ok(copied)
+        })
+    }
+}
+
+impl Drop for Disk {
+    fn drop(&mut self) {
+        // there's no way to report the error here; use `flush` or `sync` to get it
+        let _ = self.flush();
+    }
+}
+
+impl<T: IO + ?Sized> IO for &mut T {
+    fn read_lossy(&mut self, addr: usize, buf: &mut [u8]) -> Result<usize, DiskError> {
+        (**self).read_lossy(addr, buf)
+    }
+
+    fn write_lossy(&mut self, addr: usize, buf: &[u8]) -> Result<usize, DiskError> {
+        (**self).write_lossy(addr, buf)
+    }
+
+    fn sync(&mut self) -> Result<(), DiskError> {
+        (**self).sync()
+    }
+
+    fn size(&mut self) -> Option<u64> {
+        (**self).size()
+    }
+}
+
+impl<T: IO + ?Sized> IO for Box<T> {
+    fn read_lossy(&mut self, addr: usize, buf: &mut [u8]) -> Result<usize, DiskError> {
+        (**self).read_lossy(addr, buf)
+    }
+
+    fn write_lossy(&mut self, addr: usize, buf: &[u8]) -> Result<usize, DiskError> {
+        (**self).write_lossy(addr, buf)
+    }
+
+    fn sync(&mut self) -> Result<(), DiskError> {
+        (**self).sync()
+    }
+
+    fn size(&mut self) -> Option<u64> {
+        (**self).size()
     }
 }
 
@@ -117,9 +1124,9 @@ impl IO for Vec<u8> {
         // let blk_1 = addr / BLOCK_SIZE;
         // let blk_2 = (addr+buf.len() - 1) / BLOCK_SIZE;
         // println!("Reading {}..{} (blk {}..{})", addr, addr+buf.len(), blk_1, blk_2);
-        for i in 0..buf.len() {
+        for (i, b) in buf.iter_mut().enumerate() {
             if let Some(v) = self.get(i + addr) {
-                buf[i] = *v;
+                *b = *v;
             } else {
                 return Ok(i); // the last index we could read is i-1, and length is last_index+1, so i is the length of what we've read
             }
@@ -132,17 +1139,18 @@ impl IO for Vec<u8> {
         // let blk_2 = (addr+buf.len() - 1) / BLOCK_SIZE;
         // println!("Writing {}..{} (blk {}..{})", addr, addr+buf.len(), blk_1, blk_2);
 
-        for i in 0..buf.len() {
+        for (i, b) in buf.iter().enumerate() {
             if addr + i >= self.len() {
                 return Ok(i); // the last index we could write is i-1, and length is last_index+1, so i is the length of what we've written
             } else {
-                self[addr + i] = buf[i];
+                self[addr + i] = *b;
             }
         }
         Ok(buf.len())
     }
 }
 
+#[cfg(feature = "std")]
 impl IO for File {
     fn read_lossy(&mut self, addr: usize, buf: &mut [u8]) -> Result<usize, DiskError> {
         match self.read_at(buf, addr as u64) {
@@ -150,7 +1158,7 @@ impl IO for File {
             Err(e) => match e.kind() {
                 ErrorKind::AddrNotAvailable => Ok(0),
                 ErrorKind::WriteZero => Ok(0),
-                _ => Err(DiskError::GenericError),
+                _ => Err(e.into()),
             },
         }
     }
@@ -161,8 +1169,165 @@ impl IO for File {
             Err(e) => match e.kind() {
                 ErrorKind::AddrNotAvailable => Ok(0),
                 ErrorKind::WriteZero => Ok(0),
-                _ => Err(DiskError::GenericError),
+                _ => Err(e.into()),
             },
         }
     }
+
+    fn sync(&mut self) -> Result<(), DiskError> {
+        Ok(self.sync_data()?)
+    }
+}
+
+/// Keeps the first `memory.len()` bytes of the image in memory, where the superblock, the first
+/// block array descriptor and most inodes live, and spills everything after that into an unlinked
+/// temporary file.
+#[cfg(feature = "std")]
+pub struct SpillIO {
+    memory: Vec<u8>,
+    spill: File,
+    len: usize,
+}
+
+#[cfg(feature = "std")]
+impl SpillIO {
+    pub fn new(blocks: u32, memory_blocks: u32) -> Result<Self, DiskError> {
+        static SPILL_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let len = blocks as usize * BLOCK_SIZE;
+        let memory_len = (memory_blocks as usize * BLOCK_SIZE).min(len);
+
+        let path = std::env::temp_dir().join(format!(
+            "sfs-spill-{}-{}",
+            std::process::id(),
+            SPILL_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let spill = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        // the file stays accessible through the handle, and is cleaned up once it's closed
+        std::fs::remove_file(&path)?;
+        spill.set_len((len - memory_len) as u64)?;
+
+        Ok(Self {
+            memory: vec![0; memory_len],
+            spill,
+            len,
+        })
+    }
+}
+
+#[cfg(feature = "std")]
+impl IO for SpillIO {
+    fn read_lossy(&mut self, addr: usize, buf: &mut [u8]) -> Result<usize, DiskError> {
+        let end = (addr + buf.len()).min(self.len);
+        if addr >= end {
+            return Ok(0);
+        }
+        let buf = &mut buf[..end - addr];
+        let split = self.memory.len().clamp(addr, end) - addr;
+
+        let read = self.memory.read_lossy(addr, &mut buf[..split])?;
+        if split == buf.len() {
+            return Ok(read);
+        }
+        let spill_addr = addr + split - self.memory.len();
+        Ok(read + self.spill.read_lossy(spill_addr, &mut buf[split..])?)
+    }
+
+    fn write_lossy(&mut self, addr: usize, buf: &[u8]) -> Result<usize, DiskError> {
+        let end = (addr + buf.len()).min(self.len);
+        if addr >= end {
+            return Ok(0);
+        }
+        let buf = &buf[..end - addr];
+        let split = self.memory.len().clamp(addr, end) - addr;
+
+        let written = self.memory.write_lossy(addr, &buf[..split])?;
+        if split == buf.len() {
+            return Ok(written);
+        }
+        let spill_addr = addr + split - self.memory.len();
+        Ok(written + self.spill.write_lossy(spill_addr, &buf[split..])?)
+    }
+}
+
+/// How `RetryIO` retries failed operations
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// how often an operation is retried after the first attempt failed
+    pub retries: u32,
+    /// the wait before the first retry, doubled for every further one
+    pub backoff: Duration,
+    /// the longest wait between two attempts
+    pub max_backoff: Duration,
+    /// which errors are worth retrying, the rest are returned right away
+    pub retryable: fn(&DiskError) -> bool,
+}
+
+#[cfg(feature = "std")]
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+            retryable: DiskError::is_transient,
+        }
+    }
+}
+
+/// Retries the operations of another `IO` according to a `RetryPolicy`, for backends that fail
+/// every now and then, like network storage or flaky USB media
+#[cfg(feature = "std")]
+pub struct RetryIO<T: IO> {
+    io: T,
+    policy: RetryPolicy,
+}
+
+#[cfg(feature = "std")]
+impl<T: IO> RetryIO<T> {
+    pub fn new(io: T, policy: RetryPolicy) -> Self {
+        Self { io, policy }
+    }
+
+    fn retry<R>(
+        &mut self,
+        mut op: impl FnMut(&mut T) -> Result<R, DiskError>,
+    ) -> Result<R, DiskError> {
+        let mut backoff = self.policy.backoff;
+        let mut retries = self.policy.retries;
+        loop {
+            match op(&mut self.io) {
+                Err(e) if retries > 0 && (self.policy.retryable)(&e) => {
+                    retries -= 1;
+                    std::thread::sleep(backoff);
+                    backoff = (backoff * 2).min(self.policy.max_backoff);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: IO> IO for RetryIO<T> {
+    fn read_lossy(&mut self, addr: usize, buf: &mut [u8]) -> Result<usize, DiskError> {
+        self.retry(|io| io.read_lossy(addr, buf))
+    }
+
+    fn write_lossy(&mut self, addr: usize, buf: &[u8]) -> Result<usize, DiskError> {
+        self.retry(|io| io.write_lossy(addr, buf))
+    }
+
+    fn sync(&mut self) -> Result<(), DiskError> {
+        self.retry(|io| io.sync())
+    }
+
+    fn size(&mut self) -> Option<u64> {
+        self.io.size()
+    }
 }
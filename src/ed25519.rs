@@ -0,0 +1,494 @@
+//! A plain Ed25519 (RFC 8032) implementation for sealing images, so signatures don't need any
+//! dependencies. Field arithmetic and the scalar multiplication don't branch on secret data, but
+//! nothing beyond that was done against side channels: sign images on a machine you trust.
+
+use crate::sha512::Sha512;
+use alloc::vec::Vec;
+
+/// An element of the field of integers modulo p = 2^255 - 19, in five limbs of 51 bits
+#[derive(Clone, Copy, Debug)]
+struct Fe([u64; 5]);
+
+const MASK: u64 = (1 << 51) - 1;
+
+impl Fe {
+    const ZERO: Self = Self([0; 5]);
+    const ONE: Self = Self([1, 0, 0, 0, 0]);
+
+    fn from_u64(value: u64) -> Self {
+        Self::carry([value & MASK, value >> 51, 0, 0, 0])
+    }
+
+    /// The low 255 bits of `bytes`, little endian
+    fn from_bytes(bytes: &[u8; 32]) -> Self {
+        let load = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+        Self([
+            load(0) & MASK,
+            (load(6) >> 3) & MASK,
+            (load(12) >> 6) & MASK,
+            (load(19) >> 1) & MASK,
+            (load(24) >> 12) & MASK,
+        ])
+    }
+
+    fn to_bytes(self) -> [u8; 32] {
+        let mut h = Self::carry(Self::carry(self.0).0).0;
+        // h is below 2^255 now, and below p once p is subtracted if h + 19 reaches 2^255
+        h[0] += 19;
+        h = Self::carry(h).0;
+        // adds 2^255 - 19 and drops the 2^255 again, so 19 was subtracted unless h was below p
+        h[0] += (1 << 51) - 19;
+        for limb in &mut h[1..] {
+            *limb += (1 << 51) - 1;
+        }
+        for i in 0..4 {
+            h[i + 1] += h[i] >> 51;
+            h[i] &= MASK;
+        }
+        h[4] &= MASK;
+
+        let mut bytes = [0; 32];
+        let mut acc: u128 = 0;
+        let mut bits = 0;
+        let mut out = 0;
+        for limb in h {
+            acc |= (limb as u128) << bits;
+            bits += 51;
+            while bits >= 8 && out < 32 {
+                bytes[out] = acc as u8;
+                acc >>= 8;
+                bits -= 8;
+                out += 1;
+            }
+        }
+        if out < 32 {
+            bytes[out] = acc as u8;
+        }
+        bytes
+    }
+
+    /// Brings every limb below 2^51 plus a little
+    fn carry(mut l: [u64; 5]) -> Self {
+        for i in 0..4 {
+            l[i + 1] += l[i] >> 51;
+            l[i] &= MASK;
+        }
+        l[0] += (l[4] >> 51) * 19;
+        l[4] &= MASK;
+        l[1] += l[0] >> 51;
+        l[0] &= MASK;
+        Self(l)
+    }
+
+    fn add(self, other: Self) -> Self {
+        let mut l = self.0;
+        for (a, b) in l.iter_mut().zip(other.0) {
+            *a += b;
+        }
+        Self::carry(l)
+    }
+
+    fn sub(self, other: Self) -> Self {
+        // adds 4p first, so no limb goes below zero
+        const FOUR_P: [u64; 5] = [
+            0x1fffffffffffb4,
+            0x1ffffffffffffc,
+            0x1ffffffffffffc,
+            0x1ffffffffffffc,
+            0x1ffffffffffffc,
+        ];
+        let mut l = self.0;
+        for i in 0..5 {
+            l[i] = l[i] + FOUR_P[i] - other.0[i];
+        }
+        Self::carry(l)
+    }
+
+    fn neg(self) -> Self {
+        Self::ZERO.sub(self)
+    }
+
+    fn mul(self, other: Self) -> Self {
+        let [a0, a1, a2, a3, a4] = self.0.map(u128::from);
+        let [b0, b1, b2, b3, b4] = other.0.map(u128::from);
+        let (b1_19, b2_19, b3_19, b4_19) = (b1 * 19, b2 * 19, b3 * 19, b4 * 19);
+
+        let r0 = a0 * b0 + a1 * b4_19 + a2 * b3_19 + a3 * b2_19 + a4 * b1_19;
+        let mut r1 = a0 * b1 + a1 * b0 + a2 * b4_19 + a3 * b3_19 + a4 * b2_19;
+        let mut r2 = a0 * b2 + a1 * b1 + a2 * b0 + a3 * b4_19 + a4 * b3_19;
+        let mut r3 = a0 * b3 + a1 * b2 + a2 * b1 + a3 * b0 + a4 * b4_19;
+        let mut r4 = a0 * b4 + a1 * b3 + a2 * b2 + a3 * b1 + a4 * b0;
+
+        let mask = MASK as u128;
+        r1 += r0 >> 51;
+        r2 += r1 >> 51;
+        r3 += r2 >> 51;
+        r4 += r3 >> 51;
+        let r0 = (r0 & mask) + (r4 >> 51) * 19;
+        let r1 = (r1 & mask) + (r0 >> 51);
+        Self([
+            (r0 & mask) as u64,
+            r1 as u64,
+            (r2 & mask) as u64,
+            (r3 & mask) as u64,
+            (r4 & mask) as u64,
+        ])
+    }
+
+    fn square(self) -> Self {
+        self.mul(self)
+    }
+
+    /// `self` to the power of the little endian `exponent`
+    fn pow(self, exponent: &[u8; 32]) -> Self {
+        let mut result = Self::ONE;
+        for byte in exponent.iter().rev() {
+            for bit in (0..8).rev() {
+                result = result.square();
+                if (byte >> bit) & 1 == 1 {
+                    result = result.mul(self);
+                }
+            }
+        }
+        result
+    }
+
+    fn invert(self) -> Self {
+        // p - 2
+        let mut exponent = [0xff; 32];
+        exponent[0] = 0xeb;
+        exponent[31] = 0x7f;
+        self.pow(&exponent)
+    }
+
+    fn is_zero(self) -> bool {
+        self.to_bytes() == [0; 32]
+    }
+
+    fn equals(self, other: Self) -> bool {
+        self.to_bytes() == other.to_bytes()
+    }
+
+    fn is_negative(self) -> bool {
+        self.to_bytes()[0] & 1 == 1
+    }
+
+    /// Swaps `a` and `b` if `swap` is 1, without branching on it
+    fn cswap(a: &mut Self, b: &mut Self, swap: u64) {
+        let mask = 0u64.wrapping_sub(swap);
+        for (a, b) in a.0.iter_mut().zip(b.0.iter_mut()) {
+            let t = mask & (*a ^ *b);
+            *a ^= t;
+            *b ^= t;
+        }
+    }
+}
+
+/// The curve constant d = -121665 / 121666
+fn d() -> Fe {
+    Fe::from_u64(121665)
+        .neg()
+        .mul(Fe::from_u64(121666).invert())
+}
+
+/// A square root of -1, 2^((p - 1) / 4)
+fn sqrt_m1() -> Fe {
+    let mut exponent = [0xff; 32];
+    exponent[0] = 0xfb;
+    exponent[31] = 0x1f;
+    Fe::from_u64(2).pow(&exponent)
+}
+
+/// A point on the curve in extended coordinates: x = X/Z, y = Y/Z, x * y = T/Z
+#[derive(Clone, Copy, Debug)]
+struct Point {
+    x: Fe,
+    y: Fe,
+    z: Fe,
+    t: Fe,
+}
+
+impl Point {
+    const IDENTITY: Self = Self {
+        x: Fe::ZERO,
+        y: Fe::ONE,
+        z: Fe::ONE,
+        t: Fe::ZERO,
+    };
+
+    /// The base point, whose y is 4/5
+    fn base() -> Self {
+        let y = Fe::from_u64(4).mul(Fe::from_u64(5).invert());
+        Self::decode(&y.to_bytes()).expect("the base point is on the curve")
+    }
+
+    fn add(self, other: Self) -> Self {
+        let d2 = d().add(d());
+        let a = self.y.sub(self.x).mul(other.y.sub(other.x));
+        let b = self.y.add(self.x).mul(other.y.add(other.x));
+        let c = self.t.mul(d2).mul(other.t);
+        let zz = self.z.mul(other.z);
+        let d = zz.add(zz);
+        let (e, f, g, h) = (b.sub(a), d.sub(c), d.add(c), b.add(a));
+        Self {
+            x: e.mul(f),
+            y: g.mul(h),
+            z: f.mul(g),
+            t: e.mul(h),
+        }
+    }
+
+    /// `scalar` (little endian) times this point, with the same steps for every scalar
+    fn mul(self, scalar: &[u8; 32]) -> Self {
+        let mut r0 = Self::IDENTITY;
+        let mut r1 = self;
+        for byte in scalar.iter().rev() {
+            for bit in (0..8).rev() {
+                let swap = ((byte >> bit) & 1) as u64;
+                Self::cswap(&mut r0, &mut r1, swap);
+                r1 = r0.add(r1);
+                r0 = r0.add(r0);
+                Self::cswap(&mut r0, &mut r1, swap);
+            }
+        }
+        r0
+    }
+
+    fn cswap(a: &mut Self, b: &mut Self, swap: u64) {
+        Fe::cswap(&mut a.x, &mut b.x, swap);
+        Fe::cswap(&mut a.y, &mut b.y, swap);
+        Fe::cswap(&mut a.z, &mut b.z, swap);
+        Fe::cswap(&mut a.t, &mut b.t, swap);
+    }
+
+    fn encode(self) -> [u8; 32] {
+        let z = self.z.invert();
+        let (x, y) = (self.x.mul(z), self.y.mul(z));
+        let mut bytes = y.to_bytes();
+        bytes[31] |= (x.is_negative() as u8) << 7;
+        bytes
+    }
+
+    /// The point `bytes` encode, `None` if they don't encode one
+    fn decode(bytes: &[u8; 32]) -> Option<Self> {
+        let sign = bytes[31] >> 7;
+        let mut y_bytes = *bytes;
+        y_bytes[31] &= 0x7f;
+        let y = Fe::from_bytes(&y_bytes);
+        // y has to be below p
+        if y.to_bytes() != y_bytes {
+            return None;
+        }
+
+        let yy = y.square();
+        let u = yy.sub(Fe::ONE);
+        let v = d().mul(yy).add(Fe::ONE);
+        // x = u v^3 (u v^7)^((p - 5) / 8)
+        let mut exponent = [0xff; 32];
+        exponent[0] = 0xfd;
+        exponent[31] = 0x0f;
+        let v3 = v.square().mul(v);
+        let mut x = u.mul(v3).mul(u.mul(v3.square().mul(v)).pow(&exponent));
+        let vxx = v.mul(x.square());
+        if !vxx.equals(u) {
+            if !vxx.equals(u.neg()) {
+                return None;
+            }
+            x = x.mul(sqrt_m1());
+        }
+        if x.is_zero() && sign == 1 {
+            return None;
+        }
+        if x.is_negative() as u8 != sign {
+            x = x.neg();
+        }
+        Some(Self {
+            x,
+            y,
+            z: Fe::ONE,
+            t: x.mul(y),
+        })
+    }
+}
+
+/// The order of the base point, 2^252 + 27742317777372353535851937790883648493, in little endian
+/// 64 bit limbs
+const L: [u64; 4] = [
+    0x5812631a5cf5d3ed,
+    0x14def9dea2f79cd6,
+    0x0000000000000000,
+    0x1000000000000000,
+];
+
+/// The little endian number `bytes` modulo L
+fn reduce(bytes: &[u8]) -> [u8; 32] {
+    // long division, one bit at a time; the remainder stays below 2L < 2^254
+    let mut rem = [0u64; 4];
+    for byte in bytes.iter().rev() {
+        for bit in (0..8).rev() {
+            let mut carry = ((byte >> bit) & 1) as u64;
+            for limb in &mut rem {
+                let next = *limb >> 63;
+                *limb = (*limb << 1) | carry;
+                carry = next;
+            }
+            let below = rem.iter().rev().zip(L.iter().rev()).find(|(r, l)| r != l);
+            if below.is_none_or(|(r, l)| r > l) {
+                let mut borrow = 0;
+                for (r, l) in rem.iter_mut().zip(L) {
+                    let (value, b1) = r.overflowing_sub(l);
+                    let (value, b2) = value.overflowing_sub(borrow);
+                    *r = value;
+                    borrow = (b1 || b2) as u64;
+                }
+            }
+        }
+    }
+    let mut out = [0; 32];
+    for (chunk, limb) in out.chunks_exact_mut(8).zip(rem) {
+        chunk.copy_from_slice(&limb.to_le_bytes());
+    }
+    out
+}
+
+/// (a * b + c) modulo L
+fn mul_add(a: &[u8; 32], b: &[u8; 32], c: &[u8; 32]) -> [u8; 32] {
+    let limbs = |bytes: &[u8; 32]| -> [u64; 4] {
+        core::array::from_fn(|i| u64::from_le_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap()))
+    };
+    let (a, b, c) = (limbs(a), limbs(b), limbs(c));
+    let mut product = [0u64; 9];
+    for (i, limb) in c.iter().enumerate() {
+        product[i] = *limb;
+    }
+    for i in 0..4 {
+        let mut carry: u128 = 0;
+        for j in 0..4 {
+            let value = a[i] as u128 * b[j] as u128 + product[i + j] as u128 + carry;
+            product[i + j] = value as u64;
+            carry = value >> 64;
+        }
+        let mut k = i + 4;
+        while carry != 0 {
+            let value = product[k] as u128 + carry;
+            product[k] = value as u64;
+            carry = value >> 64;
+            k += 1;
+        }
+    }
+    let bytes: Vec<u8> = product.iter().flat_map(|limb| limb.to_le_bytes()).collect();
+    reduce(&bytes)
+}
+
+/// Whether the little endian `scalar` is below L
+fn is_canonical(scalar: &[u8; 32]) -> bool {
+    reduce(scalar) == *scalar
+}
+
+/// The secret scalar and the nonce prefix of the private key `seed`
+fn expand(seed: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let hash = Sha512::digest(&[seed]);
+    let mut scalar: [u8; 32] = hash[..32].try_into().unwrap();
+    scalar[0] &= 248;
+    scalar[31] &= 127;
+    scalar[31] |= 64;
+    (scalar, hash[32..].try_into().unwrap())
+}
+
+/// The public key of the private key `seed`
+pub(crate) fn public_key(seed: &[u8; 32]) -> [u8; 32] {
+    let (scalar, _) = expand(seed);
+    Point::base().mul(&scalar).encode()
+}
+
+pub(crate) fn sign(seed: &[u8; 32], message: &[u8]) -> [u8; 64] {
+    let (scalar, prefix) = expand(seed);
+    let public = Point::base().mul(&scalar).encode();
+    let r = reduce(&Sha512::digest(&[&prefix, message]));
+    let big_r = Point::base().mul(&r).encode();
+    let k = reduce(&Sha512::digest(&[&big_r, &public, message]));
+    let s = mul_add(&k, &scalar, &r);
+
+    let mut signature = [0; 64];
+    signature[..32].copy_from_slice(&big_r);
+    signature[32..].copy_from_slice(&s);
+    signature
+}
+
+pub(crate) fn verify(public: &[u8; 32], message: &[u8], signature: &[u8; 64]) -> bool {
+    let big_r: [u8; 32] = signature[..32].try_into().unwrap();
+    let s: [u8; 32] = signature[32..].try_into().unwrap();
+    let (Some(a), Some(r)) = (Point::decode(public), Point::decode(&big_r)) else {
+        return false;
+    };
+    if !is_canonical(&s) {
+        return false;
+    }
+    let k = reduce(&Sha512::digest(&[&big_r, public, message]));
+    Point::base().mul(&s).encode() == r.add(a.mul(&k)).encode()
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    fn unhex<const N: usize>(hex: &str) -> [u8; N] {
+        let mut bytes = [0; N];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap();
+        }
+        bytes
+    }
+
+    /// Tests 1 to 3 of RFC 8032, section 7.1: secret key, public key, message, signature
+    const VECTORS: [(&str, &str, &[u8], &str); 3] = [
+        (
+            "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+            "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+            b"",
+            "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e06522490155\
+             5fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+        ),
+        (
+            "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+            "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+            &[0x72],
+            "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da\
+             085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+        ),
+        (
+            "c5aa8df43f9f837bedb7442f31dcb7b166d38535076f094b85ce3a2e0b4458f7",
+            "fc51cd8e6218a1a38da47ed00230f0580816ed13ba3303ac5deb911548908025",
+            &[0xaf, 0x82],
+            "6291d657deec24024827e69c3abe01a30ce548a284743a445e3680d7db5ac3ac\
+             18ff9b538d16f290ae67f760984dc6594a7c15e9716ed28dc027beceea1ec40a",
+        ),
+    ];
+
+    #[test]
+    fn known_answers() {
+        for (secret, public, message, signature) in VECTORS {
+            let (secret, public) = (unhex::<32>(secret), unhex::<32>(public));
+            let signature = unhex::<64>(signature);
+            assert_eq!(public_key(&secret), public);
+            assert_eq!(sign(&secret, message), signature);
+            assert!(verify(&public, message, &signature));
+        }
+    }
+
+    #[test]
+    fn rejects_changed_signatures_and_messages() {
+        let (secret, public, _, _) = VECTORS[2];
+        let (secret, public) = (unhex::<32>(secret), unhex::<32>(public));
+        let signature = sign(&secret, b"message");
+        assert!(verify(&public, b"message", &signature));
+        assert!(!verify(&public, b"massage", &signature));
+        for bit in [0, 255, 256, 511] {
+            let mut changed = signature;
+            changed[bit / 8] ^= 1 << (bit % 8);
+            assert!(!verify(&public, b"message", &changed));
+        }
+        let (_, other, _, _) = VECTORS[0];
+        assert!(!verify(&unhex::<32>(other), b"message", &signature));
+    }
+}
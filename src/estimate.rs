@@ -0,0 +1,161 @@
+use crate::{
+    addr::POINTERS_PER_BLOCK,
+    directory::DirEntry,
+    fs::{FileSystem, FsError, BLOCK_SIZE},
+    inode::{InodeType, DIRECT_POINTERS, DOUBLY_END, SINGLY_END},
+};
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+
+/// A file or directory to be created, see `FileSystem::estimate_usage`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlannedEntry {
+    File {
+        name: String,
+        size: u64,
+    },
+    Directory {
+        name: String,
+        entries: Vec<PlannedEntry>,
+    },
+}
+
+impl PlannedEntry {
+    fn name(&self) -> &str {
+        match self {
+            Self::File { name, .. } | Self::Directory { name, .. } => name,
+        }
+    }
+
+    fn typ(&self) -> InodeType {
+        match self {
+            Self::File { .. } => InodeType::File,
+            Self::Directory { .. } => InodeType::Directory,
+        }
+    }
+}
+
+/// What creating a plan takes at most, see `FileSystem::estimate_usage`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UsageEstimate {
+    /// data, indirect pointer and directory blocks
+    pub blocks: u32,
+    pub inodes: u32,
+}
+
+impl UsageEstimate {
+    fn add(&mut self, other: UsageEstimate) -> Result<(), FsError> {
+        self.blocks = self
+            .blocks
+            .checked_add(other.blocks)
+            .ok_or(FsError::NoSpace)?;
+        self.inodes = self
+            .inodes
+            .checked_add(other.inodes)
+            .ok_or(FsError::NoSpace)?;
+        Ok(())
+    }
+}
+
+/// The indirect pointer blocks an inode with `blocks` blocks needs
+fn pointer_blocks(blocks: u32) -> u32 {
+    let mut pointers = 0;
+    if blocks > DIRECT_POINTERS {
+        pointers += 1;
+    }
+    if blocks > SINGLY_END {
+        pointers += 1 + (blocks - SINGLY_END).div_ceil(POINTERS_PER_BLOCK as u32);
+    }
+    pointers
+}
+
+/// The blocks of an inode with `blocks` blocks of contents, `FsError::NoSpace` if an inode can't
+/// have that many
+fn inode_blocks(blocks: u64) -> Result<u32, FsError> {
+    if blocks > DOUBLY_END as u64 {
+        return Err(FsError::NoSpace);
+    }
+    Ok(blocks as u32 + pointer_blocks(blocks as u32))
+}
+
+/// The length of the directory record of `name`
+fn record_size(name: &str, typ: &InodeType) -> Result<u32, FsError> {
+    Ok(DirEntry::create(0, typ, name.to_string())?.get_size())
+}
+
+/// The directory blocks `entries` take up when filled one after the other, which first fit (see
+/// `insert_into_block`) never needs more of
+fn dir_blocks(fs: &FileSystem, entries: &[PlannedEntry]) -> Result<u32, FsError> {
+    let mut sizes = Vec::new();
+    if fs.has_dot_entries() {
+        for name in [".", ".."] {
+            sizes.push(record_size(name, &InodeType::Directory)?);
+        }
+    }
+    for entry in entries {
+        sizes.push(record_size(entry.name(), &entry.typ())?);
+    }
+    Ok(packed_blocks(&sizes))
+}
+
+fn packed_blocks(sizes: &[u32]) -> u32 {
+    let mut blocks = 0;
+    let mut used = BLOCK_SIZE as u32;
+    for &size in sizes {
+        if used + size > BLOCK_SIZE as u32 {
+            blocks += 1;
+            used = 0;
+        }
+        used += size;
+    }
+    blocks
+}
+
+fn estimate_entry(fs: &FileSystem, entry: &PlannedEntry) -> Result<UsageEstimate, FsError> {
+    let mut usage = UsageEstimate {
+        blocks: 0,
+        inodes: 1,
+    };
+    match entry {
+        PlannedEntry::File { size, .. } => {
+            usage.blocks = inode_blocks(size.div_ceil(BLOCK_SIZE as u64))?;
+        }
+        PlannedEntry::Directory { entries, .. } => {
+            usage.blocks = inode_blocks(dir_blocks(fs, entries)? as u64)?;
+            for entry in entries {
+                usage.add(estimate_entry(fs, entry)?)?;
+            }
+        }
+    }
+    Ok(usage)
+}
+
+pub(crate) fn estimate(
+    fs: &mut FileSystem,
+    parent_nbr: u32,
+    plan: &[PlannedEntry],
+) -> Result<UsageEstimate, FsError> {
+    let parent = fs.read_inode(parent_nbr)?;
+    if parent.type_and_permission.get_type() != InodeType::Directory {
+        return Err(FsError::NotADirectory);
+    }
+
+    // the new entries of the parent may not fit into its existing blocks
+    let existing = parent.block_map(fs)?.len() as u32;
+    let sizes = plan
+        .iter()
+        .map(|entry| record_size(entry.name(), &entry.typ()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let grown = existing as u64 + packed_blocks(&sizes) as u64;
+    let mut usage = UsageEstimate {
+        blocks: inode_blocks(grown)? - inode_blocks(existing as u64)?,
+        inodes: 0,
+    };
+
+    for entry in plan {
+        usage.add(estimate_entry(fs, entry)?)?;
+    }
+    Ok(usage)
+}
@@ -0,0 +1,136 @@
+use std::{collections::BTreeSet, mem::size_of, ops::Range, sync::Mutex};
+
+use crate::{
+    disk::IO,
+    fs::{
+        BlockArrayDescriptor, BlockArrayEntry, FileSystem, FsError, BLOCKS_PER_BLOCKARRAY,
+        INODES_PER_BLOCK,
+    },
+    inode::Inode,
+};
+
+/// How `FileSystem::export_image_with` copies an image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExportOptions {
+    /// the threads reading block groups, 0 for one per CPU
+    pub threads: usize,
+    /// whether the checksums of the inodes are checked while they are copied, on images with the
+    /// `metadata_csum` feature
+    pub verify_checksums: bool,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            threads: 0,
+            verify_checksums: true,
+        }
+    }
+}
+
+/// What `FileSystem::export_image_with` did
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExportStats {
+    pub blocks_copied: u64,
+    /// free blocks, which are left as they are in the copy (zeros in a new file)
+    pub blocks_skipped: u64,
+    /// inodes that don't match their checksum. They are copied as they are.
+    pub bad_inodes: Vec<u32>,
+}
+
+/// The blocks `export` copies. The last block of the image is always included, so a copy to a new
+/// file gets the full length.
+struct UsedBlocks {
+    /// the runs of blocks in use, per block group
+    groups: Vec<Vec<Range<u32>>>,
+    inode_blocks: BTreeSet<u32>,
+}
+
+fn used_blocks(fs: &mut FileSystem) -> Result<UsedBlocks, FsError> {
+    let total = fs.superblock.total_blocks;
+    let mut groups = Vec::new();
+    let mut inode_blocks = BTreeSet::new();
+    // an encrypted copy gets a nonce table of its own
+    let table = fs.crypt_area();
+
+    for group in 0..total.div_ceil(BLOCKS_PER_BLOCKARRAY) {
+        let start = group * BLOCKS_PER_BLOCKARRAY;
+        let entries = BlockArrayDescriptor::from_disk(fs.disk(), group).entries(total - start)?;
+        let mut runs: Vec<Range<u32>> = Vec::new();
+        for (block, entry) in (start..).zip(entries) {
+            if table.contains(&block) {
+                continue;
+            }
+            match entry {
+                BlockArrayEntry::Unused if block != total - 1 => continue,
+                BlockArrayEntry::InodeBlock => {
+                    inode_blocks.insert(block);
+                }
+                _ => {}
+            }
+            match runs.last_mut() {
+                Some(run) if run.end == block => run.end += 1,
+                _ => runs.push(block..block + 1),
+            }
+        }
+        groups.push(runs);
+    }
+    Ok(UsedBlocks {
+        groups,
+        inode_blocks,
+    })
+}
+
+pub(crate) fn export(
+    fs: &mut FileSystem,
+    other: &mut dyn IO,
+    options: &ExportOptions,
+) -> Result<ExportStats, FsError> {
+    let UsedBlocks {
+        groups,
+        inode_blocks,
+    } = used_blocks(fs)?;
+    let verify = options.verify_checksums && fs.superblock.has_checksums();
+    let threads = match options.threads {
+        0 => std::thread::available_parallelism().map_or(1, |threads| threads.get()),
+        threads => threads,
+    };
+
+    #[cfg(feature = "encryption")]
+    let mut encrypted;
+    #[cfg(feature = "encryption")]
+    let other: &mut dyn IO = match fs.data_key() {
+        Some(key) => {
+            encrypted = key.wrap(other)?;
+            &mut encrypted
+        }
+        None => other,
+    };
+
+    let bad_inodes = Mutex::new(Vec::new());
+    let check = |block: u32, data: &[u8]| {
+        if !verify || !inode_blocks.contains(&block) {
+            return;
+        }
+        for (slot, bytes) in data.chunks_exact(size_of::<Inode>()).enumerate() {
+            // `Inode` is plain old data and `bytes` is exactly as long as one
+            let inode = unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const Inode) };
+            let inode_nbr = block * INODES_PER_BLOCK + slot as u32;
+            if !inode.verify_checksum(inode_nbr) {
+                bad_inodes
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .push(inode_nbr);
+            }
+        }
+    };
+    let blocks_copied = fs.disk().duplicate(other, &groups, threads, check)?;
+
+    let mut bad_inodes = bad_inodes.into_inner().unwrap_or_else(|e| e.into_inner());
+    bad_inodes.sort_unstable();
+    Ok(ExportStats {
+        blocks_copied,
+        blocks_skipped: fs.superblock.total_blocks as u64 - blocks_copied,
+        bad_inodes,
+    })
+}
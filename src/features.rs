@@ -0,0 +1,274 @@
+use alloc::vec::Vec;
+use core::fmt::Display;
+
+/// How an implementation that doesn't know a feature has to treat an image using it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeatureKind {
+    /// The image can be read and written without knowing the feature
+    Compat,
+    /// The image can be read without knowing the feature, but writing would break it
+    RoCompat,
+    /// The image can't even be read without knowing the feature
+    Incompat,
+}
+
+/// A feature flag in the superblock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Feature {
+    pub kind: FeatureKind,
+    pub mask: u32,
+    pub name: &'static str,
+    pub description: &'static str,
+}
+
+pub const COMPAT_INODE_TABLE: u32 = 1 << 0;
+pub const COMPAT_ALLOC_ZONES: u32 = 1 << 1;
+pub const COMPAT_BOOT_AREA: u32 = 1 << 2;
+pub const COMPAT_JOURNAL: u32 = 1 << 3;
+pub const COMPAT_SEAL: u32 = 1 << 4;
+pub const COMPAT_VERITY: u32 = 1 << 5;
+pub const RO_COMPAT_FIXED_INODE_TABLE: u32 = 1 << 0;
+pub const RO_COMPAT_INODE_VERSION: u32 = 1 << 1;
+pub const RO_COMPAT_SORTED_DIRS: u32 = 1 << 2;
+pub const RO_COMPAT_WORM: u32 = 1 << 3;
+pub const RO_COMPAT_FILE_SIZE: u32 = 1 << 4;
+pub const RO_COMPAT_XATTR: u32 = 1 << 5;
+pub const RO_COMPAT_DOT_ENTRIES: u32 = 1 << 6;
+pub const RO_COMPAT_METADATA_CSUM: u32 = 1 << 7;
+pub const RO_COMPAT_SEQUENTIAL_INODES: u32 = 1 << 8;
+pub const RO_COMPAT_INODE_GENERATION: u32 = 1 << 9;
+pub const RO_COMPAT_INODE_BITMAP: u32 = 1 << 10;
+pub const RO_COMPAT_WIDE_TIMES: u32 = 1 << 11;
+pub const RO_COMPAT_NFC_NAMES: u32 = 1 << 12;
+pub const RO_COMPAT_QUOTA: u32 = 1 << 13;
+pub const RO_COMPAT_SNAPSHOTS: u32 = 1 << 14;
+pub const INCOMPAT_DIRENT_TYPE: u32 = 1 << 0;
+pub const INCOMPAT_DIRENT_RECLEN: u32 = 1 << 1;
+pub const INCOMPAT_COMPRESSION: u32 = 1 << 2;
+pub const INCOMPAT_ENCRYPTION: u32 = 1 << 3;
+
+/// Every feature this implementation supports
+pub const KNOWN_FEATURES: [Feature; 25] = [
+    Feature {
+        kind: FeatureKind::Compat,
+        mask: COMPAT_INODE_TABLE,
+        name: "inode_table",
+        description: "inode blocks are reserved in a contiguous table at mkfs time",
+    },
+    Feature {
+        kind: FeatureKind::Compat,
+        mask: COMPAT_ALLOC_ZONES,
+        name: "alloc_zones",
+        description: "block groups are split into zones for metadata, small and large files",
+    },
+    Feature {
+        kind: FeatureKind::Compat,
+        mask: COMPAT_BOOT_AREA,
+        name: "boot_area",
+        description: "blocks right after the superblock are reserved for a bootloader",
+    },
+    Feature {
+        kind: FeatureKind::Compat,
+        mask: COMPAT_JOURNAL,
+        name: "journal",
+        description: "metadata changes can go through a journal, so a crash can't tear them",
+    },
+    Feature {
+        kind: FeatureKind::Compat,
+        mask: COMPAT_SEAL,
+        name: "sealed",
+        description: "the superblock holds a signature over every used block of the image",
+    },
+    Feature {
+        kind: FeatureKind::Compat,
+        mask: COMPAT_VERITY,
+        name: "verity",
+        description: "a hash tree covers every block, read-only mounts check every read with it",
+    },
+    Feature {
+        kind: FeatureKind::RoCompat,
+        mask: RO_COMPAT_FIXED_INODE_TABLE,
+        name: "fixed_inode_table",
+        description: "inodes may only be allocated from the inode table",
+    },
+    Feature {
+        kind: FeatureKind::RoCompat,
+        mask: RO_COMPAT_INODE_VERSION,
+        name: "inode_version",
+        description: "every change to an inode increases its version counter",
+    },
+    Feature {
+        kind: FeatureKind::RoCompat,
+        mask: RO_COMPAT_SORTED_DIRS,
+        name: "sorted_dirs",
+        description: "directories can keep their entries sorted by name",
+    },
+    Feature {
+        kind: FeatureKind::RoCompat,
+        mask: RO_COMPAT_WORM,
+        name: "worm",
+        description: "data is write-once: files can grow, but nothing written is ever changed",
+    },
+    Feature {
+        kind: FeatureKind::RoCompat,
+        mask: RO_COMPAT_FILE_SIZE,
+        name: "file_size",
+        description: "inodes store the length of regular files in bytes",
+    },
+    Feature {
+        kind: FeatureKind::RoCompat,
+        mask: RO_COMPAT_XATTR,
+        name: "xattr",
+        description: "inodes can have a block of extended attributes",
+    },
+    Feature {
+        kind: FeatureKind::RoCompat,
+        mask: RO_COMPAT_DOT_ENTRIES,
+        name: "dot_entries",
+        description: "directories store `.` and `..` entries, which count as hard links",
+    },
+    Feature {
+        kind: FeatureKind::RoCompat,
+        mask: RO_COMPAT_METADATA_CSUM,
+        name: "metadata_csum",
+        description: "the superblock and every inode carry a CRC-32 of their contents",
+    },
+    Feature {
+        kind: FeatureKind::RoCompat,
+        mask: RO_COMPAT_SEQUENTIAL_INODES,
+        name: "sequential_inodes",
+        description: "inode numbers are handed out in increasing order and never reused",
+    },
+    Feature {
+        kind: FeatureKind::RoCompat,
+        mask: RO_COMPAT_INODE_GENERATION,
+        name: "inode_generation",
+        description: "every new inode gets a generation number no earlier inode had",
+    },
+    Feature {
+        kind: FeatureKind::RoCompat,
+        mask: RO_COMPAT_INODE_BITMAP,
+        name: "inode_bitmap",
+        description: "a bitmap after the journal tracks which inodes are free",
+    },
+    Feature {
+        kind: FeatureKind::RoCompat,
+        mask: RO_COMPAT_WIDE_TIMES,
+        name: "wide_times",
+        description: "inode times are signed, for times before 1970, and carry nanoseconds",
+    },
+    Feature {
+        kind: FeatureKind::RoCompat,
+        mask: RO_COMPAT_NFC_NAMES,
+        name: "nfc_names",
+        description: "entry names are stored in Unicode NFC and looked up normalized",
+    },
+    Feature {
+        kind: FeatureKind::RoCompat,
+        mask: RO_COMPAT_QUOTA,
+        name: "quota",
+        description: "blocks and inodes are counted per user and group against their limits",
+    },
+    Feature {
+        kind: FeatureKind::RoCompat,
+        mask: RO_COMPAT_SNAPSHOTS,
+        name: "snapshots",
+        description: "file blocks held by a snapshot are moved before they are written",
+    },
+    Feature {
+        kind: FeatureKind::Incompat,
+        mask: INCOMPAT_DIRENT_TYPE,
+        name: "dirent_type",
+        description: "directory entries store the type of the inode they link to",
+    },
+    Feature {
+        kind: FeatureKind::Incompat,
+        mask: INCOMPAT_DIRENT_RECLEN,
+        name: "dirent_reclen",
+        description: "directory entries are linked records with a length and tombstones",
+    },
+    Feature {
+        kind: FeatureKind::Incompat,
+        mask: INCOMPAT_COMPRESSION,
+        name: "compression",
+        description: "regular files can store their contents compressed in clusters",
+    },
+    Feature {
+        kind: FeatureKind::Incompat,
+        mask: INCOMPAT_ENCRYPTION,
+        name: "encryption",
+        description: "blocks are encrypted with a key unlocked by a passphrase",
+    },
+];
+
+/// The feature flags of an image, see `FileSystem::features`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Features {
+    pub compat: u32,
+    pub ro_compat: u32,
+    pub incompat: u32,
+}
+
+impl Features {
+    fn flags(&self, kind: FeatureKind) -> u32 {
+        match kind {
+            FeatureKind::Compat => self.compat,
+            FeatureKind::RoCompat => self.ro_compat,
+            FeatureKind::Incompat => self.incompat,
+        }
+    }
+
+    fn known_mask(kind: FeatureKind) -> u32 {
+        let mask = KNOWN_FEATURES
+            .iter()
+            .filter(|feature| feature.kind == kind)
+            .fold(0, |mask, feature| mask | feature.mask);
+        // names can't be normalized without the tables of the `unicode` feature
+        if cfg!(not(feature = "unicode")) && kind == FeatureKind::RoCompat {
+            return mask & !RO_COMPAT_NFC_NAMES;
+        }
+        mask
+    }
+
+    /// All enabled features this implementation knows about
+    pub fn enabled(&self) -> Vec<Feature> {
+        KNOWN_FEATURES
+            .into_iter()
+            .filter(|feature| self.flags(feature.kind) & feature.mask != 0)
+            .collect()
+    }
+
+    /// The enabled flags of `kind` this implementation doesn't know about
+    pub fn unknown(&self, kind: FeatureKind) -> u32 {
+        self.flags(kind) & !Self::known_mask(kind)
+    }
+
+    /// Whether this implementation can read the image
+    pub fn can_read(&self) -> bool {
+        self.unknown(FeatureKind::Incompat) == 0
+    }
+
+    /// Whether this implementation can write to the image without breaking it
+    pub fn can_write(&self) -> bool {
+        self.can_read() && self.unknown(FeatureKind::RoCompat) == 0
+    }
+}
+
+impl Display for Features {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for feature in self.enabled() {
+            writeln!(f, "{}: {}", feature.name, feature.description)?;
+        }
+        for (kind, name) in [
+            (FeatureKind::Compat, "compat"),
+            (FeatureKind::RoCompat, "ro_compat"),
+            (FeatureKind::Incompat, "incompat"),
+        ] {
+            let unknown = self.unknown(kind);
+            if unknown != 0 {
+                writeln!(f, "unknown {name} features: {unknown:#x}")?;
+            }
+        }
+        Ok(())
+    }
+}
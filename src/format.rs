@@ -0,0 +1,320 @@
+use alloc::{vec, vec::Vec};
+use core::{fmt::Display, mem::offset_of};
+
+use crate::{
+    addr::POINTERS_PER_BLOCK,
+    directory::{DIRENTRY_NAME_LENGTH, DIRENT_HEADER_SIZE},
+    fs::{BLOCKS_PER_BLOCKARRAY, BLOCK_SIZE, INODE_SIZE},
+    inode::Inode,
+    journal::JournalHeader,
+    superblock::Superblock,
+    xattr::{XATTR_HEADER_SIZE, XATTR_NAME_LENGTH},
+};
+
+/// Where a field of an on-disk structure lives
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FieldLayout {
+    pub name: &'static str,
+    /// in bytes from the start of the structure
+    pub offset: usize,
+    /// in bytes
+    pub size: usize,
+}
+
+/// The layout of one on-disk structure, see `layout_report`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StructLayout {
+    pub name: &'static str,
+    /// the size on disk in bytes, the largest possible one for variable-length structures
+    pub size: usize,
+    /// every byte of the structure, in order. Gaps between named fields show up as `reserved`,
+    /// the rest up to `size` as `padding`; both have to be zero.
+    pub fields: Vec<FieldLayout>,
+}
+
+/// The on-disk layout of every structure, see `layout_report`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LayoutReport {
+    /// sfs stores integers in the byte order of the machine that wrote the image, so images are
+    /// only portable between machines of the same endianness
+    pub little_endian: bool,
+    pub structs: Vec<StructLayout>,
+}
+
+fn field_size<T, F>(_: impl Fn(&T) -> &F) -> usize {
+    size_of::<F>()
+}
+
+macro_rules! field {
+    ($ty:ty, $field:ident) => {
+        FieldLayout {
+            name: stringify!($field),
+            offset: offset_of!($ty, $field),
+            size: field_size(|value: &$ty| &value.$field),
+        }
+    };
+}
+
+impl StructLayout {
+    /// Sorts `fields` and fills the gaps between them with `reserved` and `padding`
+    fn new(name: &'static str, size: usize, mut fields: Vec<FieldLayout>) -> Self {
+        fields.sort_by_key(|field| field.offset);
+
+        let mut all = Vec::with_capacity(fields.len());
+        let mut end = 0;
+        for field in fields {
+            if field.offset > end {
+                all.push(FieldLayout {
+                    name: "reserved",
+                    offset: end,
+                    size: field.offset - end,
+                });
+            }
+            end = field.offset + field.size;
+            all.push(field);
+        }
+        if size > end {
+            all.push(FieldLayout {
+                name: "padding",
+                offset: end,
+                size: size - end,
+            });
+        }
+
+        Self {
+            name,
+            size,
+            fields: all,
+        }
+    }
+}
+
+/// The exact on-disk offsets and sizes of every structure as this build reads and writes them.
+/// Implementations in other languages can compare against it instead of only against `sfs.md`.
+pub fn layout_report() -> LayoutReport {
+    let superblock = StructLayout::new(
+        "Superblock",
+        BLOCK_SIZE,
+        vec![
+            field!(Superblock, signature),
+            field!(Superblock, earliest_free),
+            field!(Superblock, earliest_inode_space),
+            field!(Superblock, last_free),
+            field!(Superblock, total_unused),
+            field!(Superblock, total_blocks),
+            field!(Superblock, last_mount),
+            field!(Superblock, last_write),
+            field!(Superblock, name),
+            field!(Superblock, file_prealloc),
+            field!(Superblock, dir_prealloc),
+            field!(Superblock, root_inode),
+            field!(Superblock, inode_table_start),
+            field!(Superblock, inode_table_blocks),
+            field!(Superblock, fixed_inode_table),
+            field!(Superblock, feature_compat),
+            field!(Superblock, feature_ro_compat),
+            field!(Superblock, feature_incompat),
+            field!(Superblock, dir_hash),
+            field!(Superblock, zone_metadata_percent),
+            field!(Superblock, zone_small_files_percent),
+            field!(Superblock, boot_area_blocks),
+            field!(Superblock, dir_hash_seed),
+            field!(Superblock, journal_blocks),
+            field!(Superblock, checksum),
+            field!(Superblock, next_inode),
+            field!(Superblock, next_generation),
+            field!(Superblock, inode_bitmap_blocks),
+            field!(Superblock, free_inodes),
+            field!(Superblock, seal_key),
+            field!(Superblock, seal_signature),
+            field!(Superblock, verity_start),
+            field!(Superblock, verity_blocks),
+            field!(Superblock, quota_inode),
+            field!(Superblock, snapshot_table),
+            field!(Superblock, crypt_start),
+            field!(Superblock, crypt_blocks),
+            field!(Superblock, crypt_cipher),
+            field!(Superblock, reserved4),
+            field!(Superblock, kdf_memory),
+            field!(Superblock, kdf_iterations),
+            field!(Superblock, kdf_lanes),
+            field!(Superblock, kdf_salt),
+            field!(Superblock, key_nonce),
+            field!(Superblock, wrapped_key),
+            field!(Superblock, uuid),
+        ],
+    );
+
+    let inode = StructLayout::new(
+        "Inode",
+        INODE_SIZE,
+        vec![
+            field!(Inode, type_and_permission),
+            field!(Inode, uid),
+            field!(Inode, gid),
+            field!(Inode, compression),
+            field!(Inode, modification_time),
+            field!(Inode, creation_time),
+            field!(Inode, hardlinks),
+            field!(Inode, block_pointers),
+            field!(Inode, singly_indirect_block_pointer),
+            field!(Inode, doubly_indirect_block_pointer),
+            field!(Inode, meta),
+            field!(Inode, version),
+            field!(Inode, flags),
+            field!(Inode, xattr_block),
+            field!(Inode, size),
+            field!(Inode, checksum),
+            field!(Inode, generation),
+            field!(Inode, access_time),
+            field!(Inode, modification_nanos),
+            field!(Inode, access_nanos),
+        ],
+    );
+
+    // directory records are packed by hand, not laid out by the compiler
+    let dir_entry = StructLayout::new(
+        "DirEntry",
+        DIRENT_HEADER_SIZE as usize + DIRENTRY_NAME_LENGTH,
+        vec![
+            FieldLayout {
+                name: "inode",
+                offset: 0,
+                size: 4,
+            },
+            FieldLayout {
+                name: "rec_len",
+                offset: 4,
+                size: 2,
+            },
+            FieldLayout {
+                name: "name_size",
+                offset: 6,
+                size: 1,
+            },
+            FieldLayout {
+                name: "file_type",
+                offset: 7,
+                size: 1,
+            },
+            FieldLayout {
+                name: "name",
+                offset: DIRENT_HEADER_SIZE as usize,
+                size: DIRENTRY_NAME_LENGTH,
+            },
+        ],
+    );
+
+    // the largest attribute has the longest name and a value filling the rest of the block
+    let xattr_value = XATTR_HEADER_SIZE + XATTR_NAME_LENGTH;
+    let xattr_entry = StructLayout::new(
+        "XattrEntry",
+        BLOCK_SIZE,
+        vec![
+            FieldLayout {
+                name: "name_size",
+                offset: 0,
+                size: 1,
+            },
+            FieldLayout {
+                name: "value_size",
+                offset: 1,
+                size: 2,
+            },
+            FieldLayout {
+                name: "name",
+                offset: XATTR_HEADER_SIZE,
+                size: XATTR_NAME_LENGTH,
+            },
+            FieldLayout {
+                name: "value",
+                offset: xattr_value,
+                size: BLOCK_SIZE - xattr_value,
+            },
+        ],
+    );
+
+    let bitmap_size = BLOCKS_PER_BLOCKARRAY as usize / 8;
+    let block_array_descriptor = StructLayout::new(
+        "BlockArrayDescriptor",
+        BLOCK_SIZE,
+        vec![
+            FieldLayout {
+                name: "usage_bitmap",
+                offset: 0,
+                size: bitmap_size,
+            },
+            FieldLayout {
+                name: "type_bitmap",
+                offset: bitmap_size,
+                size: bitmap_size,
+            },
+        ],
+    );
+
+    let pointer_block = StructLayout::new(
+        "PointerBlock",
+        BLOCK_SIZE,
+        vec![FieldLayout {
+            name: "pointers",
+            offset: 0,
+            size: POINTERS_PER_BLOCK * size_of::<u32>(),
+        }],
+    );
+
+    let journal_header = StructLayout::new(
+        "JournalHeader",
+        BLOCK_SIZE,
+        vec![
+            field!(JournalHeader, signature),
+            field!(JournalHeader, sequence),
+            field!(JournalHeader, time),
+            field!(JournalHeader, committed),
+            field!(JournalHeader, count),
+            field!(JournalHeader, checksum),
+            field!(JournalHeader, at),
+            field!(JournalHeader, prev),
+            field!(JournalHeader, flags),
+            field!(JournalHeader, reserved),
+            field!(JournalHeader, blocks),
+        ],
+    );
+
+    LayoutReport {
+        little_endian: cfg!(target_endian = "little"),
+        structs: vec![
+            superblock,
+            inode,
+            dir_entry,
+            xattr_entry,
+            block_array_descriptor,
+            pointer_block,
+            journal_header,
+        ],
+    }
+}
+
+impl Display for LayoutReport {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(
+            f,
+            "byte order: {}",
+            if self.little_endian {
+                "little-endian"
+            } else {
+                "big-endian"
+            }
+        )?;
+        for layout in &self.structs {
+            writeln!(f, "{} ({} bytes)", layout.name, layout.size)?;
+            for field in &layout.fields {
+                writeln!(
+                    f,
+                    "  {:<30} {:>5} {:>5}",
+                    field.name, field.offset, field.size
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
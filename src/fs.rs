@@ -1,10 +1,78 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use alloc::{
+    borrow::Cow,
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::{fmt::Display, ops::Range, sync::atomic::Ordering};
+#[cfg(feature = "std")]
+use std::{
+    io::{Read, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use crate::{
-    directory::DirEntry,
-    disk::{Disk, DiskError},
-    inode::{Inode, InodeType, Permission, PermissionsAndType},
-    superblock::Superblock,
+    addr::Addr,
+    bulk::BulkImport,
+    compress::Compression,
+    copy::{CopyStats, TreeCopy},
+    crypt::{self, Cipher, DataKey, Passphrase},
+    dcache::{DirCache, DirIndex},
+    defrag::{Defrag, DefragProgress},
+    directory::{is_dot_name, DirEntry, DIRENTRY_NAME_LENGTH, MIN_RECORD_SIZE},
+    dirhash::DirHashAlgorithm,
+    disk::{CacheMode, Disk, DiskError},
+    estimate::{self, PlannedEntry, UsageEstimate},
+    features::{
+        Features, COMPAT_ALLOC_ZONES, COMPAT_BOOT_AREA, COMPAT_INODE_TABLE, COMPAT_JOURNAL,
+        COMPAT_SEAL, COMPAT_VERITY, INCOMPAT_COMPRESSION, INCOMPAT_ENCRYPTION,
+        RO_COMPAT_DOT_ENTRIES, RO_COMPAT_FILE_SIZE, RO_COMPAT_FIXED_INODE_TABLE,
+        RO_COMPAT_INODE_BITMAP, RO_COMPAT_NFC_NAMES, RO_COMPAT_QUOTA, RO_COMPAT_SEQUENTIAL_INODES,
+        RO_COMPAT_SORTED_DIRS, RO_COMPAT_WIDE_TIMES, RO_COMPAT_WORM, RO_COMPAT_XATTR,
+    },
+    fsck::FsckReport,
+    handle::DirHandle,
+    health::HealthReport,
+    hints::{StorageHints, COLD_RUN_BLOCKS, STORAGE_HINTS_XATTR},
+    hooks::{FileHook, HookId, Hooks},
+    index::{self, SearchHit, SearchIndex, INDEX_FILE},
+    inode::{
+        Inode, InodeType, Permission, PermissionsAndType, DOUBLY_END, INODE_FLAG_PINNED,
+        INODE_FLAG_SORTED_DIR, MAX_FILE_SIZE, PERMISSION_MASK,
+    },
+    inode_bitmap::{self, bitmap_blocks},
+    journal::{self, Transaction, TxnInfo},
+    loopback::LoopbackIO,
+    manifest::{Manifest, ManifestMismatch},
+    normalize,
+    paths::{InternedPaths, PathId},
+    quota::{self, QuotaId, QuotaLimits, QuotaUsage, Quotas},
+    recover::{self, DeletedInode},
+    redirect::{Redirect, Redirects, Resolved},
+    reservation::Reservation,
+    seal,
+    snapshot::{self, SnapshotInfo, Snapshots},
+    superblock::{Superblock, Uuid},
+    template::{DirTemplate, TEMPLATE_XATTR, TEMPLATE_XATTR_PREFIX},
+    time::Timestamp,
+    trash::{self, TrashEntry},
+    verity,
+    xattr::{self, XattrBlock},
+    zones::{AllocZone, ZoneLayout},
+};
+#[cfg(feature = "std")]
+use crate::{
+    dirhash::random_seed,
+    disk::IO,
+    export::{self, ExportOptions, ExportStats},
+    handle::FileHandle,
+    pack::{PackStats, Packer, Unpacker},
+    tar::{TarExport, TarImport, TarStats},
 };
 
 #[derive(Debug)]
@@ -16,11 +84,124 @@ pub enum FsError {
     NoEntry,
     NoSpace,
     FailSuperblockWrite,
+    NotAFile,
+    #[cfg(feature = "std")]
+    IoError(std::io::Error),
+    /// The on-disk structures contain values that can't be valid
+    Corrupted,
+    /// The image uses incompat features this implementation doesn't know, see `Features`
+    UnsupportedFeatures,
+    /// The type of an inode can't change after it was created
+    TypeChange,
+    /// The image is write-once and the operation would change or delete existing data
+    WriteOnce,
+    /// The operation only works on directories
+    NotADirectory,
+    /// A directory can only be removed once it has no entries left
+    DirectoryNotEmpty,
+    /// A directory can't be moved into itself or one of its subdirectories
+    DirectoryLoop,
+    /// The file system can't be changed, because it was opened read-only or a write to the disk
+    /// failed (see `FileSystem::state`)
+    ReadOnly,
+    /// The inode has no extended attribute with that name
+    NoAttribute,
+    /// The inode already has 65535 hard links, the most its link count holds. Directories count
+    /// the `..` entries of their subdirectories as well.
+    TooManyLinks,
+    /// The directory already has an entry with that name
+    AlreadyExists,
+    /// `.` and `..` can't be created, removed or renamed
+    InvalidName,
+    /// The superblock or an inode doesn't match its checksum, see `Superblock::has_checksums`
+    ChecksumMismatch,
+    /// The value of an attribute doesn't have the format its name requires (see `StorageHints`),
+    /// or a symbolic link target is empty
+    InvalidValue,
+    /// The superblock claims more blocks than the disk has, e.g. after an image was copied to a
+    /// smaller device
+    DiskTooSmall,
+    /// The image isn't sealed with the expected key, or was changed after it was sealed, see
+    /// `FileSystem::verify_seal`
+    BadSeal,
+    /// The hash tree of the image doesn't have the root hash `MountOptions::verity_root` expects
+    BadRootHash,
+    /// The owner or group of the inode would go over its limit, see `FileSystem::set_quota`
+    QuotaExceeded,
+    /// The file would grow past what its block pointers reach, see `FileSystem::limits`
+    FileTooLarge,
+    /// The image is encrypted and `MountOptions::passphrase` is missing or doesn't unlock it
+    BadPassphrase,
+    /// The transaction isn't in the journal anymore, or something it depends on changed outside
+    /// of the journal since, see `FileSystem::read_file_at_txn`
+    NotJournaled,
 }
 
 impl From<DiskError> for FsError {
     fn from(value: DiskError) -> Self {
-        Self::DiskError(value)
+        match value {
+            DiskError::ReadOnly => Self::ReadOnly,
+            other => Self::DiskError(other),
+        }
+    }
+}
+
+impl Display for FsError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            #[cfg(feature = "std")]
+            Self::IoError(e) => write!(f, "IoError({e})"),
+            other => write!(f, "{other:?}"),
+        }
+    }
+}
+
+impl core::error::Error for FsError {}
+
+#[cfg(feature = "std")]
+impl From<FsError> for std::io::Error {
+    fn from(value: FsError) -> Self {
+        use std::io::ErrorKind;
+
+        let kind = match &value {
+            FsError::IoError(e) => e.kind(),
+            FsError::NoEntry | FsError::NoAttribute | FsError::NotJournaled => ErrorKind::NotFound,
+            FsError::NoSpace | FsError::DiskError(DiskError::NotEnoughSpace) => {
+                ErrorKind::StorageFull
+            }
+            FsError::QuotaExceeded => ErrorKind::QuotaExceeded,
+            FsError::FileTooLarge => ErrorKind::FileTooLarge,
+            FsError::DiskError(DiskError::Interrupted) => ErrorKind::Interrupted,
+            FsError::DiskError(DiskError::PermissionDenied) => ErrorKind::PermissionDenied,
+            FsError::DiskError(DiskError::Unsupported) => ErrorKind::Unsupported,
+            FsError::DiskError(DiskError::OutOfBounds) => ErrorKind::UnexpectedEof,
+            FsError::DiskError(DiskError::ShortWrite { .. }) => ErrorKind::WriteZero,
+            FsError::ReadOnly => ErrorKind::ReadOnlyFilesystem,
+            FsError::NameTooLong => ErrorKind::InvalidFilename,
+            FsError::NotAFile => ErrorKind::IsADirectory,
+            FsError::NotADirectory => ErrorKind::NotADirectory,
+            FsError::DirectoryNotEmpty => ErrorKind::DirectoryNotEmpty,
+            FsError::TooManyLinks => ErrorKind::TooManyLinks,
+            FsError::DirectoryLoop | FsError::InvalidName | FsError::InvalidValue => {
+                ErrorKind::InvalidInput
+            }
+            FsError::AlreadyExists => ErrorKind::AlreadyExists,
+            FsError::WriteOnce | FsError::BadPassphrase => ErrorKind::PermissionDenied,
+            FsError::InvalidSignature
+            | FsError::Corrupted
+            | FsError::InvalidBlock
+            | FsError::ChecksumMismatch
+            | FsError::DiskTooSmall
+            | FsError::BadSeal
+            | FsError::BadRootHash
+            | FsError::DiskError(DiskError::VerityMismatch { .. })
+            | FsError::DiskError(DiskError::DecryptionFailed { .. }) => ErrorKind::InvalidData,
+            _ => ErrorKind::Other,
+        };
+        match value {
+            FsError::IoError(e) => e,
+            other => std::io::Error::new(kind, other),
+        }
     }
 }
 
@@ -28,6 +209,34 @@ impl From<DiskError> for FsError {
 pub struct FileSystem {
     pub superblock: Superblock,
     disk: Disk,
+    /// used instead of the clock for timestamps, see `set_fixed_time`
+    fixed_time: Option<u64>,
+    options: MountOptions,
+    /// set once an inconsistency was found, see `ErrorPolicy`
+    has_errors: bool,
+    /// per `AllocZone`, no block before this one is free in that zone
+    zone_hints: [u32; 3],
+    /// the number of free blocks per block group, counted on first use and kept up to date by
+    /// `mark_block`, so full groups can be skipped without reading their block array
+    group_free: BTreeMap<u32, u32>,
+    /// see `MountOptions::dcache_entries`
+    dcache: DirCache,
+    /// see `FileSystem::redirect`
+    redirects: Redirects,
+    /// see `FileSystem::intern_path`
+    paths: InternedPaths,
+    /// see `FileSystem::add_hook`
+    hooks: Hooks,
+    /// see `FileSystem::enable_index`
+    index: Option<SearchIndex>,
+    /// the most recently freed inode numbers, the oldest first, see `InodeReuse::Lazy`
+    freed_inodes: VecDeque<u32>,
+    /// see `FileSystem::set_quota`
+    quotas: Quotas,
+    /// see `FileSystem::create_snapshot`
+    snapshots: Snapshots,
+    /// the data key of an encrypted image, see `FileSystem::enable_encryption`
+    data_key: Option<DataKey>,
 }
 
 pub const BLOCKS_PER_BLOCKARRAY: u32 = 2048 * 8;
@@ -48,39 +257,134 @@ impl<'a> BlockArrayDescriptor<'a> {
         Self(disk, idx)
     }
 
-    pub fn create(disk: &'a mut Disk, idx: u32) -> Result<Self, DiskError> {
+    pub fn create(disk: &'a mut Disk, idx: u32) -> Result<Self, FsError> {
         let mut value = Self(disk, idx);
         value.set(0, BlockArrayEntry::BlockArrayDescriptor)?;
         Ok(value)
     }
 
-    pub fn get(&mut self, index: u32) -> Result<BlockArrayEntry, DiskError> {
+    /// The address of the byte holding the usage bit of `index`, the type bit is 2048 bytes later
+    fn bitmap_addr(&self, index: u32) -> Result<usize, FsError> {
+        Ok(Addr::block(self.1 * BLOCKS_PER_BLOCKARRAY)?
+            .offset((index / 8) as usize)?
+            .get())
+    }
+
+    pub fn get(&mut self, index: u32) -> Result<BlockArrayEntry, FsError> {
         if index == 0 {
             return Ok(BlockArrayEntry::BlockArrayDescriptor);
         }
 
-        let block_index = (index / 8) as usize;
+        let addr = self.bitmap_addr(index)?;
         let bitmap_offset = index % 8;
 
-        if self
-            .0
-            .read_struct::<u8>(block_index + (self.1 as usize * BLOCKS_PER_BLOCKARRAY as usize))?
-            & (1 << bitmap_offset)
-            == 0
-        {
+        if self.0.read_struct::<u8>(addr)? & (1 << bitmap_offset) == 0 {
             Ok(BlockArrayEntry::Unused)
-        } else if self.0.read_struct::<u8>(
-            block_index + (self.1 as usize * BLOCKS_PER_BLOCKARRAY as usize) + 2048,
-        )? & (1 << bitmap_offset)
-            > 0
-        {
+        } else if self.0.read_struct::<u8>(addr + 2048)? & (1 << bitmap_offset) > 0 {
             Ok(BlockArrayEntry::InodeBlock)
         } else {
             Ok(BlockArrayEntry::Allocated)
         }
     }
 
-    pub fn set(&mut self, index: u32, mut typ: BlockArrayEntry) -> Result<(), DiskError> {
+    /// The index of the first unused entry in `range`, reading the usage bitmap 64 entries at a
+    /// time
+    pub fn find_unused(&mut self, range: Range<u32>) -> Result<Option<u32>, FsError> {
+        if range.is_empty() {
+            return Ok(None);
+        }
+        let first_word = range.start / 64;
+        let last_word = (range.end - 1) / 64;
+        let mut bitmap = vec![0; (last_word - first_word + 1) as usize * 8];
+        self.0
+            .read_exact(self.bitmap_addr(first_word * 64)?, &mut bitmap)?;
+
+        for (i, word) in bitmap.chunks_exact(8).enumerate() {
+            let start = (first_word + i as u32) * 64;
+            let mut used = u64::from_le_bytes(word.try_into().expect("chunks of 8 bytes"));
+            // entries outside of the range count as used
+            if start < range.start {
+                used |= (1 << (range.start - start)) - 1;
+            }
+            if start + 64 > range.end {
+                used |= u64::MAX << (range.end - start);
+            }
+            if used != u64::MAX {
+                return Ok(Some(start + used.trailing_ones()));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The index of the last unused entry in `range`, the same way `find_unused` finds the first
+    pub fn find_last_unused(&mut self, range: Range<u32>) -> Result<Option<u32>, FsError> {
+        if range.is_empty() {
+            return Ok(None);
+        }
+        let first_word = range.start / 64;
+        let last_word = (range.end - 1) / 64;
+        let mut bitmap = vec![0; (last_word - first_word + 1) as usize * 8];
+        self.0
+            .read_exact(self.bitmap_addr(first_word * 64)?, &mut bitmap)?;
+
+        for (i, word) in bitmap.chunks_exact(8).enumerate().rev() {
+            let start = (first_word + i as u32) * 64;
+            let mut used = u64::from_le_bytes(word.try_into().expect("chunks of 8 bytes"));
+            if start < range.start {
+                used |= (1 << (range.start - start)) - 1;
+            }
+            if start + 64 > range.end {
+                used |= u64::MAX << (range.end - start);
+            }
+            if used != u64::MAX {
+                return Ok(Some(start + 63 - used.leading_ones()));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The first `len` entries, reading the bitmaps once instead of two bytes per entry
+    pub fn entries(&mut self, len: u32) -> Result<Vec<BlockArrayEntry>, FsError> {
+        let len = len.min(BLOCKS_PER_BLOCKARRAY);
+        let mut bitmaps = [0; BLOCK_SIZE];
+        self.0.read_exact(self.bitmap_addr(0)?, &mut bitmaps)?;
+        let (usage, types) = bitmaps.split_at(2048);
+
+        Ok((0..len)
+            .map(|index| {
+                let (byte, mask) = ((index / 8) as usize, 1 << (index % 8));
+                if index == 0 {
+                    BlockArrayEntry::BlockArrayDescriptor
+                } else if usage[byte] & mask == 0 {
+                    BlockArrayEntry::Unused
+                } else if types[byte] & mask != 0 {
+                    BlockArrayEntry::InodeBlock
+                } else {
+                    BlockArrayEntry::Allocated
+                }
+            })
+            .collect())
+    }
+
+    /// The number of unused entries among the first `len`
+    pub fn count_unused(&mut self, len: u32) -> Result<u32, FsError> {
+        let len = len.min(BLOCKS_PER_BLOCKARRAY);
+        let mut bitmap = vec![0; len.div_ceil(64) as usize * 8];
+        self.0.read_exact(self.bitmap_addr(0)?, &mut bitmap)?;
+
+        let mut unused = 0;
+        for (i, word) in bitmap.chunks_exact(8).enumerate() {
+            let start = i as u32 * 64;
+            let mut used = u64::from_le_bytes(word.try_into().expect("chunks of 8 bytes"));
+            if start + 64 > len {
+                used |= u64::MAX << (len - start);
+            }
+            unused += used.count_zeros();
+        }
+        Ok(unused)
+    }
+
+    pub fn set(&mut self, index: u32, mut typ: BlockArrayEntry) -> Result<(), FsError> {
         if index >= BLOCKS_PER_BLOCKARRAY {
             return Ok(());
         }
@@ -91,7 +395,7 @@ impl<'a> BlockArrayDescriptor<'a> {
             typ = BlockArrayEntry::Allocated;
         }
 
-        let block_index = (index / 8) as usize + (self.1 as usize * BLOCKS_PER_BLOCKARRAY as usize);
+        let block_index = self.bitmap_addr(index)?;
         let bitmap_offset = index % 8;
 
         let mut usage_bitmap = self.0.read_struct::<u8>(block_index)?;
@@ -116,201 +420,3705 @@ impl<'a> BlockArrayDescriptor<'a> {
     }
 }
 
+/// The number of distinct blocks an operation read and wrote, split into metadata (block array
+/// descriptors, the superblock, inode blocks, the journal, the inode bitmap, the hash tree, the
+/// nonce table, directory blocks, indirect pointer blocks and xattr blocks) and file contents.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IoStats {
+    pub metadata_reads: u32,
+    pub metadata_writes: u32,
+    pub data_reads: u32,
+    pub data_writes: u32,
+}
+
+impl Display for IoStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{} data reads, {} metadata reads, {} data writes, {} metadata writes",
+            self.data_reads, self.metadata_reads, self.data_writes, self.metadata_writes
+        )
+    }
+}
+
+/// Options for formatting a new file system
+#[derive(Debug, Clone, Default)]
+pub struct MkfsOptions {
+    /// Reserve one inode for every `bytes_per_inode` bytes of the image in a contiguous inode table
+    /// right after the superblock. Once the table is full, inode blocks are allocated on demand.
+    /// `None` only allocates inode blocks on demand.
+    pub bytes_per_inode: Option<u32>,
+    /// Place all inodes in the inode table (ext2-style), never allocating inode blocks on demand.
+    /// Uses `DEFAULT_BYTES_PER_INODE` if `bytes_per_inode` is not set.
+    pub fixed_inode_table: bool,
+    /// Reproducible mode: use this UNIX-Time for every timestamp instead of the clock and zero
+    /// every block of the disk, so formatting and importing the same inputs in the same order
+    /// produces byte-identical images. Blocks are always allocated first-fit, so the layout only
+    /// depends on the order of operations. See `with_source_date_epoch`.
+    pub source_date_epoch: Option<u64>,
+    /// The hash function for directory indices
+    pub dir_hash: DirHashAlgorithm,
+    /// The key for `dir_hash`. Random if not set, except in reproducible mode, where it defaults
+    /// to `[source_date_epoch, 0]` and has to be set to something secret if names can come from
+    /// untrusted sources.
+    pub dir_hash_seed: Option<[u64; 2]>,
+    /// Keep metadata, small files and large files in separate zones of every block group, see
+    /// `ZoneLayout`. `None` allocates first-fit everywhere.
+    pub zones: Option<ZoneLayout>,
+    /// Start out write-once, see `FileSystem::enable_worm`
+    pub worm: bool,
+    /// Reserve this many blocks right after the superblock for a bootloader, see
+    /// `FileSystem::boot_area`
+    pub boot_area_blocks: u32,
+    /// Reserve a journal of this many blocks after the boot area, see `FileSystem::begin_txn`. A
+    /// transaction can change two blocks less than that, but at most `JOURNAL_MAX_BLOCKS`. The
+    /// space left over keeps earlier transactions, see `FileSystem::read_file_at_txn`. 0 for no
+    /// journal.
+    pub journal_blocks: u32,
+    /// Hand out inode numbers in strictly increasing order, never reusing the number of a deleted
+    /// inode until the numbers wrap around at the end of the image (or the inode table), so they
+    /// can serve as stable identifiers. Slots skipped over stay empty until then.
+    pub sequential_inodes: bool,
+    /// Store entry names in Unicode Normalization Form C and compare them normalized on lookup,
+    /// so a name written in NFD (like macOS does) finds the entry created in NFC (like Linux
+    /// does) and can't create a second one. Needs the `unicode` feature. See
+    /// `FileSystem::enable_name_normalization`.
+    pub normalize_names: bool,
+    /// Where the timestamps come from, see `MountOptions::clock`
+    pub clock: Option<Arc<dyn Clock>>,
+    /// The UUID of the image. Random if not set, except in reproducible mode, where it is derived
+    /// from `source_date_epoch` and the name.
+    pub uuid: Option<Uuid>,
+}
+
+impl MkfsOptions {
+    /// Enables reproducible mode if the `SOURCE_DATE_EPOCH` environment variable is set, like
+    /// other reproducible-builds aware tools do
+    #[cfg(feature = "std")]
+    pub fn with_source_date_epoch(mut self) -> Self {
+        if let Some(epoch) = std::env::var("SOURCE_DATE_EPOCH")
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+        {
+            self.source_date_epoch = Some(epoch);
+        }
+        self
+    }
+}
+
+pub const DEFAULT_BYTES_PER_INODE: u32 = 16384;
+
+/// Everything known about an inode, see `FileSystem::stat`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stat {
+    pub inode: u32,
+    pub file_type: InodeType,
+    /// the permission bits, without the type (`PERMISSION_MASK`)
+    pub permissions: u16,
+    pub uid: u16,
+    pub gid: u16,
+    pub size: u64,
+    /// data and indirect pointer blocks, in blocks of `BLOCK_SIZE` bytes
+    pub blocks: u32,
+    pub hardlinks: u16,
+    /// only whole seconds
+    pub created: Timestamp,
+    pub modified: Timestamp,
+    /// see `MountOptions::atime`
+    pub accessed: Timestamp,
+    /// Increases with every change to the inode or its contents, so caches can cheaply check
+    /// whether their copy is still current. Only equality is meaningful, not the difference.
+    pub version: u64,
+    /// Differs from the generation of every inode that had the same number before, so together
+    /// with the number it identifies the file, like the generation in an NFS file handle. 0 on
+    /// images without the `inode_generation` feature.
+    pub generation: u32,
+}
+
+/// The attributes `FileSystem::set_attr` changes, `None` leaves one as it is
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SetAttr {
+    /// the permission bits (chmod), the type can't be changed
+    pub permissions: Option<u16>,
+    /// chown
+    pub uid: Option<u16>,
+    pub gid: Option<u16>,
+    /// the modification time (utimes)
+    pub modified: Option<Timestamp>,
+    /// the access time (utimes)
+    pub accessed: Option<Timestamp>,
+}
+
+/// How much of the image is in use, see `FileSystem::disk_usage`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiskUsage {
+    pub total_blocks: u32,
+    pub free_blocks: u32,
+    /// inodes with at least one hard link
+    pub inodes: u32,
+}
+
+/// What the format allows on this image, see `FileSystem::limits`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FsLimits {
+    /// the longest a file can get in bytes, holes included; writing or truncating past it fails
+    /// with `FsError::FileTooLarge`
+    pub max_file_size: u64,
+    /// the longest name of a directory entry in bytes (after normalizing on `nfc_names` images),
+    /// longer ones fail with `FsError::NameTooLong`
+    pub max_name_len: usize,
+    /// the most components a path can have below the root. Every directory on the way takes an
+    /// inode, there is no other limit.
+    pub max_path_depth: u32,
+    /// the most entries a directory can hold if all of their names are a single byte, `.` and
+    /// `..` not counted
+    pub max_dir_entries: u64,
+    /// the most inodes the image can hold: the inode table with a fixed one, otherwise every
+    /// block that isn't reserved filled with inodes
+    pub inode_capacity: u32,
+    /// the largest an image can get in bytes, as blocks are numbered with 32 bits
+    pub max_image_size: u64,
+}
+
+/// A run of contiguous free blocks, see `FileSystem::free_extents`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FreeExtent {
+    /// the first free block
+    pub start: u32,
+    /// the number of free blocks in this run
+    pub length: u32,
+}
+
+/// A directory entry whose type hint disagrees with its inode, see
+/// `FileSystem::check_dir_entry_types`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypeMismatch {
+    pub dir: u32,
+    pub name: String,
+    pub inode: u32,
+    pub entry_type: InodeType,
+    pub inode_type: InodeType,
+}
+
+/// What to do when an inconsistency in the on-disk structures is found at runtime (like the
+/// `errors=` mount option of ext4). The operation that found it always fails with
+/// `FsError::Corrupted`, the policy decides what happens to the following ones.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Keep going; the damaged structure is likely to fail again, everything else keeps working
+    #[default]
+    Continue,
+    /// Refuse all further writes, so the damage can't spread, but keep serving reads
+    RemountReadOnly,
+    /// Refuse all further reads and writes
+    Abort,
+}
+
+/// Whether a file system can still be read and written, see `FileSystem::state`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsState {
+    Writable,
+    /// opened read-only, or remounted read-only by `ErrorPolicy::RemountReadOnly`
+    ReadOnly,
+    /// a write to the disk failed, so all further writes are refused instead of applying only
+    /// part of an update. Reads keep working.
+    Degraded,
+    /// shut down by `ErrorPolicy::Abort`, nothing can be read or written anymore
+    ShutDown,
+}
+
+/// Options for opening a file system
+#[derive(Debug, Clone, Default)]
+pub struct MountOptions {
+    /// Sync every write to the disk before returning, so every successful operation is durable
+    /// once it returns. Off by default (async): writes become durable whenever the underlying IO
+    /// decides to or `FileSystem::sync` is called, which is a lot faster but can lose recent
+    /// operations (and leave the image inconsistent) on a crash or power loss.
+    pub sync: bool,
+    pub errors: ErrorPolicy,
+    /// The number of recently used blocks to keep in memory, 0 for none, see `Disk::set_cache`
+    pub cache_blocks: usize,
+    /// Whether cached writes go to the disk right away. `sync` makes every write go through.
+    pub cache_mode: CacheMode,
+    /// Read every write back and fail with the block that doesn't match, see `Disk::set_verify`
+    pub verify_writes: bool,
+    /// Move files to `/.trash/<time>/` when their last link is removed instead of freeing them,
+    /// and free them once they have been there for this many seconds. `None` disables the trash.
+    pub trash_expiry: Option<u64>,
+    /// The number of directory entries `lookup` keeps in memory, 0 for none. Path resolution then
+    /// only reads the directories whose entries changed since.
+    pub dcache_entries: usize,
+    /// The number of names `lookup` remembers as missing, 0 for none, so probing the same missing
+    /// paths over and over doesn't read the directories every time
+    pub dcache_negative_entries: usize,
+    /// The number of directories `lookup` keeps a hash index of in memory, 0 for none. Only
+    /// unsorted directories spanning more than one block get one: the first lookup reads all of
+    /// the directory to build it, later ones only read the block holding the name.
+    pub dir_index_dirs: usize,
+    /// When the numbers of deleted inodes are handed out again. Images with the
+    /// `sequential_inodes` feature never reuse them early.
+    pub inode_reuse: InodeReuse,
+    /// When reading a file updates its access time
+    pub atime: AtimeMode,
+    /// Only open images sealed with the private key of this public key and unchanged since, see
+    /// `FileSystem::verify_seal`. Checked by `mount` only, not by `remount`.
+    pub seal_key: Option<[u8; 32]>,
+    /// Only open images with a hash tree with this root hash, see `FileSystem::build_verity`.
+    /// Implies a read-only mount. Read-only mounts of images with a tree check every read
+    /// against it anyway, but only against the root hash recorded in the image itself.
+    pub verity_root: Option<[u8; 32]>,
+    /// Unlocks images encrypted with `FileSystem::enable_encryption`, which can't be opened
+    /// without it. Dropped once the image is unlocked, so `mount_options` doesn't return it.
+    pub passphrase: Option<Passphrase>,
+    /// Where the timestamps come from, unless a fixed time is set (see
+    /// `FileSystem::set_fixed_time`). `None` uses the system clock, or 0 without the `std`
+    /// feature.
+    pub clock: Option<Arc<dyn Clock>>,
+}
+
+/// A source of the current UNIX-Time for timestamps, e.g. the RTC of a kernel without `std`.
+/// See `MountOptions::clock`.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> u64;
+}
+
+impl core::fmt::Debug for dyn Clock {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Clock").finish_non_exhaustive()
+    }
+}
+
+/// The system clock, which the file system uses if no other one is set
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+#[cfg(feature = "std")]
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards ftw")
+            .as_secs()
+    }
+}
+
+/// When the number of a deleted inode can be handed out again, see `MountOptions::inode_reuse`.
+/// Either way, the new inode gets another generation (see `Stat::generation`), so handles of the
+/// deleted one can be told apart.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InodeReuse {
+    /// Right away, keeping the inodes packed into as few inode blocks as possible
+    #[default]
+    Eager,
+    /// Only once this many other inodes were deleted after it, so a client that still holds a
+    /// handle of a deleted file gets a stale handle error for longer instead of another file with
+    /// the same number. The recently deleted numbers are only kept in memory.
+    Lazy(usize),
+}
+
+/// When reading a file updates its access time, see `MountOptions::atime`. Updating it turns
+/// reads into writes of the inode.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AtimeMode {
+    /// On every read, at most once a second
+    Strict,
+    /// Only if the access time isn't later than the modification time or is at least a day old,
+    /// like Linux' `relatime`: enough to tell whether a file was read since it last changed
+    #[default]
+    Relatime,
+    /// Never (`noatime`)
+    NoAtime,
+}
+
+/// How old the access time has to be for `AtimeMode::Relatime` to update it anyway
+const RELATIME_INTERVAL: i64 = 24 * 60 * 60;
+
 pub const INODE_SIZE: usize = 128;
 pub const BLOCK_SIZE: usize = 4096;
 pub const INODES_PER_BLOCK: u32 = (BLOCK_SIZE / INODE_SIZE) as u32; // block size / inode size
 
 impl FileSystem {
-    pub fn from_disk(mut disk: Disk) -> Result<Self, FsError> {
-        let superblock = Superblock::read(&mut disk, 4096 /* block #1 */)?;
-        Ok(Self { disk, superblock })
-    }
-
-    pub fn get_disk<'a>(&'a mut self) -> &'a mut Disk {
-        &mut self.disk
+    pub fn from_disk(disk: Disk) -> Result<Self, FsError> {
+        Self::mount(disk, MountOptions::default())
     }
 
-    pub fn pointer(block_id: u32) -> Result<usize, FsError> {
-        if block_id % BLOCKS_PER_BLOCKARRAY == 0 {
-            Err(FsError::InvalidBlock)
+    /// Opens the file system on `disk`. A transaction that was committed to the journal but not
+    /// completely written before a crash is finished first, unless the disk is read-only.
+    pub fn mount(mut disk: Disk, mut options: MountOptions) -> Result<Self, FsError> {
+        let mut superblock = Superblock::read(&mut disk, Addr::block(1)?.get())?;
+        if !superblock.features().can_read() {
+            return Err(FsError::UnsupportedFeatures);
+        }
+        // the journal is encrypted as well, so the image is unlocked before it is replayed
+        let passphrase = options.passphrase.take();
+        let data_key = if superblock.feature_incompat & INCOMPAT_ENCRYPTION != 0 {
+            Some(crypt::unlock(&mut disk, &superblock, passphrase.as_ref())?)
         } else {
-            Ok(block_id as usize * BLOCK_SIZE)
+            None
+        };
+        if superblock.journal_blocks != 0
+            && !disk.is_read_only()
+            && journal::replay(
+                &mut disk,
+                2 + superblock.boot_area_blocks,
+                superblock.journal_blocks,
+            )?
+        {
+            superblock = Superblock::read(&mut disk, Addr::block(1)?.get())?;
+        }
+        if superblock.journal_blocks != 0 && !disk.is_read_only() {
+            disk.track_writes();
+        }
+        if disk
+            .size()
+            .is_some_and(|size| superblock.total_blocks as u64 * BLOCK_SIZE as u64 > size)
+        {
+            return Err(FsError::DiskTooSmall);
+        }
+        let mut fs = Self {
+            disk,
+            superblock,
+            fixed_time: None,
+            options: MountOptions::default(),
+            has_errors: false,
+            zone_hints: [0; 3],
+            group_free: BTreeMap::new(),
+            dcache: DirCache::default(),
+            redirects: Redirects::default(),
+            paths: InternedPaths::default(),
+            hooks: Hooks::default(),
+            index: None,
+            freed_inodes: VecDeque::new(),
+            quotas: Quotas::default(),
+            snapshots: Snapshots::default(),
+            data_key,
+        };
+        if options.verity_root.is_some() {
+            fs.disk.set_read_only();
+        }
+        if fs.disk.is_read_only() && fs.superblock.feature_compat & COMPAT_VERITY != 0 {
+            verity::enable(&mut fs, options.verity_root)?;
+            // read again through the tree, the first read wasn't checked
+            fs.superblock = Superblock::read(&mut fs.disk, Addr::block(1)?.get())?;
+        } else if options.verity_root.is_some() {
+            return Err(FsError::BadRootHash);
+        }
+        if let Some(public_key) = &options.seal_key {
+            seal::verify(&mut fs, public_key)?;
         }
+        fs.remount(options)?;
+        fs.quotas = Quotas::load(&mut fs)?;
+        fs.snapshots = Snapshots::load(&mut fs)?;
+        fs.index = SearchIndex::load(&mut fs);
+        Ok(fs)
     }
 
-    pub fn read_inode(&mut self, inode_nbr: u32) -> Result<Inode, FsError> {
-        Ok(self.disk.read_struct(inode_nbr as usize * 128)?)
+    pub fn mount_options(&self) -> &MountOptions {
+        &self.options
     }
 
-    pub fn write_inode(&mut self, inode_nbr: u32, inode: &Inode) -> Result<(), FsError> {
-        self.disk.write_struct(inode_nbr as usize * 128, inode)?;
+    /// Applies new mount options to the already open file system. Changing the cache writes the
+    /// blocks in the old cache to the disk first.
+    pub fn remount(&mut self, options: MountOptions) -> Result<(), FsError> {
+        if (options.cache_blocks, options.cache_mode)
+            != (self.options.cache_blocks, self.options.cache_mode)
+        {
+            self.disk
+                .set_cache(options.cache_blocks, options.cache_mode)?;
+        }
+        self.disk.set_sync(options.sync);
+        self.disk.set_verify(options.verify_writes);
+        self.dcache.set_capacity(
+            options.dcache_entries,
+            options.dcache_negative_entries,
+            options.dir_index_dirs,
+        );
+        self.options = options;
+        self.forget_freed_inodes();
         Ok(())
     }
 
-    fn get_inode_physical(&mut self) -> Result<usize, FsError> {
-        // if self.superblock.earliest_inode_space == 0 {
-        //     self.superblock.earliest_inode_space = self.allocate_block(true)?;
-        // }
-        let inode_addr = self.superblock.earliest_inode_space as usize * INODE_SIZE;
+    /// Remembers that the inode `inode_nbr` was freed, see `InodeReuse::Lazy`
+    pub(crate) fn inode_freed(&mut self, inode_nbr: u32) {
+        if self.options.inode_reuse != InodeReuse::Eager {
+            self.freed_inodes.push_back(inode_nbr);
+            self.forget_freed_inodes();
+        }
+    }
 
-        if inode_addr != 0 {
-            for i in 0..INODES_PER_BLOCK {
-                let inode = self
-                    .disk
-                    .read_struct::<Inode>(inode_addr + i as usize * INODE_SIZE)?;
-                if inode.hardlinks == 0 {
-                    return Ok(inode_addr + i as usize * INODE_SIZE);
-                }
-            }
+    /// Drops the freed inode numbers that can be reused again under the current policy
+    fn forget_freed_inodes(&mut self) {
+        let delay = match self.options.inode_reuse {
+            InodeReuse::Eager => 0,
+            InodeReuse::Lazy(delay) => delay,
+        };
+        while self.freed_inodes.len() > delay {
+            self.freed_inodes.pop_front();
         }
-        let block = self.allocate_block(true)?;
-        return Ok(Self::pointer(block)?);
     }
 
-    pub fn write_superblock(&mut self) -> Result<(), FsError> {
-        match self
-            .disk
-            .write_struct(4096 /* block #1 */, &self.superblock)
-        {
-            Err(..) => Err(FsError::FailSuperblockWrite),
-            Ok(..) => Ok(()),
+    /// Whether the free inode `inode_nbr` can be handed out again, see `InodeReuse`
+    pub(crate) fn can_reuse(&self, inode_nbr: u32) -> bool {
+        !self.freed_inodes.contains(&inode_nbr)
+    }
+
+    /// Whether an inconsistency was found since the file system was opened
+    pub fn has_errors(&self) -> bool {
+        self.has_errors
+    }
+
+    /// Applies the `ErrorPolicy` after an inconsistency was found and returns the error to report
+    pub(crate) fn corrupted(&mut self) -> FsError {
+        self.has_errors = true;
+        match self.options.errors {
+            ErrorPolicy::Continue => {}
+            ErrorPolicy::RemountReadOnly => self.disk.set_read_only(),
+            ErrorPolicy::Abort => self.disk.shut_down(),
         }
+        FsError::Corrupted
     }
 
-    pub fn create_dir_entry(
-        &mut self,
-        parent_nbr: u32,
-        mut child: Inode,
-        name: String,
-    ) -> Result<u32, FsError> {
-        child.hardlinks = 0;
-        let child_nbr = self.create_inode(&child)?;
-        self.link_to_inode(parent_nbr, child_nbr, name)
+    /// Applies the `ErrorPolicy` like `corrupted`, for metadata that doesn't match its checksum
+    pub(crate) fn checksum_mismatch(&mut self) -> FsError {
+        self.corrupted();
+        FsError::ChecksumMismatch
     }
 
-    pub fn link_to_inode(
-        &mut self,
-        parent_nbr: u32,
-        child_nbr: u32,
-        name: String,
-    ) -> Result<u32, FsError> {
-        let mut node = self.read_inode(child_nbr)?;
-        node.hardlinks += 1;
-        self.write_inode(child_nbr, &node)?;
+    /// Whether the file system can still be read and written. Once the disk reports a failed
+    /// write or sync, the file system is degraded to read-only until it is opened again; every
+    /// change from then on fails with `FsError::ReadOnly`.
+    pub fn state(&self) -> FsState {
+        if self.disk.is_shut_down() {
+            FsState::ShutDown
+        } else if self.disk.is_degraded() {
+            FsState::Degraded
+        } else if self.disk.is_read_only() {
+            FsState::ReadOnly
+        } else {
+            FsState::Writable
+        }
+    }
 
-        let mut node = self.read_inode(parent_nbr)?;
-        node.write_dir_entry(self, &DirEntry::create(child_nbr, name)?, None, parent_nbr)?;
-        Ok(child_nbr)
+    /// Writes every block that is only changed in a write-back cache to the disk, without waiting
+    /// for it to be durable like `sync` does
+    pub fn flush(&mut self) -> Result<(), FsError> {
+        Ok(self.disk.flush()?)
     }
 
-    fn clear_block(&mut self, blk_id: u32) -> Result<(), FsError> {
-        let space = [0; BLOCK_SIZE];
-        self.disk.write_exact(Self::pointer(blk_id)?, &space)?;
-        Ok(())
+    /// Makes every write so far durable. Only needed without the `sync` mount option. Saves the
+    /// search index first, if it changed, see `enable_index`.
+    pub fn sync(&mut self) -> Result<(), FsError> {
+        if !self.disk.is_read_only() {
+            self.update_index(|index, fs| index.save(fs))?;
+        }
+        journal::record_unjournaled(self, [])?;
+        Ok(self.disk.sync()?)
     }
 
-    pub fn free_block(&mut self, block_id: u32) -> Result<(), FsError> {
-        if block_id == 0 {
-            return Err(FsError::InvalidBlock);
+    /// Makes the directory `dir_nbr` durable as it is now, like `fsync` on a directory, without
+    /// writing out anything else a write-back cache holds: the blocks of its entries and its
+    /// inode, the inodes of its entries, the block arrays and inode bitmap recording them as
+    /// used, the superblock and the journal header. Creating, renaming and unlinking entries
+    /// happens in a transaction (see `with_txn`), which only waits for the disk when it is
+    /// mounted with `sync`; without it, this is what makes them durable. The contents of new
+    /// files have to be synced on their own. Also see `DirHandle::sync`.
+    pub fn sync_dir(&mut self, dir_nbr: u32) -> Result<(), FsError> {
+        let dir = self.read_inode(dir_nbr)?;
+        if dir.type_and_permission.get_type() != InodeType::Directory {
+            return Err(FsError::NotADirectory);
         }
-        if self.superblock.earliest_free > block_id {
-            self.superblock.earliest_free = block_id;
-            self.write_superblock()?;
+
+        let mut blocks: BTreeSet<u32> = dir
+            .data_blocks(self)?
+            .into_iter()
+            .map(|(_, block)| block)
+            .collect();
+        blocks.extend(dir.pointer_blocks(self)?);
+        let entries = dir.read_dir_entries(self)?;
+        for inode_nbr in entries.iter().map(|entry| entry.inode).chain([dir_nbr]) {
+            blocks.insert((Addr::inode(inode_nbr)?.get() / BLOCK_SIZE) as u32);
         }
+        let groups: Vec<u32> = blocks
+            .iter()
+            .map(|block| block - block % BLOCKS_PER_BLOCKARRAY)
+            .collect();
+        blocks.extend(groups);
+        blocks.extend(self.inode_bitmap_area());
+        blocks.insert(1);
+        let journal = self.journal_area();
+        if !journal.is_empty() {
+            blocks.insert(journal.start);
+        }
+        Ok(self.disk.sync_blocks(&blocks)?)
+    }
 
-        BlockArrayDescriptor::from_disk(&mut self.disk, block_id / BLOCKS_PER_BLOCKARRAY)
-            .set(block_id % BLOCKS_PER_BLOCKARRAY, BlockArrayEntry::Unused)?;
-        self.clear_block(block_id)?;
+    /// The feature flags of this image. Check `Features::can_write` before modifying an image that
+    /// was written by another implementation.
+    pub fn features(&self) -> Features {
+        self.superblock.features()
+    }
 
-        Ok(())
+    /// The hash function for directory indices, `None` on images that don't record one
+    pub fn dir_hash(&self) -> Option<DirHashAlgorithm> {
+        DirHashAlgorithm::from_u8(self.superblock.dir_hash)
     }
 
-    pub fn allocate_block(&mut self, for_inodes: bool) -> Result<u32, FsError> {
-        let blk = self.superblock.earliest_free;
-        if blk == 0 {
-            return Err(FsError::NoSpace);
-        } else if blk == self.superblock.last_free {
-            self.superblock.last_free = 0;
-        }
+    /// Hashes a name for a directory index with the algorithm and seed of this image
+    pub fn hash_dir_name(&mut self, name: &[u8]) -> Result<u64, FsError> {
+        let Some(algorithm) = self.dir_hash() else {
+            return Err(self.corrupted());
+        };
+        Ok(algorithm.hash(self.superblock.dir_hash_seed, name))
+    }
 
-        self.superblock.earliest_free = 0;
-        BlockArrayDescriptor::from_disk(&mut self.disk, blk / BLOCKS_PER_BLOCKARRAY).set(
-            blk % BLOCKS_PER_BLOCKARRAY,
-            if for_inodes {
-                BlockArrayEntry::InodeBlock
-            } else {
-                BlockArrayEntry::Allocated
-            },
-        )?;
+    /// How block groups are split between metadata, small and large files, `None` if blocks are
+    /// allocated first-fit everywhere
+    pub fn zone_layout(&self) -> Option<ZoneLayout> {
+        (self.superblock.feature_compat & COMPAT_ALLOC_ZONES != 0).then_some(ZoneLayout {
+            metadata_percent: self.superblock.zone_metadata_percent,
+            small_files_percent: self.superblock.zone_small_files_percent,
+        })
+    }
 
-        for i in blk + 1..self.superblock.total_blocks {
-            if BlockArrayDescriptor::from_disk(&mut self.disk, i / BLOCKS_PER_BLOCKARRAY)
-                .get(i % BLOCKS_PER_BLOCKARRAY)?
-                == BlockArrayEntry::Unused
-            {
-                self.superblock.earliest_free = i;
-                if for_inodes {
-                    self.superblock.earliest_inode_space = blk * INODES_PER_BLOCK;
-                }
-                self.write_superblock()?;
-                self.clear_block(blk)?;
-                return Ok(blk);
-            }
+    /// The current UNIX-Time, or the fixed time if one is set. See `MountOptions::clock`.
+    pub fn now(&self) -> u64 {
+        Self::time(self.fixed_time, self.options.clock.as_deref())
+    }
+
+    fn time(fixed_time: Option<u64>, clock: Option<&dyn Clock>) -> u64 {
+        match (fixed_time, clock) {
+            (Some(time), _) => time,
+            (None, Some(clock)) => clock.now(),
+            #[cfg(feature = "std")]
+            (None, None) => SystemClock.now(),
+            // nothing to ask for the time
+            #[cfg(not(feature = "std"))]
+            (None, None) => 0,
         }
+    }
 
-        self.write_superblock()?;
-        Err(FsError::NoSpace)
+    /// Uses `time` for all timestamps from now on instead of the clock, e.g. to continue a
+    /// reproducible import on an image that was opened again. `None` goes back to the clock.
+    pub fn set_fixed_time(&mut self, time: Option<u64>) {
+        self.fixed_time = time;
     }
 
-    pub fn create_inode(&mut self, inode: &Inode) -> Result<u32, FsError> {
-        let addr = (self.get_inode_physical()? / INODE_SIZE) as u32;
-        self.write_inode(addr, inode)?;
-        Ok(addr)
+    /// Takes the timestamps from `clock` from now on, `None` goes back to the system clock. A
+    /// fixed time still takes precedence, see `set_fixed_time`.
+    pub fn set_clock(&mut self, clock: Option<Arc<dyn Clock>>) {
+        self.options.clock = clock;
     }
 
-    pub fn create(num_blocks: u32, fs_name: &str) -> Result<Self, FsError> {
-        let mut disk = Disk::new_virtual(num_blocks);
+    /// Opens the regular file `inode_nbr` as a read-only disk, so an image stored inside of this
+    /// file system can be opened without extracting it first
+    pub fn loopback(&self, inode_nbr: u32) -> Result<Disk, FsError> {
+        Ok(Disk::new(Box::new(LoopbackIO::new(self, inode_nbr)?)))
+    }
 
-        if num_blocks < 3 {
-            return Err(FsError::DiskError(DiskError::NotEnoughSpace));
+    /// A read-only view of this file system that can be moved to another thread. The view starts
+    /// with a copy of the current superblock and sees later writes to the disk, but not changes
+    /// that are still buffered (e.g. by a running `BulkImport` or a write-back cache).
+    pub fn fork_readonly(&self) -> Self {
+        Self {
+            superblock: self.superblock.clone(),
+            disk: self.disk.fork_readonly(),
+            fixed_time: self.fixed_time,
+            // directories change behind the back of the view, so it can't cache their entries
+            options: MountOptions {
+                dcache_entries: 0,
+                dcache_negative_entries: 0,
+                dir_index_dirs: 0,
+                ..self.options.clone()
+            },
+            has_errors: self.has_errors,
+            zone_hints: self.zone_hints,
+            group_free: BTreeMap::new(),
+            dcache: DirCache::default(),
+            redirects: Redirects::default(),
+            paths: InternedPaths::default(),
+            hooks: Hooks::default(),
+            index: None,
+            freed_inodes: VecDeque::new(),
+            quotas: self.quotas.clone(),
+            snapshots: self.snapshots.clone(),
+            data_key: self.data_key.clone(),
         }
+    }
 
-        let superblock = Superblock::new(fs_name, num_blocks)?;
-        disk.write_struct(4096 /* block */, &superblock)?;
+    /// An immutable view of this file system as it is now, that can be moved to another thread.
+    /// Unlike `fork_readonly`, the view never sees later writes: this file system keeps the old
+    /// contents of every block it overwrites in memory until all views from before the write are
+    /// dropped. For serving files while a single writer keeps working, take a new snapshot after
+    /// each change and hand out `fork_readonly`s of it, which see the same state.
+    pub fn snapshot(&mut self) -> Result<Self, FsError> {
+        Ok(Self {
+            superblock: self.superblock.clone(),
+            disk: self.disk.snapshot()?,
+            fixed_time: self.fixed_time,
+            options: self.options.clone(),
+            has_errors: self.has_errors,
+            zone_hints: self.zone_hints,
+            group_free: BTreeMap::new(),
+            dcache: DirCache::new(
+                self.options.dcache_entries,
+                self.options.dcache_negative_entries,
+                self.options.dir_index_dirs,
+            ),
+            redirects: Redirects::default(),
+            paths: InternedPaths::default(),
+            hooks: Hooks::default(),
+            index: None,
+            freed_inodes: VecDeque::new(),
+            quotas: self.quotas.clone(),
+            snapshots: self.snapshots.clone(),
+            data_key: self.data_key.clone(),
+        })
+    }
 
-        for i in 0..num_blocks.div_ceil(BLOCKS_PER_BLOCKARRAY) {
-            println!("writing block array {i}");
-            let mut blk_arr = BlockArrayDescriptor::create(&mut disk, i)?;
-            if i == 0 {
-                blk_arr.set(1, BlockArrayEntry::Allocated)?;
-            }
+    #[deprecated = "writing through the disk directly bypasses the bitmaps and inodes, use read_block/write_block, export_image or dump_block instead"]
+    pub fn get_disk(&mut self) -> &mut Disk {
+        &mut self.disk
+    }
+
+    pub(crate) fn disk(&mut self) -> &mut Disk {
+        &mut self.disk
+    }
+
+    /// Copies the image into `other` with the default `ExportOptions`, see `export_image_with`
+    #[cfg(feature = "std")]
+    pub fn export_image(&mut self, other: &mut dyn IO) -> Result<ExportStats, FsError> {
+        self.export_image_with(other, &ExportOptions::default())
+    }
+
+    /// Copies every block in use to the same place in `other`, reading the block groups on
+    /// several threads and checking the inode checksums on the way. Free blocks are skipped, so
+    /// `other` should be empty (like a new file) for the copy to be an exact one.
+    #[cfg(feature = "std")]
+    pub fn export_image_with(
+        &mut self,
+        other: &mut dyn IO,
+        options: &ExportOptions,
+    ) -> Result<ExportStats, FsError> {
+        export::export(self, other, options)
+    }
+
+    /// Reads any block, including metadata blocks, for debugging purposes
+    pub fn dump_block(&mut self, block_id: u32) -> Result<[u8; BLOCK_SIZE], FsError> {
+        if block_id >= self.superblock.total_blocks {
+            return Err(FsError::InvalidBlock);
         }
+        let mut block = [0; BLOCK_SIZE];
+        self.disk
+            .read_exact(Addr::block(block_id)?.get(), &mut block)?;
+        Ok(block)
+    }
 
-        let mut fs = Self { superblock, disk };
+    /// Opens the inode `inode_nbr`, see `FileHandle`
+    #[cfg(feature = "std")]
+    pub fn open(&mut self, inode_nbr: u32) -> Result<FileHandle<'_>, FsError> {
+        FileHandle::new(self, inode_nbr)
+    }
 
-        let inode = Inode::create(
-            PermissionsAndType::new(
-                InodeType::Directory,
-                &[
-                    Permission::group_all(),
-                    Permission::user_all(),
-                    Permission::OtherRead,
-                    Permission::OtherExecute,
-                ],
-            ),
-            0,
-            0,
-            SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .expect("Time went backwards ftw")
-                .as_secs(),
-            1,
-            0,
-        );
+    /// Opens the directory `dir_nbr`, see `DirHandle`
+    pub fn open_dir(&mut self, dir_nbr: u32) -> Result<DirHandle<'_>, FsError> {
+        DirHandle::new(self, dir_nbr)
+    }
 
-        fs.superblock.root_inode = fs.create_inode(&inode)?;
-        fs.write_superblock()?;
+    /// Starts a bulk import, see `BulkImport`
+    pub fn bulk_import(&mut self) -> BulkImport<'_> {
+        BulkImport::new(self)
+    }
 
-        Ok(fs)
+    /// Lists every path reachable from the root directory with its size, mode, owner and a
+    /// SHA-256 of its contents, see `Manifest`
+    pub fn export_manifest(&mut self) -> Result<Manifest, FsError> {
+        Manifest::build(self)
+    }
+
+    /// Re-checks this image against a manifest from `export_manifest`, returning all differences
+    pub fn verify_manifest(
+        &mut self,
+        manifest: &Manifest,
+    ) -> Result<Vec<ManifestMismatch>, FsError> {
+        manifest.verify(self)
+    }
+
+    /// Signs the image with the Ed25519 private key `secret`, so consumers can check it with
+    /// `verify_seal` (or `MountOptions::seal_key`) before trusting its contents. The signature
+    /// covers the superblock and every used block, so any later change breaks the seal until
+    /// the image is sealed again. See `seal::public_key` for the key to hand out.
+    pub fn seal(&mut self, secret: &[u8; 32]) -> Result<(), FsError> {
+        seal::seal(self, secret)
+    }
+
+    /// Checks that the image is sealed with the private key of `public_key` and wasn't changed
+    /// since, `FsError::BadSeal` otherwise
+    pub fn verify_seal(&mut self, public_key: &[u8; 32]) -> Result<(), FsError> {
+        seal::verify(self, public_key)
+    }
+
+    /// Builds a hash tree over every block of the image in a run of free blocks, like dm-verity,
+    /// and returns its root hash. Read-only mounts then check every block they read against the
+    /// tree, and fail with `DiskError::VerityMismatch` for blocks that were changed. Any write
+    /// makes the tree stale, so it is built once the image is final and again after every
+    /// change. Pass the root hash to `MountOptions::verity_root` from a trusted source (e.g. a
+    /// signed boot configuration) to detect tampering, not only corruption.
+    pub fn build_verity(&mut self) -> Result<[u8; 32], FsError> {
+        verity::build(self)
+    }
+
+    /// Encrypts every block of the image but the superblock and the boot area with a new random
+    /// key, sealed with `cipher` so changed blocks fail to read with `DiskError::DecryptionFailed`.
+    /// The key is stored in the superblock, encrypted with a key derived from `passphrase`, which
+    /// `MountOptions::passphrase` has to give from then on. Needs the `encryption` cargo feature.
+    /// Fails with `FsError::InvalidValue` on images with snapshots, rolling back to one would
+    /// drop the encryption.
+    pub fn enable_encryption(
+        &mut self,
+        passphrase: impl Into<Passphrase>,
+        cipher: Cipher,
+    ) -> Result<(), FsError> {
+        if self.disk.is_read_only() {
+            return Err(FsError::ReadOnly);
+        }
+        if self.superblock.feature_incompat & INCOMPAT_ENCRYPTION != 0 {
+            return Err(FsError::AlreadyExists);
+        }
+        if !self.list_snapshots().is_empty() {
+            return Err(FsError::InvalidValue);
+        }
+        self.data_key = Some(crypt::enable(self, &passphrase.into(), cipher)?);
+        Ok(())
+    }
+
+    /// Whether the image is encrypted, see `enable_encryption`
+    pub fn is_encrypted(&self) -> bool {
+        self.data_key().is_some()
+    }
+
+    pub(crate) fn data_key(&self) -> Option<&DataKey> {
+        self.data_key.as_ref()
+    }
+
+    /// Encrypts the key of an encrypted image with `new` instead of `old`. The blocks stay as
+    /// they are.
+    pub fn change_passphrase(
+        &mut self,
+        old: impl Into<Passphrase>,
+        new: impl Into<Passphrase>,
+    ) -> Result<(), FsError> {
+        if self.disk.is_read_only() {
+            return Err(FsError::ReadOnly);
+        }
+        if !self.is_encrypted() {
+            return Err(FsError::InvalidValue);
+        }
+        crypt::change_passphrase(self, &old.into(), &new.into())
+    }
+
+    /// The root hash recorded in the hash tree of the image, `None` if it has no tree. It isn't
+    /// checked against the tree.
+    pub fn verity_root(&mut self) -> Result<Option<[u8; 32]>, FsError> {
+        verity::recorded_root(self)
+    }
+
+    /// Reads every block of the image, so a read-only mount of an image with a hash tree checks
+    /// all of them at once instead of when they are first read
+    pub fn check_verity(&mut self) -> Result<(), FsError> {
+        let mut block = vec![0; BLOCK_SIZE];
+        for block_id in 0..self.superblock.total_blocks {
+            self.disk
+                .read_exact(Addr::block(block_id)?.get(), &mut block)?;
+        }
+        Ok(())
+    }
+
+    /// The public key the image was sealed with, if it was. The seal isn't checked.
+    pub fn seal_key(&self) -> Option<[u8; 32]> {
+        (self.superblock.feature_compat & COMPAT_SEAL != 0).then_some(self.superblock.seal_key)
+    }
+
+    /// Checks the superblock, free space, inodes and a sample of block pointers, see
+    /// `HealthReport`
+    pub fn health(&mut self) -> Result<HealthReport, FsError> {
+        HealthReport::build(self)
+    }
+
+    /// Checks the whole image for inconsistencies, see `FsckReport`. With `repair`, the block
+    /// arrays are rebuilt from the blocks the inodes actually use and directory entries linking to
+    /// unused inodes are removed. Repairing a write-once image fails with `FsError::WriteOnce`.
+    pub fn fsck(&mut self, repair: bool) -> Result<FsckReport, FsError> {
+        if repair && self.is_worm() {
+            return Err(FsError::WriteOnce);
+        }
+        let report = FsckReport::build(self, repair);
+        if repair {
+            self.quotas.invalidate();
+        }
+        report
+    }
+
+    /// Continues the online defragmentation `defrag`, doing at most `max_blocks` blocks of work:
+    /// every inode block scanned and every data block moved count as one. See `Defrag`.
+    /// Write-once images can't be defragmented, moving a block rewrites the pointers to it.
+    pub fn defrag_step(
+        &mut self,
+        defrag: &mut Defrag,
+        max_blocks: u32,
+    ) -> Result<DefragProgress, FsError> {
+        if self.is_worm() {
+            return Err(FsError::WriteOnce);
+        }
+        defrag.step(self, max_blocks)
+    }
+
+    /// The superblock and all block array descriptors
+    pub(crate) fn metadata_block_ids(&self) -> BTreeSet<u32> {
+        let mut blocks: BTreeSet<u32> =
+            (0..self.superblock.total_blocks.div_ceil(BLOCKS_PER_BLOCKARRAY))
+                .map(|i| i * BLOCKS_PER_BLOCKARRAY)
+                .collect();
+        blocks.insert(1);
+        blocks
+    }
+
+    /// Every inode with at least one hard link
+    pub(crate) fn inodes_in_use(&mut self) -> Result<BTreeSet<u32>, FsError> {
+        let mut in_use = BTreeSet::new();
+        for inode_nbr in self.inode_slots()? {
+            if self.read_inode_unchecked(inode_nbr)?.hardlinks != 0 {
+                in_use.insert(inode_nbr);
+            }
+        }
+        Ok(in_use)
+    }
+
+    /// Every inode number in an inode block, used or not
+    pub(crate) fn inode_slots(&mut self) -> Result<Vec<u32>, FsError> {
+        let mut slots = Vec::new();
+        for block_id in 0..self.superblock.total_blocks {
+            if self.block_type(block_id)? == BlockArrayEntry::InodeBlock {
+                slots.extend(block_id * INODES_PER_BLOCK..(block_id + 1) * INODES_PER_BLOCK);
+            }
+        }
+        Ok(slots)
+    }
+
+    /// Scans the inode blocks for unlinked files that still point at their blocks, as unlinking
+    /// leaves the pointers in place until the inode is reused. The data is intact as long as none
+    /// of the blocks were reused either, see `DeletedInode::recoverable` and `relink_deleted`.
+    pub fn recover_deleted(&mut self) -> Result<Vec<DeletedInode>, FsError> {
+        recover::scan(self)
+    }
+
+    /// Links the unlinked file `inode_nbr` found by `recover_deleted` into `/lost+found` as
+    /// `#<inode>`, allocating its blocks again. Fails with `FsError::InvalidBlock` if it isn't
+    /// recoverable anymore.
+    pub fn relink_deleted(&mut self, inode_nbr: u32) -> Result<(), FsError> {
+        // the blocks are claimed before the inode is linked again, it's simpler to count anew
+        self.quotas.invalidate();
+        recover::relink(self, inode_nbr)
+    }
+
+    /// Runs `op` and reports how many blocks it read and wrote. Telling directory and pointer
+    /// blocks from file contents walks every inode before and after `op`, so this is meant for
+    /// diagnostics rather than hot paths.
+    pub fn measure_io<R>(&mut self, op: impl FnOnce(&mut Self) -> R) -> (R, IoStats) {
+        let mut structure = self.structure_blocks().unwrap_or_default();
+        self.disk.start_accounting();
+        let result = op(self);
+        let accounting = self.disk.stop_accounting().unwrap_or_default();
+        // blocks the operation allocated or freed count as well
+        structure.extend(self.structure_blocks().unwrap_or_default());
+
+        let mut stats = IoStats::default();
+        for block in accounting.blocks_read {
+            if self.is_metadata_block(block, &structure) {
+                stats.metadata_reads += 1;
+            } else {
+                stats.data_reads += 1;
+            }
+        }
+        for block in accounting.blocks_written {
+            if self.is_metadata_block(block, &structure) {
+                stats.metadata_writes += 1;
+            } else {
+                stats.data_writes += 1;
+            }
+        }
+
+        (result, stats)
+    }
+
+    /// Whether `block_id` holds file system structures rather than file contents or the boot
+    /// area. `structure` are the directory, pointer and xattr blocks from `structure_blocks`.
+    fn is_metadata_block(&mut self, block_id: u32, structure: &BTreeSet<u32>) -> bool {
+        block_id == 1 /* superblock */
+            || self.journal_area().contains(&block_id)
+            || self.inode_bitmap_area().contains(&block_id)
+            || self.verity_area().contains(&block_id)
+            || self.crypt_area().contains(&block_id)
+            || self.is_inode_table_block(block_id)
+            || structure.contains(&block_id)
+            || !matches!(
+                self.block_type(block_id),
+                Ok(BlockArrayEntry::Allocated | BlockArrayEntry::Unused)
+            )
+    }
+
+    /// The blocks of every inode in use that hold directory entries, indirect pointers or
+    /// extended attributes
+    fn structure_blocks(&mut self) -> Result<BTreeSet<u32>, FsError> {
+        let mut blocks = BTreeSet::new();
+        for inode_nbr in self.inodes_in_use()? {
+            let inode = self.read_inode_unchecked(inode_nbr)?;
+            blocks.extend(inode.pointer_blocks(self)?);
+            if inode.xattr_block != 0 {
+                blocks.insert(inode.xattr_block);
+            }
+            if inode.type_and_permission.get_type() == InodeType::Directory {
+                blocks.extend(inode.data_blocks(self)?.into_iter().map(|(_, block)| block));
+            }
+        }
+        Ok(blocks)
+    }
+
+    pub fn pointer(block_id: u32) -> Result<usize, FsError> {
+        if block_id.is_multiple_of(BLOCKS_PER_BLOCKARRAY) {
+            Err(FsError::InvalidBlock)
+        } else {
+            Ok(Addr::block(block_id)?.get())
+        }
+    }
+
+    pub fn block_type(&mut self, block_id: u32) -> Result<BlockArrayEntry, FsError> {
+        if block_id >= self.superblock.total_blocks {
+            return Err(FsError::InvalidBlock);
+        }
+        BlockArrayDescriptor::from_disk(&mut self.disk, block_id / BLOCKS_PER_BLOCKARRAY)
+            .get(block_id % BLOCKS_PER_BLOCKARRAY)
+    }
+
+    /// Makes sure `block_id` is an allocated data block, as opposed to a free block or one holding
+    /// file system metadata (block array descriptors, the superblock or inodes)
+    fn check_data_block(&mut self, block_id: u32) -> Result<(), FsError> {
+        if block_id == 1 /* superblock */
+            || self.is_reserved(block_id)
+            || self.block_type(block_id)? != BlockArrayEntry::Allocated
+        {
+            Err(FsError::InvalidBlock)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn read_block(&mut self, block_id: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<(), FsError> {
+        self.check_data_block(block_id)?;
+        self.disk.read_exact(Self::pointer(block_id)?, buf)?;
+        Ok(())
+    }
+
+    /// Overwrites the data block `block_id`. A block shared with a snapshot (see
+    /// `create_snapshot`) fails with `FsError::InvalidBlock`, only its file can move it away. On
+    /// a write-once image every allocated block is committed, so this fails with
+    /// `FsError::WriteOnce`.
+    pub fn write_block(&mut self, block_id: u32, buf: &[u8; BLOCK_SIZE]) -> Result<(), FsError> {
+        self.check_data_block(block_id)?;
+        if self.is_worm() {
+            return Err(FsError::WriteOnce);
+        }
+        if self.snapshots.is_frozen(block_id) {
+            return Err(FsError::InvalidBlock);
+        }
+        // the block may hold the entries of any directory
+        self.dcache.clear();
+        self.paths.clear();
+        self.disk.write_exact(Self::pointer(block_id)?, buf)?;
+        Ok(())
+    }
+
+    /// Reads the inode `inode_nbr`. On images from before the `file_size` feature, the size of
+    /// regular files is derived from their blocks, so `Inode::size` is always valid. With the
+    /// `metadata_csum` feature, an inode that doesn't match its checksum fails with
+    /// `FsError::ChecksumMismatch`.
+    pub fn read_inode(&mut self, inode_nbr: u32) -> Result<Inode, FsError> {
+        let inode = self.read_inode_unchecked(inode_nbr)?;
+        if self.superblock.has_checksums() && !inode.verify_checksum(inode_nbr) {
+            return Err(self.checksum_mismatch());
+        }
+        Ok(inode)
+    }
+
+    /// `read_inode` without verifying the checksum, for `fsck` and scans that have to get past
+    /// damaged inodes
+    pub(crate) fn read_inode_unchecked(&mut self, inode_nbr: u32) -> Result<Inode, FsError> {
+        self.check_inode_nbr(inode_nbr)?;
+        let mut inode: Inode = self.disk.read_struct(Addr::inode(inode_nbr)?.get())?;
+        if self.superblock.feature_ro_compat & RO_COMPAT_FILE_SIZE == 0
+            && inode.hardlinks != 0
+            && matches!(
+                inode.type_and_permission.get_type(),
+                InodeType::File | InodeType::Symlink
+            )
+        {
+            inode.size = inode.legacy_size(self)?;
+        }
+        Ok(inode)
+    }
+
+    /// Writes `inode` and bumps its version past both the on-disk one and the one in `inode`, so
+    /// the version keeps increasing even if the caller holds an outdated copy
+    pub fn write_inode(&mut self, inode_nbr: u32, inode: &Inode) -> Result<(), FsError> {
+        self.check_inode_nbr(inode_nbr)?;
+        // every change to the entries of a directory ends with writing its inode
+        self.dcache.forget_dir(inode_nbr);
+        self.paths.forget_dir(inode_nbr);
+        let addr = Addr::inode(inode_nbr)?.get();
+        let current: Inode = self.disk.read_struct(addr)?;
+
+        // a free slot (no hard links) can be reused for an inode of any type
+        if current.hardlinks != 0
+            && current.type_and_permission.get_type() != inode.type_and_permission.get_type()
+        {
+            return Err(FsError::TypeChange);
+        }
+        // dropping the last link would let the inode and its data be reused
+        if self.is_worm() && inode.hardlinks < current.hardlinks {
+            return Err(FsError::WriteOnce);
+        }
+
+        let mut inode = *inode;
+        inode.version = current.version.max(inode.version).wrapping_add(1);
+        if !self.superblock.has_wide_times() {
+            inode.narrow_times();
+        }
+        if self.superblock.has_checksums() {
+            inode.update_checksum(inode_nbr);
+        }
+        self.disk.write_struct(addr, &inode)?;
+        if self.has_inode_bitmap() && (current.hardlinks == 0) != (inode.hardlinks == 0) {
+            inode_bitmap::set_free(self, inode_nbr, inode.hardlinks == 0)?;
+        }
+        Ok(())
+    }
+
+    /// The type, permissions, owner, size, timestamps and link count of the inode `inode_nbr`
+    pub fn stat(&mut self, inode_nbr: u32) -> Result<Stat, FsError> {
+        let inode = self.read_inode(inode_nbr)?;
+        Ok(Stat {
+            inode: inode_nbr,
+            file_type: inode.type_and_permission.get_type(),
+            permissions: inode.type_and_permission.get_raw() & PERMISSION_MASK,
+            uid: inode.uid,
+            gid: inode.gid,
+            size: inode.file_size(self)? as u64,
+            blocks: inode.allocated_blocks(self)?,
+            hardlinks: inode.hardlinks,
+            created: inode.created(),
+            modified: inode.modified(),
+            accessed: inode.accessed(),
+            version: inode.version,
+            generation: inode.generation,
+        })
+    }
+
+    /// Changes the permissions, owner or modification time of `inode_nbr` (like chmod, chown and
+    /// utimes) and returns the new `Stat`. On a write-once image, anything that would actually
+    /// change fails with `FsError::WriteOnce`.
+    pub fn set_attr(&mut self, inode_nbr: u32, attr: &SetAttr) -> Result<Stat, FsError> {
+        let current = self.read_inode(inode_nbr)?;
+        let mut inode = current;
+        let owner = (inode.uid, inode.gid);
+        if let Some(permissions) = attr.permissions {
+            inode.type_and_permission.set_mode(permissions);
+        }
+        if let Some(uid) = attr.uid {
+            inode.uid = uid;
+        }
+        if let Some(gid) = attr.gid {
+            inode.gid = gid;
+        }
+        if let Some(modified) = attr.modified {
+            inode.set_modified(modified);
+        }
+        if let Some(accessed) = attr.accessed {
+            inode.set_accessed(accessed);
+        }
+        if self.is_worm()
+            && (inode.type_and_permission.get_raw() != current.type_and_permission.get_raw()
+                || (inode.uid, inode.gid) != owner
+                || inode.modified() != current.modified()
+                || inode.accessed() != current.accessed())
+        {
+            return Err(FsError::WriteOnce);
+        }
+        let new_owner = (inode.uid, inode.gid);
+        if new_owner != owner && self.superblock.has_quotas() {
+            // the usage moves along, even past the limits of the new owner
+            self.count_quota_usage()?;
+            let blocks = inode.allocated_blocks(self)? as i32;
+            self.quotas.charge(owner, -blocks, -1);
+            self.quotas.charge(new_owner, blocks, 1);
+        }
+        self.write_inode(inode_nbr, &inode)?;
+        self.stat(inode_nbr)
+    }
+
+    /// The inode numbers of every inode in the inode table, empty if there is no inode table.
+    /// With a fixed inode table, these are all inodes that can ever exist.
+    pub fn inode_table_range(&self) -> Range<u32> {
+        let start = self.superblock.inode_table_start * INODES_PER_BLOCK;
+        start..start + self.superblock.inode_table_blocks * INODES_PER_BLOCK
+    }
+
+    fn check_inode_nbr(&self, inode_nbr: u32) -> Result<(), FsError> {
+        if self.superblock.fixed_inode_table != 0 && !self.inode_table_range().contains(&inode_nbr)
+        {
+            Err(FsError::NoEntry)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn is_inode_table_block(&self, block_id: u32) -> bool {
+        let start = self.superblock.inode_table_start;
+        start != 0 && block_id >= start && block_id < start + self.superblock.inode_table_blocks
+    }
+
+    /// Looks for an unused inode in the inode table, starting at the block of the earliest inode
+    /// space hint if it points into the table
+    fn find_free_table_inode(&mut self) -> Result<Option<Addr>, FsError> {
+        let hint = self.superblock.earliest_inode_space / INODES_PER_BLOCK;
+        let first = if self.is_inode_table_block(hint) {
+            hint
+        } else {
+            self.superblock.inode_table_start
+        };
+
+        for block in first..self.superblock.inode_table_start + self.superblock.inode_table_blocks {
+            let inodes = self
+                .disk
+                .read_struct::<[Inode; INODES_PER_BLOCK as usize]>(Self::pointer(block)?)?;
+            let free = (block * INODES_PER_BLOCK..)
+                .zip(inodes)
+                .find(|(inode_nbr, inode)| inode.hardlinks == 0 && self.can_reuse(*inode_nbr));
+            if let Some((inode_nbr, _)) = free {
+                if self.superblock.earliest_inode_space != block * INODES_PER_BLOCK {
+                    self.superblock.earliest_inode_space = block * INODES_PER_BLOCK;
+                    self.write_superblock()?;
+                }
+                return Ok(Some(Addr::inode(inode_nbr)?));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Whether free inodes are tracked in a bitmap, see `crate::inode_bitmap`. Images formatted
+    /// before it, or too large for the bitmap to fit into the first block group, scan the inode
+    /// blocks instead.
+    pub fn has_inode_bitmap(&self) -> bool {
+        self.superblock.feature_ro_compat & RO_COMPAT_INODE_BITMAP != 0
+    }
+
+    /// Whether inode numbers are never reused, see `MkfsOptions::sequential_inodes`
+    pub fn has_sequential_inodes(&self) -> bool {
+        self.superblock.feature_ro_compat & RO_COMPAT_SEQUENTIAL_INODES != 0
+    }
+
+    /// The first free inode number at or after the next inode counter, wrapping around once.
+    /// Free blocks on the way become inode blocks, unless inodes only live in the inode table.
+    fn find_sequential_inode(&mut self) -> Result<Addr, FsError> {
+        let fixed = self.superblock.fixed_inode_table != 0;
+        let range = if fixed {
+            self.inode_table_range()
+        } else {
+            0..self
+                .superblock
+                .total_blocks
+                .saturating_mul(INODES_PER_BLOCK)
+        };
+        let mut inode_nbr = self.superblock.next_inode;
+        let mut checked = 0;
+        while checked < range.len() {
+            if !range.contains(&inode_nbr) {
+                inode_nbr = range.start;
+            }
+            let block = inode_nbr / INODES_PER_BLOCK;
+            let free = match self.block_type(block)? {
+                BlockArrayEntry::InodeBlock => {
+                    self.disk
+                        .read_struct::<Inode>(Addr::inode(inode_nbr)?.get())?
+                        .hardlinks
+                        == 0
+                }
+                BlockArrayEntry::Unused if !fixed => {
+                    self.allocate_block_id(block)?;
+                    self.mark_block(block, BlockArrayEntry::InodeBlock)?;
+                    self.clear_block(block)?;
+                    true
+                }
+                _ => {
+                    // none of the inode numbers in this block can be used
+                    let skipped = INODES_PER_BLOCK - inode_nbr % INODES_PER_BLOCK;
+                    inode_nbr += skipped;
+                    checked += skipped as usize;
+                    continue;
+                }
+            };
+            if free {
+                self.superblock.next_inode = inode_nbr + 1;
+                self.write_superblock()?;
+                return Addr::inode(inode_nbr);
+            }
+            inode_nbr += 1;
+            checked += 1;
+        }
+
+        Err(FsError::NoSpace)
+    }
+
+    /// The first free inode in the bitmap, preferring the inode table. A new inode block is only
+    /// allocated if there is no free inode left anywhere.
+    fn find_bitmap_inode(&mut self) -> Result<Addr, FsError> {
+        let usable = |fs: &FileSystem, inode_nbr: u32| fs.can_reuse(inode_nbr);
+        let table = self.inode_table_range();
+        let all = 0..self
+            .superblock
+            .total_blocks
+            .saturating_mul(INODES_PER_BLOCK);
+        let mut free = inode_bitmap::find_free(self, table, usable)?;
+        if free.is_none() && self.superblock.fixed_inode_table == 0 {
+            free = inode_bitmap::find_free(self, all, usable)?;
+        }
+        if let Some(inode_nbr) = free {
+            return Addr::inode(inode_nbr);
+        }
+        if self.superblock.fixed_inode_table != 0 {
+            return Err(FsError::NoSpace);
+        }
+
+        let first = self.allocate_block(true)? * INODES_PER_BLOCK;
+        let inode_nbr = inode_bitmap::find_free(self, first..first + INODES_PER_BLOCK, usable)?;
+        Addr::inode(inode_nbr.unwrap_or(first))
+    }
+
+    fn get_inode_physical(&mut self) -> Result<Addr, FsError> {
+        if self.has_sequential_inodes() {
+            return self.find_sequential_inode();
+        }
+        if self.has_inode_bitmap() {
+            return self.find_bitmap_inode();
+        }
+        if self.superblock.inode_table_blocks != 0 {
+            if let Some(addr) = self.find_free_table_inode()? {
+                return Ok(addr);
+            }
+            if self.superblock.fixed_inode_table != 0 {
+                return Err(FsError::NoSpace);
+            }
+        }
+
+        // if self.superblock.earliest_inode_space == 0 {
+        //     self.superblock.earliest_inode_space = self.allocate_block(true)?;
+        // }
+        let first_inode = self.superblock.earliest_inode_space;
+
+        if first_inode != 0 {
+            for i in 0..INODES_PER_BLOCK {
+                let inode_addr = Addr::inode(first_inode + i)?;
+                let inode = self.disk.read_struct::<Inode>(inode_addr.get())?;
+                if inode.hardlinks == 0 && self.can_reuse(first_inode + i) {
+                    return Ok(inode_addr);
+                }
+            }
+        }
+        let first = self.allocate_block(true)? * INODES_PER_BLOCK;
+        let inode_nbr = (first..first + INODES_PER_BLOCK)
+            .find(|inode_nbr| self.can_reuse(*inode_nbr))
+            .unwrap_or(first);
+        Addr::inode(inode_nbr)
+    }
+
+    pub fn write_superblock(&mut self) -> Result<(), FsError> {
+        self.superblock.update_checksum();
+        match self
+            .disk
+            .write_struct(Addr::block(1)?.get(), &self.superblock)
+        {
+            Err(..) => Err(FsError::FailSuperblockWrite),
+            Ok(..) => Ok(()),
+        }
+    }
+
+    /// Replaces the contents of the file `inode_nbr` with everything `reader` returns, without
+    /// buffering it all in memory. Returns the new length of the file.
+    #[cfg(feature = "std")]
+    pub fn write_file_from_reader(
+        &mut self,
+        inode_nbr: u32,
+        reader: &mut dyn Read,
+    ) -> Result<usize, FsError> {
+        let mut inode = self.read_inode(inode_nbr)?;
+        inode.write_from_reader(reader, self, inode_nbr)
+    }
+
+    /// Writes `buf` at byte `offset` of the file `inode_nbr`, leaving the rest of its contents
+    /// intact. Writing past the end extends the file with zeroes. See `Inode::write_at`. Runs the
+    /// `on_write` hooks afterwards, see `add_hook`.
+    pub fn write_at(
+        &mut self,
+        inode_nbr: u32,
+        offset: usize,
+        buf: &[u8],
+    ) -> Result<usize, FsError> {
+        let written = self.with_txn(|fs| {
+            let mut inode = fs.read_inode(inode_nbr)?;
+            inode.write_at(offset, buf, fs, inode_nbr)
+        })?;
+        if !self.hooks.is_empty() {
+            Hooks::run(self, |hook, fs| {
+                hook.on_write(fs, inode_nbr, offset as u64, &buf[..written])
+            })?;
+        }
+        Ok(written)
+    }
+
+    /// Tells the hooks that a writer of the file `inode_nbr` is done with it, see
+    /// `FileHook::on_close`. `FileHandle` and `Sfs::write` do this themselves, code writing with
+    /// `write_at` should call it once the file is complete.
+    pub fn close(&mut self, inode_nbr: u32) -> Result<(), FsError> {
+        self.update_index(|index, fs| index.contents_changed(fs, inode_nbr))?;
+        if self.hooks.is_empty() {
+            return Ok(());
+        }
+        Hooks::run(self, |hook, fs| hook.on_close(fs, inode_nbr))
+    }
+
+    /// Registers `hook` to see every write to a file and every `close`, until it is removed
+    /// with `remove_hook`. Hooks aren't stored in the image, and forks of the file system don't
+    /// have them.
+    pub fn add_hook(&mut self, hook: Box<dyn FileHook>) -> HookId {
+        self.hooks.add(hook)
+    }
+
+    /// Unregisters the hook `id`, returning it. Hooks can't remove themselves while they run.
+    pub fn remove_hook(&mut self, id: HookId) -> Option<Box<dyn FileHook>> {
+        self.hooks.remove(id)
+    }
+
+    pub(crate) fn hooks_mut(&mut self) -> &mut Hooks {
+        &mut self.hooks
+    }
+
+    /// Sets the length of the file `inode_nbr` to `len` bytes, see `Inode::truncate`
+    pub fn truncate(&mut self, inode_nbr: u32, len: u64) -> Result<(), FsError> {
+        self.with_txn(|fs| {
+            let mut inode = fs.read_inode(inode_nbr)?;
+            inode.truncate(len, fs, inode_nbr)
+        })
+    }
+
+    /// Deallocates `len` bytes at `offset` of the file `inode_nbr`, which read as zeroes afterwards.
+    /// See `Inode::punch_hole`.
+    pub fn punch_hole(&mut self, inode_nbr: u32, offset: usize, len: usize) -> Result<(), FsError> {
+        let mut inode = self.read_inode(inode_nbr)?;
+        inode.punch_hole(offset, len, self, inode_nbr)
+    }
+
+    /// Updates the access time of the file `inode_nbr` after its contents were read, as far as
+    /// `MountOptions::atime` asks for it. The reads of the file system do this themselves, code
+    /// reading with `Inode::read` should call it afterwards. Nothing happens on read-only disks.
+    pub fn mark_accessed(&mut self, inode_nbr: u32) -> Result<(), FsError> {
+        if self.options.atime == AtimeMode::NoAtime || self.disk.is_read_only() {
+            return Ok(());
+        }
+        let mut inode = self.read_inode(inode_nbr)?;
+        let now = Timestamp::from_secs(self.now());
+        let due = match self.options.atime {
+            AtimeMode::Strict => true,
+            AtimeMode::Relatime => {
+                inode.accessed() <= inode.modified()
+                    || now.secs.saturating_sub(inode.access_time) >= RELATIME_INTERVAL
+            }
+            AtimeMode::NoAtime => false,
+        };
+        if !due || inode.accessed() == now {
+            return Ok(());
+        }
+        inode.set_accessed(now);
+        // through the journal, so reading a file doesn't hide its earlier versions
+        self.with_txn(|fs| fs.write_inode(inode_nbr, &inode))
+    }
+
+    /// Writes the contents of the file `inode_nbr` to `writer` without reading it into memory
+    /// first. Returns the number of bytes written.
+    #[cfg(feature = "std")]
+    pub fn read_file_to_writer(
+        &mut self,
+        inode_nbr: u32,
+        writer: &mut dyn Write,
+    ) -> Result<usize, FsError> {
+        let read = self
+            .read_inode(inode_nbr)?
+            .read_to_writer(writer, self, None)?;
+        self.mark_accessed(inode_nbr)?;
+        Ok(read)
+    }
+
+    /// Same as `read_file_to_writer`, but writes at most `bytes_per_second` bytes per second
+    #[cfg(feature = "std")]
+    pub fn read_file_to_writer_throttled(
+        &mut self,
+        inode_nbr: u32,
+        writer: &mut dyn Write,
+        bytes_per_second: u64,
+    ) -> Result<usize, FsError> {
+        let read =
+            self.read_inode(inode_nbr)?
+                .read_to_writer(writer, self, Some(bytes_per_second))?;
+        self.mark_accessed(inode_nbr)?;
+        Ok(read)
+    }
+
+    /// The inode number of the entry `name` in the directory `dir_nbr`. On images that normalize
+    /// names, any entry whose name normalizes to the same as `name` matches.
+    pub fn lookup(&mut self, dir_nbr: u32, name: &str) -> Result<u32, FsError> {
+        let name = &*self.normalize_name(name);
+        match self.dcache.get(dir_nbr, name.as_bytes()) {
+            Some(Some(inode)) => return Ok(inode),
+            Some(None) => return Err(FsError::NoEntry),
+            None => {}
+        }
+        let entry = match self.find_entry(dir_nbr, name.as_bytes())? {
+            None if self.normalizes_names() => self.find_unnormalized(dir_nbr, name)?,
+            entry => entry,
+        };
+        match entry {
+            Some(entry) => {
+                self.dcache.insert(dir_nbr, name.as_bytes(), entry.inode);
+                Ok(entry.inode)
+            }
+            None => {
+                self.dcache.insert_missing(dir_nbr, name.as_bytes());
+                Err(FsError::NoEntry)
+            }
+        }
+    }
+
+    /// Finds the entry `name` of the directory `dir_nbr`, through its cached hash index if it is
+    /// large enough to have one, see `MountOptions::dir_index_dirs`
+    fn find_entry(&mut self, dir_nbr: u32, name: &[u8]) -> Result<Option<DirEntry>, FsError> {
+        let dir = self.read_inode(dir_nbr)?;
+        if !self.dcache.has_indices()
+            || dir.type_and_permission.get_type() != InodeType::Directory
+            || dir.flags & INODE_FLAG_SORTED_DIR != 0
+        {
+            return dir.find_dir_entry(self, name);
+        }
+
+        let hash = self.index_hash(name);
+        let blocks = match self.dcache.index_blocks(dir_nbr, hash) {
+            Some(blocks) => blocks,
+            None => {
+                let block_count = dir.block_map(self)?.len() as u32;
+                if block_count < 2 {
+                    return dir.find_dir_entry(self, name);
+                }
+                let mut index = DirIndex::new();
+                for blk_id in 0..block_count {
+                    for entry in dir.read_dir_block(self, blk_id)? {
+                        let blocks: &mut Vec<u32> = index
+                            .entry(self.index_hash(entry.name_bytes()))
+                            .or_default();
+                        if blocks.last() != Some(&blk_id) {
+                            blocks.push(blk_id);
+                        }
+                    }
+                }
+                let blocks = index.get(&hash).cloned().unwrap_or_default();
+                self.dcache.insert_index(dir_nbr, index);
+                blocks
+            }
+        };
+
+        for blk_id in blocks {
+            let entry = dir
+                .read_dir_block(self, blk_id)?
+                .into_iter()
+                .find(|entry| entry.name_bytes() == name);
+            if entry.is_some() {
+                return Ok(entry);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Whether entry names are stored normalized, see `MkfsOptions::normalize_names`
+    pub fn normalizes_names(&self) -> bool {
+        self.superblock.feature_ro_compat & RO_COMPAT_NFC_NAMES != 0
+    }
+
+    /// Normalizes the names of new entries and lookups from now on, see
+    /// `MkfsOptions::normalize_names`. Existing entries keep their names, lookups still find them
+    /// by their normalized form. Fails with `FsError::UnsupportedFeatures` without the `unicode`
+    /// feature.
+    pub fn enable_name_normalization(&mut self) -> Result<(), FsError> {
+        if cfg!(not(feature = "unicode")) {
+            return Err(FsError::UnsupportedFeatures);
+        }
+        if !self.normalizes_names() {
+            self.superblock.feature_ro_compat |= RO_COMPAT_NFC_NAMES;
+            self.write_superblock()?;
+            // names that weren't found before can match in their normalized form now
+            self.dcache.clear();
+            self.paths.clear();
+        }
+        Ok(())
+    }
+
+    /// `name` as the image stores new entries
+    fn normalize_name<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        if self.normalizes_names() {
+            normalize::nfc(name)
+        } else {
+            Cow::Borrowed(name)
+        }
+    }
+
+    /// The entry of `dir_nbr` whose name normalizes to `name`, for entries written before the
+    /// image normalized names. Their names aren't ASCII, which always is normalized already.
+    fn find_unnormalized(&mut self, dir_nbr: u32, name: &str) -> Result<Option<DirEntry>, FsError> {
+        let dir = self.read_inode(dir_nbr)?;
+        if dir.type_and_permission.get_type() != InodeType::Directory {
+            return Ok(None);
+        }
+        Ok(dir.read_dir_entries(self)?.into_iter().find(|entry| {
+            !entry.name_bytes().is_ascii() && normalize::nfc(&entry.get_name()) == name
+        }))
+    }
+
+    /// The name the entry `name` of `dir_nbr` has on disk, which may differ from `name` on images
+    /// that normalize names. `name` normalized if there is no such entry.
+    fn stored_name(&mut self, dir_nbr: u32, name: &str) -> Result<String, FsError> {
+        let normalized = self.normalize_name(name).into_owned();
+        if !self.normalizes_names() || self.find_entry(dir_nbr, normalized.as_bytes())?.is_some() {
+            return Ok(normalized);
+        }
+        Ok(match self.find_unnormalized(dir_nbr, &normalized)? {
+            Some(entry) => entry.get_name(),
+            None => normalized,
+        })
+    }
+
+    /// The hash of `name` in directory indices. Images that don't record a hash function still
+    /// get the default one, keyed with their seed.
+    fn index_hash(&self, name: &[u8]) -> u64 {
+        self.dir_hash()
+            .unwrap_or_default()
+            .hash(self.superblock.dir_hash_seed, name)
+    }
+
+    /// Resolves `path` to an inode number, starting at the root directory. Empty components and
+    /// `.` are skipped, `..` goes back to the previous directory (staying at the root), so
+    /// `/a//b/./../c` is the same as `/a/c`. Relative paths are resolved from the root as well.
+    /// Redirects are not followed, see `resolve_path`.
+    pub fn lookup_path(&mut self, path: &str) -> Result<u32, FsError> {
+        self.walk_path(path, &mut Vec::new())
+    }
+
+    /// Interns `path` for `lookup_interned`, which only resolves it again after a directory on
+    /// the way changed. The same path always gets the same id. Interned paths stay until the file
+    /// system is dropped and, like redirects, aren't shared with forks or snapshots.
+    pub fn intern_path(&mut self, path: &str) -> PathId {
+        self.paths.intern(path)
+    }
+
+    /// `lookup_path` for an interned path. The inode it resolves to is cached until one of the
+    /// directories it was looked up in is written, so a rename, unlink or new entry along the way
+    /// is seen by the next call. Fails with `FsError::InvalidValue` for an id of another file
+    /// system.
+    pub fn lookup_interned(&mut self, id: PathId) -> Result<u32, FsError> {
+        if let Some(inode_nbr) = self.paths.resolved(id)? {
+            return Ok(inode_nbr);
+        }
+        let path = self.paths.path(id)?.to_string();
+        let mut dirs = Vec::new();
+        let inode_nbr = self.walk_path(&path, &mut dirs)?;
+        self.paths.set_resolved(id, inode_nbr, dirs);
+        Ok(inode_nbr)
+    }
+
+    /// The path `id` was interned from
+    pub fn interned_path(&self, id: PathId) -> Result<&str, FsError> {
+        self.paths.path(id)
+    }
+
+    /// `lookup_path`, adding every directory a name is looked up in to `dirs`
+    fn walk_path(&mut self, path: &str, dirs: &mut Vec<u32>) -> Result<u32, FsError> {
+        let mut stack = vec![self.superblock.root_inode];
+        for component in path.split('/') {
+            match component {
+                "" | "." => {}
+                ".." => {
+                    let current = *stack.last().expect("the root is never popped");
+                    if self.read_inode(current)?.type_and_permission.get_type()
+                        != InodeType::Directory
+                    {
+                        return Err(FsError::NoEntry);
+                    }
+                    if stack.len() > 1 {
+                        stack.pop();
+                    }
+                }
+                name => {
+                    let dir = *stack.last().expect("the root is never popped");
+                    dirs.push(dir);
+                    stack.push(self.lookup(dir, name)?);
+                }
+            }
+        }
+        Ok(*stack.last().expect("the root is never popped"))
+    }
+
+    /// Like `lookup_path`, but paths below a prefix registered with `redirect` go to its target
+    /// instead. The longest matching prefix wins, and `..` is applied before matching.
+    pub fn resolve_path(&mut self, path: &str) -> Result<Resolved, FsError> {
+        let Some((prefix, redirect, rest)) = self.redirects.find(path) else {
+            return self.lookup_path(path).map(Resolved::Inode);
+        };
+        Ok(match redirect {
+            Redirect::FileSystem(fs) => Resolved::Redirected {
+                inode: fs.lookup_path(&rest)?,
+                prefix,
+            },
+            Redirect::Callback(callback) => Resolved::Callback {
+                handle: callback(&rest)?,
+                prefix,
+            },
+        })
+    }
+
+    /// Redirects `prefix` and every path below it to `redirect` in `resolve_path`, like a bind
+    /// mount. The prefix doesn't have to exist, and hides what does. Returns the redirect it
+    /// replaces. Redirects only live in memory and aren't shared with forks or snapshots.
+    pub fn redirect(&mut self, prefix: &str, redirect: Redirect) -> Option<Redirect> {
+        self.redirects.insert(prefix, redirect)
+    }
+
+    /// Stops redirecting `prefix`, returning what it was redirected to
+    pub fn remove_redirect(&mut self, prefix: &str) -> Option<Redirect> {
+        self.redirects.remove(prefix)
+    }
+
+    /// The file system `prefix` is redirected to, to work with the inodes `resolve_path` returns
+    /// for it
+    pub fn redirected_fs(&mut self, prefix: &str) -> Option<&mut FileSystem> {
+        match self.redirects.get_mut(prefix)? {
+            Redirect::FileSystem(fs) => Some(fs),
+            Redirect::Callback(_) => None,
+        }
+    }
+
+    /// Every entry of the directory `dir_nbr` whose type hint doesn't match the type of the inode
+    /// it links to
+    pub fn check_dir_entry_types(&mut self, dir_nbr: u32) -> Result<Vec<TypeMismatch>, FsError> {
+        let entries = self.read_inode(dir_nbr)?.read_dir_entries(self)?;
+        let mut mismatches = Vec::new();
+        for entry in entries {
+            let inode_type = self.read_inode(entry.inode)?.type_and_permission.get_type();
+            if entry.get_type() != inode_type {
+                mismatches.push(TypeMismatch {
+                    dir: dir_nbr,
+                    name: entry.get_name(),
+                    inode: entry.inode,
+                    entry_type: entry.get_type(),
+                    inode_type,
+                });
+            }
+        }
+        Ok(mismatches)
+    }
+
+    /// Whether the image is write-once, see `enable_worm`
+    pub fn is_worm(&self) -> bool {
+        self.superblock.feature_ro_compat & RO_COMPAT_WORM != 0
+    }
+
+    /// Makes the image write-once (WORM), for archives and audit logs: files and directories can
+    /// still be created and files can grow, but no byte that was written can be overwritten,
+    /// truncated or deleted, and no entry can be removed. This can't be turned off again.
+    pub fn enable_worm(&mut self) -> Result<(), FsError> {
+        if !self.is_worm() {
+            self.superblock.feature_ro_compat |= RO_COMPAT_WORM;
+            self.write_superblock()?;
+        }
+        Ok(())
+    }
+
+    /// Lets inode times go back before 1970 and keep nanoseconds (the `wide_times` feature, on
+    /// for new images). Older images store whole seconds from 1970 on, anything else is rounded
+    /// down or clamped to 1970 when it is written.
+    pub fn enable_wide_times(&mut self) -> Result<(), FsError> {
+        if !self.superblock.has_wide_times() {
+            self.superblock.feature_ro_compat |= RO_COMPAT_WIDE_TIMES;
+            self.write_superblock()?;
+        }
+        Ok(())
+    }
+
+    /// Keeps the entries of the directory `dir_nbr` sorted by name (byte-wise), so lookups can
+    /// binary search and listings come out in the same order no matter the order of insertion.
+    /// Inserting becomes O(n) in the size of the directory.
+    pub fn set_dir_sorted(&mut self, dir_nbr: u32, sorted: bool) -> Result<(), FsError> {
+        if sorted && self.superblock.feature_ro_compat & RO_COMPAT_SORTED_DIRS == 0 {
+            self.superblock.feature_ro_compat |= RO_COMPAT_SORTED_DIRS;
+            self.write_superblock()?;
+        }
+        self.read_inode(dir_nbr)?
+            .set_dir_sorted(self, sorted, dir_nbr)
+    }
+
+    /// Pins the blocks of `inode_nbr` in place or releases them again. Pinned blocks are never
+    /// relocated (by `defrag_step` or a future shrink), so a bootloader can read the file through
+    /// a block list recorded once with `pinned_blocks`. Writing to the file can still allocate or
+    /// free blocks at its end.
+    pub fn set_pinned(&mut self, inode_nbr: u32, pinned: bool) -> Result<(), FsError> {
+        let mut inode = self.read_inode(inode_nbr)?;
+        if pinned {
+            inode.flags |= INODE_FLAG_PINNED;
+        } else {
+            inode.flags &= !INODE_FLAG_PINNED;
+        }
+        self.write_inode(inode_nbr, &inode)
+    }
+
+    pub fn is_pinned(&mut self, inode_nbr: u32) -> Result<bool, FsError> {
+        Ok(self.read_inode(inode_nbr)?.flags & INODE_FLAG_PINNED != 0)
+    }
+
+    /// Replaces the storage hints of `inode_nbr`, which the allocator and the block cache follow
+    /// from then on. Blocks that are already allocated stay where they are until the file is
+    /// rewritten. The hints can also be set through the `sfs.hints` extended attribute.
+    pub fn set_storage_hints(
+        &mut self,
+        inode_nbr: u32,
+        hints: StorageHints,
+    ) -> Result<(), FsError> {
+        let mut inode = self.read_inode(inode_nbr)?;
+        inode.flags = (inode.flags & !StorageHints::FLAGS) | hints.to_flags();
+        self.write_inode(inode_nbr, &inode)
+    }
+
+    pub fn storage_hints(&mut self, inode_nbr: u32) -> Result<StorageHints, FsError> {
+        Ok(StorageHints::from_flags(self.read_inode(inode_nbr)?.flags))
+    }
+
+    /// Stores the contents of the regular file `inode_nbr` compressed with `algo` from then on,
+    /// or uncompressed with `Compression::None`, rewriting what it holds already. Reads
+    /// decompress transparently. Enables the `compression` feature, which older implementations
+    /// can't open images with. Fails with `FsError::UnsupportedFeatures` if the codec wasn't
+    /// built in or the image doesn't store file sizes (see `crate::compress`).
+    pub fn set_compression(&mut self, inode_nbr: u32, algo: Compression) -> Result<(), FsError> {
+        if self.disk.is_read_only() {
+            return Err(FsError::ReadOnly);
+        }
+        if !algo.is_available() || self.superblock.feature_ro_compat & RO_COMPAT_FILE_SIZE == 0 {
+            return Err(FsError::UnsupportedFeatures);
+        }
+        let mut inode = self.read_inode(inode_nbr)?;
+        if inode.type_and_permission.get_type() != InodeType::File {
+            return Err(FsError::NotAFile);
+        }
+        if inode.compression()? == algo {
+            return Ok(());
+        }
+        if self.is_worm() && inode.size() != 0 {
+            return Err(FsError::WriteOnce);
+        }
+        if algo != Compression::None && self.superblock.feature_incompat & INCOMPAT_COMPRESSION == 0
+        {
+            self.superblock.feature_incompat |= INCOMPAT_COMPRESSION;
+            self.write_superblock()?;
+        }
+
+        let data = inode.read_all(self)?;
+        inode.truncate(0, self, inode_nbr)?;
+        inode.compression = algo.as_u8();
+        inode.file_write(&data, self, inode_nbr)
+    }
+
+    pub fn compression(&mut self, inode_nbr: u32) -> Result<Compression, FsError> {
+        self.read_inode(inode_nbr)?.compression()
+    }
+
+    /// The extended attributes of `inode`, none if it has no xattr block
+    fn read_xattrs(&mut self, inode: &Inode) -> Result<XattrBlock, FsError> {
+        if inode.xattr_block == 0 {
+            return Ok(XattrBlock::default());
+        }
+        XattrBlock::parse(&self.dump_block(inode.xattr_block)?)
+    }
+
+    /// Replaces the extended attributes of `inode`, allocating its xattr block for the first
+    /// attribute and freeing it again with the last one
+    fn write_xattrs(
+        &mut self,
+        inode_nbr: u32,
+        mut inode: Inode,
+        xattrs: &XattrBlock,
+    ) -> Result<(), FsError> {
+        let owner = (inode.uid, inode.gid);
+        if xattrs.entries.is_empty() {
+            let block = core::mem::take(&mut inode.xattr_block);
+            if block != 0 {
+                self.write_inode(inode_nbr, &inode)?;
+                self.charged(inode_nbr, owner, |fs| fs.free_block(block))?;
+            }
+            return Ok(());
+        }
+
+        let data = xattrs.to_block()?;
+        if inode.xattr_block == 0 {
+            if self.superblock.feature_ro_compat & RO_COMPAT_XATTR == 0 {
+                self.superblock.feature_ro_compat |= RO_COMPAT_XATTR;
+                self.write_superblock()?;
+            }
+            inode.xattr_block = self.charged(inode_nbr, owner, |fs| {
+                fs.allocate_block_in(AllocZone::Metadata)
+            })?;
+        }
+        self.disk
+            .write_exact(Self::pointer(inode.xattr_block)?, data.as_slice())?;
+        self.write_inode(inode_nbr, &inode)
+    }
+
+    /// The value of the extended attribute `name` of `inode_nbr`
+    pub fn get_xattr(&mut self, inode_nbr: u32, name: &str) -> Result<Vec<u8>, FsError> {
+        let inode = self.read_inode(inode_nbr)?;
+        if name == STORAGE_HINTS_XATTR {
+            let hints = StorageHints::from_flags(inode.flags);
+            if hints.is_empty() {
+                return Err(FsError::NoAttribute);
+            }
+            return Ok(hints.to_string().into_bytes());
+        }
+        self.read_xattrs(&inode)?
+            .entries
+            .remove(name)
+            .ok_or(FsError::NoAttribute)
+    }
+
+    /// Sets the extended attribute `name` of `inode_nbr`, creating it if needed. All attributes of
+    /// an inode share a single block, so their names and values have to fit into 4 KiB together;
+    /// `FsError::NoSpace` otherwise.
+    pub fn set_xattr(&mut self, inode_nbr: u32, name: &str, value: &[u8]) -> Result<(), FsError> {
+        xattr::check_name(name)?;
+        if name == STORAGE_HINTS_XATTR {
+            let value = core::str::from_utf8(value).map_err(|_| FsError::InvalidValue)?;
+            return self.set_storage_hints(inode_nbr, StorageHints::parse(value)?);
+        }
+        if name == TEMPLATE_XATTR {
+            DirTemplate::parse(core::str::from_utf8(value).map_err(|_| FsError::InvalidValue)?)?;
+        }
+        let inode = self.read_inode(inode_nbr)?;
+        let mut xattrs = self.read_xattrs(&inode)?;
+        let old = xattrs.entries.insert(name.to_string(), value.to_vec());
+        if self.is_worm() && old.is_some_and(|old| old != value) {
+            return Err(FsError::WriteOnce);
+        }
+        self.write_xattrs(inode_nbr, inode, &xattrs)
+    }
+
+    /// The names of every extended attribute of `inode_nbr`, sorted byte-wise
+    pub fn list_xattr(&mut self, inode_nbr: u32) -> Result<Vec<String>, FsError> {
+        let inode = self.read_inode(inode_nbr)?;
+        let mut names: Vec<String> = self.read_xattrs(&inode)?.entries.into_keys().collect();
+        if !StorageHints::from_flags(inode.flags).is_empty() {
+            let pos = names.partition_point(|name| name.as_str() < STORAGE_HINTS_XATTR);
+            names.insert(pos, STORAGE_HINTS_XATTR.to_string());
+        }
+        Ok(names)
+    }
+
+    /// Removes the extended attribute `name` of `inode_nbr`
+    pub fn remove_xattr(&mut self, inode_nbr: u32, name: &str) -> Result<(), FsError> {
+        if self.is_worm() {
+            return Err(FsError::WriteOnce);
+        }
+        if name == STORAGE_HINTS_XATTR {
+            if self.storage_hints(inode_nbr)?.is_empty() {
+                return Err(FsError::NoAttribute);
+            }
+            return self.set_storage_hints(inode_nbr, StorageHints::default());
+        }
+        let inode = self.read_inode(inode_nbr)?;
+        let mut xattrs = self.read_xattrs(&inode)?;
+        if xattrs.entries.remove(name).is_none() {
+            return Err(FsError::NoAttribute);
+        }
+        self.write_xattrs(inode_nbr, inode, &xattrs)
+    }
+
+    /// The metadata the directory `dir_nbr` gives new entries, see `set_dir_template`
+    pub fn dir_template(&mut self, dir_nbr: u32) -> Result<DirTemplate, FsError> {
+        let inode = self.read_inode(dir_nbr)?;
+        if inode.type_and_permission.get_type() != InodeType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        DirTemplate::from_xattrs(&self.read_xattrs(&inode)?.entries)
+    }
+
+    /// Makes every entry created in the directory `dir_nbr` from now on get the owner,
+    /// permissions and extended attributes of `template`, whatever its creator asked for. New
+    /// subdirectories get the template too, so it covers the whole tree below `dir_nbr`. Later
+    /// changes to an entry, like `set_attr`, still apply. The template is stored in extended
+    /// attributes of the directory, see `TEMPLATE_XATTR`; an empty one removes it.
+    pub fn set_dir_template(
+        &mut self,
+        dir_nbr: u32,
+        template: &DirTemplate,
+    ) -> Result<(), FsError> {
+        let inode = self.read_inode(dir_nbr)?;
+        if inode.type_and_permission.get_type() != InodeType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        template.check()?;
+        for name in template.xattrs.keys() {
+            xattr::check_name(&format!("{TEMPLATE_XATTR_PREFIX}{name}"))?;
+        }
+
+        let mut xattrs = self.read_xattrs(&inode)?;
+        let old = core::mem::take(&mut xattrs.entries);
+        let (old_template, mut entries): (BTreeMap<_, _>, BTreeMap<_, _>) = old
+            .into_iter()
+            .partition(|(name, _)| DirTemplate::is_template_xattr(name));
+        let new_template = template.to_xattrs();
+        if self.is_worm() && old_template != new_template {
+            return Err(FsError::WriteOnce);
+        }
+        entries.extend(new_template);
+        xattrs.entries = entries;
+        self.write_xattrs(dir_nbr, inode, &xattrs)
+    }
+
+    /// The physical blocks of every pinned inode, in file order
+    pub fn pinned_blocks(&mut self) -> Result<BTreeMap<u32, Vec<u32>>, FsError> {
+        let mut pinned = BTreeMap::new();
+        for inode_nbr in self.inodes_in_use()? {
+            let inode = self.read_inode(inode_nbr)?;
+            if inode.flags & INODE_FLAG_PINNED != 0 {
+                let blocks = inode.data_blocks(self)?;
+                pinned.insert(
+                    inode_nbr,
+                    blocks.into_iter().map(|(_, block)| block).collect(),
+                );
+            }
+        }
+        Ok(pinned)
+    }
+
+    /// Creates `child` as the entry `name` of the directory `parent_nbr` and returns its inode
+    /// number. Timestamps of `child` that are 0 are set to now, see `create_inode`.
+    pub fn create_dir_entry(
+        &mut self,
+        parent_nbr: u32,
+        mut child: Inode,
+        name: String,
+    ) -> Result<u32, FsError> {
+        child.hardlinks = 0;
+        let template = self.dir_template(parent_nbr)?;
+        template.apply(&mut child);
+        let is_dir = child.type_and_permission.get_type() == InodeType::Directory;
+        // the `..` of the new directory links to the parent
+        if is_dir && self.has_dot_entries() && self.read_inode(parent_nbr)?.hardlinks == u16::MAX {
+            return Err(FsError::TooManyLinks);
+        }
+        self.with_txn(|fs| {
+            let child_nbr = fs.create_inode(&child)?;
+            fs.link_to_inode(parent_nbr, child_nbr, name)?;
+            if is_dir && fs.has_dot_entries() {
+                fs.add_dot_entries(child_nbr, parent_nbr)?;
+            }
+            if !template.is_empty() {
+                let xattrs = XattrBlock {
+                    entries: template.child_xattrs(is_dir),
+                };
+                let inode = fs.read_inode(child_nbr)?;
+                fs.write_xattrs(child_nbr, inode, &xattrs)?;
+            }
+            Ok(child_nbr)
+        })
+    }
+
+    /// Whether directories store `.` and `..` entries. Like on POSIX systems, a directory is then
+    /// linked from its parent, its own `.` and the `..` of every subdirectory.
+    pub fn has_dot_entries(&self) -> bool {
+        self.superblock.feature_ro_compat & RO_COMPAT_DOT_ENTRIES != 0
+    }
+
+    /// Adds `.` and `..` to the new directory `dir_nbr` and counts the links they add
+    fn add_dot_entries(&mut self, dir_nbr: u32, parent_nbr: u32) -> Result<(), FsError> {
+        let mut dir = self.read_inode(dir_nbr)?;
+        for (name, inode_nbr) in [(".", dir_nbr), ("..", parent_nbr)] {
+            let entry = DirEntry::create(inode_nbr, &InodeType::Directory, name.to_string())?;
+            dir.write_dir_entry(self, &entry, dir_nbr)?;
+        }
+        self.add_hardlinks(dir_nbr, 1)?;
+        self.add_hardlinks(parent_nbr, 1)
+    }
+
+    /// Whether `inode` is a directory with `.` and `..` entries that count as links. Directories
+    /// without them (from images without `dot_entries`) and other inodes have none.
+    fn has_dot_links(&mut self, inode: &Inode) -> Result<bool, FsError> {
+        if inode.type_and_permission.get_type() != InodeType::Directory {
+            return Ok(false);
+        }
+        Ok(inode.find_dir_entry(self, b"..")?.is_some())
+    }
+
+    /// Drops the links the `.` and `..` entries of the directory `dir` add, before it is deleted
+    fn drop_dot_links(&mut self, parent_nbr: u32, dir: &mut Inode) -> Result<(), FsError> {
+        if self.has_dot_links(dir)? {
+            dir.hardlinks -= 1;
+            self.add_hardlinks(parent_nbr, -1)?;
+        }
+        Ok(())
+    }
+
+    fn add_hardlinks(&mut self, inode_nbr: u32, links: i16) -> Result<(), FsError> {
+        let mut inode = self.read_inode(inode_nbr)?;
+        inode.hardlinks = match inode.hardlinks.checked_add_signed(links) {
+            Some(hardlinks) => hardlinks,
+            None if links > 0 => return Err(FsError::TooManyLinks),
+            None => return Err(self.corrupted()),
+        };
+        self.write_inode(inode_nbr, &inode)
+    }
+
+    /// Adds the hard link `name` in the directory `parent_nbr` to the existing inode `inode_nbr`.
+    /// Directories can't be hard linked, they only ever have the links of their entry, `.` and
+    /// the `..` of their subdirectories.
+    pub fn link(&mut self, inode_nbr: u32, parent_nbr: u32, name: &str) -> Result<(), FsError> {
+        let typ = self.read_inode(inode_nbr)?.type_and_permission.get_type();
+        if typ == InodeType::Directory {
+            return Err(FsError::NotAFile);
+        }
+        if self.read_inode(parent_nbr)?.type_and_permission.get_type() != InodeType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        match self.lookup(parent_nbr, name) {
+            Ok(_) => return Err(FsError::AlreadyExists),
+            Err(FsError::NoEntry) => {}
+            Err(e) => return Err(e),
+        }
+        self.with_txn(|fs| fs.link_to_inode(parent_nbr, inode_nbr, name.to_string()))?;
+        Ok(())
+    }
+
+    /// Creates a named pipe called `name` in `parent_nbr`. `mode` holds the permission bits (the
+    /// lower 12 bits), the type bits are ignored.
+    pub fn mkfifo(&mut self, parent_nbr: u32, name: String, mode: u16) -> Result<u32, FsError> {
+        self.create_special(parent_nbr, name, InodeType::FiFo, mode)
+    }
+
+    /// Creates a unix socket inode called `name` in `parent_nbr`, see `mkfifo`
+    pub fn mksock(&mut self, parent_nbr: u32, name: String, mode: u16) -> Result<u32, FsError> {
+        self.create_special(parent_nbr, name, InodeType::Socket, mode)
+    }
+
+    /// Creates a symbolic link called `name` in `parent_nbr` that points to `target`. The target
+    /// isn't checked, it can be relative, absolute or point to nothing at all, but it has to be
+    /// shorter than a block.
+    pub fn symlink(&mut self, parent_nbr: u32, name: String, target: &str) -> Result<u32, FsError> {
+        if target.is_empty() {
+            return Err(FsError::InvalidValue);
+        }
+        if target.len() >= BLOCK_SIZE {
+            return Err(FsError::NameTooLong);
+        }
+        let inode_nbr = self.create_special(parent_nbr, name, InodeType::Symlink, 0o777)?;
+        self.set_link_target(inode_nbr, target)?;
+        Ok(inode_nbr)
+    }
+
+    /// Replaces the target of the symbolic link `inode_nbr`
+    pub(crate) fn set_link_target(&mut self, inode_nbr: u32, target: &str) -> Result<(), FsError> {
+        let mut inode = self.read_inode(inode_nbr)?;
+        inode.set_link_target(target.as_bytes(), self, inode_nbr)
+    }
+
+    /// The target of the symbolic link `inode_nbr`, `FsError::InvalidValue` for every other type
+    pub fn read_link(&mut self, inode_nbr: u32) -> Result<String, FsError> {
+        let inode = self.read_inode(inode_nbr)?;
+        if inode.type_and_permission.get_type() != InodeType::Symlink {
+            return Err(FsError::InvalidValue);
+        }
+        String::from_utf8(inode.read_all(self)?).map_err(|_| FsError::Corrupted)
+    }
+
+    /// The entry `name` of `parent_nbr`, created as an empty directory only its owner can access
+    /// if it doesn't exist
+    pub(crate) fn lookup_or_mkdir(&mut self, parent_nbr: u32, name: &str) -> Result<u32, FsError> {
+        match self.lookup(parent_nbr, name) {
+            Err(FsError::NoEntry) => {
+                let now = self.now();
+                let inode = Inode::create(
+                    PermissionsAndType::new(InodeType::Directory, &[Permission::user_all()]),
+                    0,
+                    0,
+                    now,
+                    0,
+                    0,
+                );
+                self.create_dir_entry(parent_nbr, inode, name.to_string())
+            }
+            other => other,
+        }
+    }
+
+    /// Creates an inode of a type that has no data blocks (yet)
+    fn create_special(
+        &mut self,
+        parent_nbr: u32,
+        name: String,
+        typ: InodeType,
+        mode: u16,
+    ) -> Result<u32, FsError> {
+        let now = self.now();
+        let inode = Inode::create(
+            PermissionsAndType::new(typ, &[Permission::Other(mode & 0o7777)]),
+            0,
+            0,
+            now,
+            0,
+            0,
+        );
+        self.create_dir_entry(parent_nbr, inode, name)
+    }
+
+    /// Removes the entry `name` from the directory `parent_nbr`. The inode is freed once its last
+    /// link is gone. Directories can't be unlinked.
+    ///
+    /// With `MountOptions::trash_expiry`, removing the last link moves the file to the trash
+    /// instead, see `undelete`. Unlinking from the trash itself frees it.
+    pub fn unlink(&mut self, parent_nbr: u32, name: &str) -> Result<(), FsError> {
+        let name = &self.stored_name(parent_nbr, name)?;
+        if let Some(expiry) = self.options.trash_expiry {
+            let child_nbr = self.lookup(parent_nbr, name)?;
+            let child = self.read_inode(child_nbr)?;
+            if child.type_and_permission.get_type() != InodeType::Directory
+                && child.hardlinks == 1
+                && !self.is_worm()
+                && !trash::is_trash(self, parent_nbr)?
+            {
+                return trash::move_to_trash(self, parent_nbr, name, expiry);
+            }
+        }
+        self.unlink_now(parent_nbr, name)
+    }
+
+    /// `unlink` without the trash
+    pub(crate) fn unlink_now(&mut self, parent_nbr: u32, name: &str) -> Result<(), FsError> {
+        let name = &self.stored_name(parent_nbr, name)?;
+        let child_nbr = self.lookup(parent_nbr, name)?;
+        let mut child = self.read_inode(child_nbr)?;
+        if child.type_and_permission.get_type() == InodeType::Directory {
+            return Err(FsError::NotAFile);
+        }
+
+        self.with_txn(|fs| {
+            let mut parent = fs.read_inode(parent_nbr)?;
+            parent.remove_dir_entry(fs, name.as_bytes(), parent_nbr)?;
+            child.delete(child_nbr, fs)
+        })
+    }
+
+    /// Every file in the trash, the oldest first, see `MountOptions::trash_expiry`
+    pub fn list_trash(&mut self) -> Result<Vec<TrashEntry>, FsError> {
+        trash::list(self)
+    }
+
+    /// Moves the file `name` unlinked at `deleted` back to where it was unlinked from and returns
+    /// its inode. Fails with `FsError::NoEntry` if it isn't in the trash or its directory is gone,
+    /// and with `FsError::AlreadyExists` if its name was taken since.
+    pub fn undelete(&mut self, deleted: u64, name: &str) -> Result<u32, FsError> {
+        let entry = trash::list(self)?
+            .into_iter()
+            .find(|entry| entry.deleted == deleted && entry.name == name)
+            .ok_or(FsError::NoEntry)?;
+        trash::restore(self, &entry)?;
+        Ok(entry.inode)
+    }
+
+    /// Frees every file that has been in the trash for at least `min_age` seconds, 0 to empty it.
+    /// Returns how many were freed. `unlink` does this with `MountOptions::trash_expiry` by itself.
+    pub fn purge_trash(&mut self, min_age: u64) -> Result<u32, FsError> {
+        let before = self.now().saturating_sub(min_age).saturating_add(1);
+        trash::purge(self, before)
+    }
+
+    /// Removes the empty directory `name` from the directory `parent_nbr` and frees it
+    pub fn rmdir(&mut self, parent_nbr: u32, name: &str) -> Result<(), FsError> {
+        if is_dot_name(name.as_bytes()) {
+            return Err(FsError::InvalidName);
+        }
+        let name = &self.stored_name(parent_nbr, name)?;
+        let child_nbr = self.lookup(parent_nbr, name)?;
+        let mut child = self.read_inode(child_nbr)?;
+        if child.type_and_permission.get_type() != InodeType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        if !child.read_dir_entries(self)?.is_empty() {
+            return Err(FsError::DirectoryNotEmpty);
+        }
+
+        self.with_txn(|fs| {
+            let mut parent = fs.read_inode(parent_nbr)?;
+            parent.remove_dir_entry(fs, name.as_bytes(), parent_nbr)?;
+            fs.drop_dot_links(parent_nbr, &mut child)?;
+            child.delete(child_nbr, fs)
+        })
+    }
+
+    /// Moves the entry `old_name` of `old_parent` to `new_name` in `new_parent`. An existing
+    /// `new_name` is replaced like with POSIX `rename`: a file only by a non-directory, a
+    /// directory only by a directory and only if it is empty. A directory moved to another parent
+    /// gets its `..` entry pointed at the new one.
+    ///
+    /// Everything that can fail is checked first and the changes are made in one transaction (see
+    /// `with_txn`), so a failed rename leaves both directories as they were. An interrupted one
+    /// does too on an image with a journal; without one, it can be left half done.
+    pub fn rename(
+        &mut self,
+        old_parent: u32,
+        old_name: &str,
+        new_parent: u32,
+        new_name: &str,
+    ) -> Result<(), FsError> {
+        if self.is_worm() {
+            return Err(FsError::WriteOnce);
+        }
+        if is_dot_name(old_name.as_bytes()) || is_dot_name(new_name.as_bytes()) {
+            return Err(FsError::InvalidName);
+        }
+        let old_name = &self.stored_name(old_parent, old_name)?;
+        let child_nbr = self.lookup(old_parent, old_name)?;
+        let typ = self.read_inode(child_nbr)?.type_and_permission.get_type();
+        let entry = DirEntry::create(child_nbr, &typ, self.normalize_name(new_name).into_owned())?;
+        if self.read_inode(new_parent)?.type_and_permission.get_type() != InodeType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        // the entry this replaces may be stored under another form of the name
+        let new_name = &self.stored_name(new_parent, new_name)?;
+        if typ == InodeType::Directory && self.is_in_subtree(child_nbr, new_parent)? {
+            return Err(FsError::DirectoryLoop);
+        }
+
+        let replaced = match self.lookup(new_parent, new_name) {
+            Ok(existing) if existing == child_nbr => return Ok(()),
+            Ok(existing) => {
+                let existing_inode = self.read_inode(existing)?;
+                match (typ, existing_inode.type_and_permission.get_type()) {
+                    (InodeType::Directory, InodeType::Directory)
+                        if !existing_inode.read_dir_entries(self)?.is_empty() =>
+                    {
+                        return Err(FsError::DirectoryNotEmpty);
+                    }
+                    (InodeType::Directory, InodeType::Directory) => {}
+                    (InodeType::Directory, _) => return Err(FsError::NotADirectory),
+                    (_, InodeType::Directory) => return Err(FsError::NotAFile),
+                    _ => {}
+                }
+                Some((existing, existing_inode))
+            }
+            Err(FsError::NoEntry) => None,
+            Err(e) => return Err(e),
+        };
+        let replaced_dots = match &replaced {
+            Some((_, existing_inode)) => self.has_dot_links(existing_inode)?,
+            None => false,
+        };
+        // a directory moved to another parent takes the link of its `..` along
+        let moves_dots = if typ == InodeType::Directory && old_parent != new_parent {
+            let child = self.read_inode(child_nbr)?;
+            self.has_dot_links(&child)?
+        } else {
+            false
+        };
+        if moves_dots && !replaced_dots && self.read_inode(new_parent)?.hardlinks == u16::MAX {
+            return Err(FsError::TooManyLinks);
+        }
+        let dot_dot = DirEntry::create(new_parent, &InodeType::Directory, "..".to_string())?;
+
+        self.with_txn(|fs| {
+            if replaced.is_some() {
+                let mut parent = fs.read_inode(new_parent)?;
+                parent.remove_dir_entry(fs, new_name.as_bytes(), new_parent)?;
+            }
+            let mut parent = fs.read_inode(new_parent)?;
+            parent.write_dir_entry(fs, &entry, new_parent)?;
+            let mut parent = fs.read_inode(old_parent)?;
+            parent.remove_dir_entry(fs, old_name.as_bytes(), old_parent)?;
+
+            if let Some((existing, mut existing_inode)) = replaced {
+                if replaced_dots {
+                    existing_inode.hardlinks -= 1;
+                    fs.add_hardlinks(new_parent, -1)?;
+                }
+                existing_inode.delete(existing, fs)?;
+            }
+            if moves_dots {
+                let mut child = fs.read_inode(child_nbr)?;
+                child.remove_dir_entry(fs, b"..", child_nbr)?;
+                child.write_dir_entry(fs, &dot_dot, child_nbr)?;
+                fs.add_hardlinks(old_parent, -1)?;
+                fs.add_hardlinks(new_parent, 1)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Whether the directory `inode_nbr` is the directory `dir_nbr` or somewhere below it. This
+    /// follows the `..` entries from `inode_nbr` up to the root, and only searches the whole
+    /// subtree of `dir_nbr` if it reaches a directory without them (from images without
+    /// `dot_entries`).
+    fn is_in_subtree(&mut self, dir_nbr: u32, inode_nbr: u32) -> Result<bool, FsError> {
+        let root = self.superblock.root_inode;
+        let mut current = inode_nbr;
+        let mut seen = BTreeSet::new();
+        loop {
+            if current == dir_nbr {
+                return Ok(true);
+            }
+            if current == root {
+                return Ok(false);
+            }
+            // the `..` entries can only go in circles on a corrupted image
+            if !seen.insert(current) {
+                return Err(self.corrupted());
+            }
+            let inode = self.read_inode(current)?;
+            match inode.find_dir_entry(self, b"..")? {
+                Some(entry) => current = entry.inode,
+                None => break,
+            }
+        }
+
+        let mut pending = vec![dir_nbr];
+        let mut seen = BTreeSet::new();
+        while let Some(dir) = pending.pop() {
+            if dir == inode_nbr {
+                return Ok(true);
+            }
+            if !seen.insert(dir) {
+                continue;
+            }
+            let inode = self.read_inode(dir)?;
+            if inode.type_and_permission.get_type() == InodeType::Directory {
+                pending.extend(
+                    inode
+                        .read_dir_entries(self)?
+                        .iter()
+                        .map(|entry| entry.inode),
+                );
+            }
+        }
+        Ok(false)
+    }
+
+    fn link_to_inode(
+        &mut self,
+        parent_nbr: u32,
+        child_nbr: u32,
+        mut name: String,
+    ) -> Result<u32, FsError> {
+        if is_dot_name(name.as_bytes()) {
+            return Err(FsError::InvalidName);
+        }
+        if let Cow::Owned(normalized) = self.normalize_name(&name) {
+            name = normalized;
+        }
+        let mut node = self.read_inode(child_nbr)?;
+        node.hardlinks = node.hardlinks.checked_add(1).ok_or(FsError::TooManyLinks)?;
+        self.write_inode(child_nbr, &node)?;
+
+        let typ = node.type_and_permission.get_type();
+
+        let mut node = self.read_inode(parent_nbr)?;
+        node.write_dir_entry(self, &DirEntry::create(child_nbr, &typ, name)?, parent_nbr)?;
+        Ok(child_nbr)
+    }
+
+    fn clear_block(&mut self, blk_id: u32) -> Result<(), FsError> {
+        let space = [0; BLOCK_SIZE];
+        self.disk.write_exact(Self::pointer(blk_id)?, &space)?;
+        Ok(())
+    }
+
+    pub fn free_block(&mut self, block_id: u32) -> Result<(), FsError> {
+        if block_id == 0 {
+            return Err(FsError::InvalidBlock);
+        }
+        if self.snapshots.is_frozen(block_id) {
+            // stays allocated for the snapshots, but the owner doesn't use it anymore
+            return self
+                .quotas
+                .block_changed(BlockArrayEntry::Allocated, BlockArrayEntry::Unused);
+        }
+        // 0 means there was no free block left
+        if self.superblock.earliest_free == 0 || self.superblock.earliest_free > block_id {
+            self.superblock.earliest_free = block_id;
+            self.write_superblock()?;
+        }
+
+        // cleared when it is allocated again, until then `recover_deleted` can still read it
+        self.mark_block(block_id, BlockArrayEntry::Unused)?;
+        if let Some(layout) = self.zone_layout() {
+            let zone = layout.zone_of(block_id, self.superblock.total_blocks) as usize;
+            self.zone_hints[zone] = self.zone_hints[zone].min(block_id);
+        }
+
+        Ok(())
+    }
+
+    pub fn allocate_block(&mut self, for_inodes: bool) -> Result<u32, FsError> {
+        // the hints below are cleared before the block array is written, a failing write would
+        // leave them cleared and report a full disk from then on
+        if self.disk.is_read_only() {
+            return Err(FsError::ReadOnly);
+        }
+        if !for_inodes {
+            self.quotas.check_block()?;
+        }
+        if for_inodes {
+            if let Some(blk) = self.allocate_in_zone(AllocZone::Metadata)? {
+                self.mark_block(blk, BlockArrayEntry::InodeBlock)?;
+                self.superblock.earliest_inode_space = blk * INODES_PER_BLOCK;
+                self.write_superblock()?;
+                return Ok(blk);
+            }
+        }
+
+        let blk = self.superblock.earliest_free;
+        if blk == 0 {
+            return Err(FsError::NoSpace);
+        } else if blk == self.superblock.last_free {
+            self.superblock.last_free = 0;
+        }
+
+        self.superblock.earliest_free = 0;
+        self.mark_block(
+            blk,
+            if for_inodes {
+                BlockArrayEntry::InodeBlock
+            } else {
+                BlockArrayEntry::Allocated
+            },
+        )?;
+
+        // no block left after this one leaves the hint at 0
+        self.superblock.earliest_free = self
+            .find_unused(blk + 1..self.superblock.total_blocks)?
+            .unwrap_or(0);
+        if for_inodes {
+            self.superblock.earliest_inode_space = blk * INODES_PER_BLOCK;
+        }
+        self.write_superblock()?;
+        self.clear_block(blk)?;
+        Ok(blk)
+    }
+
+    /// Allocates a data block in `zone` if the image uses allocation zones and the zone has space
+    /// left, otherwise wherever there is space
+    pub(crate) fn allocate_block_in(&mut self, zone: AllocZone) -> Result<u32, FsError> {
+        match self.allocate_in_zone(zone)? {
+            Some(blk) => Ok(blk),
+            None => self.allocate_block(false),
+        }
+    }
+
+    /// Allocates a data block of a file with `hints` that would go into `zone` without them.
+    /// `previous` is the block before it in the file, if there is one.
+    pub(crate) fn allocate_block_for(
+        &mut self,
+        zone: AllocZone,
+        hints: StorageHints,
+        previous: Option<u32>,
+    ) -> Result<u32, FsError> {
+        match hints.zone(zone) {
+            Some(zone) => self.allocate_block_in(zone),
+            None => self.allocate_block_from_end(previous),
+        }
+    }
+
+    /// Allocates and clears a block at the end of the image, for the data of cold files: the one
+    /// after `previous` if it is free, so the file stays in order, otherwise the start of the last
+    /// run of free blocks, at most `COLD_RUN_BLOCKS` before its end
+    fn allocate_block_from_end(&mut self, previous: Option<u32>) -> Result<u32, FsError> {
+        if self.disk.is_read_only() {
+            return Err(FsError::ReadOnly);
+        }
+        if let Some(previous) = previous {
+            if self.allocate_block_id(previous + 1)? {
+                self.clear_block(previous + 1)?;
+                return Ok(previous + 1);
+            }
+        }
+
+        let end = self
+            .find_last_unused(0..self.superblock.total_blocks)?
+            .ok_or(FsError::NoSpace)?;
+        let mut blk = end;
+        while end - blk + 1 < COLD_RUN_BLOCKS
+            && blk > 0
+            && self.block_type(blk - 1)? == BlockArrayEntry::Unused
+        {
+            blk -= 1;
+        }
+        self.allocate_block_id(blk)?;
+        self.clear_block(blk)?;
+        Ok(blk)
+    }
+
+    /// Allocates and clears the first free block of `zone` in any block group, `None` if there
+    /// are no zones or the zone is full everywhere
+    fn allocate_in_zone(&mut self, zone: AllocZone) -> Result<Option<u32>, FsError> {
+        let Some(layout) = self.zone_layout() else {
+            return Ok(None);
+        };
+        let total_blocks = self.superblock.total_blocks;
+        let hint = self.zone_hints[zone as usize];
+
+        for group in hint / BLOCKS_PER_BLOCKARRAY..total_blocks.div_ceil(BLOCKS_PER_BLOCKARRAY) {
+            let range = layout.range(group, total_blocks, zone);
+            if let Some(block_id) = self.find_unused(range.start.max(hint)..range.end)? {
+                self.allocate_block_id(block_id)?;
+                self.zone_hints[zone as usize] = block_id + 1;
+                self.clear_block(block_id)?;
+                return Ok(Some(block_id));
+            }
+        }
+
+        self.zone_hints[zone as usize] = total_blocks;
+        Ok(None)
+    }
+
+    /// Allocates the specific block `block_id` for data. Returns `false` (and changes nothing) if
+    /// the block is already in use.
+    pub(crate) fn allocate_block_id(&mut self, block_id: u32) -> Result<bool, FsError> {
+        if block_id >= self.superblock.total_blocks
+            || self.block_type(block_id)? != BlockArrayEntry::Unused
+        {
+            return Ok(false);
+        }
+
+        self.mark_block(block_id, BlockArrayEntry::Allocated)?;
+        if block_id == self.superblock.last_free {
+            self.superblock.last_free = 0;
+        }
+        if block_id == self.superblock.earliest_free {
+            self.superblock.earliest_free = self
+                .find_unused(block_id + 1..self.superblock.total_blocks)?
+                .unwrap_or(0);
+            self.write_superblock()?;
+        }
+
+        Ok(true)
+    }
+
+    /// Sets the block array entry of `block_id` without touching the block itself
+    pub(crate) fn mark_block(
+        &mut self,
+        block_id: u32,
+        typ: BlockArrayEntry,
+    ) -> Result<(), FsError> {
+        if block_id >= self.superblock.total_blocks {
+            return Err(FsError::InvalidBlock);
+        }
+        let group = block_id / BLOCKS_PER_BLOCKARRAY;
+        let mut descriptor = BlockArrayDescriptor::from_disk(&mut self.disk, group);
+        let was = descriptor.get(block_id % BLOCKS_PER_BLOCKARRAY)?;
+        self.quotas.block_changed(was, typ)?;
+        descriptor.set(block_id % BLOCKS_PER_BLOCKARRAY, typ)?;
+
+        if let Some(free) = self.group_free.get_mut(&group) {
+            match (
+                was == BlockArrayEntry::Unused,
+                typ == BlockArrayEntry::Unused,
+            ) {
+                (true, false) => *free -= 1,
+                (false, true) => *free += 1,
+                _ => {}
+            }
+        }
+        let is_inode_block = typ == BlockArrayEntry::InodeBlock;
+        if self.has_inode_bitmap() && (was == BlockArrayEntry::InodeBlock) != is_inode_block {
+            // a new inode block is cleared by the caller, so all of its inodes are free
+            inode_bitmap::set_block_free(self, block_id, is_inode_block)?;
+        }
+        Ok(())
+    }
+
+    /// The first free block in `range`, skipping block groups without free blocks
+    fn find_unused(&mut self, range: Range<u32>) -> Result<Option<u32>, FsError> {
+        let mut start = range.start;
+        while start < range.end {
+            let group = start / BLOCKS_PER_BLOCKARRAY;
+            let group_start = group * BLOCKS_PER_BLOCKARRAY;
+            let end = (group_start + BLOCKS_PER_BLOCKARRAY).min(range.end);
+
+            if self.free_in_group(group)? > 0 {
+                if let Some(index) = BlockArrayDescriptor::from_disk(&mut self.disk, group)
+                    .find_unused(start - group_start..end - group_start)?
+                {
+                    return Ok(Some(group_start + index));
+                }
+            }
+            start = end;
+        }
+        Ok(None)
+    }
+
+    /// The last unused block in `range`, skipping full block groups
+    fn find_last_unused(&mut self, range: Range<u32>) -> Result<Option<u32>, FsError> {
+        let mut end = range.end;
+        while end > range.start {
+            let group = (end - 1) / BLOCKS_PER_BLOCKARRAY;
+            let group_start = group * BLOCKS_PER_BLOCKARRAY;
+            let start = group_start.max(range.start);
+
+            if self.free_in_group(group)? > 0 {
+                if let Some(index) = BlockArrayDescriptor::from_disk(&mut self.disk, group)
+                    .find_last_unused(start - group_start..end - group_start)?
+                {
+                    return Ok(Some(group_start + index));
+                }
+            }
+            end = start;
+        }
+        Ok(None)
+    }
+
+    /// The number of free blocks in the block group `group`
+    fn free_in_group(&mut self, group: u32) -> Result<u32, FsError> {
+        if let Some(free) = self.group_free.get(&group) {
+            return Ok(*free);
+        }
+        let len = self.superblock.total_blocks - group * BLOCKS_PER_BLOCKARRAY;
+        let free = BlockArrayDescriptor::from_disk(&mut self.disk, group).count_unused(len)?;
+        self.group_free.insert(group, free);
+        Ok(free)
+    }
+
+    /// Recomputes the first and last free block from the block arrays, after they were changed
+    /// behind the back of the allocator
+    pub(crate) fn reset_free_hints(&mut self) -> Result<(), FsError> {
+        self.superblock.earliest_free = 0;
+        self.superblock.last_free = 0;
+        for block_id in 2..self.superblock.total_blocks {
+            if self.block_type(block_id)? == BlockArrayEntry::Unused {
+                if self.superblock.earliest_free == 0 {
+                    self.superblock.earliest_free = block_id;
+                }
+                self.superblock.last_free = block_id;
+            }
+        }
+        self.zone_hints = [0; 3];
+        self.group_free.clear();
+        self.write_superblock()
+    }
+
+    /// The blocks and inodes in use, like `df`
+    pub fn disk_usage(&mut self) -> Result<DiskUsage, FsError> {
+        Ok(DiskUsage {
+            total_blocks: self.superblock.total_blocks,
+            free_blocks: self.free_block_count()?,
+            inodes: self.inodes_in_use()?.len() as u32,
+        })
+    }
+
+    /// What the format allows on this image, with the settings it was created with
+    pub fn limits(&self) -> FsLimits {
+        let total_blocks = self.superblock.total_blocks;
+        let inode_capacity = if self.superblock.fixed_inode_table != 0 {
+            self.superblock.inode_table_blocks as u64 * INODES_PER_BLOCK as u64
+        } else {
+            // the block arrays, the superblock and the reserved areas can't hold inodes
+            let unusable = total_blocks.div_ceil(BLOCKS_PER_BLOCKARRAY) as u64
+                + 1
+                + self.reserved_blocks().len() as u64
+                + self.verity_area().len() as u64
+                + self.crypt_area().len() as u64;
+            (total_blocks as u64).saturating_sub(unusable) * INODES_PER_BLOCK as u64
+        };
+        let inode_capacity = inode_capacity.min(u32::MAX as u64) as u32;
+        let mut max_dir_entries = DOUBLY_END as u64 * (BLOCK_SIZE as u64 / MIN_RECORD_SIZE as u64);
+        if self.superblock.feature_ro_compat & RO_COMPAT_DOT_ENTRIES != 0 {
+            max_dir_entries -= 2;
+        }
+
+        FsLimits {
+            // offsets are `usize`, which is the tighter limit on 32-bit targets
+            max_file_size: MAX_FILE_SIZE.min(usize::MAX as u64),
+            // `DirEntry::create` wants names shorter than its buffer
+            max_name_len: DIRENTRY_NAME_LENGTH - 1,
+            max_path_depth: inode_capacity.saturating_sub(1),
+            max_dir_entries,
+            inode_capacity,
+            max_image_size: (u32::MAX as u64 * BLOCK_SIZE as u64).min(usize::MAX as u64),
+        }
+    }
+
+    /// Every run of contiguous free blocks, in block order
+    pub fn free_extents(&mut self) -> Result<Vec<FreeExtent>, FsError> {
+        let mut extents: Vec<FreeExtent> = Vec::new();
+        for block_id in 0..self.superblock.total_blocks {
+            if self.block_type(block_id)? != BlockArrayEntry::Unused {
+                continue;
+            }
+            match extents.last_mut() {
+                Some(last) if last.start + last.length == block_id => last.length += 1,
+                _ => extents.push(FreeExtent {
+                    start: block_id,
+                    length: 1,
+                }),
+            }
+        }
+        Ok(extents)
+    }
+
+    /// The blocks reserved for a bootloader at mkfs time (see `MkfsOptions::boot_area_blocks`),
+    /// empty if there are none. Block 0 can't hold a boot sector, it is the first block array
+    /// descriptor, so the bootloader has to be loaded from the boot area by something that knows
+    /// where the image starts, e.g. a stage in the partition table gap or firmware that reads the
+    /// superblock.
+    pub fn boot_area(&self) -> Range<u32> {
+        2..2 + self.superblock.boot_area_blocks
+    }
+
+    /// The blocks of the journal (see `MkfsOptions::journal_blocks`), empty if there is none
+    pub fn journal_area(&self) -> Range<u32> {
+        let start = self.boot_area().end;
+        start..start + self.superblock.journal_blocks
+    }
+
+    /// The blocks of the inode bitmap, empty on images without the `inode_bitmap` feature
+    pub fn inode_bitmap_area(&self) -> Range<u32> {
+        let start = self.journal_area().end;
+        start..start + self.superblock.inode_bitmap_blocks
+    }
+
+    /// The blocks reserved at mkfs time that no inode uses: the boot area, the journal and the
+    /// inode bitmap
+    pub(crate) fn reserved_blocks(&self) -> Range<u32> {
+        2..self.inode_bitmap_area().end
+    }
+
+    /// The blocks of the hash tree, empty on images without the `verity` feature
+    pub fn verity_area(&self) -> Range<u32> {
+        if self.superblock.feature_compat & COMPAT_VERITY == 0 {
+            return 0..0;
+        }
+        let start = self.superblock.verity_start;
+        start..start + self.superblock.verity_blocks
+    }
+
+    /// The blocks of the nonce table, empty on images without the `encryption` feature
+    pub fn crypt_area(&self) -> Range<u32> {
+        if self.superblock.feature_incompat & INCOMPAT_ENCRYPTION == 0 {
+            return 0..0;
+        }
+        let start = self.superblock.crypt_start;
+        start..start + self.superblock.crypt_blocks
+    }
+
+    /// Whether no inode may use `block_id`, because it is one of the `reserved_blocks` or part of
+    /// the hash tree or the nonce table
+    pub(crate) fn is_reserved(&self, block_id: u32) -> bool {
+        self.reserved_blocks().contains(&block_id)
+            || self.verity_area().contains(&block_id)
+            || self.crypt_area().contains(&block_id)
+    }
+
+    /// Whether the journal holds a committed transaction that wasn't replayed, see `mount`
+    pub fn journal_dirty(&mut self) -> Result<bool, FsError> {
+        let journal = self.journal_area();
+        if journal.is_empty() {
+            return Ok(false);
+        }
+        journal::is_dirty(&mut self.disk, journal.start, journal.len() as u32)
+    }
+
+    /// The transactions still in the journal, the newest first. The journal keeps as many as fit
+    /// into it, see `MkfsOptions::journal_blocks`.
+    pub fn journal_history(&mut self) -> Result<Vec<TxnInfo>, FsError> {
+        let journal = self.journal_area();
+        if journal.is_empty() {
+            return Ok(Vec::new());
+        }
+        journal::history(&mut self.disk, journal.start, journal.len() as u32)
+    }
+
+    /// The contents of the file `inode_nbr` right after the transaction `txn` (see
+    /// `journal_history`), e.g. to get back a file that was overwritten by mistake. The blocks
+    /// that changed since are read from their copies in the journal. Fails with
+    /// `FsError::NotJournaled` if the transaction isn't in the journal anymore, or if a block the
+    /// file needs changed outside of a transaction since (see `TxnInfo::unrecorded`). Such
+    /// writes are only noted in the journal with the next transaction or `sync`, so after a
+    /// crash before that, the file reads as if they happened before `txn`.
+    pub fn read_file_at_txn(&mut self, inode_nbr: u32, txn: u64) -> Result<Vec<u8>, FsError> {
+        let journal = self.journal_area();
+        if journal.is_empty() {
+            return Err(FsError::NotJournaled);
+        }
+        self.disk.flush()?;
+        let written = self.disk.written_blocks();
+        let io = journal::past_io(
+            &mut self.disk,
+            journal.start,
+            journal.len() as u32,
+            txn,
+            written,
+        )?;
+        let missed = io.missed.clone();
+        let mut past = self.fork_readonly();
+        past.disk = Disk::new(Box::new(io));
+        past.disk.set_read_only();
+
+        let result = past.read_past_file(inode_nbr);
+        if result.is_err() && missed.load(Ordering::Relaxed) {
+            return Err(FsError::NotJournaled);
+        }
+        result
+    }
+
+    /// Reads the file `inode_nbr` of a view made by `read_file_at_txn`
+    fn read_past_file(&mut self, inode_nbr: u32) -> Result<Vec<u8>, FsError> {
+        self.superblock = Superblock::read(&mut self.disk, Addr::block(1)?.get())?;
+        let inode = self.read_inode(inode_nbr)?;
+        if inode.type_and_permission.get_type() != InodeType::File {
+            return Err(FsError::NotAFile);
+        }
+        inode.read_all(self)
+    }
+
+    /// Starts a transaction: nothing done through it reaches the disk before it is committed,
+    /// and with a journal, a crash during the commit can't leave only part of it on the disk.
+    /// See `Transaction`.
+    pub fn begin_txn(&mut self) -> Transaction<'_> {
+        Transaction::new(self)
+    }
+
+    /// Runs `f` in a transaction that is committed if it succeeds and thrown away if it fails, so
+    /// an error halfway through several changes leaves none of them behind. Inside another
+    /// transaction (or a bulk import), `f` just becomes part of it. Unlike `Transaction::commit`,
+    /// changes too big for the journal don't fail: they are written in the same order as on an
+    /// image without a journal, and a crash can leave them half done.
+    pub fn with_txn<T>(
+        &mut self,
+        f: impl FnOnce(&mut FileSystem) -> Result<T, FsError>,
+    ) -> Result<T, FsError> {
+        if self.disk.is_buffering() {
+            return f(self);
+        }
+        let mut txn = self.begin_txn();
+        match f(txn.fs()) {
+            Ok(value) => {
+                txn.commit_any_size()?;
+                Ok(value)
+            }
+            Err(e) => {
+                txn.abort()?;
+                Err(e)
+            }
+        }
+    }
+
+    /// The blocks and inodes creating `plan` in the directory `parent_nbr` takes at most, with the
+    /// features of this image. The result can be passed to `reserve` as is.
+    pub fn estimate_usage(
+        &mut self,
+        parent_nbr: u32,
+        plan: &[PlannedEntry],
+    ) -> Result<UsageEstimate, FsError> {
+        estimate::estimate(self, parent_nbr, plan)
+    }
+
+    /// Copies the inode `src_nbr` of the image `src`, and everything below it if it is a
+    /// directory, to the new entry `name` of `parent_nbr` in this image. Permissions, owners,
+    /// times, flags, extended attributes, holes and hard links within the tree are kept; inode
+    /// numbers and generations are new. A failure halfway through leaves what was copied so far.
+    pub fn copy_from(
+        &mut self,
+        src: &mut FileSystem,
+        src_nbr: u32,
+        parent_nbr: u32,
+        name: &str,
+    ) -> Result<CopyStats, FsError> {
+        TreeCopy::new(src, self).run(src_nbr, parent_nbr, name)
+    }
+
+    /// Creates everything in the tar stream `reader` below the root directory. Files,
+    /// directories, hard and symbolic links, devices and fifos keep their permissions, owners,
+    /// modification times and extended attributes (pax `SCHILY.xattr.*` records). Missing parent
+    /// directories are created, existing entries are replaced like tar does, directories are
+    /// merged. Owners have to fit into 16 bits.
+    #[cfg(feature = "std")]
+    pub fn import_tar(&mut self, reader: &mut dyn Read) -> Result<TarStats, FsError> {
+        TarImport::new(self).run(reader)
+    }
+
+    /// Writes everything below the root directory to `writer` as a pax tar stream, the reverse of
+    /// `import_tar`. Sockets can't be stored in tar and are left out.
+    #[cfg(feature = "std")]
+    pub fn export_tar(&mut self, writer: &mut dyn Write) -> Result<TarStats, FsError> {
+        TarExport::new(self, writer).run()
+    }
+
+    /// Keeps a search index of the names of every entry, and with `contents` of the words in
+    /// every file, in `/.index`, for `search`. The index is updated as entries are created and
+    /// removed and as files are closed after writing (see `close`), and written to the file by
+    /// `sync`. An index that wasn't saved before a crash is rebuilt by the next `search`.
+    /// Enabling it again rebuilds it, e.g. to start or stop indexing contents.
+    pub fn enable_index(&mut self, contents: bool) -> Result<(), FsError> {
+        let file = match self.index.take() {
+            Some(index) => index.file(),
+            None => match self.lookup(self.superblock.root_inode, INDEX_FILE) {
+                Err(FsError::NoEntry) => index::create_file(self)?,
+                found => found?,
+            },
+        };
+        let mut index = SearchIndex::build(self, file, contents)?;
+        let saved = index.save(self);
+        self.index = Some(index);
+        saved
+    }
+
+    /// Stops keeping a search index and removes `/.index`
+    pub fn disable_index(&mut self) -> Result<(), FsError> {
+        if self.index.take().is_some() {
+            self.unlink_now(self.superblock.root_inode, INDEX_FILE)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the image keeps a search index, see `enable_index`
+    pub fn has_index(&self) -> bool {
+        self.index.is_some()
+    }
+
+    /// Builds the search index again from what is in the image, e.g. after files were written
+    /// without `close`
+    pub fn rebuild_index(&mut self) -> Result<(), FsError> {
+        match &self.index {
+            Some(index) => self.enable_index(index.indexes_contents()),
+            None => Ok(()),
+        }
+    }
+
+    /// The entries whose name contains every word of `query` and, if the index has them, the
+    /// files with every word (or a word starting with it) in their contents, ignoring case.
+    /// Images without an index are searched by name only, reading every directory.
+    pub fn search(&mut self, query: &str) -> Result<Vec<SearchHit>, FsError> {
+        let root = self.superblock.root_inode;
+        let Some(mut index) = self.index.take() else {
+            return Ok(SearchIndex::build(self, 0, false)?.search(query, root));
+        };
+        if index.is_stale() {
+            match SearchIndex::build(self, index.file(), index.indexes_contents()) {
+                Ok(built) => index = built,
+                Err(e) => {
+                    self.index = Some(index);
+                    return Err(e);
+                }
+            }
+        }
+        let hits = index.search(query, root);
+        self.index = Some(index);
+        Ok(hits)
+    }
+
+    /// Runs `update` on the search index while it is taken out of the file system, if there is
+    /// one
+    pub(crate) fn update_index(
+        &mut self,
+        update: impl FnOnce(&mut SearchIndex, &mut Self) -> Result<(), FsError>,
+    ) -> Result<(), FsError> {
+        let Some(mut index) = self.index.take() else {
+            return Ok(());
+        };
+        let result = update(&mut index, self);
+        self.index = Some(index);
+        result
+    }
+
+    /// Copies everything in the host directory `host` into the directory `dir_nbr`, which takes
+    /// over the permissions and modification time of `host`. Files, directories and symbolic
+    /// links keep their permissions, modification and creation times, and hard links within the
+    /// tree are kept; everything is owned by root. Entries are created in the order of their
+    /// names, so packing the same tree gives the same image.
+    #[cfg(feature = "std")]
+    pub fn pack_dir(&mut self, host: &Path, dir_nbr: u32) -> Result<PackStats, FsError> {
+        Packer::new(self).run(host, dir_nbr)
+    }
+
+    /// Copies everything in the directory `dir_nbr` into the host directory `host`, creating it
+    /// if necessary, the reverse of `pack_dir`. Permissions, modification times and hard links
+    /// are kept, the owner is whoever runs this. Existing host files are overwritten.
+    #[cfg(feature = "std")]
+    pub fn unpack_dir(&mut self, dir_nbr: u32, host: &Path) -> Result<PackStats, FsError> {
+        Unpacker::new(self).run(dir_nbr, host)
+    }
+
+    /// Makes sure `blocks` blocks and `inodes` inodes can be allocated, so an operation of a known
+    /// size (importing an archive, copying a directory) can fail up front instead of halfway
+    /// through. `blocks` has to count every block the operation allocates, including indirect
+    /// pointer, directory and attribute blocks; the inode blocks the inodes may need are added
+    /// on top. Fails with `FsError::NoSpace` if there isn't enough room.
+    pub fn reserve(&mut self, blocks: u32, inodes: u32) -> Result<Reservation<'_>, FsError> {
+        if self.disk.is_read_only() {
+            return Err(FsError::ReadOnly);
+        }
+        let slots = self.free_inode_slots()?;
+        let inode_blocks = if inodes <= slots {
+            0
+        } else if self.superblock.fixed_inode_table != 0 {
+            return Err(FsError::NoSpace);
+        } else {
+            (inodes - slots).div_ceil(INODES_PER_BLOCK)
+        };
+
+        let needed = blocks.checked_add(inode_blocks).ok_or(FsError::NoSpace)?;
+        if needed > self.free_block_count()? {
+            return Err(FsError::NoSpace);
+        }
+        Ok(Reservation::new(self, blocks, inodes))
+    }
+
+    /// Limits the blocks and inodes the files of the user or group `id` can use, enabling the
+    /// `quota` feature and creating the quota inode on the first call. Limits of `None` remove
+    /// the quota. A limit below the current usage doesn't free anything, it only keeps the usage
+    /// from growing: allocating a block or creating an inode over the limit of the owner or group
+    /// of the inode fails with `FsError::QuotaExceeded`.
+    pub fn set_quota(&mut self, id: QuotaId, limits: QuotaLimits) -> Result<(), FsError> {
+        if self.disk.is_read_only() {
+            return Err(FsError::ReadOnly);
+        }
+        if self.superblock.quota_inode == 0 {
+            // created before the feature is enabled, so it isn't charged to root
+            self.superblock.quota_inode = quota::create_file(self)?;
+            self.write_superblock()?;
+        }
+        if !self.superblock.has_quotas() {
+            self.superblock.feature_ro_compat |= RO_COMPAT_QUOTA;
+            self.write_superblock()?;
+            self.quotas.invalidate();
+        }
+        self.quotas.set_limits(id, limits);
+        let data = self.quotas.encode();
+        let file = self.superblock.quota_inode;
+        let mut inode = self.read_inode(file)?;
+        inode.file_write(&data, self, file)
+    }
+
+    /// The blocks and inodes the files of `id` use and its limits. The usage is counted from
+    /// every inode on the first call, and on every call on images without the `quota` feature.
+    pub fn get_usage(&mut self, id: QuotaId) -> Result<QuotaUsage, FsError> {
+        if !self.superblock.has_quotas() {
+            // nothing keeps the usage up to date without the feature
+            self.quotas.invalidate();
+        }
+        self.count_quota_usage()?;
+        Ok(self.quotas.usage(id))
+    }
+
+    /// Counts the usage of every user and group unless it is already known
+    fn count_quota_usage(&mut self) -> Result<(), FsError> {
+        if !self.quotas.is_counted() {
+            let mut quotas = core::mem::take(&mut self.quotas);
+            let counted = quotas.count(self);
+            self.quotas = quotas;
+            counted?;
+        }
+        Ok(())
+    }
+
+    /// Runs `op`, charging the data blocks it allocates to the user and group `owner` of the
+    /// inode `inode_nbr` and giving back the ones it frees, see `set_quota`
+    pub(crate) fn charged<T>(
+        &mut self,
+        inode_nbr: u32,
+        owner: (u16, u16),
+        op: impl FnOnce(&mut Self) -> Result<T, FsError>,
+    ) -> Result<T, FsError> {
+        if !self.superblock.has_quotas() || inode_nbr == self.superblock.quota_inode {
+            return op(self);
+        }
+        self.count_quota_usage()?;
+        let outer = self.quotas.set_owner(Some(owner));
+        let result = op(self);
+        self.quotas.set_owner(outer);
+        result
+    }
+
+    /// Gives the inode `inode_nbr` back to `owner`, before its last link is removed
+    pub(crate) fn uncharge_inode(
+        &mut self,
+        inode_nbr: u32,
+        owner: (u16, u16),
+    ) -> Result<(), FsError> {
+        if self.superblock.has_quotas() && inode_nbr != self.superblock.quota_inode {
+            self.count_quota_usage()?;
+            self.quotas.charge(owner, 0, -1);
+        }
+        Ok(())
+    }
+
+    /// Takes a snapshot of the whole image named `name`, enabling the `snapshots` feature on the
+    /// first call. The data blocks of files and symbolic links are shared with the snapshot
+    /// instead of copied: from now on, writing to one of them moves it to a new block first, and
+    /// freeing one leaves it allocated until the last snapshot holding on to it is deleted. The
+    /// blocks that are changed in place (the superblock, the block arrays, inode, directory,
+    /// pointer and attribute blocks and the reserved areas) are copied, see `crate::snapshot`.
+    /// Fails with `FsError::AlreadyExists` if there is a snapshot with that name, and with
+    /// `FsError::NoSpace` if there are `MAX_SNAPSHOTS` already.
+    ///
+    /// Named `create_snapshot`, as `snapshot` takes an in-memory view instead.
+    pub fn create_snapshot(&mut self, name: &str) -> Result<(), FsError> {
+        if self.disk.is_read_only() {
+            return Err(FsError::ReadOnly);
+        }
+        snapshot::create(self, name)
+    }
+
+    /// Every snapshot taken by `create_snapshot`, the oldest first
+    pub fn list_snapshots(&self) -> Vec<SnapshotInfo> {
+        self.snapshots.list()
+    }
+
+    /// Puts the whole image back into the state it had when the snapshot `name` was taken,
+    /// dropping every newer snapshot, but keeping `name` itself so it can be rolled back to
+    /// again. Handles and inode numbers from before refer to whatever had them in the snapshot.
+    /// Fails with `FsError::WriteOnce` on a write-once image.
+    pub fn rollback_to_snapshot(&mut self, name: &str) -> Result<(), FsError> {
+        if self.disk.is_read_only() {
+            return Err(FsError::ReadOnly);
+        }
+        if self.is_worm() {
+            return Err(FsError::WriteOnce);
+        }
+        snapshot::rollback(self, name)
+    }
+
+    /// Deletes the snapshot `name`, freeing its copies and the data blocks only it held on to
+    /// that no file uses anymore. Deleting the last one disables the `snapshots` feature again.
+    pub fn delete_snapshot(&mut self, name: &str) -> Result<(), FsError> {
+        if self.disk.is_read_only() {
+            return Err(FsError::ReadOnly);
+        }
+        snapshot::delete(self, name)
+    }
+
+    pub(crate) fn snapshots(&self) -> &Snapshots {
+        &self.snapshots
+    }
+
+    /// Reads the snapshot table again, after it was changed
+    pub(crate) fn reload_snapshots(&mut self) -> Result<(), FsError> {
+        self.snapshots = Snapshots::load(self)?;
+        Ok(())
+    }
+
+    /// The number of free blocks in all block groups
+    fn free_block_count(&mut self) -> Result<u32, FsError> {
+        let mut free = 0;
+        for group in 0..self.superblock.total_blocks.div_ceil(BLOCKS_PER_BLOCKARRAY) {
+            free += self.free_in_group(group)?;
+        }
+        Ok(free)
+    }
+
+    /// The number of free inodes `get_inode_physical` hands out before it needs a new inode
+    /// block: in sequential mode every free one in an inode block, otherwise those in the inode
+    /// table, or without a table those in the block of the earliest inode space hint
+    fn free_inode_slots(&mut self) -> Result<u32, FsError> {
+        let sequential = self.has_sequential_inodes();
+        if self.has_inode_bitmap() && !sequential {
+            // every free inode is found, only the ones held back for lazy reuse aren't handed out
+            let held_back = self.freed_inodes.len() as u32;
+            return Ok(self.superblock.free_inodes.saturating_sub(held_back));
+        }
+        let slots: Vec<u32> = if sequential {
+            let table = self.inode_table_range();
+            let fixed = self.superblock.fixed_inode_table != 0;
+            self.inode_slots()?
+                .into_iter()
+                .filter(|inode_nbr| !fixed || table.contains(inode_nbr))
+                .collect()
+        } else if self.superblock.inode_table_blocks != 0 {
+            self.inode_table_range().collect()
+        } else {
+            let first = self.superblock.earliest_inode_space;
+            if first == 0 {
+                Vec::new()
+            } else {
+                (first..first + INODES_PER_BLOCK).collect()
+            }
+        };
+
+        let mut free = 0;
+        for inode_nbr in slots {
+            let inode = self
+                .disk
+                .read_struct::<Inode>(Addr::inode(inode_nbr)?.get())?;
+            if inode.hardlinks == 0 && (sequential || self.can_reuse(inode_nbr)) {
+                free += 1;
+            }
+        }
+        Ok(free)
+    }
+
+    /// Forgets everything cached about the image, after writes to it were thrown away
+    pub(crate) fn rollback(&mut self) -> Result<(), FsError> {
+        self.superblock = Superblock::read(&mut self.disk, Addr::block(1)?.get())?;
+        self.zone_hints = [0; 3];
+        self.group_free.clear();
+        self.dcache.clear();
+        self.paths.clear();
+        if let Some(index) = &mut self.index {
+            index.invalidate();
+        }
+        self.quotas = Quotas::load(self)?;
+        self.snapshots = Snapshots::load(self)?;
+        Ok(())
+    }
+
+    /// Replaces the contents of the boot area with `data`, zeroing the rest of it. A write-once
+    /// image only takes this while the boot area is still all zeroes.
+    pub fn write_boot_area(&mut self, data: &[u8]) -> Result<(), FsError> {
+        let area = self.boot_area();
+        if data.len() > area.len() * BLOCK_SIZE {
+            return Err(FsError::NoSpace);
+        }
+        if self.is_worm() && self.read_boot_area()?.iter().any(|&byte| byte != 0) {
+            return Err(FsError::WriteOnce);
+        }
+        let mut padded = vec![0; area.len() * BLOCK_SIZE];
+        padded[..data.len()].copy_from_slice(data);
+        self.disk
+            .write_exact(Addr::block(area.start)?.get(), &padded)?;
+        Ok(())
+    }
+
+    /// The whole boot area, including the zeroes after what was written to it
+    pub fn read_boot_area(&mut self) -> Result<Vec<u8>, FsError> {
+        let area = self.boot_area();
+        let mut data = vec![0; area.len() * BLOCK_SIZE];
+        self.disk
+            .read_exact(Addr::block(area.start)?.get(), &mut data)?;
+        Ok(data)
+    }
+
+    /// The first block of the first run of `len` free blocks
+    pub(crate) fn find_free_run(&mut self, len: u32) -> Result<Option<u32>, FsError> {
+        if len == 0 {
+            return Ok(None);
+        }
+        Ok(self
+            .free_extents()?
+            .into_iter()
+            .find(|extent| extent.length >= len)
+            .map(|extent| extent.start))
+    }
+
+    /// Writes `inode` to a free inode and returns its number, without linking it anywhere. Its
+    /// creation, modification and access time are set to now unless they are already set (not
+    /// 0), so copies and imports can keep the times of the original.
+    pub fn create_inode(&mut self, inode: &Inode) -> Result<u32, FsError> {
+        let owner = (inode.uid, inode.gid);
+        if self.superblock.has_quotas() {
+            self.count_quota_usage()?;
+            self.quotas.check(owner, 0, 1)?;
+        }
+        let addr = self.get_inode_physical()?.inode_nbr()?;
+        let mut inode = *inode;
+        let now = Timestamp::from_secs(self.now()).secs;
+        for time in [
+            &mut inode.creation_time,
+            &mut inode.modification_time,
+            &mut inode.access_time,
+        ] {
+            if *time == 0 {
+                *time = now;
+            }
+        }
+        if self.superblock.has_inode_generations() {
+            inode.generation = self.superblock.next_generation;
+            self.superblock.next_generation = self.superblock.next_generation.wrapping_add(1);
+            self.write_superblock()?;
+        }
+        self.write_inode(addr, &inode)?;
+        self.quotas.charge(owner, 0, 1);
+        Ok(addr)
+    }
+
+    pub fn create(num_blocks: u32, fs_name: &str) -> Result<Self, FsError> {
+        Self::create_with(num_blocks, fs_name, &MkfsOptions::default())
+    }
+
+    pub fn create_with(
+        num_blocks: u32,
+        fs_name: &str,
+        options: &MkfsOptions,
+    ) -> Result<Self, FsError> {
+        Self::format(Disk::new_virtual(num_blocks), num_blocks, fs_name, options)
+    }
+
+    /// Creates a new file system with `num_blocks` blocks on `disk`. Only the blocks holding
+    /// metadata are cleared, so the disk doesn't have to be zeroed.
+    pub fn format(
+        mut disk: Disk,
+        num_blocks: u32,
+        fs_name: &str,
+        options: &MkfsOptions,
+    ) -> Result<Self, FsError> {
+        // the first block after the superblock and the boot area, there has to be space for at
+        // least one inode block after it
+        let first_free = options
+            .boot_area_blocks
+            .checked_add(options.journal_blocks)
+            .and_then(|reserved| reserved.checked_add(2))
+            .filter(|first| *first < num_blocks && *first < BLOCKS_PER_BLOCKARRAY)
+            .ok_or(FsError::DiskError(DiskError::NotEnoughSpace))?;
+
+        let now = Self::time(options.source_date_epoch, options.clock.as_deref());
+        let mut superblock = Superblock::new(fs_name, num_blocks, now)?;
+        superblock.dir_hash = options.dir_hash as u8;
+        superblock.dir_hash_seed = match (options.dir_hash_seed, options.source_date_epoch) {
+            (Some(seed), _) => seed,
+            (None, Some(epoch)) => [epoch, 0],
+            #[cfg(feature = "std")]
+            (None, None) => random_seed(),
+            // nothing to draw a random seed from, the time is the best there is
+            #[cfg(not(feature = "std"))]
+            (None, None) => [now, 0],
+        };
+        superblock.uuid = match (options.uuid, options.source_date_epoch) {
+            (Some(uuid), _) => uuid,
+            (None, Some(epoch)) => Uuid::derive(epoch, fs_name),
+            #[cfg(feature = "std")]
+            (None, None) => Uuid::random(),
+            #[cfg(not(feature = "std"))]
+            (None, None) => Uuid::derive(now, fs_name),
+        };
+        if let Some(zones) = options.zones {
+            superblock.zone_metadata_percent = zones.metadata_percent;
+            superblock.zone_small_files_percent = zones.small_files_percent;
+            superblock.feature_compat |= COMPAT_ALLOC_ZONES;
+        }
+        if options.sequential_inodes {
+            superblock.feature_ro_compat |= RO_COMPAT_SEQUENTIAL_INODES;
+        }
+        if options.normalize_names {
+            if cfg!(not(feature = "unicode")) {
+                return Err(FsError::UnsupportedFeatures);
+            }
+            superblock.feature_ro_compat |= RO_COMPAT_NFC_NAMES;
+        }
+
+        // the inode bitmap goes right after the journal, if it fits into the first block group
+        let bitmap = bitmap_blocks(num_blocks);
+        let first_free = match first_free.checked_add(bitmap) {
+            Some(end) if end < num_blocks && end < BLOCKS_PER_BLOCKARRAY => {
+                superblock.inode_bitmap_blocks = bitmap;
+                superblock.feature_ro_compat |= RO_COMPAT_INODE_BITMAP;
+                end
+            }
+            _ => first_free,
+        };
+
+        let empty_block = [0; BLOCK_SIZE];
+        if options.source_date_epoch.is_some() {
+            // whatever was on the disk before would end up in the image otherwise
+            for block in 0..num_blocks {
+                disk.write_exact(Addr::block(block)?.get(), &empty_block)?;
+            }
+        } else {
+            disk.write_exact(Addr::block(1)?.get(), &empty_block)?;
+        }
+        for i in 0..num_blocks.div_ceil(BLOCKS_PER_BLOCKARRAY) {
+            disk.write_exact(Addr::block(i * BLOCKS_PER_BLOCKARRAY)?.get(), &empty_block)?;
+            let mut blk_arr = BlockArrayDescriptor::create(&mut disk, i)?;
+            if i == 0 {
+                blk_arr.set(1, BlockArrayEntry::Allocated)?;
+            }
+        }
+
+        if first_free > 2 {
+            let mut blk_arr = BlockArrayDescriptor::from_disk(&mut disk, 0);
+            for block in 2..first_free {
+                blk_arr.set(block, BlockArrayEntry::Allocated)?;
+            }
+            // a zeroed journal header marks the journal as empty
+            for block in 2..first_free {
+                disk.write_exact(Addr::block(block)?.get(), &empty_block)?;
+            }
+
+            superblock.boot_area_blocks = options.boot_area_blocks;
+            if options.boot_area_blocks > 0 {
+                superblock.feature_compat |= COMPAT_BOOT_AREA;
+            }
+            superblock.journal_blocks = options.journal_blocks;
+            if options.journal_blocks > 0 {
+                superblock.feature_compat |= COMPAT_JOURNAL;
+            }
+            superblock.earliest_free = first_free;
+            superblock.total_unused -= first_free - 2;
+        }
+
+        let bytes_per_inode = match options.bytes_per_inode {
+            None if options.fixed_inode_table => Some(DEFAULT_BYTES_PER_INODE),
+            other => other,
+        };
+        if let Some(bytes_per_inode) = bytes_per_inode {
+            let inodes =
+                (num_blocks as u64 * BLOCK_SIZE as u64).div_ceil(bytes_per_inode.max(1) as u64);
+            // the table has to stay inside of the first block array and leave space for at least
+            // one data block
+            let table_blocks = (inodes.div_ceil(INODES_PER_BLOCK as u64) as u32)
+                .min(BLOCKS_PER_BLOCKARRAY - first_free)
+                .min(num_blocks - first_free - 1)
+                .max(1);
+
+            let mut blk_arr = BlockArrayDescriptor::from_disk(&mut disk, 0);
+            for block in first_free..first_free + table_blocks {
+                blk_arr.set(block, BlockArrayEntry::InodeBlock)?;
+            }
+            for block in first_free..first_free + table_blocks {
+                disk.write_exact(Addr::block(block)?.get(), &empty_block)?;
+            }
+
+            superblock.inode_table_start = first_free;
+            superblock.inode_table_blocks = table_blocks;
+            superblock.fixed_inode_table = options.fixed_inode_table as u8;
+            superblock.feature_compat |= COMPAT_INODE_TABLE;
+            if options.fixed_inode_table {
+                superblock.feature_ro_compat |= RO_COMPAT_FIXED_INODE_TABLE;
+            }
+            superblock.earliest_inode_space = first_free * INODES_PER_BLOCK;
+            superblock.earliest_free = first_free + table_blocks;
+            superblock.total_unused -= table_blocks;
+        }
+
+        superblock.update_checksum();
+        disk.write_struct(Addr::block(1)?.get(), &superblock)?;
+
+        let mut fs = Self {
+            superblock,
+            disk,
+            fixed_time: options.source_date_epoch,
+            options: MountOptions {
+                clock: options.clock.clone(),
+                ..MountOptions::default()
+            },
+            has_errors: false,
+            zone_hints: [0; 3],
+            group_free: BTreeMap::new(),
+            dcache: DirCache::default(),
+            redirects: Redirects::default(),
+            paths: InternedPaths::default(),
+            hooks: Hooks::default(),
+            index: None,
+            freed_inodes: VecDeque::new(),
+            quotas: Quotas::default(),
+            snapshots: Snapshots::default(),
+            data_key: None,
+        };
+        if options.journal_blocks > 0 {
+            fs.disk.track_writes();
+        }
+        if fs.has_inode_bitmap() {
+            // the inodes of the inode table start out free
+            inode_bitmap::rebuild(&mut fs)?;
+        }
+
+        let inode = Inode::create(
+            PermissionsAndType::new(
+                InodeType::Directory,
+                &[
+                    Permission::group_all(),
+                    Permission::user_all(),
+                    Permission::OtherRead,
+                    Permission::OtherExecute,
+                ],
+            ),
+            0,
+            0,
+            now,
+            1,
+            0,
+        );
+
+        fs.superblock.root_inode = fs.create_inode(&inode)?;
+        fs.write_superblock()?;
+        if fs.has_dot_entries() {
+            // both entries of the root link to itself, which makes up for the missing parent
+            let root = fs.superblock.root_inode;
+            fs.add_dot_entries(root, root)?;
+            fs.add_hardlinks(root, -1)?;
+        }
+        // only now, setting up the root drops a link
+        if options.worm {
+            fs.enable_worm()?;
+        }
+
+        Ok(fs)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::Sfs;
+
+    #[test]
+    fn worm_refuses_raw_writes_to_committed_data() {
+        let options = MkfsOptions {
+            worm: true,
+            boot_area_blocks: 1,
+            ..Default::default()
+        };
+        let mut sfs = Sfs::from(FileSystem::create_with(300, "worm", &options).unwrap());
+        sfs.write("/log", b"first").unwrap();
+        let fs = sfs.fs();
+        let log = fs.lookup_path("/log").unwrap();
+        let block = fs.read_inode(log).unwrap().block_map(fs).unwrap()[0];
+
+        let zeroes = [0; BLOCK_SIZE];
+        assert!(matches!(
+            fs.write_block(block, &zeroes),
+            Err(FsError::WriteOnce)
+        ));
+        let chmod = SetAttr {
+            permissions: Some(0o600),
+            ..Default::default()
+        };
+        assert!(matches!(fs.set_attr(log, &chmod), Err(FsError::WriteOnce)));
+        let unchanged = SetAttr {
+            permissions: Some(fs.stat(log).unwrap().permissions),
+            ..Default::default()
+        };
+        fs.set_attr(log, &unchanged).unwrap();
+        assert!(matches!(fs.fsck(true), Err(FsError::WriteOnce)));
+        assert!(fs.fsck(false).unwrap().is_clean());
+
+        fs.write_boot_area(b"boot").unwrap();
+        assert!(matches!(
+            fs.write_boot_area(b"other"),
+            Err(FsError::WriteOnce)
+        ));
+
+        // appending is still fine
+        fs.write_at(log, 5, b", second").unwrap();
+        assert_eq!(sfs.read("/log").unwrap(), b"first, second");
+    }
+
+    #[test]
+    fn rename_refuses_to_move_a_directory_below_itself() {
+        for dot_entries in [true, false] {
+            let mut fs = FileSystem::create(300, "loop").unwrap();
+            if !dot_entries {
+                fs.superblock.feature_ro_compat &= !RO_COMPAT_DOT_ENTRIES;
+            }
+            let mut sfs = Sfs::from(fs);
+            sfs.create_dir("/a").unwrap();
+            sfs.create_dir("/a/b").unwrap();
+            sfs.create_dir("/a/b/c").unwrap();
+            sfs.create_dir("/d").unwrap();
+            let fs = sfs.fs();
+            let root = fs.superblock.root_inode;
+            let c = fs.lookup_path("/a/b/c").unwrap();
+            let d = fs.lookup_path("/d").unwrap();
+            assert!(matches!(
+                fs.rename(root, "a", c, "a"),
+                Err(FsError::DirectoryLoop)
+            ));
+            assert!(matches!(fs.rename(root, "a", root, "a"), Ok(())));
+            fs.rename(root, "a", d, "a").unwrap();
+            assert!(fs.lookup_path("/d/a/b/c").is_ok());
+            assert!(fs.fsck(false).unwrap().is_clean());
+        }
+    }
+
+    #[test]
+    fn measure_io_counts_directory_blocks_as_metadata() {
+        let mut fs = FileSystem::create(300, "io").unwrap();
+        let root = fs.superblock.root_inode;
+        let mode = PermissionsAndType::new(InodeType::File, &[Permission::user_rw()]);
+        let file = Inode::create(mode, 0, 0, 0, 1, 0);
+        let (file_nbr, stats) = fs.measure_io(|fs| fs.create_dir_entry(root, file, "a".into()));
+        let file_nbr = file_nbr.unwrap();
+        assert_eq!((stats.data_reads, stats.data_writes), (0, 0));
+        assert!(stats.metadata_writes > 0);
+
+        let (written, stats) = fs.measure_io(|fs| fs.write_at(file_nbr, 0, &[1; BLOCK_SIZE]));
+        written.unwrap();
+        assert_eq!(stats.data_writes, 1);
+    }
+
+    #[test]
+    fn failed_writes_leave_nothing_behind() {
+        let options = MkfsOptions {
+            journal_blocks: 16,
+            ..Default::default()
+        };
+        let mut sfs = Sfs::from(FileSystem::create_with(300, "txn", &options).unwrap());
+        sfs.write("/file", b"contents").unwrap();
+        let fs = sfs.fs();
+        let file = fs.lookup_path("/file").unwrap();
+        let usage = fs.disk_usage().unwrap();
+
+        // more than fits, the blocks allocated before running out are given back
+        let huge = vec![1; 400 * BLOCK_SIZE];
+        assert!(matches!(fs.write_at(file, 0, &huge), Err(FsError::NoSpace)));
+        assert_eq!(fs.disk_usage().unwrap().free_blocks, usage.free_blocks);
+        assert!(fs.fsck(false).unwrap().is_clean());
+        assert_eq!(sfs.read("/file").unwrap(), b"contents");
+
+        // bigger than the journal, but fits on the disk
+        let fs = sfs.fs();
+        let big = vec![2; 100 * BLOCK_SIZE];
+        assert_eq!(fs.write_at(file, 0, &big).unwrap(), big.len());
+        fs.truncate(file, 10).unwrap();
+        fs.link(file, fs.superblock.root_inode, "other").unwrap();
+        assert!(fs.fsck(false).unwrap().is_clean());
+        assert_eq!(sfs.read("/other").unwrap(), [2; 10]);
+    }
+
+    #[test]
+    fn new_inodes_get_the_current_time() {
+        let mut fs = FileSystem::create(300, "times").unwrap();
+        fs.set_fixed_time(Some(1_700_000_000));
+        let root = fs.superblock.root_inode;
+        let mode = PermissionsAndType::new(InodeType::File, &[Permission::user_rw()]);
+        let new = fs
+            .create_dir_entry(root, Inode::create(mode, 0, 0, 0, 0, 0), "new".into())
+            .unwrap();
+        let stat = fs.stat(new).unwrap();
+        for time in [stat.created, stat.modified, stat.accessed] {
+            assert_eq!(time.secs, 1_700_000_000);
+        }
+
+        // times that are already set are kept
+        let mut copy = Inode::create(mode, 0, 0, 1_000, 0, 0);
+        copy.creation_time = 500;
+        let copy = fs.create_dir_entry(root, copy, "copy".into()).unwrap();
+        let stat = fs.stat(copy).unwrap();
+        assert_eq!(
+            (stat.created.secs, stat.modified.secs, stat.accessed.secs),
+            (500, 1_000, 1_000)
+        );
     }
 }
@@ -0,0 +1,403 @@
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    string::String,
+    vec,
+    vec::Vec,
+};
+use core::fmt::Display;
+
+use crate::{
+    fs::{BlockArrayEntry, FileSystem, FsError, TypeMismatch},
+    health::HealthReport,
+    inode::{Inode, InodeType},
+    inode_bitmap,
+};
+
+/// Something `FileSystem::fsck` found wrong with an image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsckProblem {
+    /// the superblock is inconsistent, see `HealthReport::superblock_problems`
+    Superblock(String),
+    /// `inode` points at a block that is out of range or holds file system metadata
+    BadPointer { inode: u32, block: u32 },
+    /// `block` is used by `inode`, but marked as free in the block array
+    UnmarkedBlock { inode: u32, block: u32 },
+    /// `block` is marked as allocated, but no inode uses it
+    LeakedBlock { block: u32 },
+    /// `block` is used by more than one inode
+    DoubleAllocated { block: u32, inodes: Vec<u32> },
+    /// `inode` is in use, but not reachable from the root directory
+    Orphan { inode: u32 },
+    /// the entry `name` of `dir` links to an inode that isn't in use
+    DanglingEntry { dir: u32, name: String, inode: u32 },
+    /// the type hint of a directory entry doesn't match its inode
+    EntryType(TypeMismatch),
+    /// `inode` doesn't match its checksum, see `Superblock::has_checksums`. Repairing only
+    /// updates the checksum, the rest of the check has to tell whether the inode makes sense.
+    BadChecksum { inode: u32 },
+    /// the inode bitmap says `inode` is free when it isn't, or the other way around
+    InodeBitmap { inode: u32 },
+    /// the superblock records `recorded` free inodes, but the inode blocks have `counted`
+    FreeInodes { recorded: u32, counted: u32 },
+}
+
+impl FsckProblem {
+    /// Whether `FileSystem::fsck` fixes this problem when asked to repair. Everything else needs
+    /// a decision about which data to keep.
+    pub fn is_repairable(&self) -> bool {
+        matches!(
+            self,
+            Self::UnmarkedBlock { .. }
+                | Self::LeakedBlock { .. }
+                | Self::DanglingEntry { .. }
+                | Self::BadChecksum { .. }
+                | Self::InodeBitmap { .. }
+                | Self::FreeInodes { .. }
+        )
+    }
+}
+
+impl Display for FsckProblem {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Superblock(problem) => write!(f, "superblock: {problem}"),
+            Self::BadPointer { inode, block } => {
+                write!(f, "inode {inode}: invalid block pointer {block}")
+            }
+            Self::UnmarkedBlock { inode, block } => {
+                write!(f, "inode {inode}: block {block} is marked as free")
+            }
+            Self::LeakedBlock { block } => write!(f, "block {block}: allocated, but not used"),
+            Self::DoubleAllocated { block, inodes } => {
+                write!(f, "block {block}: used by inodes {inodes:?}")
+            }
+            Self::Orphan { inode } => write!(f, "inode {inode}: not reachable from the root"),
+            Self::DanglingEntry { dir, name, inode } => {
+                write!(
+                    f,
+                    "inode {dir}: entry {name:?} links to unused inode {inode}"
+                )
+            }
+            Self::EntryType(mismatch) => write!(
+                f,
+                "inode {}: entry {:?} is a {:?}, but inode {} is a {:?}",
+                mismatch.dir,
+                mismatch.name,
+                mismatch.entry_type,
+                mismatch.inode,
+                mismatch.inode_type
+            ),
+            Self::BadChecksum { inode } => write!(f, "inode {inode}: checksum mismatch"),
+            Self::InodeBitmap { inode } => {
+                write!(f, "inode {inode}: wrong bit in the inode bitmap")
+            }
+            Self::FreeInodes { recorded, counted } => {
+                write!(
+                    f,
+                    "superblock: {recorded} free inodes recorded, {counted} counted"
+                )
+            }
+        }
+    }
+}
+
+/// The result of `FileSystem::fsck`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FsckReport {
+    pub problems: Vec<FsckProblem>,
+    pub inodes_checked: u32,
+    /// data and indirect pointer blocks used by inodes
+    pub blocks_in_use: u32,
+    /// whether the repairable problems were fixed
+    pub repaired: bool,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// The problems that are still there after the check
+    pub fn remaining(&self) -> impl Iterator<Item = &FsckProblem> {
+        self.problems
+            .iter()
+            .filter(|problem| !self.repaired || !problem.is_repairable())
+    }
+
+    pub(crate) fn build(fs: &mut FileSystem, repair: bool) -> Result<Self, FsError> {
+        let mut report = Self::default();
+        report.problems.extend(
+            HealthReport::check_superblock(fs)
+                .into_iter()
+                .map(FsckProblem::Superblock),
+        );
+
+        let in_use = fs.inodes_in_use()?;
+        report.inodes_checked = in_use.len() as u32;
+        let bad_checksums = report.check_checksums(fs)?;
+
+        let owners = report.check_blocks(fs, &in_use)?;
+        report.blocks_in_use = owners.len() as u32;
+        let dangling = report.check_tree(fs, &in_use)?;
+        let bitmap_ok = report.check_inode_bitmap(fs)?;
+
+        if repair {
+            for inode_nbr in bad_checksums {
+                let inode = fs.read_inode_unchecked(inode_nbr)?;
+                fs.write_inode(inode_nbr, &inode)?;
+            }
+            rebuild_block_arrays(fs, &owners)?;
+            for (dir, name) in dangling {
+                let mut inode = fs.read_inode_unchecked(dir)?;
+                inode.remove_dir_entry(fs, name.as_bytes(), dir)?;
+            }
+            if !bitmap_ok {
+                inode_bitmap::rebuild(fs)?;
+            }
+            report.repaired = true;
+        }
+
+        Ok(report)
+    }
+
+    /// Reports every inode that doesn't match its checksum and returns them
+    fn check_checksums(&mut self, fs: &mut FileSystem) -> Result<Vec<u32>, FsError> {
+        if !fs.superblock.has_checksums() {
+            return Ok(Vec::new());
+        }
+        let mut bad = Vec::new();
+        for inode_nbr in fs.inode_slots()? {
+            if !fs
+                .read_inode_unchecked(inode_nbr)?
+                .verify_checksum(inode_nbr)
+            {
+                self.problems
+                    .push(FsckProblem::BadChecksum { inode: inode_nbr });
+                bad.push(inode_nbr);
+            }
+        }
+        Ok(bad)
+    }
+
+    /// Compares the inode bitmap and the free inode count with the inode blocks, returns whether
+    /// they match
+    fn check_inode_bitmap(&mut self, fs: &mut FileSystem) -> Result<bool, FsError> {
+        if !fs.has_inode_bitmap() {
+            return Ok(true);
+        }
+        let (wrong, counted) = inode_bitmap::check(fs)?;
+        let recorded = fs.superblock.free_inodes;
+        let ok = wrong.is_empty() && recorded == counted;
+        self.problems.extend(
+            wrong
+                .into_iter()
+                .map(|inode| FsckProblem::InodeBitmap { inode }),
+        );
+        if recorded != counted {
+            self.problems
+                .push(FsckProblem::FreeInodes { recorded, counted });
+        }
+        Ok(ok)
+    }
+
+    /// Cross-checks the blocks of every inode in use against the block arrays. Returns the first
+    /// inode using every valid block.
+    fn check_blocks(
+        &mut self,
+        fs: &mut FileSystem,
+        in_use: &BTreeSet<u32>,
+    ) -> Result<BTreeMap<u32, u32>, FsError> {
+        let mut owners = BTreeMap::new();
+        let mut shared: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
+
+        for &inode_nbr in in_use {
+            let inode = fs.read_inode_unchecked(inode_nbr)?;
+            for block in inode_blocks(fs, &inode, inode_nbr, &mut self.problems)? {
+                if let Some(&owner) = owners.get(&block) {
+                    shared
+                        .entry(block)
+                        .or_insert_with(|| vec![owner])
+                        .push(inode_nbr);
+                    continue;
+                }
+                owners.insert(block, inode_nbr);
+                if fs.block_type(block)? == BlockArrayEntry::Unused {
+                    self.problems.push(FsckProblem::UnmarkedBlock {
+                        inode: inode_nbr,
+                        block,
+                    });
+                }
+            }
+        }
+
+        for (block, inodes) in shared {
+            self.problems
+                .push(FsckProblem::DoubleAllocated { block, inodes });
+        }
+        for block in fs.reserved_blocks().end..fs.superblock.total_blocks {
+            if fs.block_type(block)? == BlockArrayEntry::Allocated
+                && !owners.contains_key(&block)
+                && !fs.is_reserved(block)
+                && !fs.snapshots().holds(block)
+            {
+                self.problems.push(FsckProblem::LeakedBlock { block });
+            }
+        }
+
+        Ok(owners)
+    }
+
+    /// Walks the directory tree from the root, reporting dangling entries, wrong type hints and
+    /// orphaned inodes. Returns the directory and name of every dangling entry.
+    fn check_tree(
+        &mut self,
+        fs: &mut FileSystem,
+        in_use: &BTreeSet<u32>,
+    ) -> Result<Vec<(u32, String)>, FsError> {
+        let mut dangling = Vec::new();
+        let mut reachable = BTreeSet::new();
+        let mut pending = vec![fs.superblock.root_inode];
+        // the quota inode has no directory entry, see `FileSystem::set_quota`
+        if fs.superblock.quota_inode != 0 {
+            pending.push(fs.superblock.quota_inode);
+        }
+
+        while let Some(dir) = pending.pop() {
+            if !reachable.insert(dir) {
+                continue;
+            }
+            let inode = fs.read_inode_unchecked(dir)?;
+            if inode.type_and_permission.get_type() != InodeType::Directory {
+                continue;
+            }
+            // a directory with bad block pointers was already reported
+            let Ok(entries) = inode.read_dir_entries(fs) else {
+                continue;
+            };
+
+            for entry in entries {
+                if !in_use.contains(&entry.inode) {
+                    self.problems.push(FsckProblem::DanglingEntry {
+                        dir,
+                        name: entry.get_name(),
+                        inode: entry.inode,
+                    });
+                    dangling.push((dir, entry.get_name()));
+                    continue;
+                }
+                let inode_type = fs
+                    .read_inode_unchecked(entry.inode)?
+                    .type_and_permission
+                    .get_type();
+                if entry.get_type() != inode_type {
+                    self.problems.push(FsckProblem::EntryType(TypeMismatch {
+                        dir,
+                        name: entry.get_name(),
+                        inode: entry.inode,
+                        entry_type: entry.get_type(),
+                        inode_type,
+                    }));
+                }
+                pending.push(entry.inode);
+            }
+        }
+
+        self.problems.extend(
+            in_use
+                .difference(&reachable)
+                .map(|&inode| FsckProblem::Orphan { inode }),
+        );
+        Ok(dangling)
+    }
+}
+
+impl Display for FsckReport {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for problem in &self.problems {
+            if self.repaired && problem.is_repairable() {
+                writeln!(f, "{problem} (repaired)")?;
+            } else {
+                writeln!(f, "{problem}")?;
+            }
+        }
+        writeln!(
+            f,
+            "{} inodes, {} blocks in use, {} problems, {} left",
+            self.inodes_checked,
+            self.blocks_in_use,
+            self.problems.len(),
+            self.remaining().count()
+        )
+    }
+}
+
+/// Whether an inode may point at `block_id`
+fn is_valid_pointer(fs: &mut FileSystem, block_id: u32) -> Result<bool, FsError> {
+    if block_id == 1 /* superblock */
+        || block_id >= fs.superblock.total_blocks
+        || fs.is_reserved(block_id)
+    {
+        return Ok(false);
+    }
+    Ok(matches!(
+        fs.block_type(block_id)?,
+        BlockArrayEntry::Allocated | BlockArrayEntry::Unused
+    ))
+}
+
+/// All valid data, indirect pointer and xattr blocks of `inode`, reporting the invalid ones. Unlike
+/// `Inode::block_map`, this doesn't stop at the first hole.
+pub(crate) fn inode_blocks(
+    fs: &mut FileSystem,
+    inode: &Inode,
+    inode_nbr: u32,
+    problems: &mut Vec<FsckProblem>,
+) -> Result<Vec<u32>, FsError> {
+    let mut blocks = Vec::new();
+    // (block, levels of indirection below it)
+    let mut pending: Vec<(u32, u8)> = inode.block_pointers.iter().map(|&b| (b, 0)).collect();
+    pending.push((inode.singly_indirect_block_pointer, 1));
+    pending.push((inode.doubly_indirect_block_pointer, 2));
+    pending.push((inode.xattr_block, 0));
+
+    while let Some((block, depth)) = pending.pop() {
+        if block == 0 {
+            continue;
+        }
+        if !is_valid_pointer(fs, block)? {
+            problems.push(FsckProblem::BadPointer {
+                inode: inode_nbr,
+                block,
+            });
+            continue;
+        }
+        blocks.push(block);
+        if depth > 0 {
+            let data = fs.dump_block(block)?;
+            for ptr in data.chunks_exact(4) {
+                let ptr = u32::from_le_bytes(ptr.try_into().expect("chunks of 4 bytes"));
+                pending.push((ptr, depth - 1));
+            }
+        }
+    }
+
+    Ok(blocks)
+}
+
+/// Marks exactly the blocks in `owners` and the ones snapshots hold on to as allocated, leaving
+/// inode blocks, the blocks reserved at mkfs time and the hash tree alone
+fn rebuild_block_arrays(fs: &mut FileSystem, owners: &BTreeMap<u32, u32>) -> Result<(), FsError> {
+    for block in fs.reserved_blocks().end..fs.superblock.total_blocks {
+        if fs.is_reserved(block) {
+            continue;
+        }
+        let typ = fs.block_type(block)?;
+        let used = owners.contains_key(&block) || fs.snapshots().holds(block);
+        match typ {
+            BlockArrayEntry::Unused if used => fs.mark_block(block, BlockArrayEntry::Allocated)?,
+            BlockArrayEntry::Allocated if !used => fs.mark_block(block, BlockArrayEntry::Unused)?,
+            _ => {}
+        }
+    }
+    fs.reset_free_hints()
+}
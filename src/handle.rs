@@ -0,0 +1,208 @@
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::io::{Read, Seek, SeekFrom, Write};
+
+#[cfg(feature = "std")]
+use crate::fs::BLOCK_SIZE;
+use crate::{
+    directory::DirEntry,
+    fs::{FileSystem, FsError},
+    inode::InodeType,
+};
+
+/// This is the last extent of the file
+pub const FIEMAP_EXTENT_LAST: u32 = 0x1;
+/// The extent was put together from single block pointers, sfs has no on-disk extents
+pub const FIEMAP_EXTENT_MERGED: u32 = 0x1000;
+
+/// One extent as returned by `FileHandle::fiemap`, modeled after Linux' `struct fiemap_extent`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FiemapExtent {
+    /// byte offset of the extent in the file
+    pub logical: u64,
+    /// the first block of the extent on disk
+    pub physical_block: u32,
+    /// length of the extent in bytes, always a multiple of the block size
+    pub length: u64,
+    /// `FIEMAP_EXTENT_*` flags
+    pub flags: u32,
+}
+
+/// An open inode, see `FileSystem::open`. Regular files can be used through `Read`, `Write` and
+/// `Seek` like a `std::fs::File`, starting at offset 0.
+#[cfg(feature = "std")]
+pub struct FileHandle<'a> {
+    fs: &'a mut FileSystem,
+    inode_nbr: u32,
+    /// the offset the next read or write starts at
+    pos: u64,
+    /// whether anything was written, so closing the handle runs the `on_close` hooks
+    written: bool,
+}
+
+#[cfg(feature = "std")]
+impl<'a> FileHandle<'a> {
+    pub(crate) fn new(fs: &'a mut FileSystem, inode_nbr: u32) -> Result<Self, FsError> {
+        // fails early if the inode number can't be valid
+        fs.read_inode(inode_nbr)?;
+        Ok(Self {
+            fs,
+            inode_nbr,
+            pos: 0,
+            written: false,
+        })
+    }
+
+    pub fn inode_nbr(&self) -> u32 {
+        self.inode_nbr
+    }
+
+    /// The length of the file in bytes
+    pub fn len(&mut self) -> Result<u64, FsError> {
+        let inode = self.fs.read_inode(self.inode_nbr)?;
+        Ok(inode.file_size(self.fs)? as u64)
+    }
+
+    pub fn is_empty(&mut self) -> Result<bool, FsError> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Truncates or extends the file to `len` bytes, like `std::fs::File::set_len`. The position
+    /// doesn't change. See `Inode::truncate`.
+    pub fn set_len(&mut self, len: u64) -> Result<(), FsError> {
+        self.fs.truncate(self.inode_nbr, len)?;
+        self.written = true;
+        Ok(())
+    }
+
+    /// Runs the `on_close` hooks if anything was written through this handle, see
+    /// `FileSystem::close`. Dropping the handle does the same, but can't report errors.
+    pub fn close(mut self) -> Result<(), FsError> {
+        if core::mem::take(&mut self.written) {
+            self.fs.close(self.inode_nbr)?;
+        }
+        Ok(())
+    }
+
+    /// The physical layout of the file as a list of extents, ordered by their logical offset
+    pub fn fiemap(&mut self) -> Result<Vec<FiemapExtent>, FsError> {
+        let inode = self.fs.read_inode(self.inode_nbr)?;
+        let extents = inode.block_extents(self.fs)?;
+
+        let last = extents.len().saturating_sub(1);
+        Ok(extents
+            .into_iter()
+            .enumerate()
+            .map(|(i, extent)| FiemapExtent {
+                logical: extent.logical as u64 * BLOCK_SIZE as u64,
+                physical_block: extent.physical,
+                length: extent.length as u64 * BLOCK_SIZE as u64,
+                flags: if i == last {
+                    FIEMAP_EXTENT_MERGED | FIEMAP_EXTENT_LAST
+                } else {
+                    FIEMAP_EXTENT_MERGED
+                },
+            })
+            .collect())
+    }
+}
+
+#[cfg(feature = "std")]
+impl Read for FileHandle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let inode = self.fs.read_inode(self.inode_nbr)?;
+        if inode.type_and_permission.get_type() != InodeType::File {
+            return Err(FsError::NotAFile.into());
+        }
+
+        let size = inode.size();
+        if self.pos >= size {
+            return Ok(0);
+        }
+        let len = (size - self.pos).min(buf.len() as u64) as usize;
+        let read = inode.read(self.pos as usize, &mut buf[..len], self.fs)?;
+        self.pos += read as u64;
+        self.fs.mark_accessed(self.inode_nbr)?;
+        Ok(read)
+    }
+}
+
+#[cfg(feature = "std")]
+impl Write for FileHandle<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let offset = usize::try_from(self.pos).map_err(|_| FsError::FileTooLarge)?;
+        let written = self.fs.write_at(self.inode_nbr, offset, buf)?;
+        self.pos += written as u64;
+        self.written = true;
+        Ok(written)
+    }
+
+    /// Nothing is buffered in the handle. Use `FileSystem::sync` to make writes durable.
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl Seek for FileHandle<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => self.len()?.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        self.pos = new_pos.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for FileHandle<'_> {
+    fn drop(&mut self) {
+        if self.written {
+            // there's no way to report the error here; use `close` to get it
+            let _ = self.fs.close(self.inode_nbr);
+        }
+    }
+}
+
+/// An open directory, see `FileSystem::open_dir`. Changes made through `fs` (creating, renaming
+/// and unlinking entries) can be made durable one directory at a time with `sync`, without
+/// syncing the whole file system, for programs that fsync directories (like mail servers do).
+pub struct DirHandle<'a> {
+    fs: &'a mut FileSystem,
+    inode_nbr: u32,
+}
+
+impl<'a> DirHandle<'a> {
+    pub(crate) fn new(fs: &'a mut FileSystem, inode_nbr: u32) -> Result<Self, FsError> {
+        if fs.read_inode(inode_nbr)?.type_and_permission.get_type() != InodeType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(Self { fs, inode_nbr })
+    }
+
+    pub fn inode_nbr(&self) -> u32 {
+        self.inode_nbr
+    }
+
+    /// The file system to make the changes through
+    pub fn fs(&mut self) -> &mut FileSystem {
+        self.fs
+    }
+
+    pub fn entries(&mut self) -> Result<Vec<DirEntry>, FsError> {
+        let dir = self.fs.read_inode(self.inode_nbr)?;
+        dir.read_dir_entries(self.fs)
+    }
+
+    /// Makes the entries of this directory durable as they are now, see `FileSystem::sync_dir`
+    pub fn sync(&mut self) -> Result<(), FsError> {
+        self.fs.sync_dir(self.inode_nbr)
+    }
+}
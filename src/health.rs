@@ -0,0 +1,208 @@
+use alloc::{
+    collections::BTreeSet,
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::fmt::Display;
+
+use crate::{
+    directory::DirectoryIterator,
+    fs::{BlockArrayEntry, FileSystem, FsError, BLOCK_SIZE},
+    inode::InodeType,
+};
+
+/// The number of in-use inodes whose block pointers are checked
+pub const HEALTH_SAMPLE_INODES: u32 = 64;
+
+/// A quick summary of the state of an image, see `FileSystem::health`. Meant for monitoring many
+/// images: it only reads metadata and a sample of inodes, never file contents.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HealthReport {
+    /// everything wrong with the superblock, empty if it is fine
+    pub superblock_problems: Vec<String>,
+    /// whether the journal holds a transaction that still has to be replayed, which only happens
+    /// if the image was opened read-only after a crash
+    pub journal_dirty: bool,
+    pub free_blocks: u32,
+    /// the number of runs of contiguous free blocks
+    pub free_runs: u32,
+    pub largest_free_run: u32,
+    /// how scattered the free space is: 0 if it is all in one run, close to 1 if every free block
+    /// stands alone
+    pub fragmentation: f64,
+    pub inodes_in_use: u32,
+    /// in-use inodes that aren't reachable from the root directory
+    pub orphans: u32,
+    /// sfs has no checksums, so the sample instead checks that the block pointers of some inodes
+    /// point at allocated data blocks
+    pub sampled_inodes: u32,
+    /// sampled inodes with pointers to free, metadata or out-of-range blocks
+    pub bad_inodes: u32,
+    /// 100 for a healthy image, lower the more problems were found
+    pub score: u8,
+}
+
+impl HealthReport {
+    pub(crate) fn build(fs: &mut FileSystem) -> Result<Self, FsError> {
+        let mut report = Self {
+            superblock_problems: Self::check_superblock(fs),
+            journal_dirty: fs.journal_dirty()?,
+            ..Default::default()
+        };
+        report.scan_free_space(fs)?;
+        report.scan_inodes(fs)?;
+        report.score = report.compute_score();
+        Ok(report)
+    }
+
+    pub(crate) fn check_superblock(fs: &mut FileSystem) -> Vec<String> {
+        let mut problems = Vec::new();
+        let total_blocks = fs.superblock.total_blocks;
+
+        let features = fs.features();
+        if !features.can_write() {
+            problems.push("features this implementation doesn't know are enabled".to_string());
+        }
+        if fs.superblock.earliest_free >= total_blocks {
+            problems.push(format!(
+                "earliest free block {} is past the end",
+                fs.superblock.earliest_free
+            ));
+        }
+        if fs.superblock.inode_table_start + fs.superblock.inode_table_blocks > total_blocks {
+            problems.push("the inode table is past the end".to_string());
+        }
+        if fs.superblock.dir_hash != 0 && fs.dir_hash().is_none() {
+            problems.push(format!("unknown directory hash {}", fs.superblock.dir_hash));
+        }
+        if fs.dump_block(total_blocks.saturating_sub(1)).is_err() {
+            problems.push("the image is shorter than the superblock says".to_string());
+        }
+        match fs.read_inode(fs.superblock.root_inode) {
+            Ok(root) if root.type_and_permission.get_type() == InodeType::Directory => {}
+            Ok(_) => problems.push("the root inode isn't a directory".to_string()),
+            Err(e) => problems.push(format!("the root inode can't be read: {e}")),
+        }
+
+        problems
+    }
+
+    fn scan_free_space(&mut self, fs: &mut FileSystem) -> Result<(), FsError> {
+        let extents = fs.free_extents()?;
+        self.free_blocks = extents.iter().map(|extent| extent.length).sum();
+        self.free_runs = extents.len() as u32;
+        self.largest_free_run = extents
+            .iter()
+            .map(|extent| extent.length)
+            .max()
+            .unwrap_or(0);
+
+        if self.free_blocks > 0 {
+            self.fragmentation = 1.0 - self.largest_free_run as f64 / self.free_blocks as f64;
+        }
+        Ok(())
+    }
+
+    fn scan_inodes(&mut self, fs: &mut FileSystem) -> Result<(), FsError> {
+        let in_use = fs.inodes_in_use()?;
+        self.inodes_in_use = in_use.len() as u32;
+
+        let mut reachable = BTreeSet::new();
+        let mut pending = vec![fs.superblock.root_inode];
+        // the quota inode has no directory entry, see `FileSystem::set_quota`
+        if fs.superblock.quota_inode != 0 {
+            pending.push(fs.superblock.quota_inode);
+        }
+        while let Some(inode_nbr) = pending.pop() {
+            if !reachable.insert(inode_nbr) {
+                continue;
+            }
+            let Ok(inode) = fs.read_inode(inode_nbr) else {
+                continue;
+            };
+            if inode.type_and_permission.get_type() == InodeType::Directory {
+                pending.extend(DirectoryIterator::new(inode, fs).map(|entry| entry.inode));
+            }
+        }
+        self.orphans = in_use
+            .iter()
+            .filter(|inode_nbr| !reachable.contains(inode_nbr))
+            .count() as u32;
+
+        // spread the sample evenly over all inodes
+        let stride = in_use.len().div_ceil(HEALTH_SAMPLE_INODES as usize).max(1);
+        for &inode_nbr in in_use.iter().step_by(stride) {
+            self.sampled_inodes += 1;
+            if !Self::pointers_valid(fs, inode_nbr)? {
+                self.bad_inodes += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn pointers_valid(fs: &mut FileSystem, inode_nbr: u32) -> Result<bool, FsError> {
+        let inode = fs.read_inode(inode_nbr)?;
+        let blocks = match inode.block_map(fs) {
+            Ok(blocks) => blocks,
+            Err(FsError::Corrupted) => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        for block_id in blocks {
+            if block_id == 1 /* superblock */
+                || fs.is_reserved(block_id)
+                || fs.block_type(block_id)? != BlockArrayEntry::Allocated
+            {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn compute_score(&self) -> u8 {
+        let penalty = self.superblock_problems.len() as f64 * 25.0
+            + self.orphans.min(20) as f64
+            + self.bad_inodes.min(3) as f64 * 10.0
+            + self.fragmentation * 10.0;
+        // `f64::round` needs std, the score is never negative
+        ((100.0 - penalty).clamp(0.0, 100.0) + 0.5) as u8
+    }
+}
+
+impl Display for HealthReport {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if self.superblock_problems.is_empty() {
+            writeln!(f, "superblock: ok")?;
+        }
+        for problem in &self.superblock_problems {
+            writeln!(f, "superblock: {problem}")?;
+        }
+        writeln!(
+            f,
+            "journal: {}",
+            if self.journal_dirty { "dirty" } else { "clean" }
+        )?;
+        writeln!(
+            f,
+            "free space: {} blocks ({} bytes) in {} runs, largest run {} blocks, fragmentation {:.2}",
+            self.free_blocks,
+            self.free_blocks as usize * BLOCK_SIZE,
+            self.free_runs,
+            self.largest_free_run,
+            self.fragmentation
+        )?;
+        writeln!(
+            f,
+            "inodes: {} in use, {} orphaned",
+            self.inodes_in_use, self.orphans
+        )?;
+        writeln!(
+            f,
+            "sample: {} of {} inodes have bad block pointers",
+            self.bad_inodes, self.sampled_inodes
+        )?;
+        writeln!(f, "score: {}/100", self.score)
+    }
+}
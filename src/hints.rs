@@ -0,0 +1,171 @@
+use alloc::vec::Vec;
+use core::fmt::Display;
+
+use crate::{
+    fs::FsError,
+    inode::{
+        INODE_FLAG_COLD, INODE_FLAG_COMPRESS, INODE_FLAG_HOT, INODE_FLAG_NOCOMPRESS,
+        INODE_FLAG_RANDOM, INODE_FLAG_SEQUENTIAL,
+    },
+    zones::AllocZone,
+};
+
+/// The extended attribute the storage hints of an inode can be read and set through. It isn't
+/// stored in the xattr block, but in the inode flags, see `StorageHints::parse`.
+pub const STORAGE_HINTS_XATTR: &str = "sfs.hints";
+
+/// The longest run of blocks at the end of the image a cold file is started at the beginning of,
+/// so the blocks it gets after that follow its first one
+pub(crate) const COLD_RUN_BLOCKS: u32 = 1024;
+
+/// How often a file is used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Temperature {
+    Hot,
+    Cold,
+}
+
+/// How a file is read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessPattern {
+    Sequential,
+    Random,
+}
+
+/// How a file is expected to be used, see `FileSystem::set_storage_hints`. `None` leaves the
+/// choice to the file system.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StorageHints {
+    /// hot files are kept next to the metadata, cold ones at the end of the image, the slow tail
+    /// of a spinning disk, and their data is read past the block cache
+    pub temperature: Option<Temperature>,
+    /// sequential files are kept in one run of blocks and their data is read past the block
+    /// cache, since a file read from start to end doesn't read the same block twice
+    pub access: Option<AccessPattern>,
+    /// whether the contents should be compressed. Recorded for compressing writers; this
+    /// implementation stores every file as it is.
+    pub compress: Option<bool>,
+}
+
+impl StorageHints {
+    /// Every inode flag the hints are stored in
+    pub(crate) const FLAGS: u32 = INODE_FLAG_HOT
+        | INODE_FLAG_COLD
+        | INODE_FLAG_SEQUENTIAL
+        | INODE_FLAG_RANDOM
+        | INODE_FLAG_COMPRESS
+        | INODE_FLAG_NOCOMPRESS;
+
+    /// Every hint, by its name in `STORAGE_HINTS_XATTR`
+    const NAMES: [(&'static str, u32); 6] = [
+        ("hot", INODE_FLAG_HOT),
+        ("cold", INODE_FLAG_COLD),
+        ("sequential", INODE_FLAG_SEQUENTIAL),
+        ("random", INODE_FLAG_RANDOM),
+        ("compress", INODE_FLAG_COMPRESS),
+        ("nocompress", INODE_FLAG_NOCOMPRESS),
+    ];
+
+    pub(crate) fn from_flags(flags: u32) -> Self {
+        let pick = |a: u32, b: u32| match (flags & a != 0, flags & b != 0) {
+            (true, false) => Some(true),
+            (false, true) => Some(false),
+            // both bits of a pair are never written, and mean as little as none
+            _ => None,
+        };
+        Self {
+            temperature: pick(INODE_FLAG_HOT, INODE_FLAG_COLD).map(|hot| {
+                if hot {
+                    Temperature::Hot
+                } else {
+                    Temperature::Cold
+                }
+            }),
+            access: pick(INODE_FLAG_SEQUENTIAL, INODE_FLAG_RANDOM).map(|sequential| {
+                if sequential {
+                    AccessPattern::Sequential
+                } else {
+                    AccessPattern::Random
+                }
+            }),
+            compress: pick(INODE_FLAG_COMPRESS, INODE_FLAG_NOCOMPRESS),
+        }
+    }
+
+    pub(crate) fn to_flags(self) -> u32 {
+        let temperature = match self.temperature {
+            Some(Temperature::Hot) => INODE_FLAG_HOT,
+            Some(Temperature::Cold) => INODE_FLAG_COLD,
+            None => 0,
+        };
+        let access = match self.access {
+            Some(AccessPattern::Sequential) => INODE_FLAG_SEQUENTIAL,
+            Some(AccessPattern::Random) => INODE_FLAG_RANDOM,
+            None => 0,
+        };
+        let compress = match self.compress {
+            Some(true) => INODE_FLAG_COMPRESS,
+            Some(false) => INODE_FLAG_NOCOMPRESS,
+            None => 0,
+        };
+        temperature | access | compress
+    }
+
+    /// Parses the value of `STORAGE_HINTS_XATTR`: a comma separated list of `hot`, `cold`,
+    /// `sequential`, `random`, `compress` and `nocompress`, like `cold,sequential`. Unknown names
+    /// and both hints of a pair are `FsError::InvalidValue`.
+    pub fn parse(value: &str) -> Result<Self, FsError> {
+        let mut flags = 0;
+        for name in value
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            let (_, flag) = Self::NAMES
+                .iter()
+                .find(|(known, _)| *known == name)
+                .ok_or(FsError::InvalidValue)?;
+            flags |= flag;
+        }
+
+        let hints = Self::from_flags(flags);
+        if hints.to_flags() != flags {
+            return Err(FsError::InvalidValue);
+        }
+        Ok(hints)
+    }
+
+    /// Whether no hint is set
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// The zone a block of the file that would go into `zone` is allocated in instead, `None` for
+    /// the end of the image
+    pub(crate) fn zone(&self, zone: AllocZone) -> Option<AllocZone> {
+        match (self.temperature, self.access) {
+            (Some(Temperature::Cold), _) => None,
+            (Some(Temperature::Hot), _) => Some(AllocZone::SmallFiles),
+            (None, Some(AccessPattern::Sequential)) => Some(AllocZone::LargeFiles),
+            _ => Some(zone),
+        }
+    }
+
+    /// Whether reads of the file's data should not displace other blocks in the block cache
+    pub(crate) fn bypass_cache(&self) -> bool {
+        self.temperature == Some(Temperature::Cold)
+            || self.access == Some(AccessPattern::Sequential)
+    }
+}
+
+impl Display for StorageHints {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let flags = self.to_flags();
+        let names = Self::NAMES
+            .iter()
+            .filter(|(_, flag)| flags & flag != 0)
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>();
+        f.write_str(&names.join(","))
+    }
+}
@@ -0,0 +1,97 @@
+use alloc::{boxed::Box, vec::Vec};
+use core::fmt::Debug;
+
+use crate::fs::{FileSystem, FsError};
+
+/// Code of the embedder that sees the contents of files as they are written, registered with
+/// `FileSystem::add_hook`. Hooks run in the order they were added, after the write or close
+/// succeeded. An error of a hook stops the hooks after it and is returned by the call that ran
+/// them, but doesn't undo the write: a validator that rejects a file has to remove or truncate
+/// it itself, e.g. in `on_close`.
+///
+/// `fs` is the file system the hook was added to. What a hook writes through it doesn't run the
+/// hooks again.
+pub trait FileHook: Send {
+    /// `data` was written at byte `offset` of the file `inode_nbr`, by `FileSystem::write_at`
+    /// or everything built on it (`FileHandle`, `Sfs::write`, a mounted image)
+    fn on_write(
+        &mut self,
+        fs: &mut FileSystem,
+        inode_nbr: u32,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<(), FsError> {
+        let _ = (fs, inode_nbr, offset, data);
+        Ok(())
+    }
+
+    /// A writer of the file `inode_nbr` is done, see `FileSystem::close`. The contents are
+    /// complete, so this is the place to hash, validate or index the whole file.
+    fn on_close(&mut self, fs: &mut FileSystem, inode_nbr: u32) -> Result<(), FsError> {
+        let _ = (fs, inode_nbr);
+        Ok(())
+    }
+}
+
+/// Identifies a hook added with `FileSystem::add_hook`, to remove it again
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct HookId(u64);
+
+/// The registered hooks of a file system
+#[derive(Default)]
+pub(crate) struct Hooks {
+    next: u64,
+    hooks: Vec<(HookId, Box<dyn FileHook>)>,
+}
+
+impl Debug for Hooks {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_list()
+            .entries(self.hooks.iter().map(|(id, _)| id))
+            .finish()
+    }
+}
+
+impl Hooks {
+    pub(crate) fn add(&mut self, hook: Box<dyn FileHook>) -> HookId {
+        let id = HookId(self.next);
+        self.next += 1;
+        self.hooks.push((id, hook));
+        id
+    }
+
+    pub(crate) fn remove(&mut self, id: HookId) -> Option<Box<dyn FileHook>> {
+        let index = self.hooks.iter().position(|(hook_id, _)| *hook_id == id)?;
+        Some(self.hooks.remove(index).1)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    /// Runs `event` on every hook of `fs`. The hooks are taken out of `fs` meanwhile, so they
+    /// can use it without running themselves again; hooks added by them are kept.
+    pub(crate) fn run(
+        fs: &mut FileSystem,
+        mut event: impl FnMut(&mut dyn FileHook, &mut FileSystem) -> Result<(), FsError>,
+    ) -> Result<(), FsError> {
+        let next = fs.hooks_mut().next;
+        let mut hooks = core::mem::replace(
+            fs.hooks_mut(),
+            Hooks {
+                next,
+                hooks: Vec::new(),
+            },
+        );
+        let result = hooks
+            .hooks
+            .iter_mut()
+            .try_for_each(|(_, hook)| event(hook.as_mut(), fs));
+
+        let added = core::mem::take(fs.hooks_mut());
+        hooks.next = added.next;
+        hooks.hooks.extend(added.hooks);
+        *fs.hooks_mut() = hooks;
+        result
+    }
+}
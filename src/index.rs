@@ -0,0 +1,457 @@
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+
+use crate::{
+    directory::{is_dot_name, DirEntry},
+    fs::{FileSystem, FsError},
+    inode::{Inode, InodeType, Permission, PermissionsAndType},
+};
+
+/// The file in the root directory the search index is kept in, see `FileSystem::enable_index`
+pub const INDEX_FILE: &str = ".index";
+/// Only the start of a file is tokenized, so a huge log doesn't blow up the index
+pub const MAX_INDEXED_BYTES: usize = 1 << 20;
+
+const INDEX_MAGIC: &[u8; 8] = b"sfsindex";
+const INDEX_VERSION: u8 = 1;
+/// The header byte that is set while the file is older than the index in memory
+const DIRTY_OFFSET: usize = 8;
+const FLAG_CONTENTS: u8 = 1 << 0;
+
+/// Words shorter than this aren't worth indexing
+const MIN_TOKEN_LEN: usize = 2;
+/// Longer words are left out, they are rarely searched for and mostly encoded data
+const MAX_TOKEN_LEN: usize = 32;
+
+/// Something `FileSystem::search` found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchHit {
+    pub inode: u32,
+    /// absolute path, starting with `/`
+    pub path: String,
+    /// every word of the query is part of the name
+    pub name_match: bool,
+    /// the contents have every word of the query (or a word starting with it)
+    pub content_match: bool,
+}
+
+/// The lowercased words of `text`: runs of letters and digits
+pub(crate) fn tokens(text: &str) -> BTreeSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| (MIN_TOKEN_LEN..=MAX_TOKEN_LEN).contains(&word.len()))
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// The names of every entry and optionally the words in every file, kept in memory while the
+/// image is open and written to `INDEX_FILE` by `FileSystem::sync`
+#[derive(Debug, Default)]
+pub(crate) struct SearchIndex {
+    /// the inode of `INDEX_FILE`, 0 for an index that is only kept in memory
+    file: u32,
+    contents: bool,
+    /// the file has its dirty byte set or is older than this
+    dirty: bool,
+    /// the file couldn't be read or is older than the image, the index has to be rebuilt before
+    /// it can be used or updated
+    stale: bool,
+    /// the inode of every entry by its directory and name, with whether it is a directory
+    entries: BTreeMap<(u32, String), (u32, bool)>,
+    /// where every directory is, to build paths
+    dirs: BTreeMap<u32, (u32, String)>,
+    /// the files with each word
+    words: BTreeMap<String, BTreeSet<u32>>,
+    /// the words of each file, to take them out of `words` when it changes
+    file_words: BTreeMap<u32, BTreeSet<String>>,
+}
+
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8], FsError> {
+    if data.len() < len {
+        return Err(FsError::Corrupted);
+    }
+    let (head, rest) = data.split_at(len);
+    *data = rest;
+    Ok(head)
+}
+
+fn take_u32(data: &mut &[u8]) -> Result<u32, FsError> {
+    Ok(u32::from_le_bytes(take(data, 4)?.try_into().unwrap()))
+}
+
+fn take_string(data: &mut &[u8], len: usize) -> Result<String, FsError> {
+    String::from_utf8(take(data, len)?.to_vec()).map_err(|_| FsError::Corrupted)
+}
+
+impl SearchIndex {
+    /// The index of an open image, `None` if it has none. The index isn't worth failing to open
+    /// an image over, an unreadable one is rebuilt on first use.
+    pub(crate) fn load(fs: &mut FileSystem) -> Option<Self> {
+        let root = fs.superblock.root_inode;
+        let file = fs.lookup(root, INDEX_FILE).ok()?;
+        let index = fs
+            .read_inode(file)
+            .and_then(|inode| inode.read_all(fs))
+            .and_then(|data| Self::decode(&data))
+            .unwrap_or(Self {
+                stale: true,
+                ..Default::default()
+            });
+        Some(Self { file, ..index })
+    }
+
+    fn decode(mut data: &[u8]) -> Result<Self, FsError> {
+        let data = &mut data;
+        if take(data, 8)? != INDEX_MAGIC {
+            return Err(FsError::Corrupted);
+        }
+        let [dirty, version, flags] = take(data, 3)? else {
+            unreachable!()
+        };
+        if *version != INDEX_VERSION {
+            return Err(FsError::Corrupted);
+        }
+        let mut index = Self {
+            contents: flags & FLAG_CONTENTS != 0,
+            dirty: *dirty != 0,
+            stale: *dirty != 0,
+            ..Default::default()
+        };
+        if index.stale {
+            return Ok(index);
+        }
+
+        for _ in 0..take_u32(data)? {
+            let parent = take_u32(data)?;
+            let inode = take_u32(data)?;
+            let [is_dir, len] = take(data, 2)? else {
+                unreachable!()
+            };
+            let name = take_string(data, *len as usize)?;
+            index.add(parent, name, inode, *is_dir != 0);
+        }
+        for _ in 0..take_u32(data)? {
+            let len = take(data, 1)?[0] as usize;
+            let word = take_string(data, len)?;
+            for _ in 0..take_u32(data)? {
+                let inode = take_u32(data)?;
+                index.words.entry(word.clone()).or_default().insert(inode);
+                index
+                    .file_words
+                    .entry(inode)
+                    .or_default()
+                    .insert(word.clone());
+            }
+        }
+        Ok(index)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut data = INDEX_MAGIC.to_vec();
+        let flags = if self.contents { FLAG_CONTENTS } else { 0 };
+        data.extend([0, INDEX_VERSION, flags]);
+
+        data.extend((self.entries.len() as u32).to_le_bytes());
+        for ((parent, name), (inode, is_dir)) in &self.entries {
+            data.extend(parent.to_le_bytes());
+            data.extend(inode.to_le_bytes());
+            // names are shorter than a directory entry, less than 256 bytes
+            data.extend([*is_dir as u8, name.len() as u8]);
+            data.extend(name.as_bytes());
+        }
+        data.extend((self.words.len() as u32).to_le_bytes());
+        for (word, inodes) in &self.words {
+            data.push(word.len() as u8);
+            data.extend(word.as_bytes());
+            data.extend((inodes.len() as u32).to_le_bytes());
+            for inode in inodes {
+                data.extend(inode.to_le_bytes());
+            }
+        }
+        data
+    }
+
+    /// Indexes everything reachable from the root directory, to be saved to `file`
+    pub(crate) fn build(fs: &mut FileSystem, file: u32, contents: bool) -> Result<Self, FsError> {
+        let mut index = Self {
+            file,
+            contents,
+            // the file has an older index, if any
+            dirty: true,
+            ..Default::default()
+        };
+        let mut pending = vec![fs.superblock.root_inode];
+        let mut seen = BTreeSet::new();
+        while let Some(dir_nbr) = pending.pop() {
+            // a directory linked into itself would never end
+            if !seen.insert(dir_nbr) {
+                continue;
+            }
+            let dir = fs.read_inode(dir_nbr)?;
+            for entry in dir.read_dir_entries(fs)? {
+                let typ = entry.get_type();
+                index.add(
+                    dir_nbr,
+                    entry.get_name(),
+                    entry.inode,
+                    typ == InodeType::Directory,
+                );
+                match typ {
+                    InodeType::Directory => pending.push(entry.inode),
+                    InodeType::File
+                        if contents
+                            && entry.inode != file
+                            && !index.file_words.contains_key(&entry.inode) =>
+                    {
+                        index.index_contents(fs, entry.inode)?;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(index)
+    }
+
+    /// Writes the index to `INDEX_FILE` if it changed
+    pub(crate) fn save(&mut self, fs: &mut FileSystem) -> Result<(), FsError> {
+        if !self.dirty || self.stale || self.file == 0 {
+            return Ok(());
+        }
+        let data = self.encode();
+        let mut inode = fs.read_inode(self.file)?;
+        // not through `FileSystem::write_at`, the hooks have no business with the index
+        inode.file_write(&data, fs, self.file)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Sets the dirty byte of the file before the first change since it was written, so a crash
+    /// before the next `save` makes the next mount rebuild it. Also done while the index is
+    /// stale: the file must not claim to match an image that changed since.
+    fn touch(&mut self, fs: &mut FileSystem) -> Result<(), FsError> {
+        if self.dirty || self.file == 0 {
+            self.dirty = true;
+            return Ok(());
+        }
+        let mut inode = fs.read_inode(self.file)?;
+        inode.write_at(DIRTY_OFFSET, &[1], fs, self.file)?;
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn add(&mut self, parent: u32, name: String, inode: u32, is_dir: bool) {
+        if is_dir {
+            self.dirs.insert(inode, (parent, name.clone()));
+        }
+        self.entries.insert((parent, name), (inode, is_dir));
+    }
+
+    /// Records the new entry `entry` of `parent`
+    pub(crate) fn entry_added(
+        &mut self,
+        fs: &mut FileSystem,
+        parent: u32,
+        entry: &DirEntry,
+    ) -> Result<(), FsError> {
+        if is_dot_name(entry.name_bytes()) {
+            return Ok(());
+        }
+        self.touch(fs)?;
+        if self.stale {
+            return Ok(());
+        }
+        let is_dir = entry.get_type() == InodeType::Directory;
+        self.add(parent, entry.get_name(), entry.inode, is_dir);
+        Ok(())
+    }
+
+    /// Forgets the entry `entry` that was removed from `parent`
+    pub(crate) fn entry_removed(
+        &mut self,
+        fs: &mut FileSystem,
+        parent: u32,
+        entry: &DirEntry,
+    ) -> Result<(), FsError> {
+        if is_dot_name(entry.name_bytes()) {
+            return Ok(());
+        }
+        self.touch(fs)?;
+        if self.stale {
+            return Ok(());
+        }
+        let key = (parent, entry.get_name());
+        self.entries.remove(&key);
+        // a renamed directory already has its new place
+        if self.dirs.get(&entry.inode) == Some(&key) {
+            self.dirs.remove(&entry.inode);
+        }
+        Ok(())
+    }
+
+    /// Tokenizes the file `inode_nbr` again, after it was written
+    pub(crate) fn contents_changed(
+        &mut self,
+        fs: &mut FileSystem,
+        inode_nbr: u32,
+    ) -> Result<(), FsError> {
+        if !self.contents || inode_nbr == self.file {
+            return Ok(());
+        }
+        self.touch(fs)?;
+        if self.stale {
+            return Ok(());
+        }
+        self.forget_contents(inode_nbr);
+        self.index_contents(fs, inode_nbr)
+    }
+
+    /// Forgets the words of `inode_nbr`, once it was freed
+    pub(crate) fn inode_freed(
+        &mut self,
+        fs: &mut FileSystem,
+        inode_nbr: u32,
+    ) -> Result<(), FsError> {
+        if !self.file_words.contains_key(&inode_nbr) {
+            return Ok(());
+        }
+        self.touch(fs)?;
+        self.forget_contents(inode_nbr);
+        Ok(())
+    }
+
+    fn forget_contents(&mut self, inode_nbr: u32) {
+        for word in self.file_words.remove(&inode_nbr).unwrap_or_default() {
+            if let Some(inodes) = self.words.get_mut(&word) {
+                inodes.remove(&inode_nbr);
+                if inodes.is_empty() {
+                    self.words.remove(&word);
+                }
+            }
+        }
+    }
+
+    fn index_contents(&mut self, fs: &mut FileSystem, inode_nbr: u32) -> Result<(), FsError> {
+        let inode = fs.read_inode(inode_nbr)?;
+        if inode.type_and_permission.get_type() != InodeType::File {
+            return Ok(());
+        }
+        let mut data = vec![0; (inode.size() as usize).min(MAX_INDEXED_BYTES)];
+        let read = inode.read(0, &mut data, fs)?;
+        data.truncate(read);
+
+        let words = tokens(&String::from_utf8_lossy(&data));
+        for word in &words {
+            self.words
+                .entry(word.clone())
+                .or_default()
+                .insert(inode_nbr);
+        }
+        if !words.is_empty() {
+            self.file_words.insert(inode_nbr, words);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn is_stale(&self) -> bool {
+        self.stale
+    }
+
+    pub(crate) fn indexes_contents(&self) -> bool {
+        self.contents
+    }
+
+    pub(crate) fn file(&self) -> u32 {
+        self.file
+    }
+
+    /// Stops using the index until it is rebuilt, after changes to the image were thrown away.
+    /// The dirty byte may have been thrown away with them.
+    pub(crate) fn invalidate(&mut self) {
+        self.stale = true;
+        self.dirty = false;
+    }
+
+    /// The path of the entry `name` in `dir`, `None` if a directory above it isn't indexed
+    fn path(&self, mut dir: u32, name: &str, root: u32) -> Option<String> {
+        let mut components = vec![name];
+        let mut seen = BTreeSet::new();
+        while dir != root {
+            if !seen.insert(dir) {
+                return None;
+            }
+            let (parent, name) = self.dirs.get(&dir)?;
+            components.push(name);
+            dir = *parent;
+        }
+        components.reverse();
+        Some(format!("/{}", components.join("/")))
+    }
+
+    /// Every entry whose name contains all words of `query` and every file that has all of them,
+    /// sorted by path
+    pub(crate) fn search(&self, query: &str, root: u32) -> Vec<SearchHit> {
+        let terms: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+        if terms.is_empty() {
+            return Vec::new();
+        }
+
+        // files with a word starting with every term
+        let mut content_hits: Option<BTreeSet<u32>> = None;
+        if self.contents {
+            for term in &terms {
+                let inodes: BTreeSet<u32> = self
+                    .words
+                    .range(term.clone()..)
+                    .take_while(|(word, _)| word.starts_with(term.as_str()))
+                    .flat_map(|(_, inodes)| inodes.iter().copied())
+                    .collect();
+                content_hits = Some(match content_hits {
+                    Some(hits) => &hits & &inodes,
+                    None => inodes,
+                });
+            }
+        }
+        let content_hits = content_hits.unwrap_or_default();
+
+        let mut hits: Vec<SearchHit> = self
+            .entries
+            .iter()
+            .filter(|((parent, name), _)| *parent != root || name != INDEX_FILE)
+            .filter_map(|((parent, name), (inode, _))| {
+                let lower = name.to_lowercase();
+                let name_match = terms.iter().all(|term| lower.contains(term.as_str()));
+                let content_match = content_hits.contains(inode);
+                if !name_match && !content_match {
+                    return None;
+                }
+                Some(SearchHit {
+                    inode: *inode,
+                    path: self.path(*parent, name, root)?,
+                    name_match,
+                    content_match,
+                })
+            })
+            .collect();
+        hits.sort_by(|a, b| a.path.cmp(&b.path));
+        hits
+    }
+}
+
+/// Creates `INDEX_FILE`, readable only by its owner
+pub(crate) fn create_file(fs: &mut FileSystem) -> Result<u32, FsError> {
+    let root = fs.superblock.root_inode;
+    let now = fs.now();
+    let inode = Inode::create(
+        PermissionsAndType::new(InodeType::File, &[Permission::user_rw()]),
+        0,
+        0,
+        now,
+        0,
+        0,
+    );
+    fs.create_dir_entry(root, inode, INDEX_FILE.to_string())
+}
@@ -1,12 +1,27 @@
-use std::mem::{size_of, MaybeUninit};
+use alloc::{vec, vec::Vec};
+use core::{
+    mem::{size_of, MaybeUninit},
+    ops::Range,
+};
+#[cfg(feature = "std")]
+use std::{
+    io::{ErrorKind, Read, Write},
+    time::{Duration, Instant},
+};
 
 use crate::{
-    directory::DirEntry,
+    addr::{Addr, POINTERS_PER_BLOCK},
+    compress::{self, Compression, CLUSTER_BLOCKS, CLUSTER_SIZE},
+    crc32::{bytes_of, Crc32},
+    directory::{insert_into_block, pack_dir_blocks, parse_dir_block, remove_from_block, DirEntry},
     disk::DiskError,
     fs::{FileSystem, FsError, BLOCK_SIZE, INODES_PER_BLOCK},
+    hints::StorageHints,
+    time::Timestamp,
+    zones::AllocZone,
 };
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u16)]
 pub enum InodeType {
     FiFo = 0x1000,
@@ -15,6 +30,7 @@ pub enum InodeType {
     BlockDevice = 0x6000,
     File = 0x8000,
     Socket = 0xa000,
+    Symlink = 0xc000,
     Unknown(u16),
 }
 
@@ -27,9 +43,23 @@ impl InodeType {
             Self::BlockDevice => 0x6000,
             Self::File => 0x8000,
             Self::Socket => 0xa000,
+            Self::Symlink => 0xc000,
             Self::Unknown(other) => *other,
         }
     }
+
+    pub fn from_u16(value: u16) -> Self {
+        match value & 0xf000 {
+            0x1000 => Self::FiFo,
+            0x2000 => Self::CharacterDevice,
+            0x4000 => Self::Directory,
+            0x6000 => Self::BlockDevice,
+            0x8000 => Self::File,
+            0xa000 => Self::Socket,
+            0xc000 => Self::Symlink,
+            other => Self::Unknown(other),
+        }
+    }
 }
 
 #[repr(u16)]
@@ -96,11 +126,14 @@ impl Permission {
 #[repr(transparent)]
 pub struct PermissionsAndType(u16);
 
+/// The bits of `PermissionsAndType` that hold permissions, everything above is the type
+pub const PERMISSION_MASK: u16 = 0o7777;
+
 impl PermissionsAndType {
     pub fn new(typ: InodeType, perms: &[Permission]) -> Self {
         let mut inner = typ.as_u16();
         for perm in perms {
-            inner |= perm.as_u16();
+            inner |= perm.as_u16() & PERMISSION_MASK;
         }
         Self(inner)
     }
@@ -110,28 +143,68 @@ impl PermissionsAndType {
     }
 
     pub fn get_type(&self) -> InodeType {
-        match self.0 & 0xf000 {
-            0x1000 => InodeType::FiFo,
-            0x2000 => InodeType::CharacterDevice,
-            0x4000 => InodeType::Directory,
-            0x6000 => InodeType::BlockDevice,
-            0x8000 => InodeType::File,
-            0xa000 => InodeType::Socket,
-            other => InodeType::Unknown(other),
-        }
+        InodeType::from_u16(self.0)
     }
 
     pub fn get_permission(&self, permission: Permission) -> bool {
         (self.0 & permission.as_u16()) > 0
     }
 
+    /// Sets or clears the permission bits of `permission`. The type bits are never touched, even
+    /// by `Permission::Other` values that include them.
     pub fn set_permission(&mut self, permission: Permission, value: bool) {
+        let bits = permission.as_u16() & PERMISSION_MASK;
         if value {
-            self.0 |= permission.as_u16()
+            self.0 |= bits
         } else {
-            self.0 &= !permission.as_u16()
+            self.0 &= !bits
         }
     }
+
+    /// Replaces all permission bits with the lower 12 bits of `mode` (chmod), keeping the type
+    pub fn set_mode(&mut self, mode: u16) {
+        self.0 = (self.0 & !PERMISSION_MASK) | (mode & PERMISSION_MASK);
+    }
+}
+
+/// The number of direct block pointers in an inode
+pub const DIRECT_POINTERS: u32 = 10;
+/// The first block index that isn't reachable through the singly indirect block pointer anymore
+pub const SINGLY_END: u32 = DIRECT_POINTERS + POINTERS_PER_BLOCK as u32;
+/// The first block index that isn't reachable through the doubly indirect block pointer anymore
+pub const DOUBLY_END: u32 = SINGLY_END + (POINTERS_PER_BLOCK * POINTERS_PER_BLOCK) as u32;
+/// The most bytes the block pointers of an inode reach, see `FileSystem::limits`
+pub const MAX_FILE_SIZE: u64 = DOUBLY_END as u64 * BLOCK_SIZE as u64;
+/// The most bytes of a compressed file, whose last cluster has to fit entirely
+pub const MAX_COMPRESSED_FILE_SIZE: u64 =
+    (DOUBLY_END - DOUBLY_END % CLUSTER_BLOCKS) as u64 * BLOCK_SIZE as u64;
+
+/// The entries of this directory are kept sorted by name, see `FileSystem::set_dir_sorted`
+pub const INODE_FLAG_SORTED_DIR: u32 = 1 << 0;
+/// The blocks of this inode must stay where they are, see `FileSystem::set_pinned`
+pub const INODE_FLAG_PINNED: u32 = 1 << 1;
+/// The file is used often, see `StorageHints`
+pub const INODE_FLAG_HOT: u32 = 1 << 2;
+/// The file is rarely used, see `StorageHints`
+pub const INODE_FLAG_COLD: u32 = 1 << 3;
+/// The file is read from start to end, see `StorageHints`
+pub const INODE_FLAG_SEQUENTIAL: u32 = 1 << 4;
+/// The file is read at random offsets, see `StorageHints`
+pub const INODE_FLAG_RANDOM: u32 = 1 << 5;
+/// The contents of the file should be compressed, see `StorageHints`
+pub const INODE_FLAG_COMPRESS: u32 = 1 << 6;
+/// The contents of the file should not be compressed, see `StorageHints`
+pub const INODE_FLAG_NOCOMPRESS: u32 = 1 << 7;
+
+/// A run of physically contiguous blocks of an inode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockExtent {
+    /// the index of the first block in the file
+    pub logical: u32,
+    /// the block id of the first block on disk
+    pub physical: u32,
+    /// the number of blocks in this run
+    pub length: u32,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -140,14 +213,40 @@ pub struct Inode {
     pub type_and_permission: PermissionsAndType,
     pub uid: u16,
     pub gid: u16,
-    pub modification_time: u64,
-    pub creation_time: u64,
+    /// how a regular file is stored, see `compression`
+    pub(crate) compression: u8,
+    // the padding is spelled out so it is always written as zeros
+    reserved0: u8,
+    /// in seconds since the UNIX epoch, like every time in the inode. Negative times only exist
+    /// on images with the `wide_times` feature, see `modified`.
+    pub modification_time: i64,
+    pub creation_time: i64,
     pub hardlinks: u16,
+    reserved1: [u8; 2],
     pub block_pointers: [u32; 10],
     pub singly_indirect_block_pointer: u32,
     pub doubly_indirect_block_pointer: u32,
     pub meta: u32,
-    padding: [u8; 48],
+    /// bumped by `FileSystem::write_inode` on every change to the inode or its contents
+    pub version: u64,
+    /// `INODE_FLAG_*` bits
+    pub flags: u32,
+    /// the block holding the extended attributes, 0 for none, see `FileSystem::set_xattr`
+    pub(crate) xattr_block: u32,
+    /// the length of a regular file in bytes, see `size`
+    pub(crate) size: u64,
+    /// the CRC-32 of the inode number and the inode with this field set to 0, only with the
+    /// `metadata_csum` feature, see `update_checksum`
+    pub(crate) checksum: u32,
+    /// with the `inode_generation` feature, set when the inode is created to a number no earlier
+    /// inode had, see `Stat::generation`
+    pub generation: u32,
+    /// the last time the contents were read, as far as `MountOptions::atime` tracks it
+    pub access_time: i64,
+    /// the nanoseconds of the modification time, 0 without the `wide_times` feature
+    pub modification_nanos: u32,
+    /// the nanoseconds of the access time, 0 without the `wide_times` feature
+    pub access_nanos: u32,
 }
 
 impl Inode {
@@ -159,6 +258,7 @@ impl Inode {
         hardlinks: u16,
         meta_data: u32,
     ) -> Self {
+        let now = Timestamp::from_secs(now).secs;
         Self {
             block_pointers: [0; 10],
             doubly_indirect_block_pointer: 0,
@@ -170,16 +270,82 @@ impl Inode {
             uid,
             hardlinks,
             type_and_permission,
-            padding: [0; 48],
+            version: 0,
+            flags: 0,
+            xattr_block: 0,
+            size: 0,
+            checksum: 0,
+            generation: 0,
+            access_time: now,
+            modification_nanos: 0,
+            access_nanos: 0,
+            compression: 0,
+            reserved0: 0,
+            reserved1: [0; 2],
         }
     }
 
+    /// The checksum of this inode stored as `inode_nbr`. The number is part of it, so an inode
+    /// written to the wrong slot doesn't pass.
+    fn compute_checksum(&self, inode_nbr: u32) -> u32 {
+        let mut copy = *self;
+        copy.checksum = 0;
+        let mut crc = Crc32::new();
+        crc.update(&inode_nbr.to_ne_bytes());
+        crc.update(bytes_of(&copy));
+        crc.finalize()
+    }
+
+    /// Whether the checksum matches. Slots that were never used are all zeros and pass.
+    pub(crate) fn verify_checksum(&self, inode_nbr: u32) -> bool {
+        self.checksum == self.compute_checksum(inode_nbr) || bytes_of(self).iter().all(|&b| b == 0)
+    }
+
+    /// Updates the checksum after a change, before the inode is written as `inode_nbr`
+    pub(crate) fn update_checksum(&mut self, inode_nbr: u32) {
+        self.checksum = self.compute_checksum(inode_nbr);
+    }
+
+    /// The modification time with its nanoseconds
+    pub fn modified(&self) -> Timestamp {
+        Timestamp::new(self.modification_time, self.modification_nanos)
+    }
+
+    pub fn set_modified(&mut self, time: Timestamp) {
+        self.modification_time = time.secs;
+        self.modification_nanos = time.nanos;
+    }
+
+    /// The access time with its nanoseconds
+    pub fn accessed(&self) -> Timestamp {
+        Timestamp::new(self.access_time, self.access_nanos)
+    }
+
+    pub fn set_accessed(&mut self, time: Timestamp) {
+        self.access_time = time.secs;
+        self.access_nanos = time.nanos;
+    }
+
+    /// The creation time, which only has whole seconds
+    pub fn created(&self) -> Timestamp {
+        Timestamp::new(self.creation_time, 0)
+    }
+
+    /// Makes the times fit an image without the `wide_times` feature: whole seconds, none before
+    /// 1970
+    pub(crate) fn narrow_times(&mut self) {
+        self.set_modified(Timestamp::from_secs(self.modified().to_unsigned()));
+        self.set_accessed(Timestamp::from_secs(self.accessed().to_unsigned()));
+        self.creation_time = self.created().to_unsigned() as i64;
+    }
+
     fn unallocate_block(
         is_double: bool,
         block_id: u32,
         fs: &mut FileSystem,
     ) -> Result<(), FsError> {
-        let block: [u32; 1024] = fs.get_disk().read_struct(FileSystem::pointer(block_id)?)?;
+        let block: [u32; POINTERS_PER_BLOCK] =
+            fs.disk().read_struct(FileSystem::pointer(block_id)?)?;
 
         for ent in block {
             if ent == 0 {
@@ -200,40 +366,202 @@ impl Inode {
         fs: &mut FileSystem,
         my_inode_addr: u32,
     ) -> Result<(), FsError> {
-        let mut blocks_required = to;
         let mut cur_block: u32 = 0;
 
-        loop {
-            if let None = self.get_block_id(cur_block, fs) {
-                self.get_next_free_block(fs, my_inode_addr)?;
+        while cur_block < to {
+            if self.get_block_id(cur_block, fs)?.is_none() {
+                self.allocate_block_at(cur_block, fs, my_inode_addr)?;
             }
-            blocks_required -= 1;
             cur_block += 1;
-            if blocks_required == 0 {
-                break;
+        }
+
+        self.free_range(to..DOUBLY_END, fs, my_inode_addr)?;
+        fs.write_inode(my_inode_addr, self)?;
+
+        Ok(())
+    }
+
+    /// Reads the indirect block `block_id`, 0 entries meaning no block
+    fn read_pointers(
+        fs: &mut FileSystem,
+        block_id: u32,
+    ) -> Result<[u32; POINTERS_PER_BLOCK], FsError> {
+        let pointers: [u32; POINTERS_PER_BLOCK] =
+            fs.disk().read_struct(FileSystem::pointer(block_id)?)?;
+        if pointers
+            .iter()
+            .any(|&ptr| ptr >= fs.superblock.total_blocks)
+        {
+            return Err(fs.corrupted());
+        }
+        Ok(pointers)
+    }
+
+    /// Frees the entries `range` of the indirect block `block_id` and the blocks they point at.
+    /// Returns whether it doesn't point at anything anymore, so it can be freed as well.
+    fn free_pointers(
+        fs: &mut FileSystem,
+        block_id: u32,
+        range: Range<usize>,
+    ) -> Result<bool, FsError> {
+        let mut pointers = Self::read_pointers(fs, block_id)?;
+        let mut changed = false;
+        for ptr in &mut pointers[range] {
+            if *ptr != 0 {
+                fs.free_block(*ptr)?;
+                *ptr = 0;
+                changed = true;
             }
         }
 
-        if cur_block < 10 {
-            for i in cur_block..10 {
-                if self.block_pointers[i as usize] != 0 {
-                    fs.free_block(self.block_pointers[i as usize])?;
-                    self.block_pointers[i as usize] = 0;
-                }
+        if pointers.iter().all(|&ptr| ptr == 0) {
+            return Ok(true);
+        }
+        if changed {
+            fs.disk()
+                .write_struct(FileSystem::pointer(block_id)?, &pointers)?;
+        }
+        Ok(false)
+    }
+
+    /// Frees the blocks at the indices `range` of this inode, leaving holes, and the indirect
+    /// blocks that end up empty. The caller writes the inode.
+    fn free_range(
+        &mut self,
+        range: Range<u32>,
+        fs: &mut FileSystem,
+        my_inode_addr: u32,
+    ) -> Result<(), FsError> {
+        let owner = (self.uid, self.gid);
+        fs.charged(my_inode_addr, owner, |fs| {
+            self.free_range_uncharged(range, fs)
+        })
+    }
+
+    fn free_range_uncharged(
+        &mut self,
+        range: Range<u32>,
+        fs: &mut FileSystem,
+    ) -> Result<(), FsError> {
+        for index in range.start.min(DIRECT_POINTERS)..range.end.min(DIRECT_POINTERS) {
+            let ptr = &mut self.block_pointers[index as usize];
+            if *ptr != 0 {
+                fs.free_block(*ptr)?;
+                *ptr = 0;
+            }
+        }
+
+        let start = range.start.clamp(DIRECT_POINTERS, SINGLY_END) - DIRECT_POINTERS;
+        let end = range.end.clamp(DIRECT_POINTERS, SINGLY_END) - DIRECT_POINTERS;
+        let singly = self.singly_indirect_block_pointer;
+        if singly != 0
+            && start < end
+            && Self::free_pointers(fs, singly, start as usize..end as usize)?
+        {
+            fs.free_block(singly)?;
+            self.singly_indirect_block_pointer = 0;
+        }
+
+        let start = (range.start.clamp(SINGLY_END, DOUBLY_END) - SINGLY_END) as usize;
+        let end = (range.end.clamp(SINGLY_END, DOUBLY_END) - SINGLY_END) as usize;
+        let doubly = self.doubly_indirect_block_pointer;
+        if doubly == 0 || start >= end {
+            return Ok(());
+        }
+        let mut singly_blocks = Self::read_pointers(fs, doubly)?;
+        let mut changed = false;
+        let covered = start / POINTERS_PER_BLOCK..end.div_ceil(POINTERS_PER_BLOCK);
+        for (i, singly) in singly_blocks.iter_mut().enumerate() {
+            if *singly == 0 || !covered.contains(&i) {
+                continue;
+            }
+            let first = i * POINTERS_PER_BLOCK;
+            let range = start.max(first) - first..end.min(first + POINTERS_PER_BLOCK) - first;
+            if Self::free_pointers(fs, *singly, range)? {
+                fs.free_block(*singly)?;
+                *singly = 0;
+                changed = true;
             }
         }
 
-        if self.singly_indirect_block_pointer != 0 && cur_block >= 10 {
-            Self::unallocate_block(false, self.singly_indirect_block_pointer, fs)?;
+        if singly_blocks.iter().all(|&ptr| ptr == 0) {
+            fs.free_block(doubly)?;
+            self.doubly_indirect_block_pointer = 0;
+        } else if changed {
+            fs.disk()
+                .write_struct(FileSystem::pointer(doubly)?, &singly_blocks)?;
         }
-        if self.doubly_indirect_block_pointer != 0 && cur_block >= 1024 + 10 {
-            Self::unallocate_block(true, self.doubly_indirect_block_pointer, fs)?;
+        Ok(())
+    }
+
+    /// Frees the blocks that lie entirely within `len` bytes at `offset` and zeroes the partially
+    /// covered ones, so the range reads as zeroes. The size of the file doesn't change.
+    pub fn punch_hole(
+        &mut self,
+        offset: usize,
+        len: usize,
+        fs: &mut FileSystem,
+        my_inode_addr: u32,
+    ) -> Result<(), FsError> {
+        if self.type_and_permission.get_type() != InodeType::File {
+            return Err(FsError::NotAFile);
+        }
+        if fs.is_worm() {
+            return Err(FsError::WriteOnce);
+        }
+
+        let size = self.size() as usize;
+        let end = offset.saturating_add(len).min(size);
+        if offset >= end {
+            return Ok(());
         }
 
+        if self.is_compressed() {
+            // clusters that end up all zeroes become holes
+            let zeroes = vec![0; CLUSTER_SIZE];
+            let mut pos = offset;
+            while pos < end {
+                let len = (CLUSTER_SIZE - pos % CLUSTER_SIZE).min(end - pos);
+                self.write_compressed(pos, &zeroes[..len], fs, my_inode_addr)?;
+                pos += len;
+            }
+        } else {
+            self.punch_blocks(offset, end, fs, my_inode_addr)?;
+        }
+        self.set_modified(fs.now().into());
         fs.write_inode(my_inode_addr, self)?;
 
-        // TODO: unallocate blocks in singly/dobly indirect block pointers
+        Ok(())
+    }
 
+    /// `punch_hole` for an uncompressed file, from `offset` up to `end` within the file
+    fn punch_blocks(
+        &mut self,
+        offset: usize,
+        end: usize,
+        fs: &mut FileSystem,
+        my_inode_addr: u32,
+    ) -> Result<(), FsError> {
+        let first = offset.div_ceil(BLOCK_SIZE);
+        let last = end / BLOCK_SIZE;
+        // the partially covered blocks at either edge, the last one only if it is within the file
+        let edges = if first > last {
+            [offset..end, end..end]
+        } else {
+            [offset..first * BLOCK_SIZE, last * BLOCK_SIZE..end]
+        };
+        for edge in edges.into_iter().filter(|edge| !edge.is_empty()) {
+            let blk_id = u32::try_from(edge.start / BLOCK_SIZE).map_err(|_| FsError::NoSpace)?;
+            if self.get_block_id(blk_id, fs)?.is_some() {
+                self.write_in_blocks(edge.start, &vec![0; edge.len()], fs, my_inode_addr)?;
+            }
+        }
+
+        if first < last {
+            let first = u32::try_from(first).map_err(|_| FsError::NoSpace)?;
+            let last = u32::try_from(last).map_err(|_| FsError::NoSpace)?;
+            self.free_range(first..last, fs, my_inode_addr)?;
+        }
         Ok(())
     }
 
@@ -246,127 +574,649 @@ impl Inode {
         if self.type_and_permission.get_type() != InodeType::File {
             return Err(FsError::NoSpace);
         }
+        if fs.is_worm() && self.size() != 0 {
+            return Err(FsError::WriteOnce);
+        }
+        if buf.len() as u64 > MAX_FILE_SIZE {
+            return Err(FsError::FileTooLarge);
+        }
 
-        let blocks = buf.len().div_ceil(BLOCK_SIZE) as u32;
-        self.resize_self(blocks, fs, my_inode_addr)?;
+        if self.is_compressed() {
+            self.free_range(0..DOUBLY_END, fs, my_inode_addr)?;
+            self.set_size(0);
+            self.write_compressed(0, buf, fs, my_inode_addr)?;
+        } else {
+            let blocks = buf.len().div_ceil(BLOCK_SIZE) as u32;
+            self.resize_self(blocks, fs, my_inode_addr)?;
 
-        for i in 0..blocks {
-            let block = self.get_block_id(i, fs).ok_or(FsError::NoEntry)?;
+            for i in 0..blocks {
+                let block = self.writable_block_id(i, fs, my_inode_addr)?;
 
-            let off = FileSystem::pointer(block)?;
-            let start = i as usize * BLOCK_SIZE;
-            let end = start + (i as usize * BLOCK_SIZE + 4096).min(buf.len());
+                let off = FileSystem::pointer(block)?;
+                let start = i as usize * BLOCK_SIZE;
+                let end = (start + BLOCK_SIZE).min(buf.len());
 
-            fs.get_disk().write_exact(off, &buf[start..end])?;
+                fs.disk().write_exact(off, &buf[start..end])?;
+            }
         }
 
-        self.meta = (buf.len() % BLOCK_SIZE) as u32;
+        self.set_size(buf.len() as u64);
+        self.set_modified(fs.now().into());
         fs.write_inode(my_inode_addr, self)?;
 
         Ok(())
     }
 
-    fn get_block_id(&self, mut index: u32, fs: &mut FileSystem) -> Option<u32> {
-        if index < 10 {
-            match self.block_pointers[index as usize] {
-                0 => None,
-                other => Some(other),
+    /// Replaces the contents of this file with everything `reader` returns, one block at a time.
+    /// Returns the new length of the file.
+    #[cfg(feature = "std")]
+    pub fn write_from_reader(
+        &mut self,
+        reader: &mut dyn Read,
+        fs: &mut FileSystem,
+        my_inode_addr: u32,
+    ) -> Result<usize, FsError> {
+        if self.type_and_permission.get_type() != InodeType::File {
+            return Err(FsError::NotAFile);
+        }
+        if fs.is_worm() && self.size() != 0 {
+            return Err(FsError::WriteOnce);
+        }
+        if self.is_compressed() {
+            return self.write_compressed_from_reader(reader, fs, my_inode_addr);
+        }
+
+        let mut block = [0; BLOCK_SIZE];
+        let mut total = 0;
+        let mut blocks = 0;
+
+        loop {
+            let filled = fill_from(reader, &mut block)?;
+            if filled == 0 {
+                break;
             }
-        } else if index >= 10 && index < 1034 {
-            index -= 10;
-            let block_ptr = if self.singly_indirect_block_pointer > 0 {
-                self.singly_indirect_block_pointer as usize
-            } else {
-                return None;
-            };
-            fs.get_disk()
-                .read_struct::<u32>(block_ptr + index as usize * 4)
-                .ok()
-        } else if index >= 1034 && index < 1024 * 1024 + 10 {
-            index -= 10;
-            let index_l1 = (index / 1024) as usize;
-            let index_l2 = (index % 1024) as usize;
-
-            let block_ptr = if self.doubly_indirect_block_pointer > 0 {
-                self.singly_indirect_block_pointer as usize
-            } else {
-                return None;
-            };
-            let addr = fs
-                .get_disk()
-                .read_struct::<u32>(block_ptr + index_l1 * 4)
-                .ok()?;
+            block[filled..].fill(0);
 
-            if addr == 0 {
-                return None;
-            };
-            let addr = fs
-                .get_disk()
-                .read_struct::<u32>(addr as usize + index_l2 * 4)
-                .ok()?;
-            if addr == 0 {
-                None
-            } else {
-                Some(addr)
+            if self.get_block_id(blocks, fs)?.is_none() {
+                self.allocate_block_at(blocks, fs, my_inode_addr)?;
+            }
+            let block_id = self.writable_block_id(blocks, fs, my_inode_addr)?;
+            fs.disk()
+                .write_exact(FileSystem::pointer(block_id)?, &block)?;
+
+            total += filled;
+            blocks += 1;
+            if filled != BLOCK_SIZE {
+                break;
+            }
+        }
+
+        // frees the blocks of the old contents that are past the new end
+        self.resize_self(blocks, fs, my_inode_addr)?;
+        self.set_size(total as u64);
+        self.set_modified(fs.now().into());
+        fs.write_inode(my_inode_addr, self)?;
+
+        Ok(total)
+    }
+
+    /// `write_from_reader` for a compressed file, one cluster at a time
+    #[cfg(feature = "std")]
+    fn write_compressed_from_reader(
+        &mut self,
+        reader: &mut dyn Read,
+        fs: &mut FileSystem,
+        my_inode_addr: u32,
+    ) -> Result<usize, FsError> {
+        self.free_range(0..DOUBLY_END, fs, my_inode_addr)?;
+        self.set_size(0);
+        let mut data = vec![0; CLUSTER_SIZE];
+        let mut total = 0;
+
+        loop {
+            let filled = fill_from(reader, &mut data)?;
+            if filled == 0 {
+                break;
+            }
+            if (total + filled) as u64 > MAX_COMPRESSED_FILE_SIZE {
+                return Err(FsError::FileTooLarge);
+            }
+            let cluster = (total / CLUSTER_SIZE) as u32;
+            self.write_cluster(cluster, &data[..filled], fs, my_inode_addr)?;
+            total += filled;
+            if filled != CLUSTER_SIZE {
+                break;
             }
+        }
+
+        self.set_size(total as u64);
+        self.set_modified(fs.now().into());
+        fs.write_inode(my_inode_addr, self)?;
+        Ok(total)
+    }
+
+    /// Writes `buf` at byte `offset` of the file, only touching the blocks in that range. Writing
+    /// past the end extends the file, the gap is left as a hole that reads as zeroes. Returns the number of bytes written.
+    pub fn write_at(
+        &mut self,
+        offset: usize,
+        buf: &[u8],
+        fs: &mut FileSystem,
+        my_inode_addr: u32,
+    ) -> Result<usize, FsError> {
+        if self.type_and_permission.get_type() != InodeType::File {
+            return Err(FsError::NotAFile);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let size = self.size() as usize;
+        let end = offset
+            .checked_add(buf.len())
+            .filter(|&end| end as u64 <= MAX_FILE_SIZE)
+            .ok_or(FsError::FileTooLarge)?;
+        if fs.is_worm() && offset < size {
+            return Err(FsError::WriteOnce);
+        }
+
+        if self.is_compressed() {
+            self.write_compressed(offset, buf, fs, my_inode_addr)?;
         } else {
-            None
+            if offset > size {
+                self.zero_tail(offset, fs, my_inode_addr)?;
+            }
+
+            let first = u32::try_from(offset / BLOCK_SIZE).map_err(|_| FsError::FileTooLarge)?;
+            let needed =
+                u32::try_from(end.div_ceil(BLOCK_SIZE)).map_err(|_| FsError::FileTooLarge)?;
+            // whole blocks between the old end and `offset` stay holes
+            for blk_id in first..needed {
+                if self.get_block_id(blk_id, fs)?.is_none() {
+                    self.allocate_block_at(blk_id, fs, my_inode_addr)?;
+                }
+            }
+
+            self.write_in_blocks(offset, buf, fs, my_inode_addr)?;
+        }
+
+        if end > size {
+            self.set_size(end as u64);
         }
+        self.set_modified(fs.now().into());
+        fs.write_inode(my_inode_addr, self)?;
+
+        Ok(buf.len())
     }
 
-    pub fn delete(&mut self, my_inode_addr: u32, fs: &mut FileSystem) -> Result<(), FsError> {
-        self.hardlinks -= 1;
+    /// Sets the length of the file to `len` bytes. Growing leaves a hole that reads as zeroes,
+    /// shrinking frees every block past the new end, and the indirect blocks that end up empty.
+    pub fn truncate(
+        &mut self,
+        len: u64,
+        fs: &mut FileSystem,
+        my_inode_addr: u32,
+    ) -> Result<(), FsError> {
+        if self.type_and_permission.get_type() != InodeType::File {
+            return Err(FsError::NotAFile);
+        }
+        let size = self.size();
+        if fs.is_worm() && len < size {
+            return Err(FsError::WriteOnce);
+        }
+        let blocks = usize::try_from(len)
+            .ok()
+            .and_then(|len| u32::try_from(len.div_ceil(BLOCK_SIZE)).ok())
+            .filter(|blocks| *blocks <= DOUBLY_END)
+            .ok_or(FsError::FileTooLarge)?;
+
+        if self.is_compressed() {
+            self.truncate_compressed(len, fs, my_inode_addr)?;
+        } else if len > size {
+            self.zero_tail(len as usize, fs, my_inode_addr)?;
+        } else {
+            self.free_range(blocks..DOUBLY_END, fs, my_inode_addr)?;
+        }
+        self.set_size(len);
+        self.set_modified(fs.now().into());
         fs.write_inode(my_inode_addr, self)?;
-        if self.hardlinks > 0 {
+
+        Ok(())
+    }
+
+    /// Stores `target` as the target of this symbolic link, in its first block. `target` has to
+    /// be shorter than a block, see `FileSystem::symlink`.
+    pub(crate) fn set_link_target(
+        &mut self,
+        target: &[u8],
+        fs: &mut FileSystem,
+        my_inode_addr: u32,
+    ) -> Result<(), FsError> {
+        if self.type_and_permission.get_type() != InodeType::Symlink {
+            return Err(FsError::InvalidValue);
+        }
+        if self.get_block_id(0, fs)?.is_none() {
+            self.allocate_block_at(0, fs, my_inode_addr)?;
+        }
+        self.write_in_blocks(0, target, fs, my_inode_addr)?;
+        self.set_size(target.len() as u64);
+        self.set_modified(fs.now().into());
+        fs.write_inode(my_inode_addr, self)
+    }
+
+    /// Zeroes the last block of the file from the end up to `until`, the rest of it can still
+    /// hold old data from before the file was shrunk
+    fn zero_tail(
+        &mut self,
+        until: usize,
+        fs: &mut FileSystem,
+        my_inode_addr: u32,
+    ) -> Result<(), FsError> {
+        let size = self.size() as usize;
+        if size.is_multiple_of(BLOCK_SIZE) {
             return Ok(());
         }
+        let last = u32::try_from(size / BLOCK_SIZE).map_err(|_| FsError::NoSpace)?;
+        if self.get_block_id(last, fs)?.is_some() {
+            let zero_end = until.min(size.next_multiple_of(BLOCK_SIZE));
+            self.write_in_blocks(size, &vec![0; zero_end - size], fs, my_inode_addr)?;
+        }
+        Ok(())
+    }
+
+    /// Writes `buf` at byte `offset`, all blocks in that range have to be allocated
+    fn write_in_blocks(
+        &mut self,
+        mut offset: usize,
+        mut buf: &[u8],
+        fs: &mut FileSystem,
+        my_inode_addr: u32,
+    ) -> Result<(), FsError> {
+        while !buf.is_empty() {
+            let blk_id = u32::try_from(offset / BLOCK_SIZE).map_err(|_| FsError::NoSpace)?;
+            let block_offset = offset % BLOCK_SIZE;
+            let len = (BLOCK_SIZE - block_offset).min(buf.len());
 
-        for ptr in self.block_pointers {
-            if ptr != 0 {
-                fs.free_block(ptr)?;
+            let block = self.writable_block_id(blk_id, fs, my_inode_addr)?;
+            let addr = Addr::block(block)?.offset(block_offset)?;
+            fs.disk().write_exact(addr.get(), &buf[..len])?;
+
+            buf = &buf[len..];
+            offset += len;
+        }
+        Ok(())
+    }
+
+    /// The whole contents of this inode, `file_size` bytes of it
+    pub fn read_all(&self, fs: &mut FileSystem) -> Result<Vec<u8>, FsError> {
+        let mut contents = vec![0; self.file_size(fs)?];
+        let read = self.read(0, &mut contents, fs)?;
+        contents.truncate(read);
+        Ok(contents)
+    }
+
+    /// Writes the contents of this file to `writer`, one block at a time. If `bytes_per_second` is
+    /// set, sleeps between blocks to stay below that rate. Returns the number of bytes written.
+    #[cfg(feature = "std")]
+    pub fn read_to_writer(
+        &self,
+        writer: &mut dyn Write,
+        fs: &mut FileSystem,
+        bytes_per_second: Option<u64>,
+    ) -> Result<usize, FsError> {
+        let start = Instant::now();
+        let mut total = 0;
+        self.read_blocks(fs, |block| {
+            writer.write_all(block).map_err(FsError::IoError)?;
+            total += block.len();
+
+            if let Some(rate) = bytes_per_second.filter(|rate| *rate > 0) {
+                let expected = Duration::from_secs_f64(total as f64 / rate as f64);
+                if let Some(ahead) = expected.checked_sub(start.elapsed()) {
+                    std::thread::sleep(ahead);
+                }
             }
+            Ok(())
+        })?;
+
+        writer.flush().map_err(FsError::IoError)?;
+        Ok(total)
+    }
+
+    /// Hands the contents of this file to `each`, one block at a time, without reading it into
+    /// memory first. Returns the length of the file.
+    pub fn read_blocks(
+        &self,
+        fs: &mut FileSystem,
+        mut each: impl FnMut(&[u8]) -> Result<(), FsError>,
+    ) -> Result<usize, FsError> {
+        if self.type_and_permission.get_type() != InodeType::File {
+            return Err(FsError::NotAFile);
         }
 
-        if let Ok(singly) = FileSystem::pointer(self.singly_indirect_block_pointer)
-            .and_then(|ptr| Ok(fs.get_disk().read_struct::<[u32; 1024]>(ptr)?))
-        {
-            for s in singly {
-                if s != 0 {
-                    fs.free_block(s)?;
+        let size = self.size() as usize;
+        if self.is_compressed() {
+            let mut data = vec![0; CLUSTER_SIZE];
+            for (cluster, start) in (0..size).step_by(CLUSTER_SIZE).enumerate() {
+                self.read_cluster(cluster as u32, fs, &mut data)?;
+                let len = (size - start).min(CLUSTER_SIZE);
+                for block in data[..len].chunks(BLOCK_SIZE) {
+                    each(block)?;
                 }
             }
-            fs.free_block(self.singly_indirect_block_pointer)?;
+            return Ok(size);
+        }
+        let mut block = [0; BLOCK_SIZE];
+        let mut total = 0;
+
+        for index in 0..size.div_ceil(BLOCK_SIZE) {
+            let len = (size - total).min(BLOCK_SIZE);
+            match self.get_block_id(index as u32, fs)? {
+                Some(block_id) => fs
+                    .disk()
+                    .read_exact(FileSystem::pointer(block_id)?, &mut block[..len])?,
+                None => block[..len].fill(0),
+            }
+            each(&block[..len])?;
+            total += len;
         }
+        Ok(total)
+    }
 
-        if let Ok(doubly) = FileSystem::pointer(self.singly_indirect_block_pointer)
-            .and_then(|ptr| Ok(fs.get_disk().read_struct::<[u32; 1024]>(ptr)?))
-        {
-            for s in doubly {
-                if let Ok(singlies) = FileSystem::pointer(s)
-                    .and_then(|ptr| Ok(fs.get_disk().read_struct::<[u32; 1024]>(ptr)?))
+    /// Reads the `index`th entry of the indirect block `block_id`, 0 meaning no block
+    fn read_pointer(fs: &mut FileSystem, block_id: u32, index: usize) -> Result<u32, FsError> {
+        let ptr = fs
+            .disk()
+            .read_struct::<u32>(Addr::pointer_entry(block_id, index)?.get())?;
+        if ptr >= fs.superblock.total_blocks {
+            Err(fs.corrupted())
+        } else {
+            Ok(ptr)
+        }
+    }
+
+    fn write_pointer(
+        fs: &mut FileSystem,
+        block_id: u32,
+        index: usize,
+        ptr: u32,
+    ) -> Result<(), FsError> {
+        fs.disk()
+            .write_struct(Addr::pointer_entry(block_id, index)?.get(), &ptr)?;
+        Ok(())
+    }
+
+    /// The physical block of the `index`th block of this inode, `None` if it isn't allocated
+    fn get_block_id(&self, index: u32, fs: &mut FileSystem) -> Result<Option<u32>, FsError> {
+        let ptr = if index < DIRECT_POINTERS {
+            self.block_pointers[index as usize]
+        } else if index < SINGLY_END {
+            if self.singly_indirect_block_pointer == 0 {
+                return Ok(None);
+            }
+            let index = (index - DIRECT_POINTERS) as usize;
+            Self::read_pointer(fs, self.singly_indirect_block_pointer, index)?
+        } else if index < DOUBLY_END {
+            if self.doubly_indirect_block_pointer == 0 {
+                return Ok(None);
+            }
+            let index = (index - SINGLY_END) as usize;
+            let singly = Self::read_pointer(
+                fs,
+                self.doubly_indirect_block_pointer,
+                index / POINTERS_PER_BLOCK,
+            )?;
+            if singly == 0 {
+                return Ok(None);
+            }
+            Self::read_pointer(fs, singly, index % POINTERS_PER_BLOCK)?
+        } else {
+            0
+        };
+
+        Ok(if ptr == 0 { None } else { Some(ptr) })
+    }
+
+    /// Points the `index`th block of this inode at `ptr`. The block (and the indirect blocks on
+    /// the way to it) has to be allocated already.
+    pub(crate) fn set_block_id(
+        &mut self,
+        index: u32,
+        ptr: u32,
+        fs: &mut FileSystem,
+        my_inode_addr: u32,
+    ) -> Result<(), FsError> {
+        if self.get_block_id(index, fs)?.is_none() {
+            return Err(FsError::InvalidBlock);
+        }
+
+        if index < DIRECT_POINTERS {
+            self.block_pointers[index as usize] = ptr;
+            fs.write_inode(my_inode_addr, self)?;
+        } else if index < SINGLY_END {
+            let index = (index - DIRECT_POINTERS) as usize;
+            Self::write_pointer(fs, self.singly_indirect_block_pointer, index, ptr)?;
+        } else {
+            let index = (index - SINGLY_END) as usize;
+            let singly = Self::read_pointer(
+                fs,
+                self.doubly_indirect_block_pointer,
+                index / POINTERS_PER_BLOCK,
+            )?;
+            Self::write_pointer(fs, singly, index % POINTERS_PER_BLOCK, ptr)?;
+        }
+
+        Ok(())
+    }
+
+    /// The length of a regular file or the target of a symbolic link in bytes, 0 for every other
+    /// type
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The storage hints of a regular file, none for every other type since only file data
+    /// follows them
+    pub fn storage_hints(&self) -> StorageHints {
+        if self.type_and_permission.get_type() != InodeType::File {
+            return StorageHints::default();
+        }
+        StorageHints::from_flags(self.flags)
+    }
+
+    /// Sets the length of a regular file or link target in bytes without touching its blocks
+    pub fn set_size(&mut self, size: u64) {
+        self.size = size;
+        // keeps the image readable for implementations that don't know the size field yet
+        self.meta = (size % BLOCK_SIZE as u64) as u32;
+    }
+
+    /// The length of the contents in bytes: the size of a regular file or the target of a symbolic
+    /// link, every block of other inodes
+    pub fn file_size(&self, fs: &mut FileSystem) -> Result<usize, FsError> {
+        if matches!(
+            self.type_and_permission.get_type(),
+            InodeType::File | InodeType::Symlink
+        ) {
+            return Ok(self.size as usize);
+        }
+        Ok(self.block_map(fs)?.len() * BLOCK_SIZE)
+    }
+
+    /// The length of a regular file on an image without the `file_size` feature, derived from the
+    /// number of blocks and the number of bytes used in the last block (`meta`)
+    pub(crate) fn legacy_size(&self, fs: &mut FileSystem) -> Result<u64, FsError> {
+        let blocks = self.block_map(fs)?.len();
+        Ok(if blocks == 0 || self.meta == 0 {
+            blocks * BLOCK_SIZE
+        } else {
+            (blocks - 1) * BLOCK_SIZE + self.meta as usize
+        } as u64)
+    }
+
+    /// The number of blocks allocated to this inode, including indirect pointer blocks
+    pub fn allocated_blocks(&self, fs: &mut FileSystem) -> Result<u32, FsError> {
+        let mut blocks = self.data_blocks(fs)?.len() as u32;
+        if self.xattr_block != 0 {
+            blocks += 1;
+        }
+        if self.singly_indirect_block_pointer != 0 {
+            blocks += 1;
+        }
+        if self.doubly_indirect_block_pointer != 0 {
+            blocks += 1;
+            for index in 0..POINTERS_PER_BLOCK {
+                if Self::read_pointer(fs, self.doubly_indirect_block_pointer, index)? != 0 {
+                    blocks += 1;
+                }
+            }
+        }
+        Ok(blocks)
+    }
+
+    /// Returns the physical block ids backing this inode, ordered by their position in the file
+    pub fn block_map(&self, fs: &mut FileSystem) -> Result<Vec<u32>, FsError> {
+        let mut blocks = Vec::new();
+        let mut index = 0;
+
+        while let Some(block) = self.get_block_id(index, fs)? {
+            blocks.push(block);
+            index += 1;
+        }
+
+        Ok(blocks)
+    }
+
+    /// Every allocated data block as its index in the file and its physical block id, in file
+    /// order. Unlike `block_map`, this goes on past holes.
+    pub fn data_blocks(&self, fs: &mut FileSystem) -> Result<Vec<(u32, u32)>, FsError> {
+        let mut blocks: Vec<_> = (0..DIRECT_POINTERS)
+            .zip(self.block_pointers)
+            .filter(|&(_, block)| block != 0)
+            .collect();
+
+        if self.singly_indirect_block_pointer != 0 {
+            let pointers = Self::read_pointers(fs, self.singly_indirect_block_pointer)?;
+            blocks.extend(
+                (DIRECT_POINTERS..)
+                    .zip(pointers)
+                    .filter(|&(_, block)| block != 0),
+            );
+        }
+        if self.doubly_indirect_block_pointer != 0 {
+            let singly_blocks = Self::read_pointers(fs, self.doubly_indirect_block_pointer)?;
+            for (i, singly) in singly_blocks.into_iter().enumerate() {
+                if singly == 0 {
+                    continue;
+                }
+                let first = SINGLY_END + (i * POINTERS_PER_BLOCK) as u32;
+                let pointers = Self::read_pointers(fs, singly)?;
+                blocks.extend((first..).zip(pointers).filter(|&(_, block)| block != 0));
+            }
+        }
+
+        Ok(blocks)
+    }
+
+    /// The indirect pointer blocks of this inode, which `data_blocks` leaves out
+    pub(crate) fn pointer_blocks(&self, fs: &mut FileSystem) -> Result<Vec<u32>, FsError> {
+        let mut blocks = Vec::new();
+        if self.singly_indirect_block_pointer != 0 {
+            blocks.push(self.singly_indirect_block_pointer);
+        }
+        if self.doubly_indirect_block_pointer != 0 {
+            blocks.push(self.doubly_indirect_block_pointer);
+            let singly_blocks = Self::read_pointers(fs, self.doubly_indirect_block_pointer)?;
+            blocks.extend(singly_blocks.into_iter().filter(|&block| block != 0));
+        }
+        Ok(blocks)
+    }
+
+    /// Same as `data_blocks`, but merges runs of blocks that are contiguous both in the file and
+    /// on disk into extents
+    pub fn block_extents(&self, fs: &mut FileSystem) -> Result<Vec<BlockExtent>, FsError> {
+        let mut extents: Vec<BlockExtent> = Vec::new();
+
+        for (logical, block) in self.data_blocks(fs)? {
+            match extents.last_mut() {
+                Some(last)
+                    if last.logical + last.length == logical
+                        && last.physical + last.length == block =>
                 {
-                    for s in singlies {
-                        fs.free_block(s)?;
-                    }
-                    fs.free_block(s)?;
+                    last.length += 1
                 }
+                _ => extents.push(BlockExtent {
+                    logical,
+                    physical: block,
+                    length: 1,
+                }),
             }
-            fs.free_block(self.doubly_indirect_block_pointer)?;
         }
 
-        self.doubly_indirect_block_pointer = 0;
-        self.singly_indirect_block_pointer = 0;
-        self.block_pointers = [0; 10];
+        Ok(extents)
+    }
+
+    pub fn delete(&mut self, my_inode_addr: u32, fs: &mut FileSystem) -> Result<(), FsError> {
+        if fs.is_worm() {
+            return Err(FsError::WriteOnce);
+        }
+        let owner = (self.uid, self.gid);
+        if self.hardlinks == 1 {
+            fs.uncharge_inode(my_inode_addr, owner)?;
+        }
+        self.hardlinks -= 1;
+        fs.write_inode(my_inode_addr, self)?;
+        if self.hardlinks > 0 {
+            return Ok(());
+        }
+        fs.inode_freed(my_inode_addr);
+        fs.update_index(|index, fs| index.inode_freed(fs, my_inode_addr))?;
+
+        let inode = *self;
+        fs.charged(my_inode_addr, owner, |fs| {
+            for ptr in inode.block_pointers {
+                if ptr != 0 {
+                    fs.free_block(ptr)?;
+                }
+            }
+
+            if inode.singly_indirect_block_pointer != 0 {
+                Self::unallocate_block(false, inode.singly_indirect_block_pointer, fs)?;
+                fs.free_block(inode.singly_indirect_block_pointer)?;
+            }
+
+            if inode.doubly_indirect_block_pointer != 0 {
+                Self::unallocate_block(true, inode.doubly_indirect_block_pointer, fs)?;
+                fs.free_block(inode.doubly_indirect_block_pointer)?;
+            }
+
+            if inode.xattr_block != 0 {
+                fs.free_block(inode.xattr_block)?;
+            }
+            Ok(())
+        })?;
+
+        // the block pointers stay, so `FileSystem::recover_deleted` can find the data until the
+        // blocks or the inode are reused
+        self.xattr_block = 0;
 
         fs.write_inode(my_inode_addr, self)?;
 
-        let inode_blk_root_addr = my_inode_addr / INODES_PER_BLOCK;
+        let inode_blk_root_addr = Addr::inode(my_inode_addr)?.block_id()?;
 
-        if let Ok(ptr) = FileSystem::pointer(inode_blk_root_addr) {
-            let inodes = fs.get_disk().read_struct::<[Inode; INODES_PER_BLOCK as usize]>(ptr)?;
-            let all_free = inodes.iter().map(|f| f.hardlinks == 0).all(|bool| bool);
+        if fs.is_inode_table_block(inode_blk_root_addr) {
+            // inode table blocks are never freed, but the next inode should be allocated here
+            let hint = fs.superblock.earliest_inode_space;
+            if !fs.is_inode_table_block(hint / INODES_PER_BLOCK) || hint > my_inode_addr {
+                fs.superblock.earliest_inode_space = inode_blk_root_addr * INODES_PER_BLOCK;
+                fs.write_superblock()?;
+            }
+        } else if let Ok(ptr) = FileSystem::pointer(inode_blk_root_addr) {
+            let inodes = fs
+                .disk()
+                .read_struct::<[Inode; INODES_PER_BLOCK as usize]>(ptr)?;
+            let all_free = inodes.iter().all(|f| f.hardlinks == 0);
             if all_free {
-                println!("Freeing block {inode_blk_root_addr}");
                 fs.free_block(inode_blk_root_addr)?;
                 if fs.superblock.earliest_inode_space == inode_blk_root_addr {
                     fs.superblock.earliest_inode_space = 0;
@@ -379,15 +1229,22 @@ impl Inode {
     }
 
     fn _read(&self, off: usize, buf: &mut [u8], fs: &mut FileSystem) -> Result<usize, FsError> {
-        let block_id = off / 4096;
-        let block_offset = off % 4096;
-
-        let addr = self
-            .get_block_id(block_id as u32, fs)
-            .ok_or(FsError::NoEntry)? as usize
-            * 4096
-            + block_offset;
-        Ok(fs.get_disk().read_lossy(addr, buf)?)
+        let block_id = u32::try_from(off / BLOCK_SIZE).map_err(|_| FsError::NoEntry)?;
+        let block_offset = off % BLOCK_SIZE;
+
+        let Some(block) = self.get_block_id(block_id, fs)? else {
+            // a hole in a sparse file, `read` already stopped at the end of the file
+            if self.type_and_permission.get_type() == InodeType::File {
+                buf.fill(0);
+                return Ok(buf.len());
+            }
+            return Err(FsError::NoEntry);
+        };
+        let addr = Addr::block(block)?.offset(block_offset)?;
+        if self.storage_hints().bypass_cache() {
+            return Ok(fs.disk().read_lossy_uncached(addr.get(), buf)?);
+        }
+        Ok(fs.disk().read_lossy(addr.get(), buf)?)
     }
 
     pub fn read_exact(
@@ -411,17 +1268,25 @@ impl Inode {
     ) -> Result<usize, FsError> {
         let mut read_already: usize = 0;
         let mut left_to_read = buf.len();
+        // regular files end at their size, and missing blocks before it are holes
+        if self.type_and_permission.get_type() == InodeType::File {
+            left_to_read = left_to_read.min((self.size as usize).saturating_sub(off));
+        }
+        if self.is_compressed() {
+            self.read_compressed(off, &mut buf[..left_to_read], fs)?;
+            return Ok(left_to_read);
+        }
 
         loop {
-            let length = (4096 - off % 4096).min(left_to_read);
+            let length = (BLOCK_SIZE - off % BLOCK_SIZE).min(left_to_read);
             if length == 0 {
                 return Ok(read_already);
             }
             let read = self._read(off, &mut buf[read_already..read_already + length], fs)?;
-            if read == 0 {
+            read_already += read;
+            if read != length {
                 return Ok(read_already);
             }
-            read_already += length;
             left_to_read -= length;
             off += length;
         }
@@ -449,56 +1314,201 @@ impl Inode {
         &mut self,
         fs: &mut FileSystem,
         dir_entry: &DirEntry,
-        entry_nbr: Option<u32>,
         my_inode_addr: u32,
-    ) -> Result<u32, FsError> {
+    ) -> Result<(), FsError> {
         if self.type_and_permission.get_type() != InodeType::Directory {
             return Err(FsError::NoEntry);
         }
+        self.set_modified(fs.now().into());
+
+        if self.flags & INODE_FLAG_SORTED_DIR != 0 {
+            let mut entries = self.read_all_dir_entries(fs)?;
+            let pos = entries.partition_point(|e| e.name_bytes() < dir_entry.name_bytes());
+            entries.insert(pos, dir_entry.clone());
+            self.write_dir_entries(fs, &entries, my_inode_addr)?;
+            return fs.update_index(|index, fs| index.entry_added(fs, my_inode_addr, dir_entry));
+        }
 
-        let (blk_id, off, entry_nbr) = match entry_nbr {
-            Some(v) => self.get_dir_entry_by_nbr(fs, v)?,
-            None => self.get_next_free_dir_entry_slot(fs, my_inode_addr)?,
+        let mut buf = [0; BLOCK_SIZE];
+        let mut blocks = self.block_map(fs)?.into_iter();
+        let block = loop {
+            let block = match blocks.next() {
+                Some(block) => block,
+                None => {
+                    let blk_id = self.get_next_free_block(fs, my_inode_addr)?;
+                    self.get_block_id(blk_id, fs)?.ok_or(FsError::NoEntry)?
+                }
+            };
+            fs.disk()
+                .read_exact(FileSystem::pointer(block)?, &mut buf)?;
+            if insert_into_block(&mut buf, dir_entry)? {
+                break block;
+            }
         };
 
-        let addr = self.get_block_id(blk_id, fs).ok_or(FsError::NoEntry)?;
+        fs.disk().write_exact(FileSystem::pointer(block)?, &buf)?;
+        // the contents of the directory changed
+        fs.write_inode(my_inode_addr, self)?;
+        fs.update_index(|index, fs| index.entry_added(fs, my_inode_addr, dir_entry))
+    }
 
-        dir_entry.write_to_disk(fs.get_disk(), addr as usize * BLOCK_SIZE + off as usize)?;
+    /// Parses the entries of the directory block at index `blk_id`, skipping tombstones
+    pub(crate) fn read_dir_block(
+        &self,
+        fs: &mut FileSystem,
+        blk_id: u32,
+    ) -> Result<Vec<DirEntry>, FsError> {
+        let Some(block) = self.get_block_id(blk_id, fs)? else {
+            return Ok(Vec::new());
+        };
+        let mut buf = [0; BLOCK_SIZE];
+        fs.disk()
+            .read_exact(FileSystem::pointer(block)?, &mut buf)?;
+
+        Ok(parse_dir_block(&buf)?
+            .into_iter()
+            .filter_map(|record| record.entry)
+            .collect())
+    }
+
+    /// All entries of this directory except `.` and `..` in on-disk order. Unlike
+    /// `DirectoryIterator`, this reports read errors instead of ending early.
+    pub fn read_dir_entries(&self, fs: &mut FileSystem) -> Result<Vec<DirEntry>, FsError> {
+        let mut entries = self.read_all_dir_entries(fs)?;
+        entries.retain(|entry| !entry.is_dot());
+        Ok(entries)
+    }
 
-        Ok(entry_nbr)
+    /// All entries of this directory including `.` and `..`, for rewriting it
+    fn read_all_dir_entries(&self, fs: &mut FileSystem) -> Result<Vec<DirEntry>, FsError> {
+        let mut entries = Vec::new();
+        for blk_id in 0..self.block_map(fs)?.len() as u32 {
+            entries.extend(self.read_dir_block(fs, blk_id)?);
+        }
+        Ok(entries)
     }
 
-    fn get_dir_entry_by_nbr(
+    /// Replaces all entries of this directory with `entries`, packed in the given order. Blocks
+    /// that aren't needed anymore stay allocated, but empty.
+    fn write_dir_entries(
         &mut self,
         fs: &mut FileSystem,
-        block_id: u32,
-    ) -> Result<(u32, u32, u32), FsError> {
-        let mut blk_id = 0;
-        let mut off: u32 = 0;
-        let mut slot_id: u32 = 0;
+        entries: &[DirEntry],
+        my_inode_addr: u32,
+    ) -> Result<(), FsError> {
+        let blocks = pack_dir_blocks(entries);
 
-        loop {
-            let block = self.get_block_id(blk_id, fs);
-            match block {
-                None => return Err(FsError::NoEntry),
-                Some(v) => {
-                    let dir_entry = fs
-                        .get_disk()
-                        .read_struct::<DirEntry>(v as usize * BLOCK_SIZE + off as usize)?;
-                    if slot_id == block_id {
-                        return Ok((blk_id, off, slot_id));
-                    }
-
-                    off += dir_entry.get_size();
-                    if off >= 3796 {
-                        // dir_entry wouldnt fit in this block anymore
-                        blk_id += 1;
-                        off = 0;
-                    }
-                    slot_id += 1;
-                }
+        let existing = self.block_map(fs)?.len();
+        for _ in existing..blocks.len() {
+            self.get_next_free_block(fs, my_inode_addr)?;
+        }
+        for (blk_id, block_id) in self.block_map(fs)?.into_iter().enumerate() {
+            let empty = [0; BLOCK_SIZE];
+            let data = blocks.get(blk_id).map_or(&empty, |b| b);
+            fs.disk()
+                .write_exact(FileSystem::pointer(block_id)?, data)?;
+        }
+
+        fs.write_inode(my_inode_addr, self)
+    }
+
+    /// Removes the entry called `name` and returns it. Its record is freed in place, except in
+    /// sorted directories: their remaining entries are packed again, so no block is left empty
+    /// in front of a full one.
+    pub(crate) fn remove_dir_entry(
+        &mut self,
+        fs: &mut FileSystem,
+        name: &[u8],
+        my_inode_addr: u32,
+    ) -> Result<DirEntry, FsError> {
+        if self.type_and_permission.get_type() != InodeType::Directory {
+            return Err(FsError::NoEntry);
+        }
+        if fs.is_worm() {
+            return Err(FsError::WriteOnce);
+        }
+        self.set_modified(fs.now().into());
+        if self.flags & INODE_FLAG_SORTED_DIR != 0 {
+            let mut entries = self.read_all_dir_entries(fs)?;
+            let pos = entries
+                .iter()
+                .position(|e| e.name_bytes() == name)
+                .ok_or(FsError::NoEntry)?;
+            let entry = entries.remove(pos);
+            self.write_dir_entries(fs, &entries, my_inode_addr)?;
+            fs.update_index(|index, fs| index.entry_removed(fs, my_inode_addr, &entry))?;
+            return Ok(entry);
+        }
+
+        let mut buf = [0; BLOCK_SIZE];
+        for block in self.block_map(fs)? {
+            fs.disk()
+                .read_exact(FileSystem::pointer(block)?, &mut buf)?;
+            if let Some(entry) = remove_from_block(&mut buf, name)? {
+                fs.disk().write_exact(FileSystem::pointer(block)?, &buf)?;
+                fs.write_inode(my_inode_addr, self)?;
+                fs.update_index(|index, fs| index.entry_removed(fs, my_inode_addr, &entry))?;
+                return Ok(entry);
+            }
+        }
+        Err(FsError::NoEntry)
+    }
+
+    /// Finds the entry called `name`. Sorted directories only read the first entry of each block
+    /// for a binary search and then the one block that can contain the name.
+    pub fn find_dir_entry(
+        &self,
+        fs: &mut FileSystem,
+        name: &[u8],
+    ) -> Result<Option<DirEntry>, FsError> {
+        if self.type_and_permission.get_type() != InodeType::Directory {
+            return Err(FsError::NoEntry);
+        }
+        if self.flags & INODE_FLAG_SORTED_DIR == 0 {
+            return Ok(self
+                .read_all_dir_entries(fs)?
+                .into_iter()
+                .find(|e| e.name_bytes() == name));
+        }
+
+        // the last block whose first entry isn't after `name`; empty blocks only come at the end
+        let (mut low, mut high) = (0, self.block_map(fs)?.len() as u32);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let first = self.read_dir_block(fs, mid)?.into_iter().next();
+            match first {
+                Some(first) if first.name_bytes() <= name => low = mid + 1,
+                _ => high = mid,
             }
         }
+        if low == 0 {
+            return Ok(None);
+        }
+        Ok(self
+            .read_dir_block(fs, low - 1)?
+            .into_iter()
+            .find(|e| e.name_bytes() == name))
+    }
+
+    /// Turns sorted mode on or off. Turning it on sorts the existing entries.
+    pub(crate) fn set_dir_sorted(
+        &mut self,
+        fs: &mut FileSystem,
+        sorted: bool,
+        my_inode_addr: u32,
+    ) -> Result<(), FsError> {
+        if self.type_and_permission.get_type() != InodeType::Directory {
+            return Err(FsError::NoEntry);
+        }
+        if !sorted {
+            self.flags &= !INODE_FLAG_SORTED_DIR;
+            return fs.write_inode(my_inode_addr, self);
+        }
+
+        let mut entries = self.read_all_dir_entries(fs)?;
+        entries.sort_by(|a, b| a.name_bytes().cmp(b.name_bytes()));
+        self.flags |= INODE_FLAG_SORTED_DIR;
+        self.write_dir_entries(fs, &entries, my_inode_addr)
     }
 
     fn get_next_free_block(
@@ -508,81 +1518,310 @@ impl Inode {
     ) -> Result<u32, FsError> {
         let mut blk_id: u32 = 0;
         loop {
-            if let None = self.get_block_id(blk_id, fs) {
+            if self.get_block_id(blk_id, fs)?.is_none() {
                 break;
             }
             blk_id += 1;
         }
 
-        if blk_id < 10 {
-            let blk = fs.allocate_block(false)?;
-            self.block_pointers[blk_id as usize] = blk;
-            fs.write_inode(my_inode_addr, &self)?;
-        } else if blk_id >= 10 && blk_id < 1024 + 10 {
-            if self.singly_indirect_block_pointer == 0 {
-                self.singly_indirect_block_pointer = fs.allocate_block(false)?;
-                fs.write_inode(my_inode_addr, &self)?;
+        self.allocate_block_at(blk_id, fs, my_inode_addr)?;
+        Ok(blk_id)
+    }
+
+    /// Allocates a new block and puts it at index `blk_id` of this inode, charging it and the
+    /// indirect blocks it needs to the owner of the inode
+    fn allocate_block_at(
+        &mut self,
+        blk_id: u32,
+        fs: &mut FileSystem,
+        my_inode_addr: u32,
+    ) -> Result<(), FsError> {
+        let owner = (self.uid, self.gid);
+        fs.charged(my_inode_addr, owner, |fs| {
+            self.allocate_block_uncharged(blk_id, fs, my_inode_addr)
+        })
+    }
+
+    /// How the contents of a regular file are stored, see `FileSystem::set_compression`. Fails
+    /// with `FsError::UnsupportedFeatures` for a codec this implementation doesn't know.
+    pub fn compression(&self) -> Result<Compression, FsError> {
+        Compression::from_u8(self.compression).ok_or(FsError::UnsupportedFeatures)
+    }
+
+    /// Whether the contents are stored in clusters, see `crate::compress`
+    pub(crate) fn is_compressed(&self) -> bool {
+        self.compression != 0 && self.type_and_permission.get_type() == InodeType::File
+    }
+
+    /// Reads the contents of the cluster `cluster` of a compressed file into `out`, which holds a
+    /// cluster. A hole reads as zeroes.
+    fn read_cluster(
+        &self,
+        cluster: u32,
+        fs: &mut FileSystem,
+        out: &mut [u8],
+    ) -> Result<(), FsError> {
+        let first = cluster * CLUSTER_BLOCKS;
+        let mut blocks = Vec::with_capacity(CLUSTER_BLOCKS as usize);
+        for index in first..first + CLUSTER_BLOCKS {
+            blocks.push(self.get_block_id(index, fs)?);
+        }
+        // a stored cluster uses all of its blocks, a compressed one only the first few
+        let used = blocks.iter().take_while(|block| block.is_some()).count();
+        if blocks[used..].iter().any(Option::is_some) {
+            return Err(fs.corrupted());
+        }
+        if used == 0 {
+            out.fill(0);
+            return Ok(());
+        }
+
+        let mut data = vec![0; used * BLOCK_SIZE];
+        for (block, chunk) in blocks
+            .into_iter()
+            .flatten()
+            .zip(data.chunks_mut(BLOCK_SIZE))
+        {
+            fs.disk().read_exact(FileSystem::pointer(block)?, chunk)?;
+        }
+        if used == CLUSTER_BLOCKS as usize {
+            out.copy_from_slice(&data);
+            return Ok(());
+        }
+        match compress::decode(&data, out) {
+            Err(FsError::Corrupted) => Err(fs.corrupted()),
+            result => result,
+        }
+    }
+
+    /// Stores `data`, the contents of the cluster `cluster` up to the end of the file, compressed
+    /// if that saves a block. A cluster of zeroes becomes a hole.
+    fn write_cluster(
+        &mut self,
+        cluster: u32,
+        data: &[u8],
+        fs: &mut FileSystem,
+        my_inode_addr: u32,
+    ) -> Result<(), FsError> {
+        let first = cluster * CLUSTER_BLOCKS;
+        if data.iter().all(|&byte| byte == 0) {
+            return self.free_range(first..first + CLUSTER_BLOCKS, fs, my_inode_addr);
+        }
+        let stored = match compress::encode(self.compression()?, data)? {
+            Some(encoded) => encoded,
+            None => {
+                let mut stored = data.to_vec();
+                stored.resize(CLUSTER_SIZE, 0);
+                stored
             }
-            let blk = fs.allocate_block(false)?;
-            fs.get_disk().write_struct(
-                self.singly_indirect_block_pointer as usize + (blk_id as usize - 10) * 4,
-                &blk,
-            )?;
-        } else if blk_id >= 1024 + 10 && blk_id < 1024 * 1024 + 10 {
-            if self.doubly_indirect_block_pointer == 0 {
-                self.doubly_indirect_block_pointer = fs.allocate_block(false)?;
-                fs.write_inode(my_inode_addr, &self)?;
+        };
+
+        let used = stored.len().div_ceil(BLOCK_SIZE) as u32;
+        self.free_range(first + used..first + CLUSTER_BLOCKS, fs, my_inode_addr)?;
+        for (index, chunk) in (first..).zip(stored.chunks(BLOCK_SIZE)) {
+            if self.get_block_id(index, fs)?.is_none() {
+                self.allocate_block_at(index, fs, my_inode_addr)?;
             }
-            let singly_blk_ptr = fs.allocate_block(false)?;
-            fs.get_disk().write_struct(
-                self.doubly_indirect_block_pointer as usize + ((blk_id as usize - 10) / 1024 * 4),
-                &singly_blk_ptr,
-            )?;
-            let blk = fs.allocate_block(false)?;
-            fs.get_disk().write_struct(
-                singly_blk_ptr as usize + ((blk_id as usize - 10) % 1024 * 4),
-                &blk,
-            )?;
-        } else {
-            return Err(FsError::DiskError(DiskError::NotEnoughSpace));
+            let block_id = self.writable_block_id(index, fs, my_inode_addr)?;
+            let mut block = [0; BLOCK_SIZE];
+            block[..chunk.len()].copy_from_slice(chunk);
+            fs.disk()
+                .write_exact(FileSystem::pointer(block_id)?, &block)?;
         }
+        Ok(())
+    }
 
-        Ok(blk_id)
+    /// `read` for a compressed file, `buf` has to end within the file
+    fn read_compressed(
+        &self,
+        off: usize,
+        buf: &mut [u8],
+        fs: &mut FileSystem,
+    ) -> Result<(), FsError> {
+        let mut data = vec![0; CLUSTER_SIZE];
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = off + done;
+            let cluster = u32::try_from(pos / CLUSTER_SIZE).map_err(|_| FsError::NoEntry)?;
+            let at = pos % CLUSTER_SIZE;
+            let len = (CLUSTER_SIZE - at).min(buf.len() - done);
+            self.read_cluster(cluster, fs, &mut data)?;
+            buf[done..done + len].copy_from_slice(&data[at..at + len]);
+            done += len;
+        }
+        Ok(())
     }
 
-    fn get_next_free_dir_entry_slot(
+    /// Writes `buf` at byte `offset` of a compressed file, compressing every cluster it touches
+    /// again. The caller sets the size.
+    fn write_compressed(
         &mut self,
+        offset: usize,
+        buf: &[u8],
         fs: &mut FileSystem,
         my_inode_addr: u32,
-    ) -> Result<(u32, u32, u32), FsError> {
-        let mut blk_id = 0;
-        let mut off: u32 = 0;
-        let mut slot_id: u32 = 0;
+    ) -> Result<(), FsError> {
+        let end = offset + buf.len();
+        if end as u64 > MAX_COMPRESSED_FILE_SIZE {
+            return Err(FsError::FileTooLarge);
+        }
+        let size = (self.size() as usize).max(end);
+        let mut data = vec![0; CLUSTER_SIZE];
+        let mut pos = offset;
+        while pos < end {
+            let cluster = pos / CLUSTER_SIZE;
+            let start = cluster * CLUSTER_SIZE;
+            let len = (size - start).min(CLUSTER_SIZE);
+            let at = pos - start;
+            let written = (CLUSTER_SIZE - at).min(end - pos);
+            // the old contents only matter if some of them are left
+            if at != 0 || written < len {
+                self.read_cluster(cluster as u32, fs, &mut data)?;
+            }
+            data[at..at + written].copy_from_slice(&buf[pos - offset..pos - offset + written]);
+            self.write_cluster(cluster as u32, &data[..len], fs, my_inode_addr)?;
+            pos += written;
+        }
+        Ok(())
+    }
 
-        loop {
-            let block = self.get_block_id(blk_id, fs);
-            match block {
-                None => {
-                    blk_id = self.get_next_free_block(fs, my_inode_addr)?;
-                    continue;
-                }
-                Some(v) => {
-                    let dir_entry = fs
-                        .get_disk()
-                        .read_struct::<DirEntry>(v as usize * BLOCK_SIZE + off as usize)?;
-                    if dir_entry.inode == 0 || dir_entry.is_empty() {
-                        return Ok((blk_id, off, slot_id));
-                    } else {
-                        off += dir_entry.get_size();
-                        if off >= 3796 {
-                            // dir_entry wouldnt fit in this block anymore
-                            blk_id += 1;
-                            off = 0;
-                        }
-                        slot_id += 1;
-                    }
-                }
+    /// `truncate` for a compressed file. Clusters past the end are freed and the last one is
+    /// stored again without the contents past the end, so growing the file reads zeroes there.
+    fn truncate_compressed(
+        &mut self,
+        len: u64,
+        fs: &mut FileSystem,
+        my_inode_addr: u32,
+    ) -> Result<(), FsError> {
+        if len > MAX_COMPRESSED_FILE_SIZE {
+            return Err(FsError::FileTooLarge);
+        }
+        if len >= self.size() {
+            return Ok(());
+        }
+        let len = len as usize;
+        let cluster = (len / CLUSTER_SIZE) as u32;
+        let at = len % CLUSTER_SIZE;
+        if at != 0 {
+            let mut data = vec![0; CLUSTER_SIZE];
+            self.read_cluster(cluster, fs, &mut data)?;
+            self.write_cluster(cluster, &data[..at], fs, my_inode_addr)?;
+        }
+        let kept = len.div_ceil(CLUSTER_SIZE) as u32 * CLUSTER_BLOCKS;
+        self.free_range(kept..DOUBLY_END, fs, my_inode_addr)
+    }
+
+    /// The physical block of the allocated block `blk_id`, moved to a new block first if a
+    /// snapshot shares it, see `FileSystem::create_snapshot`
+    fn writable_block_id(
+        &mut self,
+        blk_id: u32,
+        fs: &mut FileSystem,
+        my_inode_addr: u32,
+    ) -> Result<u32, FsError> {
+        let block = self.get_block_id(blk_id, fs)?.ok_or(FsError::NoEntry)?;
+        if !fs.snapshots().is_frozen(block) {
+            return Ok(block);
+        }
+        let mut data = [0; BLOCK_SIZE];
+        fs.disk()
+            .read_exact(FileSystem::pointer(block)?, &mut data)?;
+        let (data_zone, hints, previous) = self.placement(blk_id, fs)?;
+        let owner = (self.uid, self.gid);
+        fs.charged(my_inode_addr, owner, |fs| {
+            let moved = fs.allocate_block_for(data_zone, hints, previous)?;
+            fs.disk().write_exact(FileSystem::pointer(moved)?, &data)?;
+            self.set_block_id(blk_id, moved, fs, my_inode_addr)?;
+            // only gives the block back to the owner, the snapshots keep it
+            fs.free_block(block)?;
+            Ok(moved)
+        })
+    }
+
+    /// Where a new block at `blk_id` goes: its zone, the storage hints of the file and the block
+    /// before it for cold files
+    fn placement(
+        &self,
+        blk_id: u32,
+        fs: &mut FileSystem,
+    ) -> Result<(AllocZone, StorageHints, Option<u32>), FsError> {
+        let data_zone = if self.type_and_permission.get_type() == InodeType::Directory {
+            AllocZone::Metadata
+        } else if blk_id < DIRECT_POINTERS {
+            AllocZone::SmallFiles
+        } else {
+            AllocZone::LargeFiles
+        };
+        let hints = self.storage_hints();
+        // cold files are allocated from the end of the image, block after block
+        let previous = match hints.zone(data_zone) {
+            None if blk_id > 0 => self.get_block_id(blk_id - 1, fs)?,
+            _ => None,
+        };
+        Ok((data_zone, hints, previous))
+    }
+
+    fn allocate_block_uncharged(
+        &mut self,
+        blk_id: u32,
+        fs: &mut FileSystem,
+        my_inode_addr: u32,
+    ) -> Result<(), FsError> {
+        let (data_zone, hints, previous) = self.placement(blk_id, fs)?;
+
+        if blk_id < DIRECT_POINTERS {
+            let blk = fs.allocate_block_for(data_zone, hints, previous)?;
+            self.block_pointers[blk_id as usize] = blk;
+            fs.write_inode(my_inode_addr, self)?;
+        } else if blk_id < SINGLY_END {
+            if self.singly_indirect_block_pointer == 0 {
+                self.singly_indirect_block_pointer = fs.allocate_block_in(AllocZone::Metadata)?;
+                fs.write_inode(my_inode_addr, self)?;
+            }
+            let blk = fs.allocate_block_for(data_zone, hints, previous)?;
+            let index = (blk_id - DIRECT_POINTERS) as usize;
+            Self::write_pointer(fs, self.singly_indirect_block_pointer, index, blk)?;
+        } else if blk_id < DOUBLY_END {
+            if self.doubly_indirect_block_pointer == 0 {
+                self.doubly_indirect_block_pointer = fs.allocate_block_in(AllocZone::Metadata)?;
+                fs.write_inode(my_inode_addr, self)?;
+            }
+            let index = (blk_id - SINGLY_END) as usize;
+            let mut singly = Self::read_pointer(
+                fs,
+                self.doubly_indirect_block_pointer,
+                index / POINTERS_PER_BLOCK,
+            )?;
+            if singly == 0 {
+                singly = fs.allocate_block_in(AllocZone::Metadata)?;
+                Self::write_pointer(
+                    fs,
+                    self.doubly_indirect_block_pointer,
+                    index / POINTERS_PER_BLOCK,
+                    singly,
+                )?;
             }
+            let blk = fs.allocate_block_for(data_zone, hints, previous)?;
+            Self::write_pointer(fs, singly, index % POINTERS_PER_BLOCK, blk)?;
+        } else {
+            return Err(FsError::FileTooLarge);
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads from `reader` until `buf` is full or it ends, returns how much was read
+#[cfg(feature = "std")]
+fn fill_from(reader: &mut dyn Read, buf: &mut [u8]) -> Result<usize, FsError> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(FsError::IoError(e)),
         }
     }
+    Ok(filled)
 }
@@ -0,0 +1,148 @@
+use alloc::{vec, vec::Vec};
+use core::ops::Range;
+
+use crate::{
+    addr::Addr,
+    fs::{BlockArrayEntry, FileSystem, FsError, BLOCK_SIZE, INODES_PER_BLOCK},
+    inode::Inode,
+};
+
+/// The inodes one block of the inode bitmap covers
+pub const INODES_PER_BITMAP_BLOCK: u32 = BLOCK_SIZE as u32 * 8;
+
+/// The blocks the inode bitmap of an image with `total_blocks` blocks takes up
+pub(crate) fn bitmap_blocks(total_blocks: u32) -> u32 {
+    (total_blocks as u64 * INODES_PER_BLOCK as u64).div_ceil(INODES_PER_BITMAP_BLOCK as u64) as u32
+}
+
+/// The address of the byte holding the bit of `inode_nbr` and the mask of the bit
+fn bit_addr(fs: &FileSystem, inode_nbr: u32) -> Result<(usize, u8), FsError> {
+    let block = fs.inode_bitmap_area().start + inode_nbr / INODES_PER_BITMAP_BLOCK;
+    let byte = (inode_nbr % INODES_PER_BITMAP_BLOCK / 8) as usize;
+    Ok((
+        Addr::block(block)?.offset(byte)?.get(),
+        1 << (inode_nbr % 8),
+    ))
+}
+
+/// Marks the inode `inode_nbr` as free or in use, keeping the free inode count up to date
+pub(crate) fn set_free(fs: &mut FileSystem, inode_nbr: u32, free: bool) -> Result<(), FsError> {
+    let (addr, mask) = bit_addr(fs, inode_nbr)?;
+    let byte = fs.disk().read_struct::<u8>(addr)?;
+    if (byte & mask != 0) == free {
+        return Ok(());
+    }
+
+    fs.disk().write_struct(addr, &(byte ^ mask))?;
+    if free {
+        fs.superblock.free_inodes += 1;
+    } else {
+        fs.superblock.free_inodes = fs.superblock.free_inodes.saturating_sub(1);
+    }
+    fs.write_superblock()
+}
+
+/// Marks every inode of the inode block `block` as free, after it became an inode block, or as
+/// unusable, after it stopped being one
+pub(crate) fn set_block_free(fs: &mut FileSystem, block: u32, free: bool) -> Result<(), FsError> {
+    // the inodes of a block are 4 aligned bytes of the bitmap
+    let (addr, _) = bit_addr(fs, block * INODES_PER_BLOCK)?;
+    let bits = fs.disk().read_struct::<u32>(addr)?;
+    let new_bits = if free { u32::MAX } else { 0 };
+    if bits == new_bits {
+        return Ok(());
+    }
+
+    fs.disk().write_struct(addr, &new_bits)?;
+    fs.superblock.free_inodes =
+        fs.superblock.free_inodes - bits.count_ones() + new_bits.count_ones();
+    fs.write_superblock()
+}
+
+/// The first free inode in `range` that `usable` accepts
+pub(crate) fn find_free(
+    fs: &mut FileSystem,
+    range: Range<u32>,
+    usable: impl Fn(&FileSystem, u32) -> bool,
+) -> Result<Option<u32>, FsError> {
+    if fs.superblock.free_inodes == 0 || range.is_empty() {
+        return Ok(None);
+    }
+
+    let area = fs.inode_bitmap_area();
+    let mut buf = [0; BLOCK_SIZE];
+    let first = range.start / INODES_PER_BITMAP_BLOCK;
+    let last = (range.end - 1) / INODES_PER_BITMAP_BLOCK;
+    for index in first..=last.min(area.len() as u32 - 1) {
+        fs.disk()
+            .read_exact(FileSystem::pointer(area.start + index)?, &mut buf)?;
+        for (byte_idx, &byte) in buf.iter().enumerate() {
+            if byte == 0 {
+                continue;
+            }
+            let base = index * INODES_PER_BITMAP_BLOCK + byte_idx as u32 * 8;
+            for bit in 0..8 {
+                let inode_nbr = base + bit;
+                if byte & (1 << bit) != 0 && range.contains(&inode_nbr) && usable(fs, inode_nbr) {
+                    return Ok(Some(inode_nbr));
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// The bitmap as it should be: a bit for every unlinked inode in an inode block
+fn expected(fs: &mut FileSystem) -> Result<Vec<u8>, FsError> {
+    let mut bitmap = vec![0; fs.inode_bitmap_area().len() * BLOCK_SIZE];
+    for block in 0..fs.superblock.total_blocks {
+        if fs.block_type(block)? != BlockArrayEntry::InodeBlock {
+            continue;
+        }
+        let inodes = fs
+            .disk()
+            .read_struct::<[Inode; INODES_PER_BLOCK as usize]>(FileSystem::pointer(block)?)?;
+        for (inode_nbr, inode) in (block * INODES_PER_BLOCK..).zip(inodes) {
+            if inode.hardlinks == 0 {
+                bitmap[inode_nbr as usize / 8] |= 1 << (inode_nbr % 8);
+            }
+        }
+    }
+    Ok(bitmap)
+}
+
+/// Every inode whose bit is wrong and the number of free inodes
+pub(crate) fn check(fs: &mut FileSystem) -> Result<(Vec<u32>, u32), FsError> {
+    let bitmap = expected(fs)?;
+    let mut wrong = Vec::new();
+    let mut buf = [0; BLOCK_SIZE];
+    for (index, block) in fs.inode_bitmap_area().enumerate() {
+        fs.disk()
+            .read_exact(FileSystem::pointer(block)?, &mut buf)?;
+        let expected = &bitmap[index * BLOCK_SIZE..(index + 1) * BLOCK_SIZE];
+        for (byte_idx, (&actual, &expected)) in buf.iter().zip(expected).enumerate() {
+            let diff = actual ^ expected;
+            let base = (index * BLOCK_SIZE + byte_idx) as u32 * 8;
+            wrong.extend(
+                (0..8)
+                    .filter(|bit| diff & (1 << bit) != 0)
+                    .map(|bit| base + bit),
+            );
+        }
+    }
+    let free = bitmap.iter().map(|byte| byte.count_ones()).sum();
+    Ok((wrong, free))
+}
+
+/// Writes the bitmap and the free inode count from the inode blocks
+pub(crate) fn rebuild(fs: &mut FileSystem) -> Result<(), FsError> {
+    let bitmap = expected(fs)?;
+    for (index, block) in fs.inode_bitmap_area().enumerate() {
+        fs.disk().write_exact(
+            FileSystem::pointer(block)?,
+            &bitmap[index * BLOCK_SIZE..(index + 1) * BLOCK_SIZE],
+        )?;
+    }
+    fs.superblock.free_inodes = bitmap.iter().map(|byte| byte.count_ones()).sum();
+    fs.write_superblock()
+}
@@ -0,0 +1,815 @@
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{
+    addr::Addr,
+    disk::{Disk, DiskError, IO},
+    fs::{BlockArrayEntry, FileSystem, FsError, BLOCKS_PER_BLOCKARRAY, BLOCK_SIZE},
+    sha256::Sha256,
+};
+
+pub const JOURNAL_SIGNATURE: &[u8; 8] = b"SFs jrnl";
+/// The most blocks a single transaction can change, limited by the block list in the header
+pub const JOURNAL_MAX_BLOCKS: usize = 1004;
+/// `JournalHeader::prev` of the first record
+const NO_RECORD: u32 = u32::MAX;
+/// `JournalHeader::flags`: the record only lists blocks that were written without the journal,
+/// their new contents aren't in it
+const UNRECORDED: u32 = 1;
+
+type Block = Box<[u8; BLOCK_SIZE]>;
+
+/// The header of a record in the journal. The first block of the journal (the head) holds a
+/// copy of the header of the newest record, the blocks after it form a ring of records: each one
+/// is a header followed by the new contents of the blocks in `blocks`, in the same order.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub(crate) struct JournalHeader {
+    pub(crate) signature: [u8; 8],
+    /// increases with every record
+    pub(crate) sequence: u64,
+    /// when the record was written, in seconds since the UNIX epoch
+    pub(crate) time: u64,
+    /// 1 in the head if the transaction is complete but maybe not written to its home blocks yet
+    pub(crate) committed: u32,
+    pub(crate) count: u32,
+    /// SHA-256 over the sequence, the time, the flags, the block list and the contents of the
+    /// blocks
+    pub(crate) checksum: [u8; 32],
+    /// where the header is in the ring, the block after the head is 0
+    pub(crate) at: u32,
+    /// `at` of the record before this one, `NO_RECORD` for the first one
+    pub(crate) prev: u32,
+    pub(crate) flags: u32,
+    pub(crate) reserved: u32,
+    pub(crate) blocks: [u32; JOURNAL_MAX_BLOCKS],
+}
+
+impl JournalHeader {
+    fn read(disk: &mut Disk, block: u32) -> Result<Self, FsError> {
+        Ok(disk.read_struct(Addr::block(block)?.get())?)
+    }
+
+    /// Whether this is a record that fits into the ring of a journal of `len` blocks
+    fn is_valid(&self, len: u32) -> bool {
+        self.signature == *JOURNAL_SIGNATURE
+            && self.count as usize <= JOURNAL_MAX_BLOCKS
+            && self.end() < len as u64
+    }
+
+    fn is_unrecorded(&self) -> bool {
+        self.flags & UNRECORDED != 0
+    }
+
+    fn homes(&self) -> &[u32] {
+        &self.blocks[..self.count as usize]
+    }
+
+    /// The number of blocks after the header that hold contents
+    fn copies(&self) -> u32 {
+        if self.is_unrecorded() {
+            0
+        } else {
+            self.count
+        }
+    }
+
+    /// The position in the ring right after this record
+    fn end(&self) -> u64 {
+        self.at as u64 + 1 + self.copies() as u64
+    }
+
+    fn checksum(&self, data: &[Block]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(&self.sequence.to_le_bytes());
+        hasher.update(&self.time.to_le_bytes());
+        hasher.update(&self.flags.to_le_bytes());
+        for block in self.homes() {
+            hasher.update(&block.to_le_bytes());
+        }
+        for block in data {
+            hasher.update(block.as_slice());
+        }
+        hasher.finalize()
+    }
+
+    /// Reads the contents of the blocks of this record in the journal starting at `start`
+    fn read_copies(&self, disk: &mut Disk, start: u32) -> Result<Vec<Block>, FsError> {
+        (0..self.copies())
+            .map(|i| {
+                let mut block = Box::new([0; BLOCK_SIZE]);
+                disk.read_exact(
+                    Addr::block(start + 2 + self.at + i)?.get(),
+                    block.as_mut_slice(),
+                )?;
+                Ok(block)
+            })
+            .collect()
+    }
+}
+
+/// A record in the journal, see `FileSystem::journal_history`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TxnInfo {
+    /// The id of the transaction, see `FileSystem::read_file_at_txn`
+    pub sequence: u64,
+    /// When it was committed, in seconds since the UNIX epoch
+    pub time: u64,
+    /// The number of blocks it changed, 0 for an unrecorded record that stands for any block
+    pub blocks: u32,
+    /// Whether the record only notes blocks that were written outside of a transaction, e.g. by
+    /// `FileSystem::write_block`. Their old contents are gone, so nothing that depends on them
+    /// can be read as of an earlier transaction.
+    pub unrecorded: bool,
+}
+
+impl From<&JournalHeader> for TxnInfo {
+    fn from(header: &JournalHeader) -> Self {
+        Self {
+            sequence: header.sequence,
+            time: header.time,
+            blocks: header.count,
+            unrecorded: header.is_unrecorded(),
+        }
+    }
+}
+
+/// A group of changes that reach the disk either completely or not at all, see
+/// `FileSystem::begin_txn`. Every write made through `fs` is kept in memory until `commit`. If
+/// the transaction is dropped without committing, the changes are thrown away.
+pub struct Transaction<'a> {
+    fs: &'a mut FileSystem,
+    done: bool,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(fs: &'a mut FileSystem) -> Self {
+        fs.disk().start_buffering_all();
+        Self { fs, done: false }
+    }
+
+    /// The file system to make the changes through
+    pub fn fs(&mut self) -> &mut FileSystem {
+        self.fs
+    }
+
+    /// Writes all changes, through the journal if the image has one. If the transaction doesn't
+    /// fit into the journal, nothing is written and `FsError::NoSpace` is returned. The changes
+    /// are durable once this returns if the disk is mounted with `sync`, otherwise after the next
+    /// `FileSystem::sync`.
+    pub fn commit(self) -> Result<(), FsError> {
+        self.finish(false)
+    }
+
+    /// Same as `commit`, but a transaction that doesn't fit into the journal is written like on
+    /// an image without one, see `write_in_order`
+    pub(crate) fn commit_any_size(self) -> Result<(), FsError> {
+        self.finish(true)
+    }
+
+    fn finish(mut self, any_size: bool) -> Result<(), FsError> {
+        self.done = true;
+        let start = self.fs.journal_area().start;
+        let len = self.fs.journal_area().len() as u32;
+        let fits = fits(self.fs.disk().buffered_blocks().len(), len);
+
+        let result = if len == 0 || (any_size && !fits) {
+            write_in_order(self.fs)
+        } else {
+            let blocks = self.fs.disk().take_buffer();
+            let time = self.fs.now();
+            // earlier writes outside of transactions come before this one in the journal
+            record_unjournaled(self.fs, [])
+                .and_then(|()| commit(self.fs.disk(), start, len, time, blocks))
+        };
+        if result.is_err() {
+            self.fs.rollback()?;
+        } else {
+            // everything that was written is in the journal now
+            self.fs.disk().take_written();
+        }
+        result
+    }
+
+    /// Throws away all changes
+    pub fn abort(mut self) -> Result<(), FsError> {
+        self.done = true;
+        self.fs.disk().take_buffer();
+        self.fs.rollback()
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.fs.disk().take_buffer();
+            // there's no way to report the error here; use `abort` to get it
+            let _ = self.fs.rollback();
+        }
+    }
+}
+
+/// Whether a transaction changing `blocks` blocks fits into a journal of `len` blocks: the head,
+/// the header of the record and the contents have to fit
+fn fits(blocks: usize, len: u32) -> bool {
+    blocks <= JOURNAL_MAX_BLOCKS && blocks + 2 <= len as usize
+}
+
+/// Where a block comes in the order `write_in_order` writes them
+fn write_rank(fs: &mut FileSystem, block: u32) -> Result<u8, FsError> {
+    Ok(if block == 1 {
+        3
+    } else if fs.is_inode_table_block(block)
+        || fs.inode_bitmap_area().contains(&block)
+        || fs.block_type(block)? == BlockArrayEntry::InodeBlock
+    {
+        2
+    } else if block.is_multiple_of(BLOCKS_PER_BLOCKARRAY) {
+        1
+    } else {
+        0
+    })
+}
+
+/// Writes the buffered blocks of an image without a journal straight to their home locations, in
+/// a fixed order, with a barrier (see `Disk::barrier`) after each group: first data,
+/// directory and pointer blocks, then the block arrays, then the inodes that point at the
+/// blocks, and the superblock last. A crash in between can leak newly allocated blocks, which
+/// fsck gives back, but only leaves an inode pointing at free blocks if the transaction freed
+/// some. On an image with a journal (for transactions too big for it), the blocks are noted in
+/// the journal first, see `record_unjournaled`.
+fn write_in_order(fs: &mut FileSystem) -> Result<(), FsError> {
+    let ranks: Result<BTreeMap<u32, u8>, FsError> = fs
+        .disk()
+        .buffered_blocks()
+        .into_iter()
+        .map(|block| Ok((block, write_rank(fs, block)?)))
+        .collect();
+    let blocks = fs.disk().take_buffer();
+    let ranks = ranks?;
+    record_unjournaled(fs, blocks.keys().copied())?;
+
+    for rank in 0..=3 {
+        let mut written = BTreeSet::new();
+        for (block, data) in &blocks {
+            if ranks[block] == rank {
+                fs.disk()
+                    .write_exact(Addr::block(*block)?.get(), data.as_slice())?;
+                written.insert(*block);
+            }
+        }
+        if !written.is_empty() {
+            fs.disk().barrier(&written)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes `blocks` to the journal, then to their home locations. Only the blocks of the journal
+/// and the transaction are written out of a write-back cache, so committing stays cheap however
+/// much else the cache holds.
+fn commit(
+    disk: &mut Disk,
+    start: u32,
+    len: u32,
+    time: u64,
+    blocks: BTreeMap<u32, Block>,
+) -> Result<(), FsError> {
+    if blocks.is_empty() {
+        return Ok(());
+    }
+    let (header, data) = write_journal(disk, start, len, time, blocks)?;
+    checkpoint(disk, start, &header, &data)
+}
+
+/// Writes `blocks` to the journal and marks it as committed, without touching their home blocks
+fn write_journal(
+    disk: &mut Disk,
+    start: u32,
+    len: u32,
+    time: u64,
+    blocks: BTreeMap<u32, Block>,
+) -> Result<(Box<JournalHeader>, Vec<Block>), FsError> {
+    if !fits(blocks.len(), len) {
+        return Err(FsError::NoSpace);
+    }
+    let (homes, data): (Vec<u32>, Vec<_>) = blocks.into_iter().unzip();
+    let header = append(disk, start, len, time, 0, &homes, &data)?;
+    Ok((header, data))
+}
+
+/// Writes a record after the newest one in the ring, or at its start if it doesn't fit there
+/// anymore, overwriting the oldest records. Then the head is pointed at it, with `committed` set
+/// if the record has contents to copy to their home blocks.
+fn append(
+    disk: &mut Disk,
+    start: u32,
+    len: u32,
+    time: u64,
+    flags: u32,
+    homes: &[u32],
+    data: &[Block],
+) -> Result<Box<JournalHeader>, FsError> {
+    let ring = len.saturating_sub(1) as u64;
+    let size = 1 + data.len() as u64;
+    if size > ring {
+        return Err(FsError::NoSpace);
+    }
+
+    let previous = Some(JournalHeader::read(disk, start)?).filter(|head| head.is_valid(len));
+    let mut header = Box::new(JournalHeader {
+        signature: *JOURNAL_SIGNATURE,
+        sequence: previous.map_or(0, |head| head.sequence.wrapping_add(1)),
+        time,
+        committed: 0,
+        count: homes.len() as u32,
+        checksum: [0; 32],
+        at: match previous {
+            Some(head) if head.end() + size <= ring => head.end() as u32,
+            _ => 0,
+        },
+        prev: previous.map_or(NO_RECORD, |head| head.at),
+        flags,
+        reserved: 0,
+        blocks: [0; JOURNAL_MAX_BLOCKS],
+    });
+    header.blocks[..homes.len()].copy_from_slice(homes);
+    header.checksum = header.checksum(data);
+
+    // the record has to reach the disk before the head says it is complete, and the head before
+    // the home blocks are overwritten. The barriers only wait for the disk when it is mounted
+    // with `sync`; otherwise the record becomes durable with the next `sync`.
+    let first = start + 1 + header.at;
+    disk.write_struct(Addr::block(first)?.get(), &*header)?;
+    for (i, block) in data.iter().enumerate() {
+        disk.write_exact(Addr::block(first + 1 + i as u32)?.get(), block.as_slice())?;
+    }
+    disk.barrier(&(first..first + size as u32).collect())?;
+    header.committed = u32::from(!data.is_empty());
+    disk.write_struct(Addr::block(start)?.get(), &*header)?;
+    disk.barrier(&BTreeSet::from([start]))?;
+    Ok(header)
+}
+
+/// Copies the blocks of a committed transaction to their home locations and marks the journal
+/// as clean
+fn checkpoint(
+    disk: &mut Disk,
+    start: u32,
+    header: &JournalHeader,
+    data: &[Block],
+) -> Result<(), FsError> {
+    for (home, block) in header.blocks.iter().zip(data) {
+        disk.write_exact(Addr::block(*home)?.get(), block.as_slice())?;
+    }
+    disk.barrier(&header.blocks[..data.len()].iter().copied().collect())?;
+
+    let mut header = *header;
+    header.committed = 0;
+    disk.write_struct(Addr::block(start)?.get(), &header)?;
+    Ok(())
+}
+
+/// Notes the blocks written outside of transactions since the last record (see
+/// `Disk::track_writes`) and `also` in the journal, so reads of earlier transactions know their
+/// old contents are gone. Writes that aren't noted before a crash stay unknown to the journal.
+pub(crate) fn record_unjournaled(
+    fs: &mut FileSystem,
+    also: impl IntoIterator<Item = u32>,
+) -> Result<(), FsError> {
+    let journal = fs.journal_area();
+    if journal.len() < 2 || fs.disk().is_read_only() {
+        return Ok(());
+    }
+    let mut blocks = fs.disk().take_written();
+    blocks.extend(also);
+    if blocks.is_empty() {
+        return Ok(());
+    }
+    // a record without blocks stands for all of them
+    let homes: Vec<u32> = if blocks.len() <= JOURNAL_MAX_BLOCKS {
+        blocks.into_iter().collect()
+    } else {
+        Vec::new()
+    };
+    let time = fs.now();
+    append(
+        fs.disk(),
+        journal.start,
+        journal.len() as u32,
+        time,
+        UNRECORDED,
+        &homes,
+        &[],
+    )?;
+    fs.disk().take_written();
+    Ok(())
+}
+
+/// Whether the journal holds a complete transaction that hasn't reached its home blocks yet
+pub(crate) fn is_dirty(disk: &mut Disk, start: u32, len: u32) -> Result<bool, FsError> {
+    let header = JournalHeader::read(disk, start)?;
+    Ok(header.is_valid(len) && header.committed == 1)
+}
+
+/// Finishes a transaction that was committed before a crash. A transaction that wasn't completely
+/// written to the journal (the checksum doesn't match) is thrown away, and the head points at
+/// the record before it again. Returns whether anything was replayed.
+pub(crate) fn replay(disk: &mut Disk, start: u32, len: u32) -> Result<bool, FsError> {
+    let header = JournalHeader::read(disk, start)?;
+    if !header.is_valid(len) || header.committed != 1 {
+        return Ok(false);
+    }
+
+    let data = header.read_copies(disk, start)?;
+    if header.checksum(&data) != header.checksum {
+        let mut head = header;
+        if header.prev != NO_RECORD {
+            let prev = JournalHeader::read(disk, start + 1 + header.prev)?;
+            if prev.is_valid(len) && prev.sequence == header.sequence.wrapping_sub(1) {
+                head = prev;
+            }
+        }
+        head.committed = 0;
+        disk.write_struct(Addr::block(start)?.get(), &head)?;
+        return Ok(false);
+    }
+
+    checkpoint(disk, start, &header, &data)?;
+    Ok(true)
+}
+
+/// The complete records still in the journal, the newest first. Going back stops at the first
+/// record that was overwritten, completely or in part, by a newer one.
+fn records(disk: &mut Disk, start: u32, len: u32) -> Result<Vec<JournalHeader>, FsError> {
+    let mut records = Vec::new();
+    let mut header = JournalHeader::read(disk, start)?;
+    // every record takes at least one block of the ring
+    for newest in (0..len).map(|i| i == 0) {
+        if !header.is_valid(len) {
+            break;
+        }
+        let data = header.read_copies(disk, start)?;
+        if header.checksum(&data) == header.checksum {
+            records.push(header);
+        } else if !newest {
+            // a torn newest record is skipped, it wasn't dropped on a read-only disk
+            break;
+        }
+        if header.prev == NO_RECORD || header.prev >= len - 1 {
+            break;
+        }
+        let prev = JournalHeader::read(disk, start + 1 + header.prev)?;
+        if prev.sequence != header.sequence.wrapping_sub(1) || prev.at != header.prev {
+            break;
+        }
+        header = prev;
+    }
+    Ok(records)
+}
+
+/// The records still in the journal, the newest first, see `FileSystem::journal_history`
+pub(crate) fn history(disk: &mut Disk, start: u32, len: u32) -> Result<Vec<TxnInfo>, FsError> {
+    Ok(records(disk, start, len)?
+        .iter()
+        .map(TxnInfo::from)
+        .collect())
+}
+
+/// The disk as it was right after the transaction `txn`. Blocks that changed since are read
+/// from their copies in the journal. Those that were written outside of a transaction (like
+/// `written`, the ones not noted in the journal yet) or whose copy was overwritten can't be
+/// read, see `PastIO`.
+pub(crate) fn past_io(
+    disk: &mut Disk,
+    start: u32,
+    len: u32,
+    txn: u64,
+    written: BTreeSet<u32>,
+) -> Result<PastIO, FsError> {
+    let records = records(disk, start, len)?;
+    let Some(pos) = records
+        .iter()
+        .position(|record| record.sequence == txn && !record.is_unrecorded())
+    else {
+        return Err(FsError::NotJournaled);
+    };
+
+    // the blocks changed after `txn`, which need their contents from back then
+    let mut changed = written;
+    for record in &records[..pos] {
+        if record.is_unrecorded() && record.count == 0 {
+            return Err(FsError::NotJournaled);
+        }
+        changed.extend(record.homes());
+    }
+
+    let mut blocks = BTreeMap::new();
+    let mut unknown = BTreeSet::new();
+    for record in &records[pos..] {
+        if changed.is_empty() || (record.is_unrecorded() && record.count == 0) {
+            break;
+        }
+        let homes: Vec<(usize, u32)> = record
+            .homes()
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|(_, home)| changed.remove(home))
+            .collect();
+        if record.is_unrecorded() {
+            unknown.extend(homes.into_iter().map(|(_, home)| home));
+            continue;
+        }
+        for (i, home) in homes {
+            let mut block = Box::new([0; BLOCK_SIZE]);
+            disk.read_exact(
+                Addr::block(start + 2 + record.at + i as u32)?.get(),
+                block.as_mut_slice(),
+            )?;
+            blocks.insert(home, block);
+        }
+    }
+    // older than the journal reaches back
+    unknown.append(&mut changed);
+
+    Ok(PastIO {
+        base: disk.fork_readonly(),
+        blocks,
+        unknown,
+        missed: Arc::new(AtomicBool::new(false)),
+    })
+}
+
+/// Reads a disk as it was after an earlier transaction, see `past_io`. Reads of a block whose
+/// contents back then are unknown fail and set `missed`.
+pub(crate) struct PastIO {
+    base: Disk,
+    blocks: BTreeMap<u32, Block>,
+    unknown: BTreeSet<u32>,
+    pub(crate) missed: Arc<AtomicBool>,
+}
+
+impl IO for PastIO {
+    fn read_lossy(&mut self, addr: usize, buf: &mut [u8]) -> Result<usize, DiskError> {
+        let mut done = 0;
+        while done < buf.len() {
+            let block = ((addr + done) / BLOCK_SIZE) as u32;
+            let off = (addr + done) % BLOCK_SIZE;
+            let chunk_len = (BLOCK_SIZE - off).min(buf.len() - done);
+            let chunk = &mut buf[done..done + chunk_len];
+
+            if self.unknown.contains(&block) {
+                self.missed.store(true, Ordering::Relaxed);
+                return Err(DiskError::GenericError);
+            }
+            match self.blocks.get(&block) {
+                Some(data) => chunk.copy_from_slice(&data[off..off + chunk_len]),
+                None => {
+                    let read = self.base.read_lossy(addr + done, chunk)?;
+                    if read != chunk_len {
+                        return Ok(done + read);
+                    }
+                }
+            }
+            done += chunk_len;
+        }
+        Ok(done)
+    }
+
+    fn write_lossy(&mut self, _addr: usize, _buf: &[u8]) -> Result<usize, DiskError> {
+        Err(DiskError::ReadOnly)
+    }
+
+    fn size(&mut self) -> Option<u64> {
+        self.base.size()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::{
+        disk::{DiskError, IO},
+        fs::{MkfsOptions, MountOptions},
+        Sfs,
+    };
+
+    const BLOCKS: u32 = 300;
+
+    /// An image that counts how often it is synced
+    struct SyncCounter {
+        image: Vec<u8>,
+        syncs: Arc<AtomicUsize>,
+    }
+
+    impl IO for SyncCounter {
+        fn read_lossy(&mut self, addr: usize, buf: &mut [u8]) -> Result<usize, DiskError> {
+            self.image.read_lossy(addr, buf)
+        }
+
+        fn write_lossy(&mut self, addr: usize, buf: &[u8]) -> Result<usize, DiskError> {
+            self.image.write_lossy(addr, buf)
+        }
+
+        fn sync(&mut self) -> Result<(), DiskError> {
+            self.syncs.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    fn journaled_image() -> Vec<u8> {
+        let options = MkfsOptions {
+            journal_blocks: 32,
+            ..Default::default()
+        };
+        let mut fs = FileSystem::create_with(BLOCKS, "journal", &options).unwrap();
+        let mut image = vec![0; Addr::block(BLOCKS).unwrap().get()];
+        fs.export_image(&mut image).unwrap();
+        image
+    }
+
+    #[test]
+    fn commits_only_wait_for_the_disk_when_mounted_with_sync() {
+        for sync in [false, true] {
+            let syncs = Arc::new(AtomicUsize::new(0));
+            let io = SyncCounter {
+                image: journaled_image(),
+                syncs: syncs.clone(),
+            };
+            let options = MountOptions {
+                sync,
+                ..Default::default()
+            };
+            let mut sfs = Sfs::from(FileSystem::mount(Disk::new(Box::new(io)), options).unwrap());
+            syncs.store(0, Ordering::Relaxed);
+            sfs.create_dir("/dir").unwrap();
+            assert_eq!(syncs.load(Ordering::Relaxed) > 0, sync);
+            assert!(sfs.fs().fsck(false).unwrap().is_clean());
+        }
+    }
+
+    /// Mounts `image`, makes `change` and writes it to the journal as if the system crashed right
+    /// after the commit, before any home block was written. Returns the image and the block that
+    /// holds the first copy in the journal.
+    fn crash_before_checkpoint(image: Vec<u8>, change: impl FnOnce(&mut Sfs)) -> (Vec<u8>, u32) {
+        let fs = FileSystem::mount(Disk::new(Box::new(image)), MountOptions::default()).unwrap();
+        let mut sfs = Sfs::from(fs);
+        sfs.fs().disk().start_buffering_all();
+        change(&mut sfs);
+        let fs = sfs.fs();
+        let journal = fs.journal_area();
+        let blocks = fs.disk().take_buffer();
+        let (header, _) =
+            write_journal(fs.disk(), journal.start, journal.len() as u32, 0, blocks).unwrap();
+        (fs.disk().read_all().unwrap(), journal.start + 2 + header.at)
+    }
+
+    #[test]
+    fn replays_a_committed_transaction_on_mount() {
+        let (image, _) = crash_before_checkpoint(journaled_image(), |sfs| {
+            sfs.create_dir("/dir").unwrap();
+            sfs.write("/dir/file", b"journaled").unwrap();
+        });
+        let mut fs =
+            FileSystem::mount(Disk::new(Box::new(image)), MountOptions::default()).unwrap();
+        assert!(!fs.journal_dirty().unwrap());
+        assert!(fs.fsck(false).unwrap().is_clean());
+        let mut sfs = Sfs::from(fs);
+        assert_eq!(sfs.read("/dir/file").unwrap(), b"journaled");
+    }
+
+    #[test]
+    fn drops_a_torn_transaction_on_mount() {
+        let (mut image, copy) = crash_before_checkpoint(journaled_image(), |sfs| {
+            sfs.create_dir("/dir").unwrap();
+        });
+        // the header made it, one of the copies didn't
+        let copy = Addr::block(copy).unwrap().get();
+        image[copy] ^= 1;
+        let mut fs =
+            FileSystem::mount(Disk::new(Box::new(image)), MountOptions::default()).unwrap();
+        assert!(!fs.journal_dirty().unwrap());
+        assert!(fs.fsck(false).unwrap().is_clean());
+        let mut sfs = Sfs::from(fs);
+        assert!(!sfs.exists("/dir").unwrap());
+        sfs.create_dir("/dir").unwrap();
+    }
+
+    fn mount(image: Vec<u8>) -> Sfs {
+        Sfs::from(FileSystem::mount(Disk::new(Box::new(image)), MountOptions::default()).unwrap())
+    }
+
+    /// Writes `contents` to `/file` and returns the transaction that finished it
+    fn write_version(sfs: &mut Sfs, contents: &[u8]) -> u64 {
+        sfs.write("/file", contents).unwrap();
+        sfs.fs().journal_history().unwrap()[0].sequence
+    }
+
+    #[test]
+    fn reads_files_as_of_earlier_transactions() {
+        let mut sfs = mount(journaled_image());
+        let versions: Vec<(u64, Vec<u8>)> = (1..=3)
+            .map(|i| {
+                let contents = format!("version {i}").repeat(i * 100).into_bytes();
+                (write_version(&mut sfs, &contents), contents)
+            })
+            .collect();
+        let inode_nbr = sfs.fs().lookup_path("/file").unwrap();
+
+        for (txn, contents) in &versions {
+            assert_eq!(
+                sfs.fs().read_file_at_txn(inode_nbr, *txn).unwrap(),
+                *contents
+            );
+        }
+        let history = sfs.fs().journal_history().unwrap();
+        assert!(history
+            .windows(2)
+            .all(|w| w[0].sequence == w[1].sequence + 1));
+        assert!(history.iter().all(|txn| !txn.unrecorded));
+        assert!(sfs.fs().fsck(false).unwrap().is_clean());
+
+        // the history is on the disk, not in memory
+        let mut image = vec![0; Addr::block(BLOCKS).unwrap().get()];
+        sfs.fs().export_image(&mut image).unwrap();
+        let mut sfs = mount(image);
+        let (txn, contents) = &versions[0];
+        assert_eq!(
+            sfs.fs().read_file_at_txn(inode_nbr, *txn).unwrap(),
+            *contents
+        );
+        assert_eq!(sfs.read("/file").unwrap(), versions[2].1);
+    }
+
+    #[test]
+    fn forgets_transactions_the_journal_has_no_space_for() {
+        let mut sfs = mount(journaled_image());
+        let first = write_version(&mut sfs, b"first");
+        let inode_nbr = sfs.fs().lookup_path("/file").unwrap();
+        for i in 0..40 {
+            write_version(&mut sfs, format!("later {i}").as_bytes());
+        }
+        assert!(matches!(
+            sfs.fs().read_file_at_txn(inode_nbr, first),
+            Err(FsError::NotJournaled)
+        ));
+        assert!(matches!(
+            sfs.fs().read_file_at_txn(inode_nbr, u64::MAX),
+            Err(FsError::NotJournaled)
+        ));
+        let last = sfs.fs().journal_history().unwrap()[0].sequence;
+        assert_eq!(
+            sfs.fs().read_file_at_txn(inode_nbr, last).unwrap(),
+            b"later 39"
+        );
+    }
+
+    #[test]
+    fn writes_outside_of_transactions_hide_earlier_versions() {
+        let mut sfs = mount(journaled_image());
+        let first = write_version(&mut sfs, &[1; BLOCK_SIZE]);
+        let inode_nbr = sfs.fs().lookup_path("/file").unwrap();
+        let fs = sfs.fs();
+        let (_, block) = fs.read_inode(inode_nbr).unwrap().data_blocks(fs).unwrap()[0];
+        fs.write_block(block, &[2; BLOCK_SIZE]).unwrap();
+        // noted in the journal with the next transaction
+        sfs.create_dir("/dir").unwrap();
+        let second = sfs.fs().journal_history().unwrap()[0].sequence;
+        assert!(sfs.fs().journal_history().unwrap()[1].unrecorded);
+        assert_eq!(
+            sfs.fs().read_file_at_txn(inode_nbr, second).unwrap(),
+            [2; BLOCK_SIZE]
+        );
+        sfs.fs().write_at(inode_nbr, 0, &[3; BLOCK_SIZE]).unwrap();
+        let third = sfs.fs().journal_history().unwrap()[0].sequence;
+
+        // the block changed again since the second transaction, which has no copy of it
+        let mut image = vec![0; Addr::block(BLOCKS).unwrap().get()];
+        sfs.fs().export_image(&mut image).unwrap();
+        let mut fs = mount(image).into_inner();
+        assert!(matches!(
+            fs.read_file_at_txn(inode_nbr, second),
+            Err(FsError::NotJournaled)
+        ));
+        assert_eq!(
+            fs.read_file_at_txn(inode_nbr, first).unwrap(),
+            [1; BLOCK_SIZE]
+        );
+        assert_eq!(
+            fs.read_file_at_txn(inode_nbr, third).unwrap(),
+            [3; BLOCK_SIZE]
+        );
+        assert!(fs.fsck(false).unwrap().is_clean());
+    }
+}
@@ -0,0 +1,85 @@
+//! SFS, a simple block-based file system (see `sfs.md` for the on-disk format).
+//!
+//! `Sfs` reads and writes files by path and is enough for most uses. `FileSystem` is the full
+//! API, working on inode numbers, on top of a `Disk` wrapping any `IO`.
+//!
+//! Without the default `std` feature the crate is `no_std` + `alloc`, e.g. for a kernel: the
+//! core works on any `IO`, takes timestamps from a `fs::Clock` and leaves out everything that
+//! needs files, threads or `std::io`.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+mod addr;
+#[cfg(feature = "async")]
+pub mod async_disk;
+#[cfg(feature = "blockdev")]
+pub mod blockdev;
+pub mod bulk;
+pub mod compress;
+pub mod copy;
+mod crc32;
+pub mod crypt;
+mod dcache;
+pub mod defrag;
+pub mod directory;
+pub mod dirhash;
+#[cfg(feature = "std")]
+pub mod discover;
+pub mod disk;
+mod ed25519;
+pub mod estimate;
+#[cfg(feature = "std")]
+pub mod export;
+pub mod features;
+pub mod format;
+pub mod fs;
+pub mod fsck;
+pub mod handle;
+pub mod health;
+pub mod hints;
+pub mod hooks;
+pub mod index;
+pub mod inode;
+mod inode_bitmap;
+pub mod journal;
+pub mod loopback;
+pub mod manifest;
+#[cfg(feature = "fuse")]
+pub mod mount;
+mod normalize;
+#[cfg(feature = "std")]
+pub mod pack;
+pub mod paths;
+pub mod quota;
+pub mod recover;
+pub mod redirect;
+pub mod reservation;
+pub mod seal;
+#[cfg(feature = "std")]
+mod sfs;
+mod sha256;
+mod sha512;
+pub mod snapshot;
+pub mod superblock;
+mod sync;
+#[cfg(feature = "std")]
+pub mod tar;
+pub mod template;
+pub mod testing;
+pub mod time;
+pub mod trash;
+pub mod verity;
+mod xattr;
+pub mod zones;
+
+pub use crate::{
+    directory::DirEntry,
+    disk::{Disk, DiskError, IO},
+    fs::{FileSystem, FsError, MkfsOptions, MountOptions},
+    inode::{Inode, InodeType},
+};
+
+#[cfg(feature = "std")]
+pub use crate::sfs::Sfs;
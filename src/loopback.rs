@@ -0,0 +1,45 @@
+use crate::{
+    disk::{DiskError, IO},
+    fs::{FileSystem, FsError},
+    inode::{Inode, InodeType},
+};
+
+/// Exposes a regular file of a file system as an `IO`, like a loop device. It works on a read-only
+/// fork of the file system, so all writes fail with `DiskError::ReadOnly`.
+pub struct LoopbackIO {
+    fs: FileSystem,
+    inode: Inode,
+    len: usize,
+}
+
+impl LoopbackIO {
+    pub fn new(fs: &FileSystem, inode_nbr: u32) -> Result<Self, FsError> {
+        let mut fs = fs.fork_readonly();
+        let inode = fs.read_inode(inode_nbr)?;
+        if inode.type_and_permission.get_type() != InodeType::File {
+            return Err(FsError::NotAFile);
+        }
+        let len = inode.size() as usize;
+
+        Ok(Self { fs, inode, len })
+    }
+}
+
+impl IO for LoopbackIO {
+    fn read_lossy(&mut self, addr: usize, buf: &mut [u8]) -> Result<usize, DiskError> {
+        if addr >= self.len {
+            return Ok(0);
+        }
+        let end = buf.len().min(self.len - addr);
+        match self.inode.read(addr, &mut buf[..end], &mut self.fs) {
+            Ok(read) => Ok(read),
+            Err(FsError::NoEntry) => Ok(0),
+            Err(FsError::DiskError(e)) => Err(e),
+            Err(_) => Err(DiskError::GenericError),
+        }
+    }
+
+    fn write_lossy(&mut self, _addr: usize, _buf: &[u8]) -> Result<usize, DiskError> {
+        Err(DiskError::ReadOnly)
+    }
+}
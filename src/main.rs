@@ -1,19 +1,87 @@
-use std::{fs::File, path::Path};
-
-use disk::Disk;
-use fs::{FileSystem, FsError, BLOCK_SIZE};
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
 
-use crate::{
-    directory::DirectoryIterator, fs::INODES_PER_BLOCK, inode::{Inode, InodeType, Permission, PermissionsAndType}
+use sfs::{
+    directory::DirectoryIterator,
+    discover,
+    disk::{RetryIO, RetryPolicy},
+    format,
+    fs::{BLOCKS_PER_BLOCKARRAY, BLOCK_SIZE, INODES_PER_BLOCK},
+    inode::{Permission, PermissionsAndType},
+    pack::PackStats,
+    testing, Disk, FileSystem, FsError, Inode, InodeType, MkfsOptions, Sfs,
 };
 
-mod directory;
-mod disk;
-mod fs;
-mod inode;
-mod superblock;
+mod shell;
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("list") => list_images(&args[2..]),
+        Some("health") => {
+            let Some(path) = args.get(2) else {
+                eprintln!("usage: sfs health <image>");
+                std::process::exit(2);
+            };
+            print_health(path);
+        }
+        Some("fsck") => fsck(&args[2..]),
+        Some("corrupt") => corrupt(&args[2..]),
+        Some("layout") => print!("{}", format::layout_report()),
+        Some("mkfs") => mkfs(&args[2..]),
+        #[cfg(feature = "fuse")]
+        Some("mount") => {
+            let (Some(image), Some(mountpoint)) = (args.get(2), args.get(3)) else {
+                eprintln!("usage: sfs mount <image> <dir>");
+                std::process::exit(2);
+            };
+            mount_image(image, mountpoint);
+        }
+        Some("copy") => copy(&args[2..]),
+        Some("pack") => {
+            let (Some(host), Some(image)) = (args.get(2), args.get(3)) else {
+                eprintln!("usage: sfs pack <host-dir> <image>");
+                std::process::exit(2);
+            };
+            pack(host, image);
+        }
+        Some("unpack") => {
+            let (Some(image), Some(host)) = (args.get(2), args.get(3)) else {
+                eprintln!("usage: sfs unpack <image> <host-dir>");
+                std::process::exit(2);
+            };
+            unpack(image, host);
+        }
+        Some("shell") => {
+            let Some(image) = args.get(2) else {
+                eprintln!("usage: sfs shell <image>");
+                std::process::exit(2);
+            };
+            shell::shell(image);
+        }
+        Some("import-tar") => tar(&args[2..], true),
+        Some("export-tar") => tar(&args[2..], false),
+        Some("index") => index(&args[2..]),
+        Some("search") => search(&args[2..]),
+        Some("keygen") => keygen(&args[2..]),
+        Some("seal") => seal(&args[2..]),
+        Some("verify-seal") => verify_seal(&args[2..]),
+        Some("verity") => verity(&args[2..]),
+        Some("demo") => demo(),
+        _ => {
+            eprintln!(
+                "usage: sfs <list|health|fsck|corrupt|layout|mkfs|mount|shell|copy|pack|unpack|import-tar|export-tar|index|search|keygen|seal|verify-seal|verity|demo> ..."
+            );
+            std::process::exit(2);
+        }
+    }
+}
+
+/// `sfs demo`: creates a small in-memory file system and plays around with it
+fn demo() {
     // let mut fs: FileSystem = File::options()
     //     .read(true)
     //     .write(true)
@@ -30,28 +98,33 @@ fn main() {
     let mut nodes = vec![];
 
     for i in 0..INODES_PER_BLOCK {
-        nodes.push(fs.create_dir_entry(
-            fs.superblock.root_inode,
-            Inode::create(
-                PermissionsAndType::new(
-                    InodeType::File,
-                    &[
-                        Permission::user_rw(),
-                        Permission::group_rw(),
-                        Permission::OtherRead,
-                    ],
+        let (node, stats) = fs.measure_io(|fs| {
+            fs.create_dir_entry(
+                fs.superblock.root_inode,
+                Inode::create(
+                    PermissionsAndType::new(
+                        InodeType::File,
+                        &[
+                            Permission::user_rw(),
+                            Permission::group_rw(),
+                            Permission::OtherRead,
+                        ],
+                    ),
+                    0,
+                    0,
+                    fs.now(),
+                    0,
+                    0,
                 ),
-                0,
-                0,
-                0,
-                0,
-                0,
-            ),
-            format!("my_file_{i}"),
-        ).expect("Failed to create directory entry"));
+                format!("my_file_{i}"),
+            )
+        });
+        if i == 0 {
+            println!("create_dir_entry: {stats}");
+        }
+        nodes.push(node.expect("Failed to create directory entry"));
     }
 
-
     for node in nodes {
         fs.read_inode(node).unwrap().delete(node, &mut fs).unwrap();
     }
@@ -61,20 +134,614 @@ fn main() {
         .expect("Failed to read /");
 
     for dir_entry in DirectoryIterator::new(node, &mut fs) {
-        println!("listing {:?}: {}", dir_entry.get_name(), dir_entry.inode);
+        println!(
+            "listing {:?}: {} ({:?})",
+            dir_entry.get_name(),
+            dir_entry.inode,
+            dir_entry.get_type()
+        );
     }
 }
 
+/// `sfs list [dir] [--uuid <uuid>]`: prints every image found in `dir`, or only the one with the
+/// UUID
+fn list_images(args: &[String]) {
+    let (dir, uuid) = match args {
+        [] => (".", None),
+        [dir] => (dir.as_str(), None),
+        [flag, uuid] if flag == "--uuid" => (".", Some(uuid)),
+        [dir, flag, uuid] if flag == "--uuid" => (dir.as_str(), Some(uuid)),
+        _ => {
+            eprintln!("usage: sfs list [<dir>] [--uuid <uuid>]");
+            std::process::exit(2);
+        }
+    };
+    let images = match uuid {
+        Some(uuid) => {
+            let uuid = uuid.parse().unwrap_or_else(|_| {
+                eprintln!("invalid UUID {uuid:?}");
+                std::process::exit(2);
+            });
+            let image = discover::find_image_by_uuid(dir, uuid).expect("Failed to scan directory");
+            image.into_iter().collect()
+        }
+        None => discover::find_images(dir).expect("Failed to scan directory"),
+    };
+    for image in images {
+        let uuid = image
+            .uuid
+            .map_or_else(|| "-".to_string(), |uuid| uuid.to_string());
+        println!(
+            "{}\t{}\t{}\t{:?}\t{} blocks ({} bytes)",
+            image.path.display(),
+            image.offset,
+            uuid,
+            image.label,
+            image.total_blocks,
+            image.size()
+        );
+    }
+}
+
+const MKFS_USAGE: &str =
+    "usage: sfs mkfs --blocks <n> [--name <name>] [--block-size 4096] [--boot <file>] [--journal <blocks>] [--sequential-inodes] [--normalize-names] <output>";
+
+/// `sfs mkfs`: formats a fresh image file and prints its layout
+fn mkfs(args: &[String]) {
+    let usage = || -> ! {
+        eprintln!("{MKFS_USAGE}");
+        std::process::exit(2);
+    };
+
+    let mut blocks = None;
+    let mut name = String::new();
+    let mut boot = None;
+    let mut journal_blocks = 0;
+    let mut sequential_inodes = false;
+    let mut normalize_names = false;
+    let mut output = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--blocks" => blocks = args.next().and_then(|v| v.parse::<u32>().ok()),
+            "--name" => name = args.next().cloned().unwrap_or_else(|| usage()),
+            "--journal" => {
+                journal_blocks = args
+                    .next()
+                    .and_then(|v| v.parse::<u32>().ok())
+                    .unwrap_or_else(|| usage());
+            }
+            "--sequential-inodes" => sequential_inodes = true,
+            "--normalize-names" => normalize_names = true,
+            "--boot" => {
+                let path = args.next().unwrap_or_else(|| usage());
+                boot = Some(std::fs::read(path).expect("Failed to read boot image"));
+            }
+            "--block-size" => {
+                if args.next().and_then(|v| v.parse::<usize>().ok()) != Some(BLOCK_SIZE) {
+                    eprintln!("sfs only supports a block size of {BLOCK_SIZE}");
+                    std::process::exit(2);
+                }
+            }
+            path if output.is_none() && !path.starts_with("--") => output = Some(path),
+            _ => usage(),
+        }
+    }
+    let (Some(blocks), Some(output)) = (blocks, output) else {
+        usage();
+    };
+
+    let file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(output)
+        .expect("Failed to create image");
+    file.set_len(blocks as u64 * BLOCK_SIZE as u64)
+        .expect("Failed to resize image");
+    let options = MkfsOptions {
+        boot_area_blocks: boot
+            .as_ref()
+            .map_or(0, |boot| boot.len().div_ceil(BLOCK_SIZE) as u32),
+        journal_blocks,
+        sequential_inodes,
+        normalize_names,
+        ..MkfsOptions::default().with_source_date_epoch()
+    };
+    let mut fs = FileSystem::format(Disk::new(Box::new(file)), blocks, &name, &options)
+        .unwrap_or_else(|e| {
+            eprintln!("{output}: {e}");
+            std::process::exit(1);
+        });
+
+    println!("{output}: {:?}", fs.superblock.get_name());
+    println!("uuid: {}", fs.superblock.uuid);
+    println!(
+        "total blocks: {blocks} ({} bytes)",
+        blocks as usize * BLOCK_SIZE
+    );
+    println!("root inode: {}", fs.superblock.root_inode);
+    println!("superblock: block 1");
+    if let Some(boot) = boot {
+        fs.write_boot_area(&boot)
+            .expect("Failed to write the boot area");
+        let area = fs.boot_area();
+        println!("boot area: blocks {}..{}", area.start, area.end);
+    }
+    let journal = fs.journal_area();
+    if !journal.is_empty() {
+        println!("journal: blocks {}..{}", journal.start, journal.end);
+    }
+    let bitmap = fs.inode_bitmap_area();
+    if !bitmap.is_empty() {
+        println!("inode bitmap: blocks {}..{}", bitmap.start, bitmap.end);
+    }
+    for group in 0..blocks.div_ceil(BLOCKS_PER_BLOCKARRAY) {
+        let first = group * BLOCKS_PER_BLOCKARRAY;
+        let last = (first + BLOCKS_PER_BLOCKARRAY).min(blocks) - 1;
+        println!("block array descriptor: block {first} (blocks {first}..={last})");
+    }
+    let table = fs.inode_table_range();
+    if !table.is_empty() {
+        println!(
+            "inode table: blocks {}..{} (inodes {}..{})",
+            table.start / INODES_PER_BLOCK,
+            table.end / INODES_PER_BLOCK,
+            table.start,
+            table.end
+        );
+    }
+}
+
+/// `sfs health <image>`: prints a `HealthReport` for the image, exiting with 1 if it can't be
+/// opened at all
+fn print_health(path: &str) {
+    let report = File::open(path)
+        .map_err(FsError::IoError)
+        .and_then(|file| FileSystem::from_disk(Disk::new(Box::new(file))))
+        .and_then(|mut fs| fs.health());
+    match report {
+        Ok(report) => print!("{report}"),
+        Err(e) => {
+            eprintln!("{path}: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// `sfs fsck [--repair] <image>`: checks the image and prints every problem. Exits with 0 if it
+/// is clean, 1 if all problems were repaired and 4 if some are left, like e2fsck.
+fn fsck(args: &[String]) {
+    let (repair, path) = match args {
+        [path] => (false, path),
+        [flag, path] if flag == "--repair" => (true, path),
+        _ => {
+            eprintln!("usage: sfs fsck [--repair] <image>");
+            std::process::exit(2);
+        }
+    };
+
+    let report = File::options()
+        .read(true)
+        .write(repair)
+        .open(path)
+        .map_err(FsError::IoError)
+        .map(|file| RetryIO::new(file, RetryPolicy::default()))
+        .and_then(|file| FileSystem::from_disk(Disk::new(Box::new(file))))
+        .and_then(|mut fs| fs.fsck(repair));
+    match report {
+        Ok(report) => {
+            print!("{report}");
+            if report.remaining().next().is_some() {
+                std::process::exit(4);
+            } else if !report.is_clean() {
+                std::process::exit(1);
+            }
+        }
+        Err(e) => {
+            eprintln!("{path}: {e}");
+            std::process::exit(8);
+        }
+    }
+}
+
+const CORRUPT_USAGE: &str =
+    "usage: sfs corrupt <image> <bitmap <block> | crosslink <from> <to> | orphan <path> | superblock>";
+
+/// `sfs corrupt <image> <kind> ...`: damages the image on purpose, see `testing::corrupt`
+fn corrupt(args: &[String]) {
+    let usage = || -> ! {
+        eprintln!("{CORRUPT_USAGE}");
+        std::process::exit(2);
+    };
+    let Some((path, args)) = args.split_first() else {
+        usage();
+    };
+
+    let result = File::options()
+        .read(true)
+        .write(true)
+        .open(path)
+        .map_err(FsError::IoError)
+        .and_then(|file| FileSystem::from_disk(Disk::new(Box::new(file))))
+        .and_then(|mut fs| {
+            let kind = match args {
+                [kind, block] if kind == "bitmap" => testing::CorruptionKind::FlipBitmapBit {
+                    block: block.parse().unwrap_or_else(|_| usage()),
+                },
+                [kind, from, to] if kind == "crosslink" => testing::CorruptionKind::CrossLink {
+                    from: fs.lookup_path(from)?,
+                    to: fs.lookup_path(to)?,
+                },
+                [kind, path] if kind == "orphan" => {
+                    let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+                    testing::CorruptionKind::Orphan {
+                        dir: fs.lookup_path(dir)?,
+                        name: name.to_string(),
+                    }
+                }
+                [kind] if kind == "superblock" => testing::CorruptionKind::Superblock,
+                _ => usage(),
+            };
+            testing::corrupt(&mut fs, &kind)?;
+            Ok(kind)
+        });
+    match result {
+        Ok(kind) => println!("{path}: {kind}"),
+        Err(e) => {
+            eprintln!("{path}: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+const COPY_USAGE: &str = "usage: sfs copy <src.img>:<path> <dst.img>:<path>";
+
+/// `sfs copy <src.img>:<path> <dst.img>:<path>`: copies a tree from one image to another, see
+/// `FileSystem::copy_from`. If the target is a directory, the tree is copied into it.
+fn copy(args: &[String]) {
+    let [src, dst] = args else {
+        eprintln!("{COPY_USAGE}");
+        std::process::exit(2);
+    };
+    let (Some((src_image, src_path)), Some((dst_image, dst_path))) =
+        (src.split_once(':'), dst.split_once(':'))
+    else {
+        eprintln!("{COPY_USAGE}");
+        std::process::exit(2);
+    };
+    if std::fs::canonicalize(src_image).ok() == std::fs::canonicalize(dst_image).ok() {
+        eprintln!("sfs copy: source and target have to be different images");
+        std::process::exit(2);
+    }
+
+    let result = Sfs::open_read_only(src_image).and_then(|mut src| {
+        let mut dst = Sfs::open(dst_image)?;
+        let src_nbr = src.fs().lookup_path(src_path)?;
+        let dst = dst.fs();
+        let src_name = src_path.trim_end_matches('/').rsplit('/').next();
+        let (parent_nbr, name) = match dst.lookup_path(dst_path) {
+            Ok(dir) if dst.stat(dir)?.file_type == InodeType::Directory => {
+                (dir, src_name.unwrap_or_default())
+            }
+            Ok(_) => return Err(FsError::AlreadyExists),
+            Err(FsError::NoEntry) => {
+                let (dir, name) = dst_path.rsplit_once('/').unwrap_or(("", dst_path));
+                (dst.lookup_path(dir)?, name)
+            }
+            Err(e) => return Err(e),
+        };
+        let stats = dst.copy_from(src.fs(), src_nbr, parent_nbr, name)?;
+        dst.sync()?;
+        Ok(stats)
+    });
+    match result {
+        Ok(stats) => println!(
+            "{src} -> {dst}: {} inodes, {} bytes",
+            stats.inodes, stats.bytes
+        ),
+        Err(e) => {
+            eprintln!("sfs copy: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Prints what `sfs pack` or `sfs unpack` copied and warns about what it left out
+fn print_pack_stats(command: &str, stats: &PackStats) {
+    for path in &stats.skipped {
+        eprintln!("sfs {command}: skipped {}", path.display());
+    }
+    println!(
+        "{} files, {} directories, {} symlinks, {} bytes",
+        stats.files, stats.directories, stats.symlinks, stats.bytes
+    );
+}
+
+/// `sfs pack <host-dir> <image>`: copies a host directory tree into the root of an existing
+/// image, see `FileSystem::pack_dir`
+fn pack(host: &str, image: &str) {
+    let result = Sfs::open(image).and_then(|mut sfs| {
+        let fs = sfs.fs();
+        let root = fs.superblock.root_inode;
+        let stats = fs.pack_dir(Path::new(host), root)?;
+        fs.sync()?;
+        Ok(stats)
+    });
+    match result {
+        Ok(stats) => print_pack_stats("pack", &stats),
+        Err(e) => {
+            eprintln!("sfs pack: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// `sfs unpack <image> <host-dir>`: copies everything in an image to a host directory, see
+/// `FileSystem::unpack_dir`
+fn unpack(image: &str, host: &str) {
+    let result = Sfs::open_read_only(image).and_then(|mut sfs| {
+        let fs = sfs.fs();
+        let root = fs.superblock.root_inode;
+        fs.unpack_dir(root, Path::new(host))
+    });
+    match result {
+        Ok(stats) => print_pack_stats("unpack", &stats),
+        Err(e) => {
+            eprintln!("sfs unpack: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// `sfs import-tar <image> [archive]` and `sfs export-tar <image> [archive]`: converts between
+/// an image and a tar stream, see `FileSystem::import_tar`. Without an archive (or with `-`),
+/// the stream is read from stdin or written to stdout.
+fn tar(args: &[String], import: bool) {
+    let command = if import { "import-tar" } else { "export-tar" };
+    let (image, archive) = match args {
+        [image] => (image, None),
+        [image, archive] => (
+            image,
+            Some(archive.as_str()).filter(|archive| *archive != "-"),
+        ),
+        _ => {
+            eprintln!("usage: sfs {command} <image> [archive]");
+            std::process::exit(2);
+        }
+    };
+
+    let result = if import {
+        Sfs::open(image).and_then(|mut sfs| {
+            let mut reader: Box<dyn Read> = match archive {
+                Some(archive) => Box::new(BufReader::new(
+                    File::open(archive).map_err(FsError::IoError)?,
+                )),
+                None => Box::new(std::io::stdin().lock()),
+            };
+            let stats = sfs.fs().import_tar(&mut reader)?;
+            sfs.sync()?;
+            Ok(stats)
+        })
+    } else {
+        Sfs::open_read_only(image).and_then(|mut sfs| {
+            let mut writer: Box<dyn Write> = match archive {
+                Some(archive) => Box::new(BufWriter::new(
+                    File::create(archive).map_err(FsError::IoError)?,
+                )),
+                None => Box::new(BufWriter::new(std::io::stdout().lock())),
+            };
+            sfs.fs().export_tar(&mut writer)
+        })
+    };
+    match result {
+        Ok(stats) => {
+            for path in &stats.skipped {
+                eprintln!("sfs {command}: skipped {path}");
+            }
+            eprintln!("{} entries, {} bytes", stats.entries, stats.bytes);
+        }
+        Err(e) => {
+            eprintln!("sfs {command}: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// `sfs index [--contents|--off] <image>`: starts keeping a search index of the names in the
+/// image, and with `--contents` of the words in its files, or removes it with `--off`. Run on an
+/// image with an index, it is built again.
+fn index(args: &[String]) {
+    let (contents, off, image) = match args {
+        [image] => (false, false, image),
+        [flag, image] if flag == "--contents" => (true, false, image),
+        [flag, image] if flag == "--off" => (false, true, image),
+        _ => {
+            eprintln!("usage: sfs index [--contents|--off] <image>");
+            std::process::exit(2);
+        }
+    };
+    let result = Sfs::open(image).and_then(|mut sfs| {
+        if off {
+            sfs.fs().disable_index()?;
+        } else {
+            sfs.fs().enable_index(contents)?;
+        }
+        sfs.sync()
+    });
+    if let Err(e) = result {
+        eprintln!("sfs index: {e}");
+        std::process::exit(1);
+    }
+}
+
+/// `sfs search <image> <word>...`: prints the path of every entry whose name contains all words,
+/// and of every file containing them if the image indexes contents, see `sfs index`
+fn search(args: &[String]) {
+    let [image, words @ ..] = args else {
+        eprintln!("usage: sfs search <image> <word>...");
+        std::process::exit(2);
+    };
+    if words.is_empty() {
+        eprintln!("usage: sfs search <image> <word>...");
+        std::process::exit(2);
+    }
+    let result = Sfs::open_read_only(image).and_then(|mut sfs| sfs.fs().search(&words.join(" ")));
+    match result {
+        Ok(hits) => {
+            for hit in hits {
+                println!("{}", hit.path);
+            }
+        }
+        Err(e) => {
+            eprintln!("sfs search: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// The 32 bytes of a key or hash written as 64 hex digits
+fn parse_hex32(hex: &str) -> Option<[u8; 32]> {
+    let bytes: Option<Vec<u8>> = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect();
+    bytes.and_then(|bytes| bytes.try_into().ok())
+}
+
+/// `sfs keygen <key-file>`: writes a new private key for `sfs seal` and prints its public key
+fn keygen(args: &[String]) {
+    let [key_file] = args else {
+        eprintln!("usage: sfs keygen <key-file>");
+        std::process::exit(2);
+    };
+    let mut secret = [0; 32];
+    let result = File::open("/dev/urandom")
+        .and_then(|mut random| random.read_exact(&mut secret))
+        .and_then(|_| {
+            File::options()
+                .write(true)
+                .create_new(true)
+                .open(key_file)?
+                .write_all(&secret)
+        });
+    if let Err(e) = result {
+        eprintln!("sfs keygen: {key_file}: {e}");
+        std::process::exit(1);
+    }
+    println!("{}", hex(&sfs::seal::public_key(&secret)));
+}
+
+/// `sfs seal <key-file> <image>`: signs the image with the private key from `sfs keygen`
+fn seal(args: &[String]) {
+    let [key_file, image] = args else {
+        eprintln!("usage: sfs seal <key-file> <image>");
+        std::process::exit(2);
+    };
+    let secret = std::fs::read(key_file)
+        .ok()
+        .and_then(|secret| <[u8; 32]>::try_from(secret).ok());
+    let Some(secret) = secret else {
+        eprintln!("sfs seal: {key_file} is not a key from sfs keygen");
+        std::process::exit(1);
+    };
+    if let Err(e) = Sfs::open(image).and_then(|mut sfs| sfs.fs().seal(&secret)) {
+        eprintln!("sfs seal: {e}");
+        std::process::exit(1);
+    }
+    println!("{}", hex(&sfs::seal::public_key(&secret)));
+}
+
+/// `sfs verify-seal <public-key> <image>`: checks that the image was sealed with the private key
+/// of the hex encoded public key and not changed since
+fn verify_seal(args: &[String]) {
+    let [public_key, image] = args else {
+        eprintln!("usage: sfs verify-seal <public-key> <image>");
+        std::process::exit(2);
+    };
+    let Some(public_key) = parse_hex32(public_key) else {
+        eprintln!("sfs verify-seal: the public key has to be 64 hex digits");
+        std::process::exit(2);
+    };
+    match Sfs::open_read_only(image).and_then(|mut sfs| sfs.fs().verify_seal(&public_key)) {
+        Ok(()) => println!("{image}: seal ok"),
+        Err(e) => {
+            eprintln!("sfs verify-seal: {image}: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// `sfs verity <image>`: builds the hash tree of the finished image and prints its root hash.
+/// `sfs verity --check <root-hash> <image>`: checks every block of the image against the tree.
+fn verity(args: &[String]) {
+    let result = match args {
+        [image] => Sfs::open(image).and_then(|mut sfs| sfs.fs().build_verity()),
+        [flag, root, image] if flag == "--check" => {
+            let Some(root) = parse_hex32(root) else {
+                eprintln!("sfs verity: the root hash has to be 64 hex digits");
+                std::process::exit(2);
+            };
+            let options = sfs::MountOptions {
+                verity_root: Some(root),
+                ..Default::default()
+            };
+            File::open(image)
+                .map_err(FsError::IoError)
+                .and_then(|file| FileSystem::mount(Disk::new(Box::new(file)), options))
+                .and_then(|mut fs| fs.check_verity())
+                .map(|()| root)
+        }
+        _ => {
+            eprintln!("usage: sfs verity [--check <root-hash>] <image>");
+            std::process::exit(2);
+        }
+    };
+    match result {
+        Ok(root) => println!("{}", hex(&root)),
+        Err(e) => {
+            eprintln!("sfs verity: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// `sfs mount <image> <dir>`: serves the image through FUSE until it is unmounted
+#[cfg(feature = "fuse")]
+fn mount_image(image: &str, mountpoint: &str) {
+    let file = File::options()
+        .read(true)
+        .write(true)
+        .open(image)
+        .expect("Failed to open image");
+    let options = sfs::MountOptions {
+        // 4 MiB, so directory scans don't go to the file for every entry
+        cache_blocks: 1024,
+        dcache_entries: 4096,
+        dcache_negative_entries: 1024,
+        dir_index_dirs: 64,
+        ..Default::default()
+    };
+    let file = RetryIO::new(file, RetryPolicy::default());
+    let fs = FileSystem::mount(Disk::new(Box::new(file)), options).expect("Failed to open image");
+    sfs::mount::mount(fs, mountpoint).expect("Failed to mount image");
+}
+
+#[allow(dead_code)]
 fn write_empty_fs_to_file<P: AsRef<Path>>(num_blocks: u32, name: &str, path: P) -> FileSystem {
     let mut fs = FileSystem::create(num_blocks, name).expect("Failed to create empty fs");
     let mut f = File::options()
         .write(true)
         .create(true)
+        .truncate(true)
         .open(&path)
         .expect("Failed to create file");
-    fs.get_disk()
-        .duplicate(&mut f)
-        .expect("Failed to duplicate disk");
+    fs.export_image(&mut f).expect("Failed to duplicate disk");
     drop(f);
     drop(fs);
 
@@ -87,31 +754,3 @@ fn write_empty_fs_to_file<P: AsRef<Path>>(num_blocks: u32, name: &str, path: P)
     )))
     .expect("Failed to create empty fs")
 }
-
-pub fn read_entire_inode(inode: &mut Inode, fs: &mut FileSystem) -> Result<Vec<u8>, FsError> {
-    let mut vec = Vec::with_capacity(BLOCK_SIZE);
-
-    let mut block = [0; BLOCK_SIZE];
-    let mut off = 0;
-    loop {
-        let read = match inode.read(off, &mut block, fs) {
-            Ok(v) => v,
-            Err(FsError::NoEntry) => 0,
-            e => e?,
-        };
-        
-        vec.extend(&block[0..read]);
-
-        if read != BLOCK_SIZE {
-            break;
-        }
-
-        off += BLOCK_SIZE;
-    }
-
-    for _ in 0..(4096 - inode.meta) {
-        vec.pop();
-    }
-
-    Ok(vec)
-}
\ No newline at end of file
@@ -0,0 +1,263 @@
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{
+    fmt::{Display, Write as _},
+    str::FromStr,
+};
+
+use crate::{
+    directory::DirectoryIterator,
+    fs::{FileSystem, FsError},
+    inode::InodeType,
+    sha256::Sha256,
+};
+
+const MANIFEST_HEADER: &str = "sfs-manifest 1";
+
+/// One file, directory or other inode reachable from the root directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// absolute path, starting with `/`
+    pub path: String,
+    /// the length in bytes for regular files, 0 for everything else
+    pub size: u64,
+    /// the raw type and permission bits of the inode
+    pub mode: u16,
+    pub uid: u16,
+    pub gid: u16,
+    /// SHA-256 of the contents, only set for regular files
+    pub hash: Option<[u8; 32]>,
+}
+
+/// A list of everything in an image, sorted by path. Its text form (`Display`/`FromStr`) is
+/// deterministic, so the same image always produces the same bytes and the manifest can be signed
+/// with any external tool.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ManifestMismatch {
+    /// The path is in the manifest, but not in the image
+    Missing(String),
+    /// The path is in the image, but not in the manifest
+    Unexpected(String),
+    /// The path exists in both, but its size, mode, owner or contents differ
+    Differs {
+        expected: ManifestEntry,
+        actual: ManifestEntry,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestParseError {
+    /// 1-based line the error is in
+    pub line: usize,
+}
+
+impl Manifest {
+    pub(crate) fn build(fs: &mut FileSystem) -> Result<Self, FsError> {
+        let mut entries = Vec::new();
+        let mut visited = BTreeSet::new();
+        Self::walk(
+            fs,
+            fs.superblock.root_inode,
+            "/".to_string(),
+            &mut entries,
+            &mut visited,
+        )?;
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(Self { entries })
+    }
+
+    fn walk(
+        fs: &mut FileSystem,
+        inode_nbr: u32,
+        path: String,
+        entries: &mut Vec<ManifestEntry>,
+        visited: &mut BTreeSet<u32>,
+    ) -> Result<(), FsError> {
+        let inode = fs.read_inode(inode_nbr)?;
+        let typ = inode.type_and_permission.get_type();
+
+        let (size, hash) = if typ == InodeType::File {
+            let mut hasher = Sha256::new();
+            let size = inode.read_blocks(fs, |block| {
+                hasher.update(block);
+                Ok(())
+            })?;
+            (size as u64, Some(hasher.finalize()))
+        } else {
+            (0, None)
+        };
+
+        entries.push(ManifestEntry {
+            path: path.clone(),
+            size,
+            mode: inode.type_and_permission.get_raw(),
+            uid: inode.uid,
+            gid: inode.gid,
+            hash,
+        });
+
+        // a directory linked into itself would never end
+        if typ != InodeType::Directory || !visited.insert(inode_nbr) {
+            return Ok(());
+        }
+
+        let children: Vec<_> = DirectoryIterator::new(inode, fs)
+            .map(|entry| (entry.get_name(), entry.inode))
+            .collect();
+        for (name, child_nbr) in children {
+            let child_path = if path == "/" {
+                format!("/{name}")
+            } else {
+                format!("{path}/{name}")
+            };
+            Self::walk(fs, child_nbr, child_path, entries, visited)?;
+        }
+
+        Ok(())
+    }
+
+    /// Compares the manifest against `fs`, returning every difference. An empty list means the
+    /// image matches.
+    pub(crate) fn verify(&self, fs: &mut FileSystem) -> Result<Vec<ManifestMismatch>, FsError> {
+        let actual = Self::build(fs)?;
+        let mut actual: BTreeMap<_, _> = actual
+            .entries
+            .into_iter()
+            .map(|entry| (entry.path.clone(), entry))
+            .collect();
+
+        let mut mismatches = Vec::new();
+        for expected in &self.entries {
+            match actual.remove(&expected.path) {
+                None => mismatches.push(ManifestMismatch::Missing(expected.path.clone())),
+                Some(actual) if actual != *expected => mismatches.push(ManifestMismatch::Differs {
+                    expected: expected.clone(),
+                    actual,
+                }),
+                Some(_) => {}
+            }
+        }
+        mismatches.extend(actual.into_keys().map(ManifestMismatch::Unexpected));
+
+        Ok(mismatches)
+    }
+}
+
+/// Escapes `\` and newlines, so every entry stays on one line
+fn escape_path(path: &str) -> String {
+    path.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape_path(path: &str) -> Option<String> {
+    let mut out = String::with_capacity(path.len());
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next()? {
+            '\\' => out.push('\\'),
+            'n' => out.push('\n'),
+            _ => return None,
+        }
+    }
+    Some(out)
+}
+
+impl Display for ManifestEntry {
+    /// `<sha256 or -> <size> <mode in octal> <uid>:<gid> <path>`
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.hash {
+            Some(hash) => {
+                let mut hex = String::with_capacity(64);
+                for byte in hash {
+                    let _ = write!(hex, "{byte:02x}");
+                }
+                f.write_str(&hex)?;
+            }
+            None => f.write_str("-")?,
+        }
+        write!(
+            f,
+            " {} {:06o} {}:{} {}",
+            self.size,
+            self.mode,
+            self.uid,
+            self.gid,
+            escape_path(&self.path)
+        )
+    }
+}
+
+impl Display for Manifest {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(f, "{MANIFEST_HEADER}")?;
+        for entry in &self.entries {
+            writeln!(f, "{entry}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ManifestEntry {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.splitn(5, ' ');
+        let mut next = || fields.next().ok_or(());
+
+        let hash = match next()? {
+            "-" => None,
+            hex if hex.len() == 64 => {
+                let mut hash = [0; 32];
+                for (i, byte) in hash.iter_mut().enumerate() {
+                    *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2).ok_or(())?, 16)
+                        .map_err(|_| ())?;
+                }
+                Some(hash)
+            }
+            _ => return Err(()),
+        };
+        let size = next()?.parse().map_err(|_| ())?;
+        let mode = u16::from_str_radix(next()?, 8).map_err(|_| ())?;
+        let (uid, gid) = next()?.split_once(':').ok_or(())?;
+        let path = unescape_path(next()?).ok_or(())?;
+
+        Ok(Self {
+            path,
+            size,
+            mode,
+            uid: uid.parse().map_err(|_| ())?,
+            gid: gid.parse().map_err(|_| ())?,
+            hash,
+        })
+    }
+}
+
+impl FromStr for Manifest {
+    type Err = ManifestParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut lines = s.lines().enumerate();
+        match lines.next() {
+            Some((_, MANIFEST_HEADER)) => {}
+            _ => return Err(ManifestParseError { line: 1 }),
+        }
+
+        let entries = lines
+            .filter(|(_, line)| !line.is_empty())
+            .map(|(i, line)| line.parse().map_err(|_| ManifestParseError { line: i + 1 }))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { entries })
+    }
+}
@@ -0,0 +1,503 @@
+use std::{
+    ffi::OsStr,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory,
+    ReplyEmpty, ReplyEntry, ReplyWrite, Request, TimeOrNow, FUSE_ROOT_ID,
+};
+
+use crate::{
+    disk::DiskError,
+    fs::{FileSystem, FsError, SetAttr, Stat, BLOCK_SIZE},
+    inode::{Inode, InodeType, Permission, PermissionsAndType},
+    time::Timestamp,
+};
+
+/// How long the kernel may cache attributes and lookups. Nothing changes the image behind the
+/// kernels back while it is mounted.
+const TTL: Duration = Duration::from_secs(1);
+
+/// Serves a `FileSystem` through FUSE, see `mount`. FUSE addresses the root directory as
+/// `FUSE_ROOT_ID`, every other inode keeps its sfs inode number (sfs never hands out inode 1, it
+/// would live in the first block array descriptor).
+pub struct FuseFs {
+    fs: FileSystem,
+}
+
+/// Mounts `fs` at `mountpoint` and serves it until it is unmounted (e.g. with `fusermount -u`)
+pub fn mount<P: AsRef<Path>>(fs: FileSystem, mountpoint: P) -> std::io::Result<()> {
+    let name = fs.superblock.get_name();
+    fuser::mount2(
+        FuseFs { fs },
+        mountpoint,
+        &[MountOption::FSName(name), MountOption::DefaultPermissions],
+    )
+}
+
+impl FuseFs {
+    fn to_sfs(&self, ino: u64) -> u32 {
+        if ino == FUSE_ROOT_ID {
+            self.fs.superblock.root_inode
+        } else {
+            ino as u32
+        }
+    }
+
+    fn to_fuse(&self, inode_nbr: u32) -> u64 {
+        if inode_nbr == self.fs.superblock.root_inode {
+            FUSE_ROOT_ID
+        } else {
+            inode_nbr as u64
+        }
+    }
+
+    fn attr(&mut self, inode_nbr: u32) -> Result<FileAttr, FsError> {
+        let stat = self.fs.stat(inode_nbr)?;
+        Ok(self.attr_from(&stat))
+    }
+
+    /// The attributes and the generation of `inode_nbr`, for replies that create a kernel inode
+    fn entry(&mut self, inode_nbr: u32) -> Result<(FileAttr, u64), FsError> {
+        let stat = self.fs.stat(inode_nbr)?;
+        Ok((self.attr_from(&stat), stat.generation as u64))
+    }
+
+    fn attr_from(&self, stat: &Stat) -> FileAttr {
+        let modified = stat.modified.to_system_time();
+        FileAttr {
+            ino: self.to_fuse(stat.inode),
+            size: stat.size,
+            // in 512-byte units, like st_blocks
+            blocks: stat.blocks as u64 * (BLOCK_SIZE / 512) as u64,
+            atime: stat.accessed.to_system_time(),
+            mtime: modified,
+            ctime: modified,
+            crtime: stat.created.to_system_time(),
+            kind: file_type(stat.file_type),
+            perm: stat.permissions,
+            nlink: stat.hardlinks as u32,
+            uid: stat.uid as u32,
+            gid: stat.gid as u32,
+            rdev: 0,
+            blksize: BLOCK_SIZE as u32,
+            flags: 0,
+        }
+    }
+
+    fn create_inode(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        typ: InodeType,
+        mode: u32,
+        req: &Request<'_>,
+    ) -> Result<(FileAttr, u64), FsError> {
+        let name = name.to_str().ok_or(FsError::NoEntry)?.to_string();
+        let parent = self.to_sfs(parent);
+        let inode = Inode::create(
+            PermissionsAndType::new(typ, &[Permission::Other(mode as u16 & 0o7777)]),
+            req.uid() as u16,
+            req.gid() as u16,
+            self.fs.now(),
+            0,
+            0,
+        );
+        let inode_nbr = self.fs.create_dir_entry(parent, inode, name)?;
+        self.entry(inode_nbr)
+    }
+
+    fn setattr_inner(
+        &mut self,
+        inode_nbr: u32,
+        size: Option<u64>,
+        attr: &SetAttr,
+    ) -> Result<FileAttr, FsError> {
+        if let Some(size) = size {
+            self.fs.truncate(inode_nbr, size)?;
+        }
+        let stat = self.fs.set_attr(inode_nbr, attr)?;
+        Ok(self.attr_from(&stat))
+    }
+
+    fn timestamp(&self, time: TimeOrNow) -> Timestamp {
+        match time {
+            TimeOrNow::Now => self.fs.now().into(),
+            TimeOrNow::SpecificTime(time) => Timestamp::from_system_time(time),
+        }
+    }
+}
+
+fn file_type(typ: InodeType) -> FileType {
+    match typ {
+        InodeType::FiFo => FileType::NamedPipe,
+        InodeType::CharacterDevice => FileType::CharDevice,
+        InodeType::Directory => FileType::Directory,
+        InodeType::BlockDevice => FileType::BlockDevice,
+        InodeType::Socket => FileType::Socket,
+        InodeType::Symlink => FileType::Symlink,
+        InodeType::File | InodeType::Unknown(_) => FileType::RegularFile,
+    }
+}
+
+fn errno(error: &FsError) -> i32 {
+    match error {
+        FsError::NoEntry => libc::ENOENT,
+        FsError::NoAttribute | FsError::NotJournaled => libc::ENODATA,
+        FsError::NameTooLong => libc::ENAMETOOLONG,
+        FsError::NoSpace | FsError::DiskError(DiskError::NotEnoughSpace) => libc::ENOSPC,
+        FsError::QuotaExceeded => libc::EDQUOT,
+        FsError::FileTooLarge => libc::EFBIG,
+        FsError::DiskError(DiskError::Interrupted) => libc::EINTR,
+        FsError::DiskError(DiskError::PermissionDenied) | FsError::BadPassphrase => libc::EACCES,
+        FsError::DiskError(DiskError::Unsupported) => libc::EOPNOTSUPP,
+        FsError::ReadOnly => libc::EROFS,
+        FsError::NotAFile => libc::EISDIR,
+        FsError::NotADirectory => libc::ENOTDIR,
+        FsError::DirectoryNotEmpty => libc::ENOTEMPTY,
+        FsError::TooManyLinks => libc::EMLINK,
+        FsError::DirectoryLoop | FsError::InvalidName | FsError::InvalidValue => libc::EINVAL,
+        FsError::AlreadyExists => libc::EEXIST,
+        FsError::TypeChange | FsError::WriteOnce => libc::EPERM,
+        FsError::Corrupted
+        | FsError::InvalidBlock
+        | FsError::InvalidSignature
+        | FsError::DiskTooSmall => libc::EUCLEAN,
+        FsError::ChecksumMismatch
+        | FsError::BadSeal
+        | FsError::BadRootHash
+        | FsError::DiskError(DiskError::DecryptionFailed { .. }) => libc::EBADMSG,
+        _ => libc::EIO,
+    }
+}
+
+impl Filesystem for FuseFs {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let Some(name) = name.to_str() else {
+            return reply.error(libc::ENOENT);
+        };
+        let parent = self.to_sfs(parent);
+        match self
+            .fs
+            .lookup(parent, name)
+            .and_then(|inode_nbr| self.entry(inode_nbr))
+        {
+            Ok((attr, generation)) => reply.entry(&TTL, &attr, generation),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        match self.attr(self.to_sfs(ino)) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let inode_nbr = self.to_sfs(ino);
+        let attr = SetAttr {
+            permissions: mode.map(|mode| mode as u16),
+            uid: uid.map(|uid| uid as u16),
+            gid: gid.map(|gid| gid as u16),
+            modified: mtime.map(|time| self.timestamp(time)),
+            accessed: atime.map(|time| self.timestamp(time)),
+        };
+        match self.setattr_inner(inode_nbr, size, &attr) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let inode_nbr = self.to_sfs(ino);
+        let result = self.fs.read_inode(inode_nbr).and_then(|inode| {
+            let file_size = inode.size() as usize;
+            let offset = (offset.max(0) as usize).min(file_size);
+            let mut buf = vec![0; (size as usize).min(file_size - offset)];
+            let read = inode.read(offset, &mut buf, &mut self.fs)?;
+            buf.truncate(read);
+            self.fs.mark_accessed(inode_nbr)?;
+            Ok(buf)
+        });
+        match result {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        let inode_nbr = self.to_sfs(ino);
+        match self.fs.write_at(inode_nbr, offset.max(0) as usize, data) {
+            Ok(written) => reply.written(written as u32),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let inode_nbr = self.to_sfs(ino);
+        let entries = match self
+            .fs
+            .read_inode(inode_nbr)
+            .and_then(|inode| inode.read_dir_entries(&mut self.fs))
+        {
+            Ok(entries) => entries,
+            Err(e) => return reply.error(errno(&e)),
+        };
+
+        // `read_dir_entries` leaves out `.` and `..`, and images without `dot_entries` don't
+        // store them at all; there the kernel only needs some inode for `..`
+        let parent = match self.fs.lookup(inode_nbr, "..") {
+            Ok(parent) => self.to_fuse(parent),
+            Err(_) => ino,
+        };
+        let dots = [
+            (ino, FileType::Directory, ".".to_string()),
+            (parent, FileType::Directory, "..".to_string()),
+        ];
+        let entries = dots.into_iter().chain(entries.into_iter().map(|entry| {
+            (
+                self.to_fuse(entry.inode),
+                file_type(entry.get_type()),
+                entry.get_name(),
+            )
+        }));
+        for (i, (ino, kind, name)) in entries.enumerate().skip(offset.max(0) as usize) {
+            // the offset passed back to us is the one of the next entry
+            if reply.add(ino, i as i64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn create(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        _flags: i32,
+        reply: ReplyCreate,
+    ) {
+        match self.create_inode(parent, name, InodeType::File, mode & !umask, req) {
+            Ok((attr, generation)) => reply.created(&TTL, &attr, generation, 0, 0),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        match self.create_inode(parent, name, InodeType::Directory, mode & !umask, req) {
+            Ok((attr, generation)) => reply.entry(&TTL, &attr, generation),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        // the hooks only care about files that may have been written
+        if flags & libc::O_ACCMODE == libc::O_RDONLY {
+            return reply.ok();
+        }
+        match self.fs.close(self.to_sfs(ino)) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn symlink(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        link_name: &OsStr,
+        target: &Path,
+        reply: ReplyEntry,
+    ) {
+        let (Some(name), Some(target)) = (link_name.to_str(), target.to_str()) else {
+            return reply.error(libc::EINVAL);
+        };
+        let parent = self.to_sfs(parent);
+        let result = self
+            .fs
+            .symlink(parent, name.to_string(), target)
+            .and_then(|inode_nbr| {
+                self.fs.set_attr(
+                    inode_nbr,
+                    &SetAttr {
+                        uid: Some(req.uid() as u16),
+                        gid: Some(req.gid() as u16),
+                        ..Default::default()
+                    },
+                )
+            });
+        match result {
+            Ok(stat) => reply.entry(&TTL, &self.attr_from(&stat), stat.generation as u64),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        match self.fs.read_link(self.to_sfs(ino)) {
+            Ok(target) => reply.data(target.as_bytes()),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn link(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        let Some(name) = newname.to_str() else {
+            return reply.error(libc::EINVAL);
+        };
+        let inode_nbr = self.to_sfs(ino);
+        let parent = self.to_sfs(newparent);
+        match self
+            .fs
+            .link(inode_nbr, parent, name)
+            .and_then(|()| self.entry(inode_nbr))
+        {
+            Ok((attr, generation)) => reply.entry(&TTL, &attr, generation),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let Some(name) = name.to_str() else {
+            return reply.error(libc::ENOENT);
+        };
+        let parent = self.to_sfs(parent);
+        match self.fs.unlink(parent, name) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let Some(name) = name.to_str() else {
+            return reply.error(libc::ENOENT);
+        };
+        let parent = self.to_sfs(parent);
+        match self.fs.rmdir(parent, name) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        _flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let (Some(name), Some(newname)) = (name.to_str(), newname.to_str()) else {
+            return reply.error(libc::ENOENT);
+        };
+        let (parent, newparent) = (self.to_sfs(parent), self.to_sfs(newparent));
+        match self.fs.rename(parent, name, newparent, newname) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn fsync(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        _datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        match self.fs.sync() {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn fsyncdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        _datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        let ino = self.to_sfs(ino);
+        match self.fs.sync_dir(ino) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+}
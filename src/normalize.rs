@@ -0,0 +1,21 @@
+//! Unicode normalization of entry names, see `MkfsOptions::normalize_names`
+
+use alloc::borrow::Cow;
+
+/// `name` in Unicode Normalization Form C, borrowed if it already is (like almost every name)
+#[cfg(feature = "unicode")]
+pub(crate) fn nfc(name: &str) -> Cow<'_, str> {
+    use unicode_normalization::{is_nfc_quick, IsNormalized, UnicodeNormalization};
+
+    match is_nfc_quick(name.chars()) {
+        IsNormalized::Yes => Cow::Borrowed(name),
+        _ => Cow::Owned(name.nfc().collect()),
+    }
+}
+
+/// Without the tables of the `unicode` feature names stay as they are, images asking for
+/// normalization count as having an unknown ro-compat feature then
+#[cfg(not(feature = "unicode"))]
+pub(crate) fn nfc(name: &str) -> Cow<'_, str> {
+    Cow::Borrowed(name)
+}
@@ -0,0 +1,253 @@
+use std::{
+    collections::BTreeMap,
+    fs::{File, Metadata, Permissions},
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    fs::{FileSystem, FsError, SetAttr},
+    inode::{Inode, InodeType, Permission, PermissionsAndType, PERMISSION_MASK},
+    time::Timestamp,
+};
+
+/// What `FileSystem::pack_dir` or `FileSystem::unpack_dir` copied
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PackStats {
+    /// files created, further hard links to a file don't count
+    pub files: u32,
+    pub directories: u32,
+    pub symlinks: u32,
+    /// the bytes of file contents copied
+    pub bytes: u64,
+    /// what the other side can't hold: fifos, sockets and devices, and names or link targets
+    /// that aren't UTF-8 when packing
+    pub skipped: Vec<PathBuf>,
+}
+
+fn io(error: std::io::Error) -> FsError {
+    FsError::IoError(error)
+}
+
+/// The modification time of a host file
+fn host_mtime(meta: &Metadata) -> Timestamp {
+    Timestamp::new(meta.mtime(), meta.mtime_nsec() as u32)
+}
+
+/// The creation time of a host file, its modification time where the host doesn't know it
+fn host_created(meta: &Metadata) -> Timestamp {
+    meta.created()
+        .map_or(host_mtime(meta), Timestamp::from_system_time)
+}
+
+/// Copies a host directory tree into an image, see `FileSystem::pack_dir`
+pub(crate) struct Packer<'a> {
+    fs: &'a mut FileSystem,
+    /// the inodes created for host files with more than one link, by device and inode number
+    linked: BTreeMap<(u64, u64), u32>,
+    stats: PackStats,
+}
+
+impl<'a> Packer<'a> {
+    pub(crate) fn new(fs: &'a mut FileSystem) -> Self {
+        Self {
+            fs,
+            linked: BTreeMap::new(),
+            stats: PackStats::default(),
+        }
+    }
+
+    pub(crate) fn run(mut self, host: &Path, dir_nbr: u32) -> Result<PackStats, FsError> {
+        let meta = std::fs::metadata(host).map_err(io)?;
+        if !meta.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+        self.pack_dir(host, dir_nbr)?;
+        self.finish(dir_nbr, &meta)?;
+        Ok(self.stats)
+    }
+
+    /// Packs the entries of `host` into `dir_nbr`, in the order of their names
+    fn pack_dir(&mut self, host: &Path, dir_nbr: u32) -> Result<(), FsError> {
+        let mut entries = std::fs::read_dir(host)
+            .map_err(io)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(io)?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let path = entry.path();
+            match entry.file_name().to_str() {
+                Some(name) => self.pack_entry(&path, dir_nbr, name)?,
+                None => self.stats.skipped.push(path),
+            }
+        }
+        Ok(())
+    }
+
+    fn pack_entry(&mut self, host: &Path, dir_nbr: u32, name: &str) -> Result<(), FsError> {
+        let meta = std::fs::symlink_metadata(host).map_err(io)?;
+        let key = (meta.dev(), meta.ino());
+        if let Some(&inode_nbr) = self.linked.get(&key) {
+            return self.fs.link(inode_nbr, dir_nbr, name);
+        }
+
+        let file_type = meta.file_type();
+        let inode_nbr = if file_type.is_symlink() {
+            let target = std::fs::read_link(host).map_err(io)?;
+            let Some(target) = target.to_str() else {
+                self.stats.skipped.push(host.to_path_buf());
+                return Ok(());
+            };
+            self.stats.symlinks += 1;
+            self.fs.symlink(dir_nbr, name.to_string(), target)?
+        } else if file_type.is_dir() {
+            let inode_nbr = self.create(dir_nbr, name, InodeType::Directory, &meta)?;
+            self.stats.directories += 1;
+            self.pack_dir(host, inode_nbr)?;
+            inode_nbr
+        } else if file_type.is_file() {
+            let inode_nbr = self.create(dir_nbr, name, InodeType::File, &meta)?;
+            let mut file = File::open(host).map_err(io)?;
+            let mut inode = self.fs.read_inode(inode_nbr)?;
+            self.stats.bytes += inode.write_from_reader(&mut file, self.fs, inode_nbr)? as u64;
+            self.fs.close(inode_nbr)?;
+            self.stats.files += 1;
+            inode_nbr
+        } else {
+            self.stats.skipped.push(host.to_path_buf());
+            return Ok(());
+        };
+
+        if !file_type.is_dir() && meta.nlink() > 1 {
+            self.linked.insert(key, inode_nbr);
+        }
+        self.finish(inode_nbr, &meta)
+    }
+
+    /// Creates `name` in `dir_nbr`, owned by root, with the permissions and creation time of the
+    /// host file
+    fn create(
+        &mut self,
+        dir_nbr: u32,
+        name: &str,
+        typ: InodeType,
+        meta: &Metadata,
+    ) -> Result<u32, FsError> {
+        let mut inode = Inode::create(
+            PermissionsAndType::new(
+                typ,
+                &[Permission::Other(meta.mode() as u16 & PERMISSION_MASK)],
+            ),
+            0,
+            0,
+            0,
+            0,
+            0,
+        );
+        inode.creation_time = host_created(meta).secs;
+        self.fs.create_dir_entry(dir_nbr, inode, name.to_string())
+    }
+
+    /// Sets the permissions and the modification time of `inode_nbr` to the ones of the host
+    /// file. Last, since adding contents or entries changes the modification time.
+    fn finish(&mut self, inode_nbr: u32, meta: &Metadata) -> Result<(), FsError> {
+        let is_symlink = meta.file_type().is_symlink();
+        self.fs.set_attr(
+            inode_nbr,
+            &SetAttr {
+                // the permissions of a symbolic link don't mean anything
+                permissions: (!is_symlink).then_some(meta.mode() as u16 & PERMISSION_MASK),
+                modified: Some(host_mtime(meta)),
+                ..Default::default()
+            },
+        )?;
+        Ok(())
+    }
+}
+
+/// Copies a directory tree of an image to the host, see `FileSystem::unpack_dir`
+pub(crate) struct Unpacker<'a> {
+    fs: &'a mut FileSystem,
+    /// where the inodes with more than one link were unpacked first
+    linked: BTreeMap<u32, PathBuf>,
+    stats: PackStats,
+}
+
+impl<'a> Unpacker<'a> {
+    pub(crate) fn new(fs: &'a mut FileSystem) -> Self {
+        Self {
+            fs,
+            linked: BTreeMap::new(),
+            stats: PackStats::default(),
+        }
+    }
+
+    pub(crate) fn run(mut self, dir_nbr: u32, host: &Path) -> Result<PackStats, FsError> {
+        let inode = self.fs.read_inode(dir_nbr)?;
+        if inode.type_and_permission.get_type() != InodeType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        std::fs::create_dir_all(host).map_err(io)?;
+        self.unpack_dir(&inode, host)?;
+        self.finish(&inode, host)?;
+        Ok(self.stats)
+    }
+
+    fn unpack_dir(&mut self, dir: &Inode, host: &Path) -> Result<(), FsError> {
+        for entry in dir.read_dir_entries(self.fs)? {
+            let path = host.join(entry.get_name());
+            self.unpack_entry(entry.inode, &path)?;
+        }
+        Ok(())
+    }
+
+    fn unpack_entry(&mut self, inode_nbr: u32, host: &Path) -> Result<(), FsError> {
+        if let Some(first) = self.linked.get(&inode_nbr) {
+            return std::fs::hard_link(first, host).map_err(io);
+        }
+
+        let inode = self.fs.read_inode(inode_nbr)?;
+        match inode.type_and_permission.get_type() {
+            InodeType::Directory => {
+                match std::fs::create_dir(host) {
+                    Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => return Err(io(e)),
+                    _ => {}
+                }
+                self.stats.directories += 1;
+                self.unpack_dir(&inode, host)?;
+            }
+            InodeType::File => {
+                let mut file = File::create(host).map_err(io)?;
+                self.stats.bytes += inode.read_to_writer(&mut file, self.fs, None)? as u64;
+                self.stats.files += 1;
+            }
+            InodeType::Symlink => {
+                let target = self.fs.read_link(inode_nbr)?;
+                std::os::unix::fs::symlink(target, host).map_err(io)?;
+                self.stats.symlinks += 1;
+                // std can't set the times of a link itself, and its permissions don't matter
+                return Ok(());
+            }
+            _ => {
+                self.stats.skipped.push(host.to_path_buf());
+                return Ok(());
+            }
+        }
+
+        if inode.type_and_permission.get_type() != InodeType::Directory && inode.hardlinks > 1 {
+            self.linked.insert(inode_nbr, host.to_path_buf());
+        }
+        self.finish(&inode, host)
+    }
+
+    /// Sets the modification time and then the permissions of the host file, so a read-only
+    /// file or directory is only made read-only once it is complete
+    fn finish(&mut self, inode: &Inode, host: &Path) -> Result<(), FsError> {
+        let file = File::open(host).map_err(io)?;
+        file.set_modified(inode.modified().to_system_time())
+            .map_err(io)?;
+        let mode = inode.type_and_permission.get_raw() & PERMISSION_MASK;
+        std::fs::set_permissions(host, Permissions::from_mode(mode as u32)).map_err(io)
+    }
+}
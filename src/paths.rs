@@ -0,0 +1,132 @@
+//! Paths interned with `FileSystem::intern_path`, for servers that resolve the same paths over
+//! and over
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use crate::fs::FsError;
+
+/// A path interned with `FileSystem::intern_path`, only valid with the file system that handed it
+/// out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PathId(u32);
+
+/// A path as the operations of `Sfs` take it, either a string or an interned path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathRef<'a> {
+    Str(&'a str),
+    Id(PathId),
+}
+
+impl<'a> From<&'a str> for PathRef<'a> {
+    fn from(path: &'a str) -> Self {
+        Self::Str(path)
+    }
+}
+
+impl<'a> From<&'a String> for PathRef<'a> {
+    fn from(path: &'a String) -> Self {
+        Self::Str(path)
+    }
+}
+
+impl From<PathId> for PathRef<'_> {
+    fn from(id: PathId) -> Self {
+        Self::Id(id)
+    }
+}
+
+#[derive(Debug)]
+struct Resolution {
+    inode: u32,
+    /// every directory the path was looked up in, sorted
+    dirs: Vec<u32>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    path: String,
+    /// `None` until the path is resolved, and again once a directory on the way changes
+    resolved: Option<Resolution>,
+}
+
+/// Every interned path and what it resolved to. Resolutions are dropped whenever a directory
+/// they were looked up in is written, which every change to its entries (a rename, an unlink, a
+/// new entry) does.
+#[derive(Debug, Default)]
+pub(crate) struct InternedPaths {
+    entries: Vec<Entry>,
+    ids: BTreeMap<String, PathId>,
+    /// the resolved paths that were looked up in each directory
+    by_dir: BTreeMap<u32, Vec<PathId>>,
+}
+
+impl InternedPaths {
+    pub(crate) fn intern(&mut self, path: &str) -> PathId {
+        if let Some(&id) = self.ids.get(path) {
+            return id;
+        }
+        let id = PathId(self.entries.len() as u32);
+        self.entries.push(Entry {
+            path: path.to_string(),
+            resolved: None,
+        });
+        self.ids.insert(path.to_string(), id);
+        id
+    }
+
+    fn entry(&self, id: PathId) -> Result<&Entry, FsError> {
+        self.entries.get(id.0 as usize).ok_or(FsError::InvalidValue)
+    }
+
+    pub(crate) fn path(&self, id: PathId) -> Result<&str, FsError> {
+        Ok(&self.entry(id)?.path)
+    }
+
+    /// The cached inode of `id`, `None` if it has to be resolved
+    pub(crate) fn resolved(&self, id: PathId) -> Result<Option<u32>, FsError> {
+        Ok(self
+            .entry(id)?
+            .resolved
+            .as_ref()
+            .map(|resolved| resolved.inode))
+    }
+
+    /// Caches that `id` resolved to `inode`, looked up in the directories `dirs`
+    pub(crate) fn set_resolved(&mut self, id: PathId, inode: u32, mut dirs: Vec<u32>) {
+        dirs.sort_unstable();
+        dirs.dedup();
+        for &dir in &dirs {
+            self.by_dir.entry(dir).or_default().push(id);
+        }
+        self.entries[id.0 as usize].resolved = Some(Resolution { inode, dirs });
+    }
+
+    /// Drops the resolution of every path that was looked up in `dir`
+    pub(crate) fn forget_dir(&mut self, dir: u32) {
+        for id in self.by_dir.remove(&dir).unwrap_or_default() {
+            let Some(resolved) = self.entries[id.0 as usize].resolved.take() else {
+                continue;
+            };
+            for other in resolved.dirs.into_iter().filter(|&other| other != dir) {
+                if let Some(ids) = self.by_dir.get_mut(&other) {
+                    ids.retain(|&other_id| other_id != id);
+                    if ids.is_empty() {
+                        self.by_dir.remove(&other);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Drops every resolution, the paths stay interned
+    pub(crate) fn clear(&mut self) {
+        for entry in &mut self.entries {
+            entry.resolved = None;
+        }
+        self.by_dir.clear();
+    }
+}
@@ -0,0 +1,248 @@
+//! Per-user and per-group limits on the blocks and inodes files use, see `FileSystem::set_quota`.
+//! The limits are stored in the quota inode, which has no directory entry. The usage isn't
+//! stored: it is counted from the inodes the first time it is needed and kept up to date from
+//! then on.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+
+use crate::{
+    fs::{BlockArrayEntry, FileSystem, FsError},
+    inode::{Inode, InodeType, Permission, PermissionsAndType},
+};
+
+/// Whose files a quota limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum QuotaId {
+    User(u16),
+    Group(u16),
+}
+
+/// The most blocks and inodes the files of a user or group may use, `None` for no limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QuotaLimits {
+    /// every block of the files, including indirect pointer, directory and attribute blocks
+    pub blocks: Option<u32>,
+    pub inodes: Option<u32>,
+}
+
+/// What the files of a user or group use, see `FileSystem::get_usage`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QuotaUsage {
+    pub blocks: u32,
+    pub inodes: u32,
+    pub limits: QuotaLimits,
+}
+
+const KIND_USER: u8 = 1;
+const KIND_GROUP: u8 = 2;
+/// Every record is a kind, a reserved byte, the id, the block limit and the inode limit
+const RECORD_SIZE: usize = 12;
+/// The limit of a record that doesn't limit anything
+const NO_LIMIT: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Counts {
+    blocks: u32,
+    inodes: u32,
+}
+
+/// The limits of an image and what every user and group uses
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Quotas {
+    limits: BTreeMap<QuotaId, QuotaLimits>,
+    /// `None` until it is counted, and again after writes were thrown away
+    usage: Option<BTreeMap<QuotaId, Counts>>,
+    /// the uid and gid of the inode the blocks that are allocated and freed right now belong to,
+    /// see `FileSystem::charged`
+    owner: Option<(u16, u16)>,
+}
+
+fn ids((uid, gid): (u16, u16)) -> [QuotaId; 2] {
+    [QuotaId::User(uid), QuotaId::Group(gid)]
+}
+
+impl Quotas {
+    /// The limits stored in the quota inode of `fs`
+    pub(crate) fn load(fs: &mut FileSystem) -> Result<Self, FsError> {
+        let file = fs.superblock.quota_inode;
+        if !fs.superblock.has_quotas() || file == 0 {
+            return Ok(Self::default());
+        }
+        let data = fs.read_inode(file)?.read_all(fs)?;
+        let mut limits = BTreeMap::new();
+        for record in data.chunks_exact(RECORD_SIZE) {
+            let id = u16::from_ne_bytes([record[2], record[3]]);
+            let id = match record[0] {
+                KIND_USER => QuotaId::User(id),
+                KIND_GROUP => QuotaId::Group(id),
+                _ => return Err(fs.corrupted()),
+            };
+            let limit = |at: usize| {
+                let limit = u32::from_ne_bytes(record[at..at + 4].try_into().unwrap());
+                (limit != NO_LIMIT).then_some(limit)
+            };
+            limits.insert(
+                id,
+                QuotaLimits {
+                    blocks: limit(4),
+                    inodes: limit(8),
+                },
+            );
+        }
+        Ok(Self {
+            limits,
+            ..Default::default()
+        })
+    }
+
+    /// The contents of the quota inode, one record per id with limits, sorted by id
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.limits.len() * RECORD_SIZE);
+        for (id, limits) in &self.limits {
+            let (kind, id) = match *id {
+                QuotaId::User(uid) => (KIND_USER, uid),
+                QuotaId::Group(gid) => (KIND_GROUP, gid),
+            };
+            data.extend([kind, 0]);
+            data.extend(id.to_ne_bytes());
+            data.extend(limits.blocks.unwrap_or(NO_LIMIT).to_ne_bytes());
+            data.extend(limits.inodes.unwrap_or(NO_LIMIT).to_ne_bytes());
+        }
+        data
+    }
+
+    pub(crate) fn set_limits(&mut self, id: QuotaId, limits: QuotaLimits) {
+        if limits == QuotaLimits::default() {
+            self.limits.remove(&id);
+        } else {
+            self.limits.insert(id, limits);
+        }
+    }
+
+    pub(crate) fn is_counted(&self) -> bool {
+        self.usage.is_some()
+    }
+
+    /// Counts the blocks and inodes of every inode in use, except the quota inode
+    pub(crate) fn count(&mut self, fs: &mut FileSystem) -> Result<(), FsError> {
+        let quota_file = fs.superblock.quota_inode;
+        let mut usage = BTreeMap::new();
+        for inode_nbr in fs.inodes_in_use()? {
+            if inode_nbr == quota_file {
+                continue;
+            }
+            let inode = fs.read_inode(inode_nbr)?;
+            let blocks = inode.allocated_blocks(fs)?;
+            for id in ids((inode.uid, inode.gid)) {
+                let counts: &mut Counts = usage.entry(id).or_default();
+                counts.blocks += blocks;
+                counts.inodes += 1;
+            }
+        }
+        self.usage = Some(usage);
+        Ok(())
+    }
+
+    /// Forgets the usage, after writes were thrown away or changed inodes behind its back
+    pub(crate) fn invalidate(&mut self) {
+        self.usage = None;
+    }
+
+    pub(crate) fn usage(&self, id: QuotaId) -> QuotaUsage {
+        let counts = self
+            .usage
+            .as_ref()
+            .and_then(|usage| usage.get(&id).copied())
+            .unwrap_or_default();
+        QuotaUsage {
+            blocks: counts.blocks,
+            inodes: counts.inodes,
+            limits: self.limits.get(&id).copied().unwrap_or_default(),
+        }
+    }
+
+    /// Replaces the owner blocks are charged to and returns the previous one
+    pub(crate) fn set_owner(&mut self, owner: Option<(u16, u16)>) -> Option<(u16, u16)> {
+        core::mem::replace(&mut self.owner, owner)
+    }
+
+    /// Fails with `FsError::QuotaExceeded` if `blocks` more blocks and `inodes` more inodes for
+    /// the files of `owner` go over the limits of its user or group. Usage that is already over a
+    /// lowered limit only stops growing.
+    pub(crate) fn check(&self, owner: (u16, u16), blocks: u32, inodes: u32) -> Result<(), FsError> {
+        let Some(usage) = &self.usage else {
+            return Ok(());
+        };
+        let over = |limit: Option<u32>, used: u32, more: u32| {
+            more > 0 && limit.is_some_and(|limit| used.saturating_add(more) > limit)
+        };
+        for id in ids(owner) {
+            let Some(limits) = self.limits.get(&id) else {
+                continue;
+            };
+            let used = usage.get(&id).copied().unwrap_or_default();
+            if over(limits.blocks, used.blocks, blocks) || over(limits.inodes, used.inodes, inodes)
+            {
+                return Err(FsError::QuotaExceeded);
+            }
+        }
+        Ok(())
+    }
+
+    /// `check` for one more block of the current owner, if there is one
+    pub(crate) fn check_block(&self) -> Result<(), FsError> {
+        match self.owner {
+            Some(owner) => self.check(owner, 1, 0),
+            None => Ok(()),
+        }
+    }
+
+    /// Adds `blocks` and `inodes` (negative when they are freed) to the usage of `owner`
+    pub(crate) fn charge(&mut self, owner: (u16, u16), blocks: i32, inodes: i32) {
+        let Some(usage) = &mut self.usage else {
+            return;
+        };
+        for id in ids(owner) {
+            let counts = usage.entry(id).or_default();
+            counts.blocks = counts.blocks.saturating_add_signed(blocks);
+            counts.inodes = counts.inodes.saturating_add_signed(inodes);
+        }
+    }
+
+    /// Charges a block going from `was` to `typ` to the current owner, failing before anything
+    /// changed if it goes over a limit. Only data blocks count, inode blocks are shared by
+    /// everyone.
+    pub(crate) fn block_changed(
+        &mut self,
+        was: BlockArrayEntry,
+        typ: BlockArrayEntry,
+    ) -> Result<(), FsError> {
+        let Some(owner) = self.owner else {
+            return Ok(());
+        };
+        match (was, typ) {
+            (BlockArrayEntry::Unused, BlockArrayEntry::Allocated) => {
+                self.check(owner, 1, 0)?;
+                self.charge(owner, 1, 0);
+            }
+            (BlockArrayEntry::Allocated, BlockArrayEntry::Unused) => self.charge(owner, -1, 0),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Creates the quota inode, owned by root and only readable by it. It has a single link without
+/// a directory entry, so nothing but `FileSystem::set_quota` changes it.
+pub(crate) fn create_file(fs: &mut FileSystem) -> Result<u32, FsError> {
+    let now = fs.now();
+    let inode = Inode::create(
+        PermissionsAndType::new(InodeType::File, &[Permission::user_rw()]),
+        0,
+        0,
+        now,
+        1,
+        0,
+    );
+    fs.create_inode(&inode)
+}
@@ -0,0 +1,96 @@
+use crate::{
+    fs::{BlockArrayEntry, FileSystem, FsError},
+    fsck::inode_blocks,
+    inode::{Inode, InodeType},
+    time::Timestamp,
+};
+use alloc::{format, vec::Vec};
+
+/// The directory in the root that recovered inodes are linked into
+pub const LOST_AND_FOUND: &str = "lost+found";
+
+/// An unlinked inode whose block pointers survived, see `FileSystem::recover_deleted`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeletedInode {
+    pub inode: u32,
+    pub typ: InodeType,
+    pub size: u64,
+    pub modification_time: Timestamp,
+    /// the data and pointer blocks it points at
+    pub blocks: u32,
+    /// Whether all of them are still free, so relinking it gives back the data it had. Otherwise
+    /// some of them were reused since, or its pointers are damaged.
+    pub recoverable: bool,
+}
+
+/// The blocks of the unlinked inode `inode`, `None` if some of them can't belong to it anymore
+fn free_blocks(
+    fs: &mut FileSystem,
+    inode: &Inode,
+    inode_nbr: u32,
+) -> Result<Option<Vec<u32>>, FsError> {
+    let mut problems = Vec::new();
+    let mut blocks = inode_blocks(fs, inode, inode_nbr, &mut problems)?;
+    blocks.sort_unstable();
+    blocks.dedup();
+    for &block in &blocks {
+        if fs.block_type(block)? != BlockArrayEntry::Unused {
+            return Ok(None);
+        }
+    }
+    Ok(problems.is_empty().then_some(blocks))
+}
+
+fn is_candidate(inode: &Inode) -> bool {
+    inode.hardlinks == 0
+        && inode.type_and_permission.get_type() != InodeType::Directory
+        && (inode.block_pointers.iter().any(|&ptr| ptr != 0)
+            || inode.singly_indirect_block_pointer != 0
+            || inode.doubly_indirect_block_pointer != 0)
+}
+
+/// Every unlinked inode that still points at blocks, by inode number
+pub(crate) fn scan(fs: &mut FileSystem) -> Result<Vec<DeletedInode>, FsError> {
+    let mut deleted = Vec::new();
+    for inode_nbr in fs.inode_slots()? {
+        let inode = fs.read_inode_unchecked(inode_nbr)?;
+        if !is_candidate(&inode) {
+            continue;
+        }
+        let mut problems = Vec::new();
+        let blocks = inode_blocks(fs, &inode, inode_nbr, &mut problems)?.len() as u32;
+        deleted.push(DeletedInode {
+            inode: inode_nbr,
+            typ: inode.type_and_permission.get_type(),
+            size: inode.size,
+            modification_time: inode.modified(),
+            blocks,
+            recoverable: free_blocks(fs, &inode, inode_nbr)?.is_some(),
+        });
+    }
+    Ok(deleted)
+}
+
+/// Marks the blocks of the unlinked inode `inode_nbr` as allocated again and links it into
+/// `/lost+found` as `#<inode>`
+pub(crate) fn relink(fs: &mut FileSystem, inode_nbr: u32) -> Result<(), FsError> {
+    let inode = fs.read_inode(inode_nbr)?;
+    if !is_candidate(&inode) {
+        return Err(FsError::NoEntry);
+    }
+    let blocks = free_blocks(fs, &inode, inode_nbr)?.ok_or(FsError::InvalidBlock)?;
+
+    // claims the inode and its blocks first, creating `/lost+found` could reuse either of them
+    for block in blocks {
+        fs.allocate_block_id(block)?;
+    }
+    let mut claimed = inode;
+    claimed.hardlinks = 1;
+    fs.write_inode(inode_nbr, &claimed)?;
+
+    let root = fs.superblock.root_inode;
+    let lost_and_found = fs.lookup_or_mkdir(root, LOST_AND_FOUND)?;
+    claimed.hardlinks = 0;
+    fs.write_inode(inode_nbr, &claimed)?;
+    fs.link(inode_nbr, lost_and_found, &format!("#{inode_nbr}"))
+}
@@ -0,0 +1,97 @@
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt::Debug;
+
+use crate::fs::{FileSystem, FsError};
+
+/// Resolves the rest of a path below a redirected prefix to a handle of the embedder
+pub type RedirectFn = Box<dyn FnMut(&str) -> Result<u64, FsError> + Send>;
+
+/// What a path prefix registered with `FileSystem::redirect` leads to
+pub enum Redirect {
+    /// Another file system, like a bind mount: the rest of the path is resolved from its root
+    FileSystem(Box<FileSystem>),
+    /// A function of the rest of the path, for trees the embedder takes from the host or makes
+    /// up. What the returned handle means is up to the embedder.
+    Callback(RedirectFn),
+}
+
+impl Debug for Redirect {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::FileSystem(fs) => f.debug_tuple("FileSystem").field(fs).finish(),
+            Self::Callback(_) => f.write_str("Callback"),
+        }
+    }
+}
+
+/// Where `FileSystem::resolve_path` ended up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolved {
+    /// an inode of this file system
+    Inode(u32),
+    /// an inode of the file system redirected to at `prefix`, see `FileSystem::redirected_fs`
+    Redirected { prefix: String, inode: u32 },
+    /// the handle the callback redirected to at `prefix` returned
+    Callback { prefix: String, handle: u64 },
+}
+
+/// The components of `path` with empty ones and `.` dropped and `..` applied, the same way
+/// `FileSystem::lookup_path` goes through them
+fn normalize(path: &str) -> Vec<&str> {
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            name => components.push(name),
+        }
+    }
+    components
+}
+
+/// The redirected path prefixes, by their components
+#[derive(Debug, Default)]
+pub(crate) struct Redirects(BTreeMap<Vec<String>, Redirect>);
+
+impl Redirects {
+    fn key(prefix: &str) -> Vec<String> {
+        normalize(prefix).into_iter().map(str::to_string).collect()
+    }
+
+    pub(crate) fn insert(&mut self, prefix: &str, redirect: Redirect) -> Option<Redirect> {
+        self.0.insert(Self::key(prefix), redirect)
+    }
+
+    pub(crate) fn remove(&mut self, prefix: &str) -> Option<Redirect> {
+        self.0.remove(&Self::key(prefix))
+    }
+
+    pub(crate) fn get_mut(&mut self, prefix: &str) -> Option<&mut Redirect> {
+        self.0.get_mut(&Self::key(prefix))
+    }
+
+    /// The longest redirected prefix of `path` as `/a/b`, its redirect and the rest of the path
+    /// below it
+    pub(crate) fn find(&mut self, path: &str) -> Option<(String, &mut Redirect, String)> {
+        if self.0.is_empty() {
+            return None;
+        }
+        let components = normalize(path);
+        let (len, key) = (0..=components.len()).rev().find_map(|len| {
+            let key: Vec<String> = components[..len].iter().map(|c| c.to_string()).collect();
+            self.0.contains_key(&key).then_some((len, key))
+        })?;
+
+        let prefix = format!("/{}", components[..len].join("/"));
+        let rest = components[len..].join("/");
+        Some((prefix, self.0.get_mut(&key)?, rest))
+    }
+}
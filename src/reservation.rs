@@ -0,0 +1,31 @@
+use crate::fs::FileSystem;
+
+/// Room for a known number of blocks and inodes, see `FileSystem::reserve`. Nothing else can
+/// allocate while it is alive, so as long as the changes made through `fs` stay within it, no
+/// allocation fails with `FsError::NoSpace`. Dropping it gives back whatever wasn't used.
+pub struct Reservation<'a> {
+    fs: &'a mut FileSystem,
+    blocks: u32,
+    inodes: u32,
+}
+
+impl<'a> Reservation<'a> {
+    pub(crate) fn new(fs: &'a mut FileSystem, blocks: u32, inodes: u32) -> Self {
+        Self { fs, blocks, inodes }
+    }
+
+    /// The file system to make the changes through
+    pub fn fs(&mut self) -> &mut FileSystem {
+        self.fs
+    }
+
+    /// The reserved blocks, counting data, indirect pointer, directory and attribute blocks alike
+    pub fn blocks(&self) -> u32 {
+        self.blocks
+    }
+
+    /// The reserved inodes, on top of the blocks
+    pub fn inodes(&self) -> u32 {
+        self.inodes
+    }
+}
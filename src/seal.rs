@@ -0,0 +1,151 @@
+use crate::{
+    addr::Addr,
+    crc32::bytes_of,
+    ed25519,
+    features::COMPAT_SEAL,
+    fs::{
+        BlockArrayDescriptor, BlockArrayEntry, FileSystem, FsError, BLOCKS_PER_BLOCKARRAY,
+        BLOCK_SIZE,
+    },
+    sha256::Sha256,
+};
+use alloc::{vec, vec::Vec};
+
+/// Signed before the digest, so a signature over an image can't be mistaken for one over
+/// anything else (and a later version of the digest for this one)
+const SEAL_CONTEXT: &[u8] = b"sfs-seal 1";
+
+/// The public key of the Ed25519 private key `secret` (32 random bytes), which `verify` checks
+/// the seal against
+pub fn public_key(secret: &[u8; 32]) -> [u8; 32] {
+    ed25519::public_key(secret)
+}
+
+/// The message the seal signs: `SEAL_CONTEXT` followed by the SHA-256 over every used block in
+/// ascending order, each one preceded by its little endian block number. The superblock is
+/// hashed as it is in memory with the seal and its checksum set to zero, so writing the seal
+/// doesn't change what it signs.
+fn message(fs: &mut FileSystem) -> Result<Vec<u8>, FsError> {
+    let mut superblock = fs.superblock.clone();
+    superblock.seal_key = [0; 32];
+    superblock.seal_signature = [0; 64];
+    superblock.checksum = 0;
+
+    let mut hasher = Sha256::new();
+    let total = fs.superblock.total_blocks;
+    let mut buf = vec![0; BLOCK_SIZE];
+    for group in 0..total.div_ceil(BLOCKS_PER_BLOCKARRAY) {
+        let start = group * BLOCKS_PER_BLOCKARRAY;
+        let entries = BlockArrayDescriptor::from_disk(fs.disk(), group).entries(total - start)?;
+        for (block, entry) in (start..).zip(entries) {
+            if entry == BlockArrayEntry::Unused {
+                continue;
+            }
+            hasher.update(&block.to_le_bytes());
+            if block == 1 {
+                hasher.update(bytes_of(&superblock));
+            } else {
+                fs.disk().read_exact(Addr::block(block)?.get(), &mut buf)?;
+                hasher.update(&buf);
+            }
+        }
+    }
+
+    let mut message = SEAL_CONTEXT.to_vec();
+    message.extend_from_slice(&hasher.finalize());
+    Ok(message)
+}
+
+pub(crate) fn seal(fs: &mut FileSystem, secret: &[u8; 32]) -> Result<(), FsError> {
+    if fs.disk().is_read_only() {
+        return Err(FsError::ReadOnly);
+    }
+    // everything the seal covers has to be on the disk already
+    fs.sync()?;
+    // the flag is set first, `verify` hashes the superblock with it
+    fs.superblock.feature_compat |= COMPAT_SEAL;
+    fs.superblock.seal_key = public_key(secret);
+    let message = message(fs)?;
+    fs.superblock.seal_signature = ed25519::sign(secret, &message);
+    fs.write_superblock()?;
+    Ok(fs.disk().sync()?)
+}
+
+pub(crate) fn verify(fs: &mut FileSystem, public_key: &[u8; 32]) -> Result<(), FsError> {
+    let superblock = &fs.superblock;
+    if superblock.feature_compat & COMPAT_SEAL == 0 || superblock.seal_key != *public_key {
+        return Err(FsError::BadSeal);
+    }
+    let signature = superblock.seal_signature;
+    let message = message(fs)?;
+    if ed25519::verify(public_key, &message, &signature) {
+        Ok(())
+    } else {
+        Err(FsError::BadSeal)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::{
+        disk::Disk,
+        fs::{MountOptions, BLOCK_SIZE},
+        Sfs,
+    };
+
+    const BLOCKS: u32 = 300;
+    const SECRET: [u8; 32] = [7; 32];
+
+    fn sealed_image() -> Vec<u8> {
+        let mut sfs = Sfs::from(FileSystem::create(BLOCKS, "sealed").unwrap());
+        sfs.write("/file", &[1; 2 * BLOCK_SIZE]).unwrap();
+        sfs.fs().seal(&SECRET).unwrap();
+        let mut image = vec![0; Addr::block(BLOCKS).unwrap().get()];
+        sfs.fs().export_image(&mut image).unwrap();
+        image
+    }
+
+    fn mount_sealed(image: Vec<u8>) -> Result<FileSystem, FsError> {
+        let options = MountOptions {
+            seal_key: Some(public_key(&SECRET)),
+            ..Default::default()
+        };
+        FileSystem::mount(Disk::new(Box::new(image)), options)
+    }
+
+    #[test]
+    fn sealed_images_verify_after_remounting() {
+        let mut fs = mount_sealed(sealed_image()).unwrap();
+        fs.verify_seal(&public_key(&SECRET)).unwrap();
+        assert!(matches!(
+            fs.verify_seal(&public_key(&[8; 32])),
+            Err(FsError::BadSeal)
+        ));
+        let mut sfs = Sfs::from(fs);
+        assert_eq!(sfs.read("/file").unwrap(), [1; 2 * BLOCK_SIZE]);
+    }
+
+    #[test]
+    fn changes_after_sealing_break_the_seal() {
+        let mut image = sealed_image();
+        let mut fs = mount_sealed(image.clone()).unwrap();
+        let file = fs.lookup_path("/file").unwrap();
+        let block = fs.read_inode(file).unwrap().block_map(&mut fs).unwrap()[0];
+
+        // a bit flipped behind the file system's back
+        image[Addr::block(block).unwrap().get()] ^= 1;
+        assert!(matches!(mount_sealed(image), Err(FsError::BadSeal)));
+
+        // a change through the file system, until the image is sealed again
+        let mut sfs = Sfs::from(fs);
+        sfs.write("/other", b"new").unwrap();
+        let fs = sfs.fs();
+        assert!(matches!(
+            fs.verify_seal(&public_key(&SECRET)),
+            Err(FsError::BadSeal)
+        ));
+        fs.seal(&SECRET).unwrap();
+        fs.verify_seal(&public_key(&SECRET)).unwrap();
+    }
+}
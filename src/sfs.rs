@@ -0,0 +1,207 @@
+use std::{fs::File, path::Path};
+
+use crate::{
+    directory::{is_dot_name, DirEntry},
+    disk::Disk,
+    fs::{FileSystem, FsError, MkfsOptions, Stat, BLOCK_SIZE},
+    inode::{Inode, InodeType, Permission, PermissionsAndType},
+    paths::PathRef,
+};
+
+/// A file system that is used by path, like `std::fs`: paths are relative to the root, `/`
+/// separated, and can contain `.` and `..`. Paths that are used over and over can be interned
+/// with `FileSystem::intern_path` and passed as `PathId`s, which skips resolving them again while
+/// the directories on the way don't change. `fs` gives access to everything else.
+#[derive(Debug)]
+pub struct Sfs {
+    fs: FileSystem,
+}
+
+impl Sfs {
+    /// Opens the image file at `path` for reading and writing
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, FsError> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(FsError::IoError)?;
+        Ok(Self::from(FileSystem::from_disk(Disk::new(Box::new(
+            file,
+        )))?))
+    }
+
+    /// Opens the image file at `path` without ever writing to it
+    pub fn open_read_only(path: impl AsRef<Path>) -> Result<Self, FsError> {
+        let file = File::open(path).map_err(FsError::IoError)?;
+        let mut disk = Disk::new(Box::new(file));
+        disk.set_read_only();
+        Ok(Self::from(FileSystem::from_disk(disk)?))
+    }
+
+    /// Creates the image file `path` with `blocks` blocks and an empty file system called
+    /// `name`, replacing whatever was there
+    pub fn create(path: impl AsRef<Path>, blocks: u32, name: &str) -> Result<Self, FsError> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .map_err(FsError::IoError)?;
+        file.set_len(blocks as u64 * BLOCK_SIZE as u64)
+            .map_err(FsError::IoError)?;
+        let disk = Disk::new(Box::new(file));
+        Ok(Self::from(FileSystem::format(
+            disk,
+            blocks,
+            name,
+            &MkfsOptions::default(),
+        )?))
+    }
+
+    /// The underlying file system
+    pub fn fs(&mut self) -> &mut FileSystem {
+        &mut self.fs
+    }
+
+    pub fn into_inner(self) -> FileSystem {
+        self.fs
+    }
+
+    /// The inode number `path` resolves to
+    fn lookup(&mut self, path: PathRef<'_>) -> Result<u32, FsError> {
+        match path {
+            PathRef::Str(path) => self.fs.lookup_path(path),
+            PathRef::Id(id) => self.fs.lookup_interned(id),
+        }
+    }
+
+    /// The inode number of the directory `path` is in and the last component of `path`. The
+    /// directory of an interned path is interned as well.
+    fn split(&mut self, path: PathRef<'_>) -> Result<(u32, String), FsError> {
+        let (path, interned) = match path {
+            PathRef::Str(path) => (path.to_string(), false),
+            PathRef::Id(id) => (self.fs.interned_path(id)?.to_string(), true),
+        };
+        let path = path.trim_end_matches('/');
+        let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+        if name.is_empty() || is_dot_name(name.as_bytes()) {
+            return Err(FsError::InvalidName);
+        }
+        let dir = if interned {
+            let id = self.fs.intern_path(dir);
+            self.fs.lookup_interned(id)?
+        } else {
+            self.fs.lookup_path(dir)?
+        };
+        Ok((dir, name.to_string()))
+    }
+
+    /// The contents of the file at `path`
+    pub fn read<'a>(&mut self, path: impl Into<PathRef<'a>>) -> Result<Vec<u8>, FsError> {
+        let inode_nbr = self.lookup(path.into())?;
+        let inode = self.fs.read_inode(inode_nbr)?;
+        if inode.type_and_permission.get_type() != InodeType::File {
+            return Err(FsError::NotAFile);
+        }
+        let contents = inode.read_all(&mut self.fs)?;
+        self.fs.mark_accessed(inode_nbr)?;
+        Ok(contents)
+    }
+
+    /// Replaces the contents of the file at `path` with `data`, creating it (readable by
+    /// everyone, writable by its owner) if it doesn't exist
+    pub fn write<'a>(&mut self, path: impl Into<PathRef<'a>>, data: &[u8]) -> Result<(), FsError> {
+        let (dir, name) = self.split(path.into())?;
+        let inode_nbr = match self.fs.lookup(dir, &name) {
+            Ok(inode_nbr) => {
+                self.fs.truncate(inode_nbr, 0)?;
+                inode_nbr
+            }
+            Err(FsError::NoEntry) => self.create_inode(dir, &name, InodeType::File, 0o644)?,
+            Err(e) => return Err(e),
+        };
+        self.fs.write_at(inode_nbr, 0, data)?;
+        self.fs.close(inode_nbr)
+    }
+
+    /// Creates the empty directory `path`. Its parent has to exist.
+    pub fn create_dir<'a>(&mut self, path: impl Into<PathRef<'a>>) -> Result<(), FsError> {
+        let (dir, name) = self.split(path.into())?;
+        match self.fs.lookup(dir, &name) {
+            Ok(_) => Err(FsError::AlreadyExists),
+            Err(FsError::NoEntry) => {
+                self.create_inode(dir, &name, InodeType::Directory, 0o755)?;
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn create_inode(
+        &mut self,
+        dir: u32,
+        name: &str,
+        typ: InodeType,
+        mode: u16,
+    ) -> Result<u32, FsError> {
+        let now = self.fs.now();
+        let inode = Inode::create(
+            PermissionsAndType::new(typ, &[Permission::Other(mode)]),
+            0,
+            0,
+            now,
+            0,
+            0,
+        );
+        self.fs.create_dir_entry(dir, inode, name.to_string())
+    }
+
+    /// The entries of the directory at `path`, without `.` and `..`
+    pub fn list<'a>(&mut self, path: impl Into<PathRef<'a>>) -> Result<Vec<DirEntry>, FsError> {
+        let inode_nbr = self.lookup(path.into())?;
+        let inode = self.fs.read_inode(inode_nbr)?;
+        if inode.type_and_permission.get_type() != InodeType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        inode.read_dir_entries(&mut self.fs)
+    }
+
+    /// Removes the file or empty directory at `path`
+    pub fn remove<'a>(&mut self, path: impl Into<PathRef<'a>>) -> Result<(), FsError> {
+        let (dir, name) = self.split(path.into())?;
+        let inode_nbr = self.fs.lookup(dir, &name)?;
+        let inode = self.fs.read_inode(inode_nbr)?;
+        if inode.type_and_permission.get_type() == InodeType::Directory {
+            self.fs.rmdir(dir, &name)
+        } else {
+            self.fs.unlink(dir, &name)
+        }
+    }
+
+    /// The metadata of the inode at `path`
+    pub fn metadata<'a>(&mut self, path: impl Into<PathRef<'a>>) -> Result<Stat, FsError> {
+        let inode_nbr = self.lookup(path.into())?;
+        self.fs.stat(inode_nbr)
+    }
+
+    /// Whether there is anything at `path`
+    pub fn exists<'a>(&mut self, path: impl Into<PathRef<'a>>) -> Result<bool, FsError> {
+        match self.lookup(path.into()) {
+            Ok(_) => Ok(true),
+            Err(FsError::NoEntry) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Makes every change so far durable, see `FileSystem::sync`
+    pub fn sync(&mut self) -> Result<(), FsError> {
+        self.fs.sync()
+    }
+}
+
+impl From<FileSystem> for Sfs {
+    fn from(fs: FileSystem) -> Self {
+        Self { fs }
+    }
+}
@@ -0,0 +1,188 @@
+use std::io::{BufRead, Write};
+
+use sfs::{fs::BLOCK_SIZE, FsError, InodeType, Sfs};
+
+const HELP: &str = "\
+ls [path]               list a directory
+cd [path]               change the current directory, / without a path
+cat <path>              print a file
+put <host-file> [path]  copy a file from the host into the image
+get <path> [host-file]  copy a file from the image to the host
+rm <path>               remove a file or an empty directory
+mkdir <path>            create a directory
+stat <path>             print the metadata of an inode
+df                      print the blocks and inodes in use
+help                    print this
+exit                    leave the shell";
+
+/// The state of `sfs shell`: the image and the current directory
+struct Shell {
+    sfs: Sfs,
+    /// the components of the current directory, empty for the root
+    cwd: Vec<String>,
+}
+
+/// The single letter `ls -l` shows for `typ`
+fn type_char(typ: InodeType) -> char {
+    match typ {
+        InodeType::Directory => 'd',
+        InodeType::File => '-',
+        InodeType::FiFo => 'p',
+        InodeType::Socket => 's',
+        InodeType::CharacterDevice => 'c',
+        InodeType::BlockDevice => 'b',
+        InodeType::Symlink => 'l',
+        InodeType::Unknown(_) => '?',
+    }
+}
+
+/// The last component of a host path, for `put` and `get` without a target
+fn file_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+impl Shell {
+    /// `path` relative to the current directory as an absolute path, with `.` and `..` applied
+    fn resolve(&self, path: &str) -> String {
+        let mut components = if path.starts_with('/') {
+            Vec::new()
+        } else {
+            self.cwd.clone()
+        };
+        for component in path.split('/') {
+            match component {
+                "" | "." => {}
+                ".." => {
+                    components.pop();
+                }
+                name => components.push(name.to_string()),
+            }
+        }
+        format!("/{}", components.join("/"))
+    }
+
+    fn prompt(&self) -> String {
+        format!("sfs:/{}> ", self.cwd.join("/"))
+    }
+
+    /// Runs one command line, `Ok(false)` for `exit`
+    fn run(&mut self, line: &str) -> Result<bool, FsError> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let Some((&command, args)) = args.split_first() else {
+            return Ok(true);
+        };
+
+        match (command, args) {
+            ("ls", [] | [_]) => {
+                let path = self.resolve(args.first().unwrap_or(&"."));
+                for entry in self.sfs.list(&path)? {
+                    let stat = self.sfs.fs().stat(entry.inode)?;
+                    let target = match stat.file_type {
+                        InodeType::Symlink => {
+                            format!(" -> {}", self.sfs.fs().read_link(entry.inode)?)
+                        }
+                        _ => String::new(),
+                    };
+                    println!(
+                        "{}{:o} {:>10} {}{target}",
+                        type_char(stat.file_type),
+                        stat.permissions,
+                        stat.size,
+                        entry.get_name()
+                    );
+                }
+            }
+            ("cd", []) => self.cwd.clear(),
+            ("cd", [path]) => {
+                let path = self.resolve(path);
+                if self.sfs.metadata(&path)?.file_type != InodeType::Directory {
+                    return Err(FsError::NotADirectory);
+                }
+                self.cwd = path
+                    .split('/')
+                    .filter(|c| !c.is_empty())
+                    .map(str::to_string)
+                    .collect();
+            }
+            ("cat", [path]) => {
+                let contents = self.sfs.read(&self.resolve(path))?;
+                std::io::stdout()
+                    .write_all(&contents)
+                    .map_err(FsError::IoError)?;
+            }
+            ("put", [host] | [host, _]) => {
+                let contents = std::fs::read(host).map_err(FsError::IoError)?;
+                let path = self.resolve(args.get(1).unwrap_or(&file_name(host)));
+                self.sfs.write(&path, &contents)?;
+            }
+            ("get", [path] | [path, _]) => {
+                let contents = self.sfs.read(&self.resolve(path))?;
+                let host = args.get(1).copied().unwrap_or(file_name(path));
+                std::fs::write(host, contents).map_err(FsError::IoError)?;
+            }
+            ("rm", [path]) => self.sfs.remove(&self.resolve(path))?,
+            ("mkdir", [path]) => self.sfs.create_dir(&self.resolve(path))?,
+            ("stat", [path]) => {
+                let stat = self.sfs.metadata(&self.resolve(path))?;
+                println!("inode: {}", stat.inode);
+                println!("type: {:?}", stat.file_type);
+                println!("permissions: {:o}", stat.permissions);
+                println!("owner: {}:{}", stat.uid, stat.gid);
+                println!("size: {} ({} blocks)", stat.size, stat.blocks);
+                println!("links: {}", stat.hardlinks);
+                println!("created: {}", stat.created);
+                println!("modified: {}", stat.modified);
+                println!("accessed: {}", stat.accessed);
+            }
+            ("df", []) => {
+                let usage = self.sfs.fs().disk_usage()?;
+                let used = usage.total_blocks - usage.free_blocks;
+                println!(
+                    "blocks: {used} of {} used, {} free ({} bytes)",
+                    usage.total_blocks,
+                    usage.free_blocks,
+                    usage.free_blocks as u64 * BLOCK_SIZE as u64
+                );
+                println!("inodes: {} in use", usage.inodes);
+            }
+            ("help", []) => println!("{HELP}"),
+            ("exit" | "quit", []) => return Ok(false),
+            _ => println!("unknown command, see help"),
+        }
+        Ok(true)
+    }
+}
+
+/// `sfs shell <image>`: reads commands from stdin until `exit` or the end of the input. Errors of
+/// a command are printed and don't end the shell.
+pub fn shell(image: &str) {
+    let sfs = Sfs::open(image).unwrap_or_else(|e| {
+        eprintln!("{image}: {e}");
+        std::process::exit(1);
+    });
+    let mut shell = Shell {
+        sfs,
+        cwd: Vec::new(),
+    };
+
+    let stdin = std::io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("{}", shell.prompt());
+        let _ = std::io::stdout().flush();
+        let Some(Ok(line)) = lines.next() else {
+            println!();
+            break;
+        };
+        match shell.run(&line) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => eprintln!("{}: {e}", line.trim()),
+        }
+    }
+
+    if let Err(e) = shell.sfs.sync() {
+        eprintln!("{image}: {e}");
+        std::process::exit(1);
+    }
+}
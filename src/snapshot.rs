@@ -0,0 +1,450 @@
+//! Named snapshots of a whole image, see `FileSystem::create_snapshot`. A snapshot saves a copy
+//! of every block the file system changes in place: the superblock, the block arrays, the inode,
+//! directory, pointer and attribute blocks and the reserved areas. The data blocks of files and
+//! symbolic links are shared instead: they are frozen as long as a snapshot holds on to them, and
+//! the file system moves a file to a new block before writing to a frozen one.
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    string::String,
+    vec::Vec,
+};
+
+use crate::{
+    addr::Addr,
+    features::RO_COMPAT_SNAPSHOTS,
+    fs::{BlockArrayEntry, FileSystem, FsError, BLOCK_SIZE},
+    inode::InodeType,
+    zones::AllocZone,
+};
+
+pub const SNAPSHOT_TABLE_SIGNATURE: &[u8; 8] = b"SFs snap";
+/// The longest name of a snapshot in bytes
+pub const MAX_SNAPSHOT_NAME: usize = 32;
+/// The table starts with the signature, the number of snapshots and a reserved field
+const TABLE_HEADER_SIZE: usize = 16;
+/// Every entry is the name, the creation time, the first list block and a reserved field
+const ENTRY_SIZE: usize = 48;
+/// The most snapshots an image can have, as many as fit into the table block
+pub const MAX_SNAPSHOTS: usize = (BLOCK_SIZE - TABLE_HEADER_SIZE) / ENTRY_SIZE;
+/// Every list block starts with the next list block and the number of pairs in it
+const LIST_HEADER_SIZE: usize = 8;
+const PAIRS_PER_LIST_BLOCK: usize = (BLOCK_SIZE - LIST_HEADER_SIZE) / 8;
+
+/// A block of the image and the block holding its copy, 0 for a block shared with the file system
+type Pair = (u32, u32);
+
+/// A snapshot, see `FileSystem::list_snapshots`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotInfo {
+    pub name: String,
+    /// when it was taken, see `FileSystem::now`
+    pub created: u64,
+    /// the blocks that were copied when it was taken
+    pub saved_blocks: u32,
+    /// the data blocks it shares with the file system and other snapshots
+    pub shared_blocks: u32,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    info: SnapshotInfo,
+    /// the first block of the list of the blocks it saved and shares
+    list: u32,
+}
+
+/// The snapshots of an image and the blocks they hold on to
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Snapshots {
+    /// the oldest first
+    entries: Vec<Entry>,
+    /// how many snapshots share each frozen data block
+    frozen: BTreeMap<u32, u32>,
+    /// the table, and the list blocks and saved copies of every snapshot
+    own_blocks: BTreeSet<u32>,
+}
+
+impl Snapshots {
+    /// The snapshots in the table of `fs`, reading the list of every one of them
+    pub(crate) fn load(fs: &mut FileSystem) -> Result<Self, FsError> {
+        let table = fs.superblock.snapshot_table;
+        let mut snapshots = Self::default();
+        if !fs.superblock.has_snapshots() || table == 0 {
+            return Ok(snapshots);
+        }
+        let mut block = [0; BLOCK_SIZE];
+        fs.disk()
+            .read_exact(Addr::block(table)?.get(), &mut block)?;
+        let count = u32::from_ne_bytes(block[8..12].try_into().unwrap()) as usize;
+        if block[..8] != *SNAPSHOT_TABLE_SIGNATURE || count > MAX_SNAPSHOTS {
+            return Err(fs.corrupted());
+        }
+        snapshots.own_blocks.insert(table);
+
+        for raw in block[TABLE_HEADER_SIZE..]
+            .chunks_exact(ENTRY_SIZE)
+            .take(count)
+        {
+            let name_len = raw[..MAX_SNAPSHOT_NAME]
+                .iter()
+                .position(|&byte| byte == 0)
+                .unwrap_or(MAX_SNAPSHOT_NAME);
+            let Ok(name) = String::from_utf8(raw[..name_len].to_vec()) else {
+                return Err(fs.corrupted());
+            };
+            let created = u64::from_ne_bytes(raw[32..40].try_into().unwrap());
+            let list = u32::from_ne_bytes(raw[40..44].try_into().unwrap());
+
+            let (list_blocks, pairs) = read_list(fs, list)?;
+            let mut info = SnapshotInfo {
+                name,
+                created,
+                saved_blocks: 0,
+                shared_blocks: 0,
+            };
+            snapshots.own_blocks.extend(list_blocks);
+            for (original, copy) in pairs {
+                if copy == 0 {
+                    *snapshots.frozen.entry(original).or_default() += 1;
+                    info.shared_blocks += 1;
+                } else {
+                    snapshots.own_blocks.insert(copy);
+                    info.saved_blocks += 1;
+                }
+            }
+            snapshots.entries.push(Entry { info, list });
+        }
+        Ok(snapshots)
+    }
+
+    /// The contents of the table block
+    fn encode(&self) -> [u8; BLOCK_SIZE] {
+        let mut block = [0; BLOCK_SIZE];
+        block[..8].copy_from_slice(SNAPSHOT_TABLE_SIGNATURE);
+        block[8..12].copy_from_slice(&(self.entries.len() as u32).to_ne_bytes());
+        for (entry, raw) in self
+            .entries
+            .iter()
+            .zip(block[TABLE_HEADER_SIZE..].chunks_exact_mut(ENTRY_SIZE))
+        {
+            let name = entry.info.name.as_bytes();
+            raw[..name.len()].copy_from_slice(name);
+            raw[32..40].copy_from_slice(&entry.info.created.to_ne_bytes());
+            raw[40..44].copy_from_slice(&entry.list.to_ne_bytes());
+        }
+        block
+    }
+
+    pub(crate) fn list(&self) -> Vec<SnapshotInfo> {
+        self.entries
+            .iter()
+            .map(|entry| entry.info.clone())
+            .collect()
+    }
+
+    fn find(&self, name: &str) -> Result<usize, FsError> {
+        self.entries
+            .iter()
+            .position(|entry| entry.info.name == name)
+            .ok_or(FsError::NoEntry)
+    }
+
+    /// Whether a snapshot shares the data block `block_id`, so it can't be written in place
+    pub(crate) fn is_frozen(&self, block_id: u32) -> bool {
+        self.frozen.contains_key(&block_id)
+    }
+
+    /// Whether a snapshot keeps `block_id` allocated, whether or not an inode uses it
+    pub(crate) fn holds(&self, block_id: u32) -> bool {
+        self.is_frozen(block_id) || self.own_blocks.contains(&block_id)
+    }
+}
+
+/// The blocks of the list starting at `first` and the pairs in them
+fn read_list(fs: &mut FileSystem, first: u32) -> Result<(Vec<u32>, Vec<Pair>), FsError> {
+    let mut blocks = Vec::new();
+    let mut pairs = Vec::new();
+    let mut next = first;
+    let mut block = [0; BLOCK_SIZE];
+    while next != 0 {
+        // a loop in the list would keep going forever
+        if blocks.len() as u32 >= fs.superblock.total_blocks {
+            return Err(fs.corrupted());
+        }
+        blocks.push(next);
+        fs.disk().read_exact(Addr::block(next)?.get(), &mut block)?;
+        next = u32::from_ne_bytes(block[..4].try_into().unwrap());
+        let count = u32::from_ne_bytes(block[4..8].try_into().unwrap()) as usize;
+        if count > PAIRS_PER_LIST_BLOCK {
+            return Err(fs.corrupted());
+        }
+        for raw in block[LIST_HEADER_SIZE..].chunks_exact(8).take(count) {
+            let original = u32::from_ne_bytes(raw[..4].try_into().unwrap());
+            let copy = u32::from_ne_bytes(raw[4..].try_into().unwrap());
+            pairs.push((original, copy));
+        }
+    }
+    Ok((blocks, pairs))
+}
+
+/// Writes `pairs` to the list blocks `blocks`, which have to be enough for them
+fn write_list(fs: &mut FileSystem, blocks: &[u32], pairs: &[Pair]) -> Result<(), FsError> {
+    let mut chunks = pairs.chunks(PAIRS_PER_LIST_BLOCK);
+    for (i, &block_id) in blocks.iter().enumerate() {
+        let chunk = chunks.next().unwrap_or_default();
+        let next = blocks.get(i + 1).copied().unwrap_or(0);
+        let mut block = [0; BLOCK_SIZE];
+        block[..4].copy_from_slice(&next.to_ne_bytes());
+        block[4..8].copy_from_slice(&(chunk.len() as u32).to_ne_bytes());
+        for (&(original, copy), raw) in chunk
+            .iter()
+            .zip(block[LIST_HEADER_SIZE..].chunks_exact_mut(8))
+        {
+            raw[..4].copy_from_slice(&original.to_ne_bytes());
+            raw[4..].copy_from_slice(&copy.to_ne_bytes());
+        }
+        fs.disk()
+            .write_exact(Addr::block(block_id)?.get(), &block)?;
+    }
+    Ok(())
+}
+
+fn write_table(fs: &mut FileSystem, snapshots: &Snapshots) -> Result<(), FsError> {
+    let table = fs.superblock.snapshot_table;
+    fs.disk()
+        .write_exact(Addr::block(table)?.get(), &snapshots.encode())?;
+    Ok(())
+}
+
+/// Allocates `count` blocks, giving them back if there aren't enough
+fn allocate_blocks(fs: &mut FileSystem, count: usize) -> Result<Vec<u32>, FsError> {
+    let mut blocks = Vec::with_capacity(count);
+    for _ in 0..count {
+        match fs.allocate_block_in(AllocZone::Metadata) {
+            Ok(block_id) => blocks.push(block_id),
+            Err(e) => {
+                for block_id in blocks {
+                    fs.free_block(block_id)?;
+                }
+                return Err(e);
+            }
+        }
+    }
+    Ok(blocks)
+}
+
+/// The data blocks of every file and symbolic link in use
+fn file_data_blocks(fs: &mut FileSystem) -> Result<BTreeSet<u32>, FsError> {
+    let mut blocks = BTreeSet::new();
+    for inode_nbr in fs.inodes_in_use()? {
+        let inode = fs.read_inode(inode_nbr)?;
+        if matches!(
+            inode.type_and_permission.get_type(),
+            InodeType::File | InodeType::Symlink
+        ) {
+            blocks.extend(inode.data_blocks(fs)?.into_iter().map(|(_, block)| block));
+        }
+    }
+    Ok(blocks)
+}
+
+pub(crate) fn create(fs: &mut FileSystem, name: &str) -> Result<(), FsError> {
+    if name.is_empty() || name.contains('\0') {
+        return Err(FsError::InvalidValue);
+    }
+    if name.len() > MAX_SNAPSHOT_NAME {
+        return Err(FsError::NameTooLong);
+    }
+    let mut snapshots = fs.snapshots().clone();
+    if snapshots.find(name).is_ok() {
+        return Err(FsError::AlreadyExists);
+    }
+    if snapshots.entries.len() >= MAX_SNAPSHOTS {
+        return Err(FsError::NoSpace);
+    }
+
+    let mut shared = file_data_blocks(fs)?;
+    shared.extend(snapshots.frozen.keys());
+    let mut saved = Vec::new();
+    for block_id in 0..fs.superblock.total_blocks {
+        // the nonce table of an encrypted image always describes the blocks as they are now
+        if shared.contains(&block_id)
+            || snapshots.own_blocks.contains(&block_id)
+            || fs.crypt_area().contains(&block_id)
+        {
+            continue;
+        }
+        if block_id == 1 /* superblock */ || fs.block_type(block_id)? != BlockArrayEntry::Unused {
+            saved.push(block_id);
+        }
+    }
+
+    // everything is allocated before the block arrays and the superblock are copied, so rolling
+    // back to the snapshot keeps its own blocks allocated
+    let list_len = (saved.len() + shared.len()).div_ceil(PAIRS_PER_LIST_BLOCK);
+    let new_table = fs.superblock.snapshot_table == 0;
+    let mut blocks = allocate_blocks(fs, saved.len() + list_len + new_table as usize)?;
+    if new_table {
+        fs.superblock.snapshot_table = blocks.pop().expect("allocated above");
+    }
+    let list = blocks.split_off(saved.len());
+    fs.superblock.feature_ro_compat |= RO_COMPAT_SNAPSHOTS;
+    fs.write_superblock()?;
+
+    let mut data = [0; BLOCK_SIZE];
+    for (&original, &copy) in saved.iter().zip(&blocks) {
+        fs.disk()
+            .read_exact(Addr::block(original)?.get(), &mut data)?;
+        fs.disk().write_exact(Addr::block(copy)?.get(), &data)?;
+    }
+    let pairs: Vec<Pair> = saved
+        .into_iter()
+        .zip(blocks)
+        .chain(shared.into_iter().map(|block_id| (block_id, 0)))
+        .collect();
+    write_list(fs, &list, &pairs)?;
+
+    snapshots.entries.push(Entry {
+        info: SnapshotInfo {
+            name: name.into(),
+            created: fs.now(),
+            saved_blocks: 0,
+            shared_blocks: 0,
+        },
+        list: list[0],
+    });
+    write_table(fs, &snapshots)?;
+    fs.reload_snapshots()
+}
+
+/// Copies the saved blocks of the snapshot `name` back and drops the newer snapshots
+pub(crate) fn rollback(fs: &mut FileSystem, name: &str) -> Result<(), FsError> {
+    let mut snapshots = fs.snapshots().clone();
+    let index = snapshots.find(name)?;
+    let (_, pairs) = read_list(fs, snapshots.entries[index].list)?;
+
+    let mut data = [0; BLOCK_SIZE];
+    for &(original, copy) in &pairs {
+        if copy != 0 {
+            fs.disk().read_exact(Addr::block(copy)?.get(), &mut data)?;
+            fs.disk().write_exact(Addr::block(original)?.get(), &data)?;
+        }
+    }
+    // rereads the superblock, which points at the same table as before
+    fs.rollback()?;
+    snapshots.entries.truncate(index + 1);
+    write_table(fs, &snapshots)?;
+    fs.reload_snapshots()?;
+
+    // the block arrays say the blocks of snapshots deleted since are still allocated
+    let known: BTreeSet<u32> = pairs.into_iter().map(|(original, _)| original).collect();
+    for block_id in 0..fs.superblock.total_blocks {
+        if !known.contains(&block_id)
+            && !fs.snapshots().holds(block_id)
+            && !fs.crypt_area().contains(&block_id)
+            && fs.block_type(block_id)? == BlockArrayEntry::Allocated
+        {
+            fs.free_block(block_id)?;
+        }
+    }
+    Ok(())
+}
+
+/// Deletes the snapshot `name`, freeing its own blocks and the data blocks nothing uses anymore
+pub(crate) fn delete(fs: &mut FileSystem, name: &str) -> Result<(), FsError> {
+    let mut snapshots = fs.snapshots().clone();
+    let index = snapshots.find(name)?;
+    let entry = snapshots.entries.remove(index);
+    let (list, pairs) = read_list(fs, entry.list)?;
+
+    if snapshots.entries.is_empty() {
+        let table = fs.superblock.snapshot_table;
+        fs.superblock.snapshot_table = 0;
+        fs.superblock.feature_ro_compat &= !RO_COMPAT_SNAPSHOTS;
+        fs.write_superblock()?;
+        fs.reload_snapshots()?;
+        fs.free_block(table)?;
+    } else {
+        write_table(fs, &snapshots)?;
+        fs.reload_snapshots()?;
+    }
+
+    let mut released = Vec::new();
+    for block_id in list {
+        fs.free_block(block_id)?;
+    }
+    for (original, copy) in pairs {
+        if copy != 0 {
+            fs.free_block(copy)?;
+        } else if !fs.snapshots().is_frozen(original) {
+            released.push(original);
+        }
+    }
+    if !released.is_empty() {
+        let in_use = file_data_blocks(fs)?;
+        for block_id in released {
+            if !in_use.contains(&block_id) {
+                fs.free_block(block_id)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::{disk::Disk, fs::MountOptions, Sfs};
+
+    const BLOCKS: u32 = 400;
+
+    fn remount(fs: &mut FileSystem) -> Sfs {
+        let mut image = alloc::vec![0; Addr::block(BLOCKS).unwrap().get()];
+        fs.export_image(&mut image).unwrap();
+        let fs = FileSystem::mount(Disk::new(Box::new(image)), MountOptions::default()).unwrap();
+        Sfs::from(fs)
+    }
+
+    #[test]
+    fn snapshots_survive_remounting_and_roll_back() {
+        let mut sfs = Sfs::from(FileSystem::create(BLOCKS, "snap").unwrap());
+        sfs.create_dir("/dir").unwrap();
+        sfs.write("/dir/file", &[1; 3 * BLOCK_SIZE]).unwrap();
+        sfs.fs().create_snapshot("before").unwrap();
+
+        // overwrite the shared blocks, add and remove entries
+        let fs = sfs.fs();
+        let file = fs.lookup_path("/dir/file").unwrap();
+        fs.write_at(file, BLOCK_SIZE, &[2; BLOCK_SIZE]).unwrap();
+        sfs.write("/dir/new", b"new").unwrap();
+        sfs.remove("/dir/file").unwrap();
+        sfs.fs().create_snapshot("after").unwrap();
+
+        let mut sfs = remount(sfs.fs());
+        let names: Vec<String> = sfs
+            .fs()
+            .list_snapshots()
+            .into_iter()
+            .map(|snapshot| snapshot.name)
+            .collect();
+        assert_eq!(names, ["before", "after"]);
+        assert!(!sfs.exists("/dir/file").unwrap());
+        assert!(sfs.fs().fsck(false).unwrap().is_clean());
+
+        sfs.fs().rollback_to_snapshot("before").unwrap();
+        let mut sfs = remount(sfs.fs());
+        assert_eq!(sfs.read("/dir/file").unwrap(), [1; 3 * BLOCK_SIZE]);
+        assert!(!sfs.exists("/dir/new").unwrap());
+        assert_eq!(sfs.fs().list_snapshots().len(), 1);
+        assert!(sfs.fs().fsck(false).unwrap().is_clean());
+
+        // the data blocks only the snapshot held on to are freed with it
+        let free = sfs.fs().disk_usage().unwrap().free_blocks;
+        sfs.remove("/dir/file").unwrap();
+        sfs.fs().delete_snapshot("before").unwrap();
+        let mut sfs = remount(sfs.fs());
+        assert!(sfs.fs().list_snapshots().is_empty());
+        assert!(sfs.fs().disk_usage().unwrap().free_blocks > free + 3);
+        assert!(sfs.fs().fsck(false).unwrap().is_clean());
+    }
+}
@@ -1,23 +1,101 @@
-use std::time::{SystemTime, UNIX_EPOCH};
-
-use crate::{disk::Disk, fs::{FsError, BLOCKS_PER_BLOCKARRAY}};
-
+use crate::{
+    crc32::{bytes_of, crc32},
+    disk::Disk,
+    features::{
+        Features, INCOMPAT_DIRENT_RECLEN, INCOMPAT_DIRENT_TYPE, RO_COMPAT_DOT_ENTRIES,
+        RO_COMPAT_FILE_SIZE, RO_COMPAT_INODE_GENERATION, RO_COMPAT_INODE_VERSION,
+        RO_COMPAT_METADATA_CSUM, RO_COMPAT_QUOTA, RO_COMPAT_SNAPSHOTS, RO_COMPAT_WIDE_TIMES,
+    },
+    fs::{FsError, BLOCKS_PER_BLOCKARRAY},
+    sha256::Sha256,
+};
+use alloc::string::String;
+use core::{fmt::Display, str::FromStr};
 
 #[repr(C)]
 #[derive(Debug, Clone)]
 pub struct Superblock {
-    signature: [u8; 8],
+    pub(crate) signature: [u8; 8],
     pub earliest_free: u32,
     pub earliest_inode_space: u32,
     pub last_free: u32,
     pub total_unused: u32,
     pub total_blocks: u32,
+    // the padding is spelled out so it is always written as zeros
+    reserved0: [u8; 4],
     pub last_mount: u64,
     pub last_write: u64,
     pub name: [u8; 32],
     pub file_prealloc: u8,
     pub dir_prealloc: u8,
+    reserved1: [u8; 2],
     pub root_inode: u32,
+    /// the first block of the inode table reserved at mkfs, 0 if inodes are only allocated on demand
+    pub inode_table_start: u32,
+    pub inode_table_blocks: u32,
+    /// 1 if all inodes live in the inode table, no inode blocks are allocated on demand
+    pub fixed_inode_table: u8,
+    reserved2: [u8; 3],
+    /// see `crate::features`
+    pub feature_compat: u32,
+    pub feature_ro_compat: u32,
+    pub feature_incompat: u32,
+    /// the `DirHashAlgorithm` for directory indices, 0 on images from before it was recorded
+    pub dir_hash: u8,
+    /// see `ZoneLayout`, only used with the `alloc_zones` feature
+    pub zone_metadata_percent: u8,
+    pub zone_small_files_percent: u8,
+    reserved3: [u8; 1],
+    /// the number of blocks reserved for a bootloader right after the superblock, see
+    /// `FileSystem::boot_area`
+    pub boot_area_blocks: u32,
+    pub dir_hash_seed: [u64; 2],
+    /// the number of blocks of the journal right after the boot area, see `crate::journal`
+    pub journal_blocks: u32,
+    /// the CRC-32 of the superblock with this field set to 0, only with the `metadata_csum`
+    /// feature, see `update_checksum`
+    pub(crate) checksum: u32,
+    /// with the `sequential_inodes` feature, the lowest inode number the next inode can get, see
+    /// `MkfsOptions::sequential_inodes`
+    pub next_inode: u32,
+    /// with the `inode_generation` feature, the generation of the next inode that is created
+    pub next_generation: u32,
+    /// the number of blocks of the inode bitmap right after the journal, see `crate::inode_bitmap`
+    pub inode_bitmap_blocks: u32,
+    /// with the `inode_bitmap` feature, the number of set bits in the inode bitmap
+    pub free_inodes: u32,
+    /// with the `sealed` feature, the Ed25519 public key the image is sealed with, see
+    /// `FileSystem::seal`
+    pub seal_key: [u8; 32],
+    /// with the `sealed` feature, the Ed25519 signature of the seal
+    pub seal_signature: [u8; 64],
+    /// with the `verity` feature, the first block of the hash tree, see `crate::verity`
+    pub verity_start: u32,
+    /// the number of blocks of the hash tree, including its header
+    pub verity_blocks: u32,
+    /// with the `quota` feature, the inode holding the limits, see `crate::quota`
+    pub quota_inode: u32,
+    /// with the `snapshots` feature, the block of the snapshot table, see `crate::snapshot`
+    pub snapshot_table: u32,
+    /// with the `encryption` feature, the first block of the nonce table, see `crate::crypt`
+    pub crypt_start: u32,
+    /// the number of blocks of the nonce table
+    pub crypt_blocks: u32,
+    /// the cipher of the data blocks and of the wrapped key, see `crypt::Cipher`
+    pub crypt_cipher: u8,
+    pub reserved4: [u8; 3],
+    /// the Argon2id parameters the key encryption key is derived from the passphrase with: the
+    /// memory in KiB, the number of passes and of lanes
+    pub kdf_memory: u32,
+    pub kdf_iterations: u32,
+    pub kdf_lanes: u32,
+    pub kdf_salt: [u8; 16],
+    /// the nonce the data key is wrapped with
+    pub key_nonce: [u8; 24],
+    /// the data key, encrypted with the key encryption key, followed by its tag
+    pub wrapped_key: [u8; 48],
+    /// picked at mkfs to tell images apart, nil on images from before it was recorded
+    pub uuid: Uuid,
 }
 
 pub const SUPERBLOCK_SIGNATURE_SFS: &[u8; 8] = b"SFs sblk";
@@ -27,16 +105,67 @@ impl Superblock {
         let sblk = disk.read_struct::<Self>(addr)?;
         if sblk.signature != *SUPERBLOCK_SIGNATURE_SFS {
             Err(FsError::InvalidSignature)
+        } else if sblk.has_checksums() && sblk.checksum != sblk.compute_checksum() {
+            Err(FsError::ChecksumMismatch)
         } else {
             Ok(sblk)
         }
     }
 
+    /// Whether the superblock and the inodes carry checksums, see `update_checksum` and
+    /// `Inode::update_checksum`
+    pub fn has_checksums(&self) -> bool {
+        self.feature_ro_compat & RO_COMPAT_METADATA_CSUM != 0
+    }
+
+    /// Whether inodes carry a generation number, see `Stat::generation`
+    pub fn has_inode_generations(&self) -> bool {
+        self.feature_ro_compat & RO_COMPAT_INODE_GENERATION != 0
+    }
+
+    /// Whether inode times can be before 1970 and have nanoseconds, see `Inode::modified`
+    pub fn has_wide_times(&self) -> bool {
+        self.feature_ro_compat & RO_COMPAT_WIDE_TIMES != 0
+    }
+
+    /// Whether blocks and inodes are counted against per-user and per-group limits, see
+    /// `FileSystem::set_quota`
+    pub fn has_quotas(&self) -> bool {
+        self.feature_ro_compat & RO_COMPAT_QUOTA != 0
+    }
+
+    /// Whether the image has snapshots holding on to its blocks, see
+    /// `FileSystem::create_snapshot`
+    pub fn has_snapshots(&self) -> bool {
+        self.feature_ro_compat & RO_COMPAT_SNAPSHOTS != 0
+    }
+
+    fn compute_checksum(&self) -> u32 {
+        let mut copy = self.clone();
+        copy.checksum = 0;
+        crc32(bytes_of(&copy))
+    }
+
+    /// Updates the checksum after a change, before the superblock is written
+    pub(crate) fn update_checksum(&mut self) {
+        if self.has_checksums() {
+            self.checksum = self.compute_checksum();
+        }
+    }
+
+    pub fn features(&self) -> Features {
+        Features {
+            compat: self.feature_compat,
+            ro_compat: self.feature_ro_compat,
+            incompat: self.feature_incompat,
+        }
+    }
+
     pub fn total_used(&self) -> u32 {
         self.total_blocks - self.total_unused
     }
 
-    pub fn get_name<'a>(&'a self) -> String {
+    pub fn get_name(&self) -> String {
         let mut str = String::with_capacity(32);
 
         for i in 0..32 {
@@ -50,7 +179,8 @@ impl Superblock {
         str
     }
 
-    pub fn new(name: &str, num_blocks: u32) -> Result<Self, FsError> {
+    /// A superblock for a new file system, `now` being the mkfs time in UNIX-Time
+    pub fn new(name: &str, num_blocks: u32, now: u64) -> Result<Self, FsError> {
         let mut name_slice = [0_u8; 32];
         for (i, byte) in name.bytes().enumerate() {
             if i >= 32 {
@@ -67,17 +197,128 @@ impl Superblock {
             last_free: num_blocks - 1,
             earliest_free: 2,
             earliest_inode_space: 0,
-            last_mount: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .expect("Time went backwards ftw")
-                .as_secs(),
-            last_write: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .expect("Time went backwards ftw")
-                .as_secs(),
+            last_mount: now,
+            last_write: now,
             total_blocks: num_blocks,
             total_unused: num_blocks - 1 - num_blocks.div_ceil(BLOCKS_PER_BLOCKARRAY),
             root_inode: 0, // the FileSystem::new(...) handles this
+            inode_table_start: 0,
+            inode_table_blocks: 0,
+            fixed_inode_table: 0,
+            reserved0: [0; 4],
+            reserved1: [0; 2],
+            reserved2: [0; 3],
+            feature_compat: 0,
+            feature_ro_compat: RO_COMPAT_INODE_VERSION
+                | RO_COMPAT_FILE_SIZE
+                | RO_COMPAT_DOT_ENTRIES
+                | RO_COMPAT_METADATA_CSUM
+                | RO_COMPAT_INODE_GENERATION
+                | RO_COMPAT_WIDE_TIMES,
+            feature_incompat: INCOMPAT_DIRENT_TYPE | INCOMPAT_DIRENT_RECLEN,
+            dir_hash: 0,
+            zone_metadata_percent: 0,
+            zone_small_files_percent: 0,
+            reserved3: [0; 1],
+            boot_area_blocks: 0,
+            dir_hash_seed: [0; 2],
+            journal_blocks: 0,
+            checksum: 0,
+            next_inode: 0,
+            next_generation: 1,
+            inode_bitmap_blocks: 0,
+            free_inodes: 0,
+            seal_key: [0; 32],
+            seal_signature: [0; 64],
+            verity_start: 0,
+            verity_blocks: 0,
+            quota_inode: 0,
+            snapshot_table: 0,
+            crypt_start: 0,
+            crypt_blocks: 0,
+            crypt_cipher: 0,
+            reserved4: [0; 3],
+            kdf_memory: 0,
+            kdf_iterations: 0,
+            kdf_lanes: 0,
+            kdf_salt: [0; 16],
+            key_nonce: [0; 24],
+            wrapped_key: [0; 48],
+            uuid: Uuid::NIL,
         })
     }
-}
\ No newline at end of file
+}
+
+/// The identifier of an image, an RFC 4122 version 4 UUID. Written and parsed in the usual
+/// `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx` form.
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Uuid(pub [u8; 16]);
+
+impl Uuid {
+    /// The all-zero UUID of images from before UUIDs were recorded
+    pub const NIL: Self = Self([0; 16]);
+
+    /// Sets the version and variant bits, the rest of `bytes` is kept
+    pub fn from_random_bytes(mut bytes: [u8; 16]) -> Self {
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        Self(bytes)
+    }
+
+    /// A fresh random UUID, from the same source as `dirhash::random_seed`
+    #[cfg(feature = "std")]
+    pub fn random() -> Self {
+        let seed = crate::dirhash::random_seed();
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&seed[0].to_le_bytes());
+        bytes[8..].copy_from_slice(&seed[1].to_le_bytes());
+        Self::from_random_bytes(bytes)
+    }
+
+    /// The UUID for an image named `name` created at `time`, for reproducible images and for
+    /// targets without a source of randomness
+    pub fn derive(time: u64, name: &str) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(b"sfs uuid");
+        hasher.update(&time.to_le_bytes());
+        hasher.update(name.as_bytes());
+        let mut bytes = [0; 16];
+        bytes.copy_from_slice(&hasher.finalize()[..16]);
+        Self::from_random_bytes(bytes)
+    }
+
+    pub fn is_nil(&self) -> bool {
+        *self == Self::NIL
+    }
+}
+
+impl Display for Uuid {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if matches!(i, 4 | 6 | 8 | 10) {
+                f.write_str("-")?;
+            }
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Uuid {
+    type Err = FsError;
+
+    /// Parses the hyphenated form, or the 32 hex digits without hyphens
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits: String = s.chars().filter(|&c| c != '-').collect();
+        if digits.len() != 32 || !digits.is_ascii() {
+            return Err(FsError::InvalidValue);
+        }
+        let mut bytes = [0; 16];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&digits[i * 2..i * 2 + 2], 16)
+                .map_err(|_| FsError::InvalidValue)?;
+        }
+        Ok(Self(bytes))
+    }
+}
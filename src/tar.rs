@@ -0,0 +1,657 @@
+use std::{
+    collections::BTreeMap,
+    io::{ErrorKind, Read, Write},
+};
+
+use crate::{
+    directory::DirectoryIterator,
+    fs::{FileSystem, FsError, SetAttr},
+    inode::{Inode, InodeType, Permission, PermissionsAndType, PERMISSION_MASK},
+    time::Timestamp,
+};
+
+/// tar streams are made of 512-byte records
+const RECORD: usize = 512;
+
+/// The largest time the 11 octal digits of the ustar mtime field hold, later ones, times before
+/// 1970 and fractions of a second go into a pax `mtime` record
+const MAX_USTAR_TIME: i64 = 0o77777777777;
+
+/// Where a pax header keeps the extended attributes of an entry, like GNU tar and bsdtar
+const PAX_XATTR: &str = "SCHILY.xattr.";
+
+/// What `FileSystem::import_tar` or `FileSystem::export_tar` did
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TarStats {
+    /// entries created or written, including hard links
+    pub entries: u32,
+    /// the bytes of file contents copied
+    pub bytes: u64,
+    /// the paths of entries the other side can't hold: sockets when exporting, tar entry types
+    /// sfs doesn't know when importing
+    pub skipped: Vec<String>,
+}
+
+fn invalid() -> FsError {
+    FsError::InvalidValue
+}
+
+/// The components of a path in a tar stream, with `.` dropped and `..` applied, so nothing can
+/// end up outside of the root
+fn components(path: &str) -> Vec<&str> {
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            name => components.push(name),
+        }
+    }
+    components
+}
+
+/// The device ID sfs keeps in `meta` for a device number, like Linux' internal `dev_t`
+fn device_id(major: u32, minor: u32) -> u32 {
+    (major << 20) | (minor & 0xfffff)
+}
+
+fn device_numbers(id: u32) -> (u32, u32) {
+    (id >> 20, id & 0xfffff)
+}
+
+/// A numeric header field: octal digits ended by a space or NUL, or a big-endian number with the
+/// top bit set (the GNU extension for numbers that don't fit)
+fn parse_number(field: &[u8]) -> u64 {
+    if field.first().is_some_and(|byte| byte & 0x80 != 0) {
+        return field[1..]
+            .iter()
+            .fold(0, |value, byte| (value << 8) | *byte as u64);
+    }
+    let digits = field
+        .iter()
+        .skip_while(|byte| **byte == b' ')
+        .take_while(|byte| (b'0'..=b'7').contains(*byte));
+    digits.fold(0, |value, byte| (value << 3) | (byte - b'0') as u64)
+}
+
+/// A text header field, ending at the first NUL
+fn parse_text(field: &[u8]) -> Result<String, FsError> {
+    let len = field
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(field.len());
+    String::from_utf8(field[..len].to_vec()).map_err(|_| invalid())
+}
+
+/// The records of a pax extended header, `<length> <key>=<value>\n` each
+fn parse_pax(data: &[u8]) -> Result<BTreeMap<String, Vec<u8>>, FsError> {
+    let mut records = BTreeMap::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let space = rest
+            .iter()
+            .position(|byte| *byte == b' ')
+            .ok_or_else(invalid)?;
+        let len: usize = std::str::from_utf8(&rest[..space])
+            .ok()
+            .and_then(|len| len.parse().ok())
+            .filter(|len| *len > space && *len <= rest.len())
+            .ok_or_else(invalid)?;
+        let record = rest[space + 1..len]
+            .strip_suffix(b"\n")
+            .ok_or_else(invalid)?;
+        let equals = record
+            .iter()
+            .position(|byte| *byte == b'=')
+            .ok_or_else(invalid)?;
+        let key = String::from_utf8(record[..equals].to_vec()).map_err(|_| invalid())?;
+        records.insert(key, record[equals + 1..].to_vec());
+        rest = &rest[len..];
+    }
+    Ok(records)
+}
+
+fn pax_record(key: &str, value: &[u8]) -> Vec<u8> {
+    // the length counts its own digits
+    let base = key.len() + value.len() + 3;
+    let mut len = base + 1;
+    while base + len.to_string().len() != len {
+        len += 1;
+    }
+    let mut record = format!("{len} {key}=").into_bytes();
+    record.extend_from_slice(value);
+    record.push(b'\n');
+    record
+}
+
+fn read_record(reader: &mut dyn Read, record: &mut [u8; RECORD]) -> Result<bool, FsError> {
+    let mut filled = 0;
+    while filled < RECORD {
+        match reader.read(&mut record[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(FsError::IoError(ErrorKind::UnexpectedEof.into())),
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(FsError::IoError(e)),
+        }
+    }
+    Ok(true)
+}
+
+/// Reads the `size` bytes of data after a header and the padding after them
+fn read_data(reader: &mut dyn Read, size: u64) -> Result<Vec<u8>, FsError> {
+    let mut data = vec![0; size.next_multiple_of(RECORD as u64) as usize];
+    reader.read_exact(&mut data).map_err(FsError::IoError)?;
+    data.truncate(size as usize);
+    Ok(data)
+}
+
+fn skip_padding(reader: &mut dyn Read, size: u64) -> Result<(), FsError> {
+    let mut padding = [0; RECORD];
+    let len = (size.next_multiple_of(RECORD as u64) - size) as usize;
+    reader
+        .read_exact(&mut padding[..len])
+        .map_err(FsError::IoError)
+}
+
+/// The longest end of `text` that is at most `max` bytes long
+fn tail(text: &str, max: usize) -> &str {
+    let mut start = text.len().saturating_sub(max);
+    while !text.is_char_boundary(start) {
+        start += 1;
+    }
+    &text[start..]
+}
+
+/// The longest start of `text` that is at most `max` bytes long
+fn head(text: &str, max: usize) -> &str {
+    let mut end = text.len().min(max);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+/// One entry of a tar stream, with the pax and GNU long name headers before it applied
+struct Entry {
+    path: String,
+    typeflag: u8,
+    mode: u16,
+    uid: u16,
+    gid: u16,
+    size: u64,
+    mtime: Timestamp,
+    /// from the pax `atime` record, the modification time without one
+    atime: Timestamp,
+    link: String,
+    device: u32,
+    xattrs: Vec<(String, Vec<u8>)>,
+}
+
+/// Builds an image from a tar stream, see `FileSystem::import_tar`
+pub(crate) struct TarImport<'a> {
+    fs: &'a mut FileSystem,
+    /// the modification times of the directories, set at the end since every entry created in
+    /// them changes it
+    dir_times: Vec<(u32, Timestamp)>,
+    stats: TarStats,
+}
+
+impl<'a> TarImport<'a> {
+    pub(crate) fn new(fs: &'a mut FileSystem) -> Self {
+        Self {
+            fs,
+            dir_times: Vec::new(),
+            stats: TarStats::default(),
+        }
+    }
+
+    pub(crate) fn run(mut self, reader: &mut dyn Read) -> Result<TarStats, FsError> {
+        while let Some(entry) = self.next_entry(reader)? {
+            self.import(entry, reader)?;
+        }
+        for (dir_nbr, mtime) in std::mem::take(&mut self.dir_times) {
+            self.fs.set_attr(
+                dir_nbr,
+                &SetAttr {
+                    modified: Some(mtime),
+                    ..Default::default()
+                },
+            )?;
+        }
+        Ok(self.stats)
+    }
+
+    /// The next entry, `None` at the end of the archive
+    fn next_entry(&mut self, reader: &mut dyn Read) -> Result<Option<Entry>, FsError> {
+        let mut pax = BTreeMap::new();
+        let mut long_name = None;
+        let mut long_link = None;
+        let mut header = [0; RECORD];
+        loop {
+            if !read_record(reader, &mut header)? || header.iter().all(|byte| *byte == 0) {
+                return Ok(None);
+            }
+            let checksum = parse_number(&header[148..156]);
+            let sum: u64 = header
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| if (148..156).contains(&i) { b' ' } else { *byte } as u64)
+                .sum();
+            if checksum != sum {
+                return Err(invalid());
+            }
+
+            let size = parse_number(&header[124..136]);
+            match header[156] {
+                b'x' => pax.extend(parse_pax(&read_data(reader, size)?)?),
+                // global pax headers apply to every entry after them, none of their keys matter
+                b'g' => {
+                    read_data(reader, size)?;
+                }
+                b'L' => long_name = Some(parse_text(&read_data(reader, size)?)?),
+                b'K' => long_link = Some(parse_text(&read_data(reader, size)?)?),
+                typeflag => {
+                    let mut path = parse_text(&header[0..100])?;
+                    if &header[257..262] == b"ustar" {
+                        let prefix = parse_text(&header[345..500])?;
+                        if !prefix.is_empty() {
+                            path = format!("{prefix}/{path}");
+                        }
+                    }
+                    let text = |key: &str| -> Result<Option<String>, FsError> {
+                        pax.get(key)
+                            .map(|value| String::from_utf8(value.clone()).map_err(|_| invalid()))
+                            .transpose()
+                    };
+                    let number = |key: &str, default: u64| -> Result<u64, FsError> {
+                        text(key)?.map_or(Ok(default), |value| value.parse().map_err(|_| invalid()))
+                    };
+                    // pax times can be negative and have a fraction
+                    let time = |key: &str, default: Timestamp| -> Result<Timestamp, FsError> {
+                        text(key)?.map_or(Ok(default), |value| value.parse().map_err(|_| invalid()))
+                    };
+                    let id = |value: u64| u16::try_from(value).map_err(|_| invalid());
+
+                    let major = parse_number(&header[329..337]) as u32;
+                    let minor = parse_number(&header[337..345]) as u32;
+                    let mtime = time("mtime", parse_number(&header[136..148]).into())?;
+                    return Ok(Some(Entry {
+                        path: text("path")?.or(long_name).unwrap_or(path),
+                        typeflag,
+                        mode: parse_number(&header[100..108]) as u16 & PERMISSION_MASK,
+                        uid: id(number("uid", parse_number(&header[108..116]))?)?,
+                        gid: id(number("gid", parse_number(&header[116..124]))?)?,
+                        size: number("size", size)?,
+                        mtime,
+                        atime: time("atime", mtime)?,
+                        link: match text("linkpath")?.or(long_link) {
+                            Some(link) => link,
+                            None => parse_text(&header[157..257])?,
+                        },
+                        device: device_id(major, minor),
+                        xattrs: pax
+                            .iter()
+                            .filter_map(|(key, value)| {
+                                let name = key.strip_prefix(PAX_XATTR)?;
+                                Some((name.to_string(), value.clone()))
+                            })
+                            .collect(),
+                    }));
+                }
+            }
+        }
+    }
+
+    fn import(&mut self, entry: Entry, reader: &mut dyn Read) -> Result<(), FsError> {
+        let path = components(&entry.path);
+        let typ = match entry.typeflag {
+            b'0' | b'\0' | b'7' => InodeType::File,
+            b'1' => InodeType::File,
+            b'2' => InodeType::Symlink,
+            b'3' => InodeType::CharacterDevice,
+            b'4' => InodeType::BlockDevice,
+            b'5' => InodeType::Directory,
+            b'6' => InodeType::FiFo,
+            _ => {
+                self.stats.skipped.push(entry.path);
+                return read_data(reader, entry.size).map(drop);
+            }
+        };
+        let Some((name, dirs)) = path.split_last() else {
+            // the root directory itself, like `./`
+            if typ == InodeType::Directory {
+                let root = self.fs.superblock.root_inode;
+                self.set_attrs(root, &entry)?;
+                self.dir_times.push((root, entry.mtime));
+            }
+            return read_data(reader, entry.size).map(drop);
+        };
+
+        let mut dir_nbr = self.fs.superblock.root_inode;
+        for dir in dirs {
+            dir_nbr = match self.fs.lookup(dir_nbr, dir) {
+                Ok(child_nbr) => child_nbr,
+                Err(FsError::NoEntry) => {
+                    self.create(dir_nbr, dir, InodeType::Directory, 0o755, &entry)?
+                }
+                Err(e) => return Err(e),
+            };
+        }
+
+        // like tar, later entries replace earlier ones, but directories are merged
+        match self.fs.lookup(dir_nbr, name) {
+            Ok(existing)
+                if typ == InodeType::Directory && self.fs.stat(existing)?.file_type == typ =>
+            {
+                self.set_attrs(existing, &entry)?;
+                self.dir_times.push((existing, entry.mtime));
+                self.stats.entries += 1;
+                return Ok(());
+            }
+            Ok(existing) if self.fs.stat(existing)?.file_type == InodeType::Directory => {
+                self.fs.rmdir(dir_nbr, name)?;
+            }
+            Ok(_) => self.fs.unlink(dir_nbr, name)?,
+            Err(FsError::NoEntry) => {}
+            Err(e) => return Err(e),
+        }
+
+        let inode_nbr = match entry.typeflag {
+            b'1' => {
+                let target = self.fs.lookup_path(&components(&entry.link).join("/"))?;
+                self.fs.link(target, dir_nbr, name)?;
+                self.stats.entries += 1;
+                return Ok(());
+            }
+            b'2' => self.fs.symlink(dir_nbr, name.to_string(), &entry.link)?,
+            _ => self.create(dir_nbr, name, typ, entry.mode, &entry)?,
+        };
+
+        match typ {
+            InodeType::File => {
+                let mut inode = self.fs.read_inode(inode_nbr)?;
+                let mut data = reader.take(entry.size);
+                let written = inode.write_from_reader(&mut data, self.fs, inode_nbr)?;
+                if written as u64 != entry.size {
+                    return Err(FsError::IoError(ErrorKind::UnexpectedEof.into()));
+                }
+                skip_padding(reader, entry.size)?;
+                self.fs.close(inode_nbr)?;
+                self.stats.bytes += entry.size;
+            }
+            // only regular files have data in a tar stream
+            _ => read_data(reader, entry.size).map(drop)?,
+        }
+
+        self.set_attrs(inode_nbr, &entry)?;
+        if typ == InodeType::Directory {
+            self.dir_times.push((inode_nbr, entry.mtime));
+        }
+        self.stats.entries += 1;
+        Ok(())
+    }
+
+    fn create(
+        &mut self,
+        dir_nbr: u32,
+        name: &str,
+        typ: InodeType,
+        mode: u16,
+        entry: &Entry,
+    ) -> Result<u32, FsError> {
+        let meta = match typ {
+            InodeType::CharacterDevice | InodeType::BlockDevice => entry.device,
+            _ => 0,
+        };
+        let mut inode = Inode::create(
+            PermissionsAndType::new(typ, &[Permission::Other(mode)]),
+            entry.uid,
+            entry.gid,
+            0,
+            0,
+            meta,
+        );
+        // archives don't record when a file was created
+        inode.creation_time = entry.mtime.secs;
+        self.fs.create_dir_entry(dir_nbr, inode, name.to_string())
+    }
+
+    /// Sets the owner, permissions, modification and access time and extended attributes of the
+    /// entry
+    fn set_attrs(&mut self, inode_nbr: u32, entry: &Entry) -> Result<(), FsError> {
+        for (name, value) in &entry.xattrs {
+            self.fs.set_xattr(inode_nbr, name, value)?;
+        }
+        let is_symlink = entry.typeflag == b'2';
+        self.fs.set_attr(
+            inode_nbr,
+            &SetAttr {
+                permissions: (!is_symlink).then_some(entry.mode),
+                uid: Some(entry.uid),
+                gid: Some(entry.gid),
+                modified: Some(entry.mtime),
+                accessed: Some(entry.atime),
+            },
+        )?;
+        Ok(())
+    }
+}
+
+/// Writes an image as a tar stream, see `FileSystem::export_tar`
+pub(crate) struct TarExport<'a> {
+    fs: &'a mut FileSystem,
+    writer: &'a mut dyn Write,
+    /// the paths inodes with more than one link were written at first
+    linked: BTreeMap<u32, String>,
+    stats: TarStats,
+}
+
+impl<'a> TarExport<'a> {
+    pub(crate) fn new(fs: &'a mut FileSystem, writer: &'a mut dyn Write) -> Self {
+        Self {
+            fs,
+            writer,
+            linked: BTreeMap::new(),
+            stats: TarStats::default(),
+        }
+    }
+
+    pub(crate) fn run(mut self) -> Result<TarStats, FsError> {
+        let root = self.fs.superblock.root_inode;
+        self.export_dir(root, "")?;
+        // the end of the archive
+        self.write(&[0; RECORD * 2])?;
+        self.writer.flush().map_err(FsError::IoError)?;
+        Ok(self.stats)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), FsError> {
+        self.writer.write_all(data).map_err(FsError::IoError)
+    }
+
+    fn pad(&mut self, len: u64) -> Result<(), FsError> {
+        let padding = len.next_multiple_of(RECORD as u64) - len;
+        self.write(&vec![0; padding as usize])
+    }
+
+    fn export_dir(&mut self, dir_nbr: u32, path: &str) -> Result<(), FsError> {
+        let dir = self.fs.read_inode(dir_nbr)?;
+        let children: Vec<_> = DirectoryIterator::new(dir, self.fs)
+            .map(|entry| (entry.get_name(), entry.inode))
+            .collect();
+        for (name, inode_nbr) in children {
+            self.export(inode_nbr, &format!("{path}{name}"))?;
+        }
+        Ok(())
+    }
+
+    fn export(&mut self, inode_nbr: u32, path: &str) -> Result<(), FsError> {
+        let inode = self.fs.read_inode(inode_nbr)?;
+        let typ = inode.type_and_permission.get_type();
+        let mut link = String::new();
+        let mut size = 0;
+        let typeflag = match typ {
+            _ if self.linked.contains_key(&inode_nbr) => {
+                link = self.linked[&inode_nbr].clone();
+                b'1'
+            }
+            InodeType::File => {
+                size = inode.size();
+                b'0'
+            }
+            InodeType::Symlink => {
+                link = self.fs.read_link(inode_nbr)?;
+                b'2'
+            }
+            InodeType::CharacterDevice => b'3',
+            InodeType::BlockDevice => b'4',
+            InodeType::Directory => b'5',
+            InodeType::FiFo => b'6',
+            InodeType::Socket | InodeType::Unknown(_) => {
+                self.stats.skipped.push(path.to_string());
+                return Ok(());
+            }
+        };
+        if typ != InodeType::Directory && inode.hardlinks > 1 {
+            self.linked
+                .entry(inode_nbr)
+                .or_insert_with(|| path.to_string());
+        }
+        let name = match typ {
+            InodeType::Directory => format!("{path}/"),
+            _ => path.to_string(),
+        };
+
+        let mut pax = Vec::new();
+        if typeflag != b'1' {
+            for attr in self.fs.list_xattr(inode_nbr)? {
+                let value = self.fs.get_xattr(inode_nbr, &attr)?;
+                pax.extend(pax_record(&format!("{PAX_XATTR}{attr}"), &value));
+            }
+        }
+
+        let mut header = [0; RECORD];
+        let (prefix, short_name) = match split_name(&name) {
+            Some(split) => split,
+            None => {
+                pax.extend(pax_record("path", name.as_bytes()));
+                ("", tail(&name, 100))
+            }
+        };
+        if link.len() > 100 {
+            pax.extend(pax_record("linkpath", link.as_bytes()));
+        }
+        let (mtime, atime) = (inode.modified(), inode.accessed());
+        if mtime.nanos != 0 || !(0..=MAX_USTAR_TIME).contains(&mtime.secs) {
+            pax.extend(pax_record("mtime", mtime.to_string().as_bytes()));
+        }
+        if atime != mtime {
+            pax.extend(pax_record("atime", atime.to_string().as_bytes()));
+        }
+        if !pax.is_empty() {
+            let mut pax_header = [0; RECORD];
+            let pax_name = format!("PaxHeaders/{}", tail(short_name, 89));
+            fill_header(
+                &mut pax_header,
+                &pax_name,
+                "",
+                &inode,
+                pax.len() as u64,
+                b'x',
+                "",
+            );
+            self.write(&pax_header)?;
+            self.write(&pax)?;
+            self.pad(pax.len() as u64)?;
+        }
+
+        fill_header(
+            &mut header,
+            short_name,
+            prefix,
+            &inode,
+            size,
+            typeflag,
+            head(&link, 100),
+        );
+        if matches!(typ, InodeType::CharacterDevice | InodeType::BlockDevice) {
+            let (major, minor) = device_numbers(inode.meta);
+            write_number(&mut header[329..337], major as u64);
+            write_number(&mut header[337..345], minor as u64);
+            write_checksum(&mut header);
+        }
+        self.write(&header)?;
+        self.stats.entries += 1;
+
+        if typeflag == b'0' {
+            let written = inode.read_to_writer(self.writer, self.fs, None)? as u64;
+            self.pad(written)?;
+            self.stats.bytes += written;
+        }
+        if typeflag == b'5' {
+            self.export_dir(inode_nbr, &name)?;
+        }
+        Ok(())
+    }
+}
+
+/// `name` as the prefix and name fields of a ustar header, `None` if it doesn't fit
+fn split_name(name: &str) -> Option<(&str, &str)> {
+    if name.len() <= 100 {
+        return Some(("", name));
+    }
+    // the split is at a `/`, the one ending a directory name doesn't count
+    let trimmed = name.strip_suffix('/').unwrap_or(name);
+    let split = trimmed
+        .match_indices('/')
+        .map(|(i, _)| i)
+        .find(|i| name.len() - i - 1 <= 100)?;
+    (split <= 155).then(|| (&name[..split], &name[split + 1..]))
+}
+
+/// An octal number filling `field`, with a NUL at the end
+fn write_number(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    let text = format!("{value:0digits$o}");
+    field[..digits].copy_from_slice(&text.as_bytes()[text.len() - digits..]);
+    field[digits] = 0;
+}
+
+fn write_checksum(header: &mut [u8; RECORD]) {
+    header[148..156].fill(b' ');
+    let sum: u64 = header.iter().map(|byte| *byte as u64).sum();
+    write_number(&mut header[148..155], sum);
+    header[155] = b' ';
+}
+
+fn fill_header(
+    header: &mut [u8; RECORD],
+    name: &str,
+    prefix: &str,
+    inode: &Inode,
+    size: u64,
+    typeflag: u8,
+    link: &str,
+) {
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    write_number(
+        &mut header[100..108],
+        (inode.type_and_permission.get_raw() & PERMISSION_MASK) as u64,
+    );
+    write_number(&mut header[108..116], inode.uid as u64);
+    write_number(&mut header[116..124], inode.gid as u64);
+    write_number(&mut header[124..136], size);
+    write_number(
+        &mut header[136..148],
+        inode.modification_time.clamp(0, MAX_USTAR_TIME) as u64,
+    );
+    header[156] = typeflag;
+    header[157..157 + link.len()].copy_from_slice(link.as_bytes());
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+    write_checksum(header);
+}
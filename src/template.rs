@@ -0,0 +1,179 @@
+use alloc::{
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt::Display;
+
+use crate::{
+    fs::FsError,
+    hints::{StorageHints, STORAGE_HINTS_XATTR},
+    inode::{Inode, InodeType, PERMISSION_MASK},
+};
+
+/// The extended attribute holding the owner and permissions of a `DirTemplate`, like
+/// `uid=0,gid=0,mode=644,dirmode=755`
+pub const TEMPLATE_XATTR: &str = "sfs.template";
+/// The extended attributes of a `DirTemplate` are stored with this prefix on the directory, e.g.
+/// `sfs.template.user.origin` for `user.origin`
+pub const TEMPLATE_XATTR_PREFIX: &str = "sfs.template.";
+
+/// Metadata a directory gives every entry created in it, see `FileSystem::set_dir_template`.
+/// `None` keeps what the creator asked for.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DirTemplate {
+    pub uid: Option<u16>,
+    pub gid: Option<u16>,
+    /// the permissions of new files, fifos, sockets and devices
+    pub mode: Option<u16>,
+    /// the permissions of new directories
+    pub dir_mode: Option<u16>,
+    /// extended attributes set on every new entry. `STORAGE_HINTS_XATTR` sets the storage hints.
+    pub xattrs: BTreeMap<String, Vec<u8>>,
+}
+
+impl DirTemplate {
+    /// Parses the value of `TEMPLATE_XATTR`: a comma separated list of `uid=<n>`, `gid=<n>`,
+    /// `mode=<octal>` and `dirmode=<octal>`. Unknown keys, repeated keys and values that don't
+    /// fit are `FsError::InvalidValue`.
+    pub fn parse(value: &str) -> Result<Self, FsError> {
+        let mut template = Self::default();
+        for item in value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+        {
+            let (key, value) = item.split_once('=').ok_or(FsError::InvalidValue)?;
+            let (field, radix, max) = match key.trim() {
+                "uid" => (&mut template.uid, 10, u16::MAX),
+                "gid" => (&mut template.gid, 10, u16::MAX),
+                "mode" => (&mut template.mode, 8, PERMISSION_MASK),
+                "dirmode" => (&mut template.dir_mode, 8, PERMISSION_MASK),
+                _ => return Err(FsError::InvalidValue),
+            };
+            let value = u16::from_str_radix(value.trim(), radix)
+                .ok()
+                .filter(|value| *value <= max)
+                .ok_or(FsError::InvalidValue)?;
+            if field.replace(value).is_some() {
+                return Err(FsError::InvalidValue);
+            }
+        }
+        Ok(template)
+    }
+
+    /// Whether the template doesn't change anything
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// The template stored in the extended attributes `xattrs` of a directory
+    pub(crate) fn from_xattrs(xattrs: &BTreeMap<String, Vec<u8>>) -> Result<Self, FsError> {
+        let mut template = match xattrs.get(TEMPLATE_XATTR) {
+            Some(value) => {
+                Self::parse(core::str::from_utf8(value).map_err(|_| FsError::InvalidValue)?)?
+            }
+            None => Self::default(),
+        };
+        template.xattrs = xattrs
+            .iter()
+            .filter_map(|(name, value)| {
+                let name = name.strip_prefix(TEMPLATE_XATTR_PREFIX)?;
+                Some((name.to_string(), value.clone()))
+            })
+            .collect();
+        Ok(template)
+    }
+
+    /// The extended attributes storing the template on a directory
+    pub(crate) fn to_xattrs(&self) -> BTreeMap<String, Vec<u8>> {
+        let mut xattrs: BTreeMap<String, Vec<u8>> = self
+            .xattrs
+            .iter()
+            .map(|(name, value)| (format!("{TEMPLATE_XATTR_PREFIX}{name}"), value.clone()))
+            .collect();
+        let owner_and_mode = self.to_string();
+        if !owner_and_mode.is_empty() {
+            xattrs.insert(TEMPLATE_XATTR.to_string(), owner_and_mode.into_bytes());
+        }
+        xattrs
+    }
+
+    /// Whether `name` is one of the extended attributes a template is stored in
+    pub(crate) fn is_template_xattr(name: &str) -> bool {
+        name == TEMPLATE_XATTR || name.starts_with(TEMPLATE_XATTR_PREFIX)
+    }
+
+    /// Checks what `FileSystem::set_dir_template` can't store or `apply` can't apply
+    pub(crate) fn check(&self) -> Result<(), FsError> {
+        if self.mode.is_some_and(|mode| mode & !PERMISSION_MASK != 0)
+            || self
+                .dir_mode
+                .is_some_and(|mode| mode & !PERMISSION_MASK != 0)
+        {
+            return Err(FsError::InvalidValue);
+        }
+        if let Some(hints) = self.xattrs.get(STORAGE_HINTS_XATTR) {
+            StorageHints::parse(core::str::from_utf8(hints).map_err(|_| FsError::InvalidValue)?)?;
+        }
+        Ok(())
+    }
+
+    /// The extended attributes a new entry gets, including the template itself for directories
+    pub(crate) fn child_xattrs(&self, is_dir: bool) -> BTreeMap<String, Vec<u8>> {
+        let mut xattrs = self.xattrs.clone();
+        // kept in the inode flags, see `apply`
+        xattrs.remove(STORAGE_HINTS_XATTR);
+        if is_dir {
+            xattrs.extend(self.to_xattrs());
+        }
+        xattrs
+    }
+
+    /// Gives the new inode `inode` the owner, permissions and storage hints of the template
+    pub(crate) fn apply(&self, inode: &mut Inode) {
+        let hints = self
+            .xattrs
+            .get(STORAGE_HINTS_XATTR)
+            .and_then(|hints| StorageHints::parse(core::str::from_utf8(hints).ok()?).ok());
+        if let Some(hints) = hints {
+            inode.flags = (inode.flags & !StorageHints::FLAGS) | hints.to_flags();
+        }
+        if let Some(uid) = self.uid {
+            inode.uid = uid;
+        }
+        if let Some(gid) = self.gid {
+            inode.gid = gid;
+        }
+        let mode = match inode.type_and_permission.get_type() {
+            InodeType::Directory => self.dir_mode,
+            // the permissions of a symbolic link don't mean anything
+            InodeType::Symlink => None,
+            _ => self.mode,
+        };
+        if let Some(mode) = mode {
+            inode.type_and_permission.set_mode(mode);
+        }
+    }
+}
+
+impl Display for DirTemplate {
+    /// The value of `TEMPLATE_XATTR`, without the extended attributes
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let mut items = Vec::new();
+        if let Some(uid) = self.uid {
+            items.push(format!("uid={uid}"));
+        }
+        if let Some(gid) = self.gid {
+            items.push(format!("gid={gid}"));
+        }
+        if let Some(mode) = self.mode {
+            items.push(format!("mode={mode:o}"));
+        }
+        if let Some(mode) = self.dir_mode {
+            items.push(format!("dirmode={mode:o}"));
+        }
+        f.write_str(&items.join(","))
+    }
+}
@@ -0,0 +1,74 @@
+use alloc::string::String;
+use core::fmt::Display;
+
+use crate::fs::{BlockArrayEntry, FileSystem, FsError};
+
+/// A specific kind of damage `corrupt` does to an image, each one showing up as a different
+/// `FsckProblem`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CorruptionKind {
+    /// flips the block array entry of the data block `block` between allocated and free, which
+    /// `fsck` reports as an unmarked or leaked block
+    FlipBitmapBit { block: u32 },
+    /// points the first block of the file `to` at the first block of the file `from`, so both
+    /// share a block and the old block of `to` is leaked
+    CrossLink { from: u32, to: u32 },
+    /// removes the entry `name` from the directory `dir` without unlinking its inode, which is
+    /// then in use but unreachable
+    Orphan { dir: u32, name: String },
+    /// moves the earliest free block hint of the superblock past the end of the image
+    Superblock,
+}
+
+impl Display for CorruptionKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::FlipBitmapBit { block } => write!(f, "flipped the bitmap bit of block {block}"),
+            Self::CrossLink { from, to } => {
+                write!(
+                    f,
+                    "cross-linked the first block of inode {from} into inode {to}"
+                )
+            }
+            Self::Orphan { dir, name } => {
+                write!(f, "orphaned entry {name:?} of inode {dir}")
+            }
+            Self::Superblock => write!(f, "moved the earliest free block past the end"),
+        }
+    }
+}
+
+/// Damages `fs` on purpose, to practice finding and repairing specific problems with `fsck`.
+/// Nothing checks whether the image is still usable afterwards, so only use this on copies.
+pub fn corrupt(fs: &mut FileSystem, kind: &CorruptionKind) -> Result<(), FsError> {
+    match kind {
+        CorruptionKind::FlipBitmapBit { block } => {
+            if *block < fs.reserved_blocks().end {
+                return Err(FsError::InvalidBlock);
+            }
+            match fs.block_type(*block)? {
+                BlockArrayEntry::Allocated => fs.mark_block(*block, BlockArrayEntry::Unused),
+                BlockArrayEntry::Unused => fs.mark_block(*block, BlockArrayEntry::Allocated),
+                _ => Err(FsError::InvalidBlock),
+            }
+        }
+        CorruptionKind::CrossLink { from, to } => {
+            let shared = fs.read_inode(*from)?.block_pointers[0];
+            let mut inode = fs.read_inode(*to)?;
+            if shared == 0 || inode.block_pointers[0] == 0 || from == to {
+                return Err(FsError::InvalidBlock);
+            }
+            inode.block_pointers[0] = shared;
+            fs.write_inode(*to, &inode)
+        }
+        CorruptionKind::Orphan { dir, name } => {
+            let mut inode = fs.read_inode(*dir)?;
+            inode.remove_dir_entry(fs, name.as_bytes(), *dir)?;
+            Ok(())
+        }
+        CorruptionKind::Superblock => {
+            fs.superblock.earliest_free = fs.superblock.total_blocks;
+            fs.write_superblock()
+        }
+    }
+}
@@ -0,0 +1,147 @@
+//! Timestamps as sfs stores them: signed seconds since the UNIX epoch and nanoseconds, so times
+//! before 1970 (e.g. from old archives) and after 2106 survive a round trip through an image
+
+use core::{fmt::Display, str::FromStr};
+
+#[cfg(feature = "std")]
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const NANOS_PER_SEC: u32 = 1_000_000_000;
+
+/// A point in time: `secs` seconds since 1970-01-01 00:00 UTC, negative before it, plus `nanos`
+/// nanoseconds (always forward in time, so -0.5s is `secs: -1, nanos: 500_000_000`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp {
+    pub secs: i64,
+    /// below `NANOS_PER_SEC`
+    pub nanos: u32,
+}
+
+impl Timestamp {
+    pub const EPOCH: Self = Self { secs: 0, nanos: 0 };
+
+    /// `nanos` past `NANOS_PER_SEC` carry over into the seconds
+    pub const fn new(secs: i64, nanos: u32) -> Self {
+        Self {
+            secs: secs.saturating_add((nanos / NANOS_PER_SEC) as i64),
+            nanos: nanos % NANOS_PER_SEC,
+        }
+    }
+
+    /// The whole seconds of `secs` UNIX-Time, like the clocks return
+    pub const fn from_secs(secs: u64) -> Self {
+        Self {
+            secs: if secs > i64::MAX as u64 {
+                i64::MAX
+            } else {
+                secs as i64
+            },
+            nanos: 0,
+        }
+    }
+
+    /// The time as an image without the `wide_times` feature stores it: whole seconds, times
+    /// before 1970 become 1970
+    pub const fn to_unsigned(self) -> u64 {
+        if self.secs < 0 {
+            0
+        } else {
+            self.secs as u64
+        }
+    }
+
+    #[cfg(feature = "std")]
+    pub fn from_system_time(time: SystemTime) -> Self {
+        match time.duration_since(UNIX_EPOCH) {
+            Ok(since) => Self::new(
+                i64::try_from(since.as_secs()).unwrap_or(i64::MAX),
+                since.subsec_nanos(),
+            ),
+            Err(error) => {
+                let before = error.duration();
+                let secs = i64::try_from(before.as_secs()).unwrap_or(i64::MAX);
+                match before.subsec_nanos() {
+                    0 => Self::new(-secs, 0),
+                    nanos => Self::new(-secs - 1, NANOS_PER_SEC - nanos),
+                }
+            }
+        }
+    }
+
+    /// The time as a `SystemTime`, clamped to the range the host can represent
+    #[cfg(feature = "std")]
+    pub fn to_system_time(self) -> SystemTime {
+        let time = if self.secs >= 0 {
+            UNIX_EPOCH.checked_add(Duration::new(self.secs as u64, self.nanos))
+        } else {
+            UNIX_EPOCH
+                .checked_sub(Duration::from_secs(self.secs.unsigned_abs()))
+                .and_then(|time| time.checked_add(Duration::from_nanos(self.nanos as u64)))
+        };
+        time.unwrap_or(UNIX_EPOCH)
+    }
+}
+
+impl From<u64> for Timestamp {
+    fn from(secs: u64) -> Self {
+        Self::from_secs(secs)
+    }
+}
+
+/// Decimal seconds like pax headers write them, `-1.5`, `1700000000` or `1700000000.25`
+impl Display for Timestamp {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if self.nanos == 0 {
+            return write!(f, "{}", self.secs);
+        }
+        let (sign, secs, nanos) = if self.secs < 0 {
+            (
+                "-",
+                (self.secs + 1).unsigned_abs(),
+                NANOS_PER_SEC - self.nanos,
+            )
+        } else {
+            ("", self.secs as u64, self.nanos)
+        };
+        let fraction = alloc::format!("{nanos:09}");
+        write!(f, "{sign}{secs}.{}", fraction.trim_end_matches('0'))
+    }
+}
+
+/// A timestamp that isn't decimal seconds, see `Timestamp::from_str`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseTimestampError;
+
+impl Display for ParseTimestampError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("invalid timestamp")
+    }
+}
+
+/// Parses what `Display` writes. Digits past nanoseconds are cut off.
+impl FromStr for Timestamp {
+    type Err = ParseTimestampError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (negative, text) = match text.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, text),
+        };
+        let (secs, fraction) = text.split_once('.').unwrap_or((text, ""));
+        let digits = |text: &str| !text.is_empty() && text.bytes().all(|b| b.is_ascii_digit());
+        if !digits(secs) || !(fraction.is_empty() || digits(fraction)) {
+            return Err(ParseTimestampError);
+        }
+        let secs: i64 = secs.parse().map_err(|_| ParseTimestampError)?;
+        let nanos = fraction
+            .bytes()
+            .chain(core::iter::repeat(b'0'))
+            .take(9)
+            .fold(0, |nanos, digit| nanos * 10 + (digit - b'0') as u32);
+        Ok(match (negative, nanos) {
+            (false, _) => Self::new(secs, nanos),
+            (true, 0) => Self::new(-secs, 0),
+            (true, _) => Self::new(-secs - 1, NANOS_PER_SEC - nanos),
+        })
+    }
+}
@@ -0,0 +1,169 @@
+use crate::{
+    fs::{FileSystem, FsError},
+    inode::InodeType,
+};
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+/// The directory in the root that holds unlinked files, see `MountOptions::trash_expiry`
+pub const TRASH_DIR: &str = ".trash";
+/// The extended attribute of a trashed inode that records where it was unlinked from, as
+/// `<parent inode>/<name>`
+pub const TRASH_ORIGIN_XATTR: &str = "trash.origin";
+
+/// A file in the trash, see `FileSystem::list_trash`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrashEntry {
+    pub inode: u32,
+    /// when it was unlinked, the name of its directory in the trash
+    pub deleted: u64,
+    /// the name in the trash, which gets a `.<n>` suffix if another file of the same name was
+    /// unlinked in the same second
+    pub name: String,
+    /// the directory it was unlinked from
+    pub origin_parent: u32,
+    /// the name it had there
+    pub origin_name: String,
+}
+
+/// The trash directory, `None` if nothing was ever trashed
+fn trash_dir(fs: &mut FileSystem) -> Result<Option<u32>, FsError> {
+    match fs.lookup(fs.superblock.root_inode, TRASH_DIR) {
+        Ok(trash) => Ok(Some(trash)),
+        Err(FsError::NoEntry) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// The subdirectories of the trash by the time their entries were unlinked
+fn buckets(fs: &mut FileSystem, trash: u32) -> Result<Vec<(u64, u32)>, FsError> {
+    let entries = fs.read_inode(trash)?.read_dir_entries(fs)?;
+    Ok(entries
+        .into_iter()
+        .filter_map(|entry| Some((entry.get_name().parse().ok()?, entry.inode)))
+        .collect())
+}
+
+/// Whether `dir_nbr` is the trash or one of its subdirectories, whose entries are freed for real
+pub(crate) fn is_trash(fs: &mut FileSystem, dir_nbr: u32) -> Result<bool, FsError> {
+    let Some(trash) = trash_dir(fs)? else {
+        return Ok(false);
+    };
+    Ok(dir_nbr == trash
+        || buckets(fs, trash)?
+            .into_iter()
+            .any(|(_, bucket)| bucket == dir_nbr))
+}
+
+/// Moves the entry `name` of `parent_nbr` into `/.trash/<now>/`, first freeing everything that
+/// expired
+pub(crate) fn move_to_trash(
+    fs: &mut FileSystem,
+    parent_nbr: u32,
+    name: &str,
+    expiry: u64,
+) -> Result<(), FsError> {
+    let now = fs.now();
+    purge(fs, now.saturating_sub(expiry))?;
+
+    let inode_nbr = fs.lookup(parent_nbr, name)?;
+    let root = fs.superblock.root_inode;
+    let trash = fs.lookup_or_mkdir(root, TRASH_DIR)?;
+    let bucket = fs.lookup_or_mkdir(trash, &now.to_string())?;
+
+    let mut trash_name = name.to_string();
+    for n in 1.. {
+        match fs.lookup(bucket, &trash_name) {
+            Ok(_) => trash_name = format!("{name}.{n}"),
+            Err(FsError::NoEntry) => break,
+            Err(e) => return Err(e),
+        }
+    }
+
+    let origin = format!("{parent_nbr}/{name}");
+    fs.set_xattr(inode_nbr, TRASH_ORIGIN_XATTR, origin.as_bytes())?;
+    fs.rename(parent_nbr, name, bucket, &trash_name)
+}
+
+/// Every file in the trash, the oldest first
+pub(crate) fn list(fs: &mut FileSystem) -> Result<Vec<TrashEntry>, FsError> {
+    let Some(trash) = trash_dir(fs)? else {
+        return Ok(Vec::new());
+    };
+    let mut buckets = buckets(fs, trash)?;
+    buckets.sort();
+
+    let mut list = Vec::new();
+    for (deleted, bucket) in buckets {
+        for entry in fs.read_inode(bucket)?.read_dir_entries(fs)? {
+            // only `unlink` puts files here, but anything else can be moved in by hand
+            let origin = match fs.get_xattr(entry.inode, TRASH_ORIGIN_XATTR) {
+                Ok(origin) => origin,
+                Err(FsError::NoAttribute) => continue,
+                Err(e) => return Err(e),
+            };
+            let (origin_parent, origin_name) = core::str::from_utf8(&origin)
+                .ok()
+                .and_then(|origin| origin.split_once('/'))
+                .and_then(|(parent, name)| Some((parent.parse().ok()?, name.to_string())))
+                .ok_or(FsError::Corrupted)?;
+            list.push(TrashEntry {
+                inode: entry.inode,
+                deleted,
+                name: entry.get_name(),
+                origin_parent,
+                origin_name,
+            });
+        }
+    }
+    Ok(list)
+}
+
+/// Moves `entry` back to where it was unlinked from
+pub(crate) fn restore(fs: &mut FileSystem, entry: &TrashEntry) -> Result<(), FsError> {
+    let trash = trash_dir(fs)?.ok_or(FsError::NoEntry)?;
+    let bucket = fs.lookup(trash, &entry.deleted.to_string())?;
+    if fs.lookup(bucket, &entry.name)? != entry.inode {
+        return Err(FsError::NoEntry);
+    }
+    // the directory may have been removed since, and its inode reused
+    let parent = fs.read_inode(entry.origin_parent)?;
+    if parent.hardlinks == 0 || parent.type_and_permission.get_type() != InodeType::Directory {
+        return Err(FsError::NoEntry);
+    }
+    match fs.lookup(entry.origin_parent, &entry.origin_name) {
+        Ok(_) => return Err(FsError::AlreadyExists),
+        Err(FsError::NoEntry) => {}
+        Err(e) => return Err(e),
+    }
+
+    fs.rename(bucket, &entry.name, entry.origin_parent, &entry.origin_name)?;
+    fs.remove_xattr(entry.inode, TRASH_ORIGIN_XATTR)?;
+    if fs.read_inode(bucket)?.read_dir_entries(fs)?.is_empty() {
+        fs.rmdir(trash, &entry.deleted.to_string())?;
+    }
+    Ok(())
+}
+
+/// Frees every file unlinked before `before`. Returns how many were freed.
+pub(crate) fn purge(fs: &mut FileSystem, before: u64) -> Result<u32, FsError> {
+    let Some(trash) = trash_dir(fs)? else {
+        return Ok(0);
+    };
+
+    let mut purged = 0;
+    for (deleted, bucket) in buckets(fs, trash)? {
+        if deleted >= before {
+            continue;
+        }
+        for entry in fs.read_inode(bucket)?.read_dir_entries(fs)? {
+            fs.unlink_now(bucket, &entry.get_name())?;
+            purged += 1;
+        }
+        fs.rmdir(trash, &deleted.to_string())?;
+    }
+    Ok(purged)
+}
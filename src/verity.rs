@@ -0,0 +1,337 @@
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::ops::Range;
+
+use crate::{
+    addr::Addr,
+    dcache::Lru,
+    disk::{Disk, DiskError, IO},
+    features::COMPAT_VERITY,
+    fs::{FileSystem, FsError, BLOCK_SIZE},
+    sha256::Sha256,
+};
+
+pub const VERITY_SIGNATURE: &[u8; 8] = b"SFs vrty";
+/// The hashes in one block of the tree
+const HASHES_PER_BLOCK: u32 = (BLOCK_SIZE / 32) as u32;
+/// How many checked blocks of the tree are kept in memory, 4 MiB covering the hashes of 512 MiB
+/// of data
+const VERIFIED_CAPACITY: usize = 1024;
+
+/// The first block of the hash tree, the levels follow it from the leaves up
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct VerityHeader {
+    signature: [u8; 8],
+    levels: u32,
+    reserved: [u8; 4],
+    /// the SHA-256 of the single block of the top level
+    root: [u8; 32],
+}
+
+impl VerityHeader {
+    fn read(disk: &mut Disk, start: u32) -> Result<Self, FsError> {
+        Ok(disk.read_struct(Addr::block(start)?.get())?)
+    }
+}
+
+/// The number of blocks of every level of the tree over `total_blocks` blocks, from the leaves
+/// (one hash per block of the image) up to the single block the root hash covers
+fn level_sizes(total_blocks: u32) -> Vec<u32> {
+    let mut sizes = vec![total_blocks.div_ceil(HASHES_PER_BLOCK)];
+    while sizes[sizes.len() - 1] > 1 {
+        sizes.push(sizes[sizes.len() - 1].div_ceil(HASHES_PER_BLOCK));
+    }
+    sizes
+}
+
+/// The number of blocks the hash tree of an image of `total_blocks` blocks takes up, including
+/// its header
+pub fn tree_blocks(total_blocks: u32) -> u32 {
+    1 + level_sizes(total_blocks).iter().sum::<u32>()
+}
+
+/// The first block of every level of the tree starting at `start`
+fn level_starts(start: u32, total_blocks: u32) -> Vec<u32> {
+    level_sizes(total_blocks)
+        .iter()
+        .scan(start + 1, |next, size| {
+            let level = *next;
+            *next += size;
+            Some(level)
+        })
+        .collect()
+}
+
+fn hash(block: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(block);
+    hasher.finalize()
+}
+
+/// Builds the hash tree over every block of the image in a run of free blocks, replacing the
+/// old one, and returns its root hash. The blocks of the tree itself are hashed as zeros.
+pub(crate) fn build(fs: &mut FileSystem) -> Result<[u8; 32], FsError> {
+    if fs.disk().is_read_only() {
+        return Err(FsError::ReadOnly);
+    }
+    let total = fs.superblock.total_blocks;
+    let len = tree_blocks(total);
+    let mut area = fs.verity_area();
+    if area.len() as u32 != len {
+        for block in area {
+            fs.free_block(block)?;
+        }
+        let start = fs.find_free_run(len)?.ok_or(FsError::NoSpace)?;
+        for block in start..start + len {
+            fs.allocate_block_id(block)?;
+        }
+        fs.superblock.feature_compat |= COMPAT_VERITY;
+        fs.superblock.verity_start = start;
+        fs.superblock.verity_blocks = len;
+        fs.write_superblock()?;
+        area = fs.verity_area();
+    }
+
+    let zeros = hash(&[0; BLOCK_SIZE]);
+    let mut block = vec![0; BLOCK_SIZE];
+    let mut hashes = Vec::with_capacity(total as usize);
+    for block_id in 0..total {
+        if area.contains(&block_id) {
+            hashes.push(zeros);
+        } else {
+            fs.disk()
+                .read_exact(Addr::block(block_id)?.get(), &mut block)?;
+            hashes.push(hash(&block));
+        }
+    }
+
+    let starts = level_starts(area.start, total);
+    for start in &starts {
+        let mut next = Vec::new();
+        for (i, chunk) in hashes.chunks(HASHES_PER_BLOCK as usize).enumerate() {
+            block.fill(0);
+            for (slot, hash) in block.chunks_exact_mut(32).zip(chunk) {
+                slot.copy_from_slice(hash);
+            }
+            fs.disk()
+                .write_exact(Addr::block(start + i as u32)?.get(), &block)?;
+            next.push(hash(&block));
+        }
+        hashes = next;
+    }
+
+    let header = VerityHeader {
+        signature: *VERITY_SIGNATURE,
+        levels: starts.len() as u32,
+        reserved: [0; 4],
+        root: hashes[0],
+    };
+    fs.disk()
+        .write_struct(Addr::block(area.start)?.get(), &header)?;
+    fs.disk().sync()?;
+    Ok(header.root)
+}
+
+/// The root hash recorded in the header of the tree, `None` if the image has none. It comes from
+/// the image itself, so it only detects accidental corruption; see `MountOptions::verity_root`.
+pub(crate) fn recorded_root(fs: &mut FileSystem) -> Result<Option<[u8; 32]>, FsError> {
+    let area = fs.verity_area();
+    if area.is_empty() {
+        return Ok(None);
+    }
+    let header = VerityHeader::read(fs.disk(), area.start)?;
+    if header.signature != *VERITY_SIGNATURE {
+        return Err(FsError::Corrupted);
+    }
+    Ok(Some(header.root))
+}
+
+/// Makes every later read of the read-only `fs` check the blocks it reads against the tree with
+/// the root hash `root`, or the recorded one if `root` is `None`
+pub(crate) fn enable(fs: &mut FileSystem, root: Option<[u8; 32]>) -> Result<(), FsError> {
+    let area = fs.verity_area();
+    let total = fs.superblock.total_blocks;
+    let header = VerityHeader::read(fs.disk(), area.start)?;
+    if header.signature != *VERITY_SIGNATURE
+        || header.levels as usize != level_sizes(total).len()
+        || area.len() as u32 != tree_blocks(total)
+    {
+        return Err(FsError::Corrupted);
+    }
+    let root = root.unwrap_or(header.root);
+    if root != header.root {
+        return Err(FsError::BadRootHash);
+    }
+    fs.disk().wrap_io(|io| {
+        Box::new(VerityIO {
+            io,
+            levels: level_starts(area.start, total),
+            area,
+            total,
+            root,
+            verified: Lru::new(VERIFIED_CAPACITY),
+        })
+    });
+    Ok(())
+}
+
+/// Checks every block read from `io` against the hash tree, failing with
+/// `DiskError::VerityMismatch` for blocks that don't match. The blocks of the tree are checked
+/// up to the root as they are needed, the most recently used ones are kept in memory afterwards.
+struct VerityIO {
+    io: Box<dyn IO>,
+    area: Range<u32>,
+    total: u32,
+    /// the first block of every level, from the leaves up
+    levels: Vec<u32>,
+    root: [u8; 32],
+    verified: Lru<u32, Box<[u8; BLOCK_SIZE]>>,
+}
+
+impl VerityIO {
+    /// Reads the block `index` of the level `level` of the tree and checks it against the levels
+    /// above it
+    fn read_tree_block(
+        &mut self,
+        level: usize,
+        index: u32,
+    ) -> Result<Box<[u8; BLOCK_SIZE]>, DiskError> {
+        let block_id = self.levels[level] + index;
+        let mut data = Box::new([0; BLOCK_SIZE]);
+        self.io
+            .read_exact(Addr::block_io(block_id)?.get(), data.as_mut_slice())?;
+        let expected = if level + 1 == self.levels.len() {
+            self.root
+        } else {
+            self.hash_of(level + 1, index)?
+        };
+        if hash(data.as_slice()) != expected {
+            return Err(DiskError::VerityMismatch { block: block_id });
+        }
+        Ok(data)
+    }
+
+    /// The hash the level `level` of the tree holds for the `index`th block of the level below
+    fn hash_of(&mut self, level: usize, index: u32) -> Result<[u8; 32], DiskError> {
+        let offset = (index % HASHES_PER_BLOCK) as usize * 32;
+        let block_id = self.levels[level] + index / HASHES_PER_BLOCK;
+        if let Some(block) = self.verified.get(&block_id) {
+            return Ok(block[offset..offset + 32].try_into().unwrap());
+        }
+        let block = self.read_tree_block(level, index / HASHES_PER_BLOCK)?;
+        let hash = block[offset..offset + 32].try_into().unwrap();
+        self.verified.insert(block_id, block);
+        Ok(hash)
+    }
+}
+
+impl IO for VerityIO {
+    fn read_lossy(&mut self, addr: usize, buf: &mut [u8]) -> Result<usize, DiskError> {
+        let mut done = 0;
+        let mut block = [0; BLOCK_SIZE];
+        while done < buf.len() {
+            let block_id = ((addr + done) / BLOCK_SIZE) as u32;
+            let off = (addr + done) % BLOCK_SIZE;
+            let len = (BLOCK_SIZE - off).min(buf.len() - done);
+            // the tree checks itself, and there is nothing past the end of the image to check
+            if self.area.contains(&block_id) || block_id >= self.total {
+                let read = self
+                    .io
+                    .read_lossy(addr + done, &mut buf[done..done + len])?;
+                done += read;
+                if read != len {
+                    break;
+                }
+                continue;
+            }
+            self.io
+                .read_exact(Addr::block_io(block_id)?.get(), &mut block)?;
+            if hash(&block) != self.hash_of(0, block_id)? {
+                return Err(DiskError::VerityMismatch { block: block_id });
+            }
+            buf[done..done + len].copy_from_slice(&block[off..off + len]);
+            done += len;
+        }
+        Ok(done)
+    }
+
+    fn write_lossy(&mut self, addr: usize, buf: &[u8]) -> Result<usize, DiskError> {
+        // only installed on read-only disks, but the tree wouldn't match after a write anyway
+        self.verified.clear();
+        self.io.write_lossy(addr, buf)
+    }
+
+    fn sync(&mut self) -> Result<(), DiskError> {
+        self.io.sync()
+    }
+
+    fn size(&mut self) -> Option<u64> {
+        self.io.size()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::{fs::MountOptions, Sfs};
+
+    const BLOCKS: u32 = 600;
+
+    /// An image with some files and a hash tree, its root hash and the tree area
+    fn image_with_tree() -> (Vec<u8>, [u8; 32], Range<u32>) {
+        let mut sfs = Sfs::from(FileSystem::create(BLOCKS, "verity").unwrap());
+        for i in 0..40u8 {
+            let path = format!("/file{i}");
+            sfs.write(path.as_str(), &[i; 3 * BLOCK_SIZE]).unwrap();
+        }
+        let root = sfs.fs().build_verity().unwrap();
+        let area = sfs.fs().verity_area();
+        let mut image = vec![0; Addr::block(BLOCKS).unwrap().get()];
+        sfs.fs().export_image(&mut image).unwrap();
+        (image, root, area)
+    }
+
+    fn verity_io(image: Vec<u8>, root: [u8; 32], area: Range<u32>, capacity: usize) -> VerityIO {
+        VerityIO {
+            io: Box::new(image),
+            levels: level_starts(area.start, BLOCKS),
+            area,
+            total: BLOCKS,
+            root,
+            verified: Lru::new(capacity),
+        }
+    }
+
+    #[test]
+    fn mounts_with_the_root_hash_and_reads_files() {
+        let (image, root, _) = image_with_tree();
+        let options = MountOptions {
+            verity_root: Some(root),
+            ..Default::default()
+        };
+        let fs = FileSystem::mount(Disk::new(Box::new(image)), options).unwrap();
+        let mut sfs = Sfs::from(fs);
+        assert_eq!(sfs.read("/file7").unwrap(), [7; 3 * BLOCK_SIZE]);
+    }
+
+    #[test]
+    fn checks_every_block_with_a_tiny_cache() {
+        let (image, root, area) = image_with_tree();
+        // one cached tree block, every other lookup evicts and checks again
+        let mut io = verity_io(image.clone(), root, area.clone(), 1);
+        let mut read = vec![0; image.len()];
+        io.read_exact(0, &mut read).unwrap();
+        assert!(read == image);
+
+        let mut tampered = image;
+        let block = BLOCKS - 1;
+        let addr = Addr::block(block).unwrap().get();
+        tampered[addr] ^= 1;
+        let mut io = verity_io(tampered, root, area, 1);
+        let mut buf = [0; BLOCK_SIZE];
+        assert!(matches!(
+            io.read_exact(addr, &mut buf),
+            Err(DiskError::VerityMismatch { block: b }) if b == block
+        ));
+    }
+}
@@ -0,0 +1,77 @@
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use crate::fs::{FsError, BLOCK_SIZE};
+
+/// The longest name of an extended attribute, in bytes
+pub const XATTR_NAME_LENGTH: usize = 255;
+/// The bytes in front of the name and value of every attribute
+pub(crate) const XATTR_HEADER_SIZE: usize = 3;
+
+/// The extended attributes of an inode, stored in a single block. Every attribute is a 1-byte name
+/// length, a 2-byte value length, the name and the value; a name length of zero ends the list.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct XattrBlock {
+    /// sorted by name, so the same attributes always give the same block
+    pub(crate) entries: BTreeMap<String, Vec<u8>>,
+}
+
+impl XattrBlock {
+    pub(crate) fn parse(data: &[u8; BLOCK_SIZE]) -> Result<Self, FsError> {
+        let mut entries = BTreeMap::new();
+        let mut off = 0;
+
+        while off + XATTR_HEADER_SIZE <= BLOCK_SIZE && data[off] != 0 {
+            let name_len = data[off] as usize;
+            let value_len = u16::from_ne_bytes([data[off + 1], data[off + 2]]) as usize;
+            let name_start = off + XATTR_HEADER_SIZE;
+            let value_start = name_start + name_len;
+            let end = value_start + value_len;
+            if end > BLOCK_SIZE {
+                return Err(FsError::Corrupted);
+            }
+
+            let name = core::str::from_utf8(&data[name_start..value_start])
+                .map_err(|_| FsError::Corrupted)?;
+            entries.insert(name.to_string(), data[value_start..end].to_vec());
+            off = end;
+        }
+
+        Ok(Self { entries })
+    }
+
+    /// The block holding every attribute, `FsError::NoSpace` if they don't fit
+    pub(crate) fn to_block(&self) -> Result<Box<[u8; BLOCK_SIZE]>, FsError> {
+        let mut data = Box::new([0; BLOCK_SIZE]);
+        let mut off = 0;
+
+        for (name, value) in &self.entries {
+            let end = off + XATTR_HEADER_SIZE + name.len() + value.len();
+            if end > BLOCK_SIZE {
+                return Err(FsError::NoSpace);
+            }
+            data[off] = name.len() as u8;
+            data[off + 1..off + 3].copy_from_slice(&(value.len() as u16).to_ne_bytes());
+            data[off + 3..off + 3 + name.len()].copy_from_slice(name.as_bytes());
+            data[off + 3 + name.len()..end].copy_from_slice(value);
+            off = end;
+        }
+
+        Ok(data)
+    }
+}
+
+/// Checks that `name` can be stored as the name of an attribute
+pub(crate) fn check_name(name: &str) -> Result<(), FsError> {
+    if name.is_empty() {
+        Err(FsError::NoAttribute)
+    } else if name.len() > XATTR_NAME_LENGTH {
+        Err(FsError::NameTooLong)
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+use core::ops::Range;
+
+use crate::fs::BLOCKS_PER_BLOCKARRAY;
+
+/// What a block is allocated for, see `ZoneLayout`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocZone {
+    /// inode blocks, indirect pointer blocks and directory blocks
+    Metadata = 0,
+    /// the blocks reachable through the direct pointers of a file, which is all of a small file
+    SmallFiles = 1,
+    /// everything past the direct pointers of a file
+    LargeFiles = 2,
+}
+
+/// Splits every block group (the blocks covered by one block array descriptor) into a region for
+/// metadata, one for small files and one for large files, so large files aren't interleaved with
+/// everything else and can be read sequentially. When a zone is full, blocks are taken from
+/// wherever there is space, like without zones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZoneLayout {
+    /// the share of every block group used for metadata, starting at the start of the group
+    pub metadata_percent: u8,
+    /// the share of every block group used for small files, right after the metadata zone. The
+    /// rest of the group holds large files.
+    pub small_files_percent: u8,
+}
+
+impl Default for ZoneLayout {
+    fn default() -> Self {
+        Self {
+            metadata_percent: 5,
+            small_files_percent: 25,
+        }
+    }
+}
+
+impl ZoneLayout {
+    /// The blocks of `zone` in the block group `group` of an image with `total_blocks` blocks
+    pub fn range(&self, group: u32, total_blocks: u32, zone: AllocZone) -> Range<u32> {
+        let start = group * BLOCKS_PER_BLOCKARRAY;
+        let end = (start + BLOCKS_PER_BLOCKARRAY).min(total_blocks);
+        let len = end.saturating_sub(start) as u64;
+        let split = |percent: u32| start + (len * percent as u64 / 100) as u32;
+
+        // shares over 100% in total are cut off at the end of the group
+        let metadata_percent = (self.metadata_percent as u32).min(100);
+        let metadata_end = split(metadata_percent);
+        let small_end = split((metadata_percent + self.small_files_percent as u32).min(100));
+        match zone {
+            AllocZone::Metadata => start..metadata_end,
+            AllocZone::SmallFiles => metadata_end..small_end,
+            AllocZone::LargeFiles => small_end..end,
+        }
+    }
+
+    /// The zone `block_id` lies in
+    pub fn zone_of(&self, block_id: u32, total_blocks: u32) -> AllocZone {
+        let group = block_id / BLOCKS_PER_BLOCKARRAY;
+        [AllocZone::Metadata, AllocZone::SmallFiles]
+            .into_iter()
+            .find(|zone| self.range(group, total_blocks, *zone).contains(&block_id))
+            .unwrap_or(AllocZone::LargeFiles)
+    }
+}